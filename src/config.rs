@@ -1,24 +1,112 @@
 use anyhow::{bail, Result};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
+use crate::patch::{join_blocks, ConvertTarget, Idempotency, SortMode};
+
 #[derive(Debug, Clone, Copy, Deserialize, Serialize)]
-#[serde(rename_all = "lowercase")]
+#[serde(rename_all = "kebab-case")]
 pub enum OperationType {
     Append,
     Replace,
     Delete,
+    Sort,
+    ReplaceBody,
+    ListAppend,
+    Toc,
+    Dedup,
+    Merge,
+    Ensure,
+    InsertSorted,
+    Convert,
+    AppendLineIfAbsent,
+    DefinitionAppend,
+    TrimTrailing,
+}
+
+/// `content` is either a single string or a list of strings, one block each
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ContentValue {
+    Single(String),
+    Blocks(Vec<String>),
+}
+
+impl ContentValue {
+    /// Content as one string, list entries separated by `separator` blank lines
+    pub fn join(&self, separator: usize) -> String {
+        match self {
+            ContentValue::Single(c) => c.clone(),
+            ContentValue::Blocks(blocks) => join_blocks(blocks, separator),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct OperationConfig {
     pub file: PathBuf,
+    #[serde(default)]
     pub heading: Vec<String>,
+    /// Apply to the whole document instead of `heading` (trim-trailing only)
+    #[serde(default)]
+    pub whole_file: bool,
     #[serde(default)]
     pub index: usize,
+    /// Count `index` across the section and all its subsections
+    #[serde(default)]
+    pub recursive_index: bool,
+    /// Target the heading line itself instead of the block at `index`
+    #[serde(default)]
+    pub heading_block: bool,
     pub operation: OperationType,
-    pub content: Option<String>,
+    pub content: Option<ContentValue>,
     pub fingerprint: Option<String>,
+    /// Select the block by a prefix of its content SHA-256 instead of `index`
+    pub block_hash: Option<String>,
+    /// Delete blocks `[first, last]` (inclusive) instead of the block at `index`
+    pub index_range: Option<[usize; 2]>,
+    /// Match the fingerprint regex case-insensitively
+    #[serde(default)]
+    pub fingerprint_ignore_case: bool,
+    /// Expected SHA-256 (hex) of the whole file before any operation runs
+    pub file_sha256: Option<String>,
+    /// Per-operation override of the global `--force` flag
+    #[serde(default)]
+    pub force: Option<bool>,
+    /// Per-operation override of the global `--no-backup` flag
+    #[serde(default)]
+    pub no_backup: Option<bool>,
+    /// How append detects already-present content
+    #[serde(default)]
+    pub idempotency: Idempotency,
+    /// Item comparison for the sort and insert-sorted operations
+    #[serde(default)]
+    pub sort_mode: SortMode,
+    /// Regex extracting the sort key for the sort and insert-sorted operations
+    pub sort_key: Option<String>,
+    /// Deepest heading level listed by the toc operation
+    #[serde(default = "default_toc_depth")]
+    pub toc_depth: u8,
+    /// Destination block type of the convert operation
+    pub to: Option<ConvertTarget>,
+    /// Regex identifying an existing line for append-line-if-absent
+    pub line_key: Option<String>,
+    /// Managed region the operation is confined to
+    pub region: Option<String>,
+    /// Append only: format the content like the target block
+    #[serde(default)]
+    pub match_style: bool,
+    /// Name other operations can refer to in `depends_on`
+    pub id: Option<String>,
+    /// Operations (by `id`, declared earlier) that must run first; this operation is then
+    /// resolved against their result instead of the original document
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+}
+
+fn default_toc_depth() -> u8 {
+    6
 }
 
 #[derive(Debug, Deserialize)]
@@ -32,17 +120,70 @@ pub fn load_config(path: &PathBuf) -> Result<Vec<OperationConfig>> {
     
     // Validate operations
     for (i, op) in config.operations.iter().enumerate() {
-        if op.heading.is_empty() {
+        if op.whole_file && !matches!(op.operation, OperationType::TrimTrailing) {
+            bail!("Operation {}: whole_file only applies to trim-trailing", i + 1);
+        }
+        if op.heading.is_empty() && !op.whole_file {
             bail!("Operation {}: heading path cannot be empty", i + 1);
         }
+        if op.index_range.is_some() && !matches!(op.operation, OperationType::Delete) {
+            bail!("Operation {}: index_range only applies to delete", i + 1);
+        }
+
+        // 正则在读取任何文件之前编译，错误直接指出所在的操作。
+        // heading 不在此列：批量配置没有正则匹配模式，heading 总是按字面比较
+        let patterns = [("fingerprint", &op.fingerprint), ("sort_key", &op.sort_key), ("line_key", &op.line_key)];
+        for (field, pattern) in patterns {
+            if let Some(pattern) = pattern {
+                if let Err(e) = Regex::new(pattern) {
+                    bail!("Operation {}: invalid {} regex '{}': {}", i + 1, field, pattern, e);
+                }
+            }
+        }
+
+        let earlier = &config.operations[..i];
+        if let Some(id) = &op.id {
+            if earlier.iter().any(|o| o.id.as_ref() == Some(id)) {
+                bail!("Operation {}: duplicate id '{}'", i + 1, id);
+            }
+        }
+        for dep in &op.depends_on {
+            if !earlier.iter().any(|o| o.id.as_ref() == Some(dep)) {
+                bail!(
+                    "Operation {}: depends_on '{}' does not name an earlier operation's id",
+                    i + 1,
+                    dep
+                );
+            }
+        }
         
         match op.operation {
-            OperationType::Append | OperationType::Replace => {
+            OperationType::Append
+            | OperationType::Replace
+            | OperationType::ReplaceBody
+            | OperationType::ListAppend
+            | OperationType::Ensure
+            | OperationType::InsertSorted
+            | OperationType::AppendLineIfAbsent
+            | OperationType::DefinitionAppend => {
                 if op.content.is_none() {
-                    bail!("Operation {}: content is required for append/replace", i + 1);
+                    bail!(
+                        "Operation {}: content is required for append/replace/replace-body/list-append/ensure/insert-sorted/append-line-if-absent/definition-append",
+                        i + 1
+                    );
+                }
+            }
+            OperationType::Convert => {
+                if op.to.is_none() {
+                    bail!("Operation {}: convert requires `to` (list, paragraph or quote)", i + 1);
                 }
             }
-            OperationType::Delete => {}
+            OperationType::Delete
+            | OperationType::Sort
+            | OperationType::Toc
+            | OperationType::Dedup
+            | OperationType::Merge
+            | OperationType::TrimTrailing => {}
         }
     }
     
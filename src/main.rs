@@ -1,6 +1,13 @@
 use anyhow::{bail, Context, Result};
 use clap::{Parser, Subcommand, ValueEnum};
-use std::path::PathBuf;
+use encoding_rs::Encoding;
+use indicatif::{ProgressBar, ProgressFinish, ProgressStyle};
+use std::io::IsTerminal;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 
 mod config;
 mod output;
@@ -8,8 +15,9 @@ mod parser;
 mod patch;
 
 use config::{load_config, OperationConfig};
-use output::{OutputFormat, OperationInfo};
-use patch::{PatchOperation, PatchResult};
+use output::{ColorTheme, FileDiff, OperationInfo, OutputFormat};
+use parser::{Flavor, HeadingMatch};
+use patch::{CommentMode, ConvertTarget, Idempotency, PatchOperation, PatchResult, SortMode};
 
 /// CLI tool for declarative, idempotent Markdown block patching
 #[derive(Parser)]
@@ -43,10 +51,39 @@ ADDRESSING MODEL:
       0 = heading 后的第一个内容块（段落、代码块等）
 ")]
 struct Cli {
+    /// Log per-phase timings (config load, read, parse/resolve, apply, write) to stderr
+    #[arg(long, global = true)]
+    trace: bool,
+
+    /// Columns per tab stop when measuring list indentation (tabs and spaces may be mixed)
+    #[arg(long, global = true, default_value = "4", value_parser = clap::value_parser!(u8).range(1..=16))]
+    tab_width: u8,
+
+    /// Parsing profile: which block features (tables, HTML heuristics) are recognized
+    #[arg(long, global = true, value_enum, default_value = "internal")]
+    profile: parser::Profile,
+
+    /// Remap exit codes, e.g. `heading_not_found=40,fingerprint_mismatch=41`
+    /// (names: error, heading_not_found, fingerprint_mismatch, ambiguous_heading,
+    /// file_changed, noop, changes_pending)
+    #[arg(long, global = true, value_parser = parse_exit_code_map)]
+    exit_code_map: Option<HashMap<&'static str, i32>>,
+
+    /// Do not take the `<file>.mdp.lock` sidecar lock that serializes concurrent
+    /// read-modify-write cycles on the same file
+    #[arg(long, global = true)]
+    no_lock: bool,
+
+    /// Seconds to wait for another mdp process to release a file's lock
+    #[arg(long, global = true, default_value = "10", value_name = "SECS")]
+    lock_timeout: u64,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+// 仅解析一次，`Patch` 的参数较多无需装箱
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
     /// Apply a single patch operation
@@ -55,37 +92,258 @@ enum Commands {
         #[arg(short, long)]
         file: PathBuf,
 
-        /// Heading path (e.g., "# Title" or "# Title ## Subtitle")
-        #[arg(short = 'H', long)]
-        heading: String,
+        /// Heading path (e.g., "# Title" or "# Title ## Subtitle"), optionally followed by a
+        /// block selector such as "## API > code[1]" or "## API > table"
+        #[arg(short = 'H', long, required_unless_present_any = ["line", "numbered", "whole_file"])]
+        heading: Option<String>,
 
         /// Block index within the heading section (0-based)
         #[arg(short, long, default_value = "0")]
         index: usize,
 
+        /// Select the block whose content SHA-256 starts with this hex prefix (see
+        /// `mdp get --format json`); errors when no block or several blocks match
+        #[arg(long, value_name = "PREFIX", conflicts_with_all = ["index", "line", "recursive_index", "after_heading", "heading_block"])]
+        block_hash: Option<String>,
+
+        /// Delete blocks START through END (inclusive, 0-based) of the section in one edit;
+        /// requires --force
+        #[arg(
+            long,
+            num_args = 2,
+            value_names = ["START", "END"],
+            conflicts_with_all = ["index", "line", "recursive_index", "after_heading", "heading_block", "block_hash"]
+        )]
+        index_range: Option<Vec<usize>>,
+
+        /// Target the block containing this document line (1-based) instead of heading/index
+        #[arg(long, conflicts_with = "heading")]
+        line: Option<usize>,
+
+        /// Address the section by outline number (e.g. 2.1.3) instead of heading text
+        #[arg(long, conflicts_with_all = ["heading", "line"])]
+        numbered: Option<String>,
+
+        /// Count the block index across the section and all its subsections
+        #[arg(long, requires = "heading")]
+        recursive_index: bool,
+
+        /// Append immediately after the heading line, before the first block (ignores --index)
+        #[arg(long, requires = "heading", conflicts_with_all = ["recursive_index", "fingerprint", "fingerprint_file"])]
+        after_heading: bool,
+
+        /// Target the heading line itself as a block (replace/delete, fingerprint matches the heading)
+        #[arg(long, conflicts_with_all = ["line", "after_heading", "recursive_index"])]
+        heading_block: bool,
+
+        /// Select the Nth (0-based) section when the heading is ambiguous
+        #[arg(long)]
+        occurrence: Option<usize>,
+
+        /// On an ambiguous heading, list every matching section with a disambiguating path
+        /// (and its --occurrence) before exiting; nothing is modified
+        #[arg(long, conflicts_with = "occurrence")]
+        keep_going: bool,
+
+        /// Match headings ignoring leading emoji; `:shortcode:`s and literal emoji are equivalent
+        #[arg(long, requires = "heading")]
+        ignore_emoji: bool,
+
+        /// Match headings ignoring inline formatting, so `## Bold` finds `## **Bold**`
+        #[arg(long, requires = "heading", conflicts_with_all = ["heading_regex", "ignore_emoji"])]
+        plain_headings: bool,
+
+        /// If no heading matches exactly, accept one that matches ignoring case and
+        /// whitespace (reported as a warning); without it, the near match is only suggested
+        #[arg(long, requires = "heading", conflicts_with = "heading_regex")]
+        fuzzy_headings: bool,
+
+        /// Treat each heading path segment as a regex matched against the heading line
+        /// (e.g. -H '^## v\d+\.\d+'); ambiguity rules still apply
+        #[arg(long, requires = "heading", conflicts_with = "ignore_emoji")]
+        heading_regex: bool,
+
+        /// Resolve the heading path relative to this parent path (e.g. --under '# Parent')
+        #[arg(long, requires = "heading")]
+        under: Option<String>,
+
         /// Operation type
         #[arg(short, long, value_enum)]
         op: OperationType,
 
-        /// Content to insert/replace (not needed for delete)
+        /// Content to insert/replace (not needed for delete). For replace, `{{block}}`
+        /// expands to the current block and `{{heading}}` to the section title.
+        /// Repeat to insert several blocks, separated by --separator blank lines
         #[arg(short, long)]
-        content: Option<String>,
+        content: Vec<String>,
+
+        /// Read the content from this environment variable instead of --content (avoids
+        /// argument length limits and shell quoting for large content)
+        #[arg(long, value_name = "VAR", conflicts_with = "content")]
+        content_env: Option<String>,
+
+        /// Strip the indentation common to all non-blank content lines before inserting
+        #[arg(long)]
+        dedent: bool,
+
+        /// Make append/replace a no-op when the content is missing, empty or whitespace-only
+        #[arg(long)]
+        no_op_on_missing_content: bool,
 
         /// Fingerprint regex for safety check
         #[arg(short = 'p', long)]
         fingerprint: Option<String>,
 
+        /// Read the fingerprint regex from this file (trailing newlines are ignored)
+        #[arg(long, conflicts_with = "fingerprint")]
+        fingerprint_file: Option<PathBuf>,
+
+        /// Match the fingerprint regex case-insensitively (block and heading fingerprints)
+        #[arg(long)]
+        fingerprint_ignore_case: bool,
+
+        /// Abort (exit code 5) unless the file's SHA-256 digest still equals this hex value
+        #[arg(long)]
+        file_sha256: Option<String>,
+
         /// Force execution of destructive operations
         #[arg(long)]
         force: bool,
 
+        /// Ask "Apply? [y/N]" (answer read from stdin) instead of refusing a destructive
+        /// operation without --force; EOF or any answer but y/yes aborts
+        #[arg(long, conflicts_with = "force")]
+        interactive: bool,
+
+        /// Confirm the --interactive prompt automatically
+        #[arg(long, requires = "interactive")]
+        yes: bool,
+
         /// Skip creating backup files (.bak)
         #[arg(long)]
         no_backup: bool,
 
+        /// Refuse to write through symlinks instead of updating the linked file
+        #[arg(long)]
+        no_follow_symlinks: bool,
+
+        /// Number of blank lines inserted before appended content
+        #[arg(long, default_value = "1")]
+        separator: usize,
+
+        /// How append detects that its content is already present
+        #[arg(long, value_enum, default_value = "exact")]
+        idempotency: Idempotency,
+
+        /// Refuse to operate on a target block larger than this many bytes
+        #[arg(long)]
+        max_block_bytes: Option<usize>,
+
+        /// Close unterminated code fences (before the next heading) when processing
+        #[arg(long)]
+        repair_fences: bool,
+
+        /// Treat content as literal prose: escape lines starting with `#` and refuse
+        /// to apply if the result would still gain a heading
+        #[arg(long)]
+        literal_content: bool,
+
+        /// Re-parse the result before writing and fail if the outline changed or the new
+        /// content is not found as a block of the target section
+        #[arg(long)]
+        verify_structure: bool,
+
+        /// Append: when a block (from the target onward) is at least this similar to the
+        /// content, update it instead of appending a near-duplicate (default ratio 0.8)
+        #[arg(long, value_name = "RATIO", num_args = 0..=1, default_missing_value = "0.8",
+              value_parser = parse_ratio, conflicts_with = "after_heading")]
+        merge_append: Option<f64>,
+
+        /// Append: format the content like the target block, e.g. as a new item when the
+        /// target is a list (renumbering ordered lists) or as `> ` lines after a quote
+        #[arg(long, conflicts_with_all = ["after_heading", "merge_append"])]
+        match_style: bool,
+
+        /// Treat consecutive paragraphs as one block, so -i 0 addresses the whole prose run
+        #[arg(long)]
+        coarse_blocks: bool,
+
+        /// Item comparison for the sort and insert-sorted operations
+        #[arg(long, value_enum, default_value = "lexical")]
+        sort_mode: SortMode,
+
+        /// Regex extracting the sort key from each list item or block (first capture group or whole match)
+        #[arg(long)]
+        sort_key: Option<String>,
+
+        /// Deepest heading level listed by the toc operation
+        #[arg(long, default_value_t = 6, value_parser = clap::value_parser!(u8).range(1..=6))]
+        toc_depth: u8,
+
+        /// Destination block type of the convert operation
+        #[arg(long, value_enum)]
+        to: Option<ConvertTarget>,
+
+        /// Regex identifying an existing line for append-line-if-absent (default: the same line)
+        #[arg(long)]
+        line_key: Option<String>,
+
+        /// Confine the operation to the managed region `<!-- mdp:begin NAME -->` ...
+        /// `<!-- mdp:end NAME -->`: only its headings and blocks resolve, and edits outside
+        /// it are refused
+        #[arg(long, value_name = "NAME")]
+        region: Option<String>,
+
+        /// Apply trim-trailing to the whole document instead of a section
+        #[arg(long, conflicts_with_all = ["heading", "line", "numbered", "under"])]
+        whole_file: bool,
+
+        /// Strip HTML comments before matching (`match`) or from the output too (`all`)
+        #[arg(long, value_enum, num_args = 0..=1, default_value = "keep", default_missing_value = "match")]
+        strip_comments: CommentMode,
+
+        /// Markdown flavor (defaults to mdx for .mdx files, markdown otherwise)
+        #[arg(long, value_enum)]
+        flavor: Option<Flavor>,
+
+        /// Character set of the input file(s) (e.g. latin1, utf-16le); content is
+        /// processed as UTF-8 and written back in the same charset
+        #[arg(long, value_parser = parse_charset)]
+        input_charset: Option<&'static Encoding>,
+
+        /// Refuse to read an input file larger than this many bytes (checked before reading)
+        #[arg(long)]
+        max_file_bytes: Option<u64>,
+
+        /// Rewrite every line ending of the written file to this style
+        #[arg(long, value_enum, value_name = "STYLE")]
+        normalize_eol: Option<Eol>,
+
+        /// Also write the unified diff to this file (applicable with `git apply` / `patch -p1`)
+        #[arg(long)]
+        emit_diff: Option<PathBuf>,
+
+        /// Print the patched document to stdout instead of writing the file
+        /// (`both`: also print the diff to stderr); the file is left untouched
+        #[arg(long, value_enum, conflicts_with = "interactive")]
+        emit: Option<Emit>,
+
+        /// Print the JSON result without writing the file, including the complete
+        /// would-be document as `new_content` (implies `--format json`)
+        #[arg(long, conflicts_with_all = ["emit", "interactive"])]
+        dry_run_json: bool,
+
+        /// Explain why the operation was a no-op when it leaves the file unchanged
+        #[arg(long)]
+        report_unchanged: bool,
+
         /// Output format
         #[arg(short = 'F', long, value_enum, default_value = "diff")]
         format: OutputFormat,
+
+        /// Color theme for diff and summary output
+        #[arg(long, visible_alias = "color-theme", value_enum, default_value = "default")]
+        theme: ColorTheme,
     },
 
     /// Apply patches from YAML configuration file
@@ -101,9 +359,64 @@ enum Commands {
         #[arg(long)]
         no_backup: bool,
 
+        /// Refuse to write through symlinks instead of updating the linked file
+        #[arg(long)]
+        no_follow_symlinks: bool,
+
+        /// Print the number of files actually modified
+        #[arg(long)]
+        print_count: bool,
+
+        /// Exit with code 10 when no file was modified
+        #[arg(long)]
+        fail_on_noop: bool,
+
+        /// Also write the combined unified diff to this file (applicable with `git apply` / `patch -p1`)
+        #[arg(long)]
+        emit_diff: Option<PathBuf>,
+
+        /// Allow operations whose targets overlap in the same file (applied in config order)
+        #[arg(long)]
+        allow_overlap: bool,
+
+        /// Explain why each operation that left its target unchanged was a no-op
+        #[arg(long)]
+        report_unchanged: bool,
+
+        /// Print only aggregate counts (operations, files touched, byte delta) as JSON,
+        /// without per-file diffs; implies `--format json`
+        #[arg(long, conflicts_with = "report_unchanged")]
+        summary_only: bool,
+
+        /// Show a progress bar on stderr while validating and writing (ignored when stderr is not a terminal)
+        #[arg(long)]
+        progress: bool,
+
+        /// Write patched files to the mirrored path under this directory instead of in place
+        /// (sources are left untouched, no backups are made)
+        #[arg(long)]
+        output_dir: Option<PathBuf>,
+
+        /// Character set of the input file(s) (e.g. latin1, utf-16le); content is
+        /// processed as UTF-8 and written back in the same charset
+        #[arg(long, value_parser = parse_charset)]
+        input_charset: Option<&'static Encoding>,
+
+        /// Refuse to read an input file larger than this many bytes (checked before reading)
+        #[arg(long)]
+        max_file_bytes: Option<u64>,
+
+        /// Rewrite every line ending of the written file to this style
+        #[arg(long, value_enum, value_name = "STYLE")]
+        normalize_eol: Option<Eol>,
+
         /// Output format
         #[arg(short = 'F', long, value_enum, default_value = "diff")]
         format: OutputFormat,
+
+        /// Color theme for diff and summary output
+        #[arg(long, visible_alias = "color-theme", value_enum, default_value = "default")]
+        theme: ColorTheme,
     },
 
     /// Preview changes without applying (dry-run)
@@ -111,12 +424,234 @@ enum Commands {
         /// Configuration file path
         config: PathBuf,
 
+        /// Exit with code 20 when at least one operation would change content (0 when all are no-ops)
+        #[arg(long)]
+        dry_run_exit_code: bool,
+
+        /// Allow operations whose targets overlap in the same file (applied in config order)
+        #[arg(long)]
+        allow_overlap: bool,
+
+        /// Explain why each operation that left its target unchanged was a no-op
+        #[arg(long)]
+        report_unchanged: bool,
+
+        /// Print only aggregate counts (operations, files touched, byte delta) as JSON,
+        /// without per-file diffs; implies `--format json`
+        #[arg(long, conflicts_with = "report_unchanged")]
+        summary_only: bool,
+
+        /// Character set of the input file(s) (e.g. latin1, utf-16le); content is
+        /// processed as UTF-8 and written back in the same charset
+        #[arg(long, value_parser = parse_charset)]
+        input_charset: Option<&'static Encoding>,
+
+        /// Refuse to read an input file larger than this many bytes (checked before reading)
+        #[arg(long)]
+        max_file_bytes: Option<u64>,
+
+        /// Output format
+        #[arg(short = 'F', long, value_enum, default_value = "diff")]
+        format: OutputFormat,
+
+        /// Color theme for diff and summary output
+        #[arg(long, visible_alias = "color-theme", value_enum, default_value = "default")]
+        theme: ColorTheme,
+    },
+
+    /// Print the parsed section/block structure
+    Parse {
+        /// Target file path
+        #[arg(short, long)]
+        file: PathBuf,
+
+        /// Output format (json for tooling, otherwise a text tree)
+        #[arg(short = 'F', long, value_enum, default_value = "diff")]
+        format: OutputFormat,
+
+        /// Markdown flavor (defaults to mdx for .mdx files, markdown otherwise)
+        #[arg(long, value_enum)]
+        flavor: Option<Flavor>,
+
+        /// Only list blocks of this type (block indices are kept)
+        #[arg(long, value_parser = clap::builder::PossibleValuesParser::new(parser::BlockType::KINDS))]
+        only_type: Option<String>,
+
+        /// Treat consecutive paragraphs as one block (same granularity as patch --coarse-blocks)
+        #[arg(long)]
+        coarse_blocks: bool,
+    },
+
+    /// Restore a file from its backup (.bak)
+    Revert {
+        /// Target file path
+        #[arg(short, long)]
+        file: PathBuf,
+    },
+
+    /// Apply a unified diff (e.g. from --emit-diff) to a file, or undo it with --reverse
+    ApplyDiff {
+        /// Target file path
+        #[arg(short, long)]
+        file: PathBuf,
+
+        /// Unified diff to apply
+        #[arg(long)]
+        diff: PathBuf,
+
+        /// Undo the diff instead of applying it
+        #[arg(long)]
+        reverse: bool,
+
+        /// Skip creating backup
+        #[arg(long)]
+        no_backup: bool,
+    },
+
+    /// Show how an address resolves, without modifying anything
+    Explain {
+        /// Target file path
+        #[arg(short, long)]
+        file: PathBuf,
+
+        /// Heading path (e.g., "# Title" or "# Title ## Subtitle")
+        #[arg(short = 'H', long)]
+        heading: String,
+
+        /// Block index within the heading section (0-based)
+        #[arg(short, long, default_value = "0")]
+        index: usize,
+
+        /// Select the Nth (0-based) section when the heading is ambiguous
+        #[arg(long)]
+        occurrence: Option<usize>,
+    },
+
+    /// Print the addressed block's raw content, without modifying anything
+    Get {
+        /// Target file path
+        #[arg(short, long)]
+        file: PathBuf,
+
+        /// Heading path (e.g., "# Title" or "# Title ## Subtitle")
+        #[arg(short = 'H', long)]
+        heading: String,
+
+        /// Block index within the heading section (0-based)
+        #[arg(short, long, default_value = "0")]
+        index: usize,
+
+        /// Select the Nth (0-based) section when the heading is ambiguous
+        #[arg(long)]
+        occurrence: Option<usize>,
+
+        /// Output format (json adds the block type, byte range and line span; otherwise
+        /// the raw content)
+        #[arg(short = 'F', long, value_enum, default_value = "diff")]
+        format: OutputFormat,
+    },
+
+    /// Check that a block still matches a fingerprint, without modifying anything
+    VerifyBlock {
+        /// Target file path
+        #[arg(short, long)]
+        file: PathBuf,
+
+        /// Heading path (e.g., "# Title" or "# Title ## Subtitle")
+        #[arg(short = 'H', long)]
+        heading: String,
+
+        /// Block index within the heading section (0-based)
+        #[arg(short, long, default_value = "0")]
+        index: usize,
+
+        /// Select the Nth (0-based) section when the heading is ambiguous
+        #[arg(long)]
+        occurrence: Option<usize>,
+
+        /// Fingerprint regex the block must match
+        #[arg(short = 'p', long)]
+        fingerprint: String,
+
+        /// Match the fingerprint regex case-insensitively
+        #[arg(long)]
+        fingerprint_ignore_case: bool,
+    },
+
+    /// Extract code blocks linked to a file (`file=path` in the info string or a
+    /// `<!-- file: path -->` comment before the block) into that file, without
+    /// modifying the document
+    Tangle {
+        /// Source Markdown file
+        #[arg(short, long)]
+        file: PathBuf,
+
+        /// Directory the linked paths are relative to
+        #[arg(long)]
+        out_dir: PathBuf,
+
+        /// Only extract code blocks of this language (first word of the info string)
+        #[arg(long)]
+        lang: Option<String>,
+    },
+
+    /// Replace the body of each code block linked to a file (see `tangle`) with that
+    /// file's current content
+    Weave {
+        /// Target Markdown file
+        #[arg(short, long)]
+        file: PathBuf,
+
+        /// Directory the linked paths are relative to
+        #[arg(long)]
+        from: PathBuf,
+
+        /// Only update code blocks of this language (first word of the info string)
+        #[arg(long)]
+        lang: Option<String>,
+
+        /// Write the changes (otherwise only the diff is shown)
+        #[arg(long)]
+        force: bool,
+
+        /// Skip creating backup
+        #[arg(long)]
+        no_backup: bool,
+
         /// Output format
         #[arg(short = 'F', long, value_enum, default_value = "diff")]
         format: OutputFormat,
     },
 }
 
+/// Line-ending style forced by `--normalize-eol`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Eol {
+    /// `\n`
+    Lf,
+    /// `\r\n`
+    Crlf,
+}
+
+impl Eol {
+    fn apply(self, text: &str) -> String {
+        let lf = text.replace("\r\n", "\n");
+        match self {
+            Eol::Lf => lf,
+            Eol::Crlf => lf.replace('\n', "\r\n"),
+        }
+    }
+}
+
+/// Where `patch --emit` sends the result instead of writing it back
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Emit {
+    /// The patched document on stdout
+    Document,
+    /// The patched document on stdout and the diff on stderr
+    Both,
+}
+
 #[derive(Clone, Copy, Debug, ValueEnum)]
 enum OperationType {
     /// Append content after the target block
@@ -125,6 +660,30 @@ enum OperationType {
     Replace,
     /// Delete the target block
     Delete,
+    /// Sort the items of the target list block
+    Sort,
+    /// Replace the whole body under the heading (up to the next sibling heading)
+    ReplaceBody,
+    /// Append an item to the target list block, renumbering ordered lists
+    ListAppend,
+    /// Insert (or refresh) a table of contents between `<!-- mdp:toc -->` markers
+    Toc,
+    /// Remove blocks that repeat an earlier block of the same section
+    Dedup,
+    /// Join adjacent paragraphs, lists or same-language code blocks of the same section
+    Merge,
+    /// Replace the block matching the fingerprint, or append the content when none matches
+    Ensure,
+    /// Insert content where it keeps the target list (or the following blocks) sorted
+    InsertSorted,
+    /// Re-render the target block as another type (see --to)
+    Convert,
+    /// Append one line to the target list or paragraph unless a line matches --line-key
+    AppendLineIfAbsent,
+    /// Append a `term` / `: definition` entry to the target definition list
+    DefinitionAppend,
+    /// Strip trailing whitespace from the lines of the section (or the file, with --whole-file)
+    TrimTrailing,
 }
 
 impl From<OperationType> for patch::Operation {
@@ -133,98 +692,813 @@ impl From<OperationType> for patch::Operation {
             OperationType::Append => patch::Operation::Append,
             OperationType::Replace => patch::Operation::Replace,
             OperationType::Delete => patch::Operation::Delete,
+            OperationType::Sort => patch::Operation::Sort,
+            OperationType::ReplaceBody => patch::Operation::ReplaceBody,
+            OperationType::ListAppend => patch::Operation::ListAppend,
+            OperationType::Toc => patch::Operation::Toc,
+            OperationType::Dedup => patch::Operation::Dedup,
+            OperationType::Merge => patch::Operation::Merge,
+            OperationType::Ensure => patch::Operation::Ensure,
+            OperationType::InsertSorted => patch::Operation::InsertSorted,
+            OperationType::Convert => patch::Operation::Convert,
+            OperationType::AppendLineIfAbsent => patch::Operation::AppendLineIfAbsent,
+            OperationType::DefinitionAppend => patch::Operation::DefinitionAppend,
+            OperationType::TrimTrailing => patch::Operation::TrimTrailing,
         }
     }
 }
 
 fn main() {
     if let Err(e) = run() {
-        eprintln!("Error: {}", e);
+        match ERROR_TARGET.get() {
+            Some(target) => output::print_error(
+                &e,
+                target.format,
+                error_kind(&e.to_string()),
+                target.file.as_ref(),
+                target.heading.as_deref(),
+                target.index,
+            ),
+            None => eprintln!("Error: {}", e),
+        }
         let exit_code = classify_error(&e.to_string());
         std::process::exit(exit_code);
     }
 }
 
+/// 出错时报告的输出格式与目标；`--format json`/`jsonl` 时错误同样以 JSON 输出
+struct ErrorTarget {
+    format: OutputFormat,
+    file: Option<PathBuf>,
+    heading: Option<String>,
+    index: Option<usize>,
+}
+
+/// 当前命令的 [`ErrorTarget`]，没有 `--format` 的命令不设置
+static ERROR_TARGET: OnceLock<ErrorTarget> = OnceLock::new();
+
+/// `--trace` 是否开启
+static TRACE: AtomicBool = AtomicBool::new(false);
+
+/// 执行 `f`，`--trace` 开启时向 stderr 输出该阶段耗时
+fn traced<T>(label: &str, f: impl FnOnce() -> T) -> T {
+    if !TRACE.load(Ordering::Relaxed) {
+        return f();
+    }
+    let start = Instant::now();
+    let result = f();
+    eprintln!("[trace] {}: {:.3} ms", label, start.elapsed().as_secs_f64() * 1000.0);
+    result
+}
+
+/// 错误类别名称及其默认退出码（`--exit-code-map` 按名称覆盖）
+const EXIT_CODES: [(&str, i32); 7] = [
+    ("error", 1),
+    ("heading_not_found", 2),
+    ("fingerprint_mismatch", 3),
+    ("ambiguous_heading", 4),
+    ("file_changed", 5),
+    ("noop", 10),
+    ("changes_pending", 20),
+];
+
+/// `--exit-code-map` 提供的退出码覆盖
+static EXIT_CODE_MAP: OnceLock<HashMap<&'static str, i32>> = OnceLock::new();
+
 /// 根据错误信息分类返回退出码
 fn classify_error(error_msg: &str) -> i32 {
-    if error_msg.contains("Fingerprint mismatch") {
-        3
+    let kind = error_kind(error_msg);
+    if let Some(&code) = EXIT_CODE_MAP.get().and_then(|map| map.get(kind)) {
+        return code;
+    }
+    EXIT_CODES.iter().find(|(name, _)| *name == kind).map_or(1, |&(_, code)| code)
+}
+
+/// 错误信息对应的类别名称（见 [`EXIT_CODES`]）
+fn error_kind(error_msg: &str) -> &'static str {
+    if error_msg.contains("(--fail-on-noop)") {
+        "noop"
+    } else if error_msg.contains("(--dry-run-exit-code)") {
+        "changes_pending"
+    } else if error_msg.contains("Fingerprint mismatch") || error_msg.contains("Block hash mismatch") {
+        "fingerprint_mismatch"
+    } else if error_msg.contains("File hash mismatch") {
+        "file_changed"
     } else if error_msg.contains("Multiple sections found") || error_msg.contains("Ambiguous") {
-        4
+        "ambiguous_heading"
     } else if error_msg.contains("Heading not found") || error_msg.contains("Subheading not found") {
-        2
-    } else if error_msg.contains("file") || error_msg.contains("path") || error_msg.contains("not found") {
-        1
+        "heading_not_found"
     } else {
-        1
+        "error"
+    }
+}
+
+/// `--exit-code-map` 的取值：逗号分隔的 `name=code`，code 为 1..=255
+fn parse_exit_code_map(value: &str) -> std::result::Result<HashMap<&'static str, i32>, String> {
+    let mut map = HashMap::new();
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let Some((name, code)) = entry.split_once('=') else {
+            return Err(format!("expected name=code, got '{}'", entry));
+        };
+        let Some(&(name, _)) = EXIT_CODES.iter().find(|(n, _)| *n == name.trim()) else {
+            let names: Vec<&str> = EXIT_CODES.iter().map(|(n, _)| *n).collect();
+            return Err(format!("unknown exit code name '{}' (expected one of: {})", name.trim(), names.join(", ")));
+        };
+        match code.trim().parse::<i32>() {
+            Ok(code) if (1..=255).contains(&code) => map.insert(name, code),
+            _ => return Err(format!("exit code for '{}' must be between 1 and 255", name)),
+        };
+    }
+    Ok(map)
+}
+
+/// 对未闭合的代码块输出警告（stderr），并返回警告文本供 JSON 输出使用
+fn warn_unterminated_fences(file: &Path, content: &str, repaired: bool) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for line in parser::unterminated_fences(content) {
+        let action = if repaired {
+            "closing it before the next heading"
+        } else {
+            "it swallows the rest of the document (use --repair-fences)"
+        };
+        let warning = format!("unterminated code fence at {}:{}; {}", file.display(), line, action);
+        eprintln!("Warning: {}", warning);
+        warnings.push(warning);
+    }
+    warnings
+}
+
+/// 非 JSON 格式下将操作产生的警告输出到 stderr（JSON 格式放在输出的 `warnings` 中）
+fn print_warnings(warnings: &[String], format: OutputFormat) {
+    if !format.is_json() {
+        for warning in warnings {
+            eprintln!("Warning: {}", warning);
+        }
+    }
+}
+
+/// 在完整文件名后追加后缀（`doc.markdown` -> `doc.markdown.bak`），不替换原扩展名
+fn with_suffix(file: &Path, suffix: &str) -> PathBuf {
+    let mut name = file.as_os_str().to_os_string();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// 文件锁：`<file>.mdp.lock` 旁路文件存在期间，其他 mdp 进程不会读改写该文件；释放时删除
+struct FileLock(PathBuf);
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// 获取文件锁，最多等待 `timeout`（符号链接按其真实路径加锁）
+fn lock_file(file: &Path, timeout: Duration) -> Result<FileLock> {
+    use std::io::Write;
+    let target = std::fs::canonicalize(file).unwrap_or_else(|_| file.to_path_buf());
+    let lock = with_suffix(&target, ".mdp.lock");
+    let started = Instant::now();
+    loop {
+        match std::fs::OpenOptions::new().write(true).create_new(true).open(&lock) {
+            Ok(mut handle) => {
+                let _ = writeln!(handle, "{}", std::process::id());
+                return Ok(FileLock(lock));
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                if started.elapsed() >= timeout {
+                    bail!(
+                        "Timed out after {}s waiting for {} held by another mdp process; \
+                         delete it if it is stale, or pass --no-lock",
+                        timeout.as_secs(),
+                        lock.display()
+                    );
+                }
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to create lock file: {}", lock.display()));
+            }
+        }
+    }
+}
+
+/// 将 diff 写入文件，供之后 `git apply` / `patch -p1` 使用（无改动时写入空文件）
+fn write_emitted_diff(path: &Path, diffs: &[String]) -> Result<()> {
+    std::fs::write(path, diffs.concat())
+        .with_context(|| format!("Failed to write diff: {}", path.display()))
+}
+
+/// 备份文件路径
+fn backup_path(file: &Path) -> PathBuf {
+    with_suffix(file, ".bak")
+}
+
+/// 校验文件（磁盘上的原始字节）的 SHA-256，不一致说明文件在获取 hash 之后被修改过
+fn verify_file_sha256(file: &Path, bytes: &[u8], expected: &str) -> Result<()> {
+    use sha2::{Digest, Sha256};
+    let actual: String = Sha256::digest(bytes).iter().map(|b| format!("{:02x}", b)).collect();
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        bail!(
+            "File hash mismatch: {} has sha256 {}, expected {}. The file changed since the hash was captured.",
+            file.display(),
+            actual,
+            expected.trim()
+        );
+    }
+    Ok(())
+}
+
+/// 从文件读取 fingerprint 正则（忽略末尾换行），避免在命令行上转义复杂的模式
+fn read_fingerprint_file(path: &Path) -> Result<String> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read fingerprint file: {}", path.display()))?;
+    let pattern = text.trim_end_matches(['\n', '\r']);
+    if pattern.is_empty() {
+        bail!("Fingerprint file {} is empty", path.display());
+    }
+    Ok(pattern.to_string())
+}
+
+/// 打印提示并从 stdin 读取一行回答；只有 y/yes 视为确认，EOF 视为拒绝
+fn confirm(prompt: &str) -> Result<bool> {
+    use std::io::Write;
+    print!("{}", prompt);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes"))
+}
+
+/// 0.0 到 1.0 之间的相似度阈值
+fn parse_ratio(value: &str) -> std::result::Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(ratio) if (0.0..=1.0).contains(&ratio) => Ok(ratio),
+        _ => Err(format!("'{}' is not a ratio between 0.0 and 1.0", value)),
+    }
+}
+
+/// `--input-charset` 的取值：WHATWG 编码标签（如 latin1、utf-16le、shift_jis）
+fn parse_charset(label: &str) -> std::result::Result<&'static Encoding, String> {
+    Encoding::for_label(label.as_bytes()).ok_or_else(|| format!("unknown charset '{}'", label))
+}
+
+/// 读取文件的原始字节
+///
+/// `max_bytes` 在读取前通过文件元数据检查，避免把超大文件整个读入内存
+fn read_bytes(file: &Path, max_bytes: Option<u64>) -> Result<Vec<u8>> {
+    if let Some(max) = max_bytes {
+        let size = std::fs::metadata(file)?.len();
+        if size > max {
+            bail!(
+                "{} is {} bytes, exceeding --max-file-bytes {}; refusing to read it",
+                file.display(),
+                size,
+                max
+            );
+        }
+    }
+    Ok(std::fs::read(file)?)
+}
+
+/// 将已读取的字节解码为 UTF-8 文本；指定字符集时先转码（去掉该字符集的 BOM），非法字节序列报错
+fn decode_text(file: &Path, bytes: Vec<u8>, charset: Option<&'static Encoding>) -> Result<String> {
+    let Some(encoding) = charset else {
+        return String::from_utf8(bytes).map_err(|_| anyhow::anyhow!("stream did not contain valid UTF-8"));
+    };
+    let bytes = match Encoding::for_bom(&bytes) {
+        Some((bom_encoding, len)) if bom_encoding == encoding => &bytes[len..],
+        _ => &bytes[..],
+    };
+    match encoding.decode_without_bom_handling_and_without_replacement(bytes) {
+        Some(text) => Ok(text.into_owned()),
+        None => bail!(
+            "Failed to decode {} as {}: invalid byte sequence",
+            file.display(),
+            encoding.name()
+        ),
+    }
+}
+
+/// 将 UTF-8 文本转回目标字符集；`bom` 为原文件是否带 BOM
+fn encode_text(file: &Path, content: &str, encoding: &'static Encoding, bom: bool) -> Result<Vec<u8>> {
+    // encoding_rs 不提供 UTF-16 编码器，手动转换
+    let utf16 = |to_bytes: fn(u16) -> [u8; 2]| -> Vec<u8> {
+        let bom_unit = bom.then_some(0xFEFF_u16);
+        bom_unit.into_iter().chain(content.encode_utf16()).flat_map(to_bytes).collect()
+    };
+    if encoding == encoding_rs::UTF_16LE {
+        return Ok(utf16(u16::to_le_bytes));
     }
+    if encoding == encoding_rs::UTF_16BE {
+        return Ok(utf16(u16::to_be_bytes));
+    }
+
+    let (bytes, _, unmappable) = encoding.encode(content);
+    if unmappable {
+        bail!(
+            "Failed to encode {} as {}: content has characters the charset cannot represent",
+            file.display(),
+            encoding.name()
+        );
+    }
+    let mut encoded = if bom { b"\xEF\xBB\xBF".to_vec() } else { Vec::new() };
+    encoded.extend_from_slice(&bytes);
+    Ok(encoded)
 }
 
 /// 原子写入文件：先备份（可选），再写临时文件，最后重命名
-fn atomic_write(file: &PathBuf, content: &str, no_backup: bool) -> Result<()> {
-    // 如果文件存在且不是禁止备份，先创建备份
+///
+/// 目标是符号链接时，默认写入链接指向的真实文件并保留链接本身；
+/// `follow_symlinks` 为 false 时拒绝写入。指定 `charset` 时按原字符集（及原有 BOM）写回。
+fn atomic_write(
+    file: &PathBuf,
+    content: &str,
+    no_backup: bool,
+    follow_symlinks: bool,
+    charset: Option<&'static Encoding>,
+    eol: Option<Eol>,
+) -> Result<()> {
+    let normalized = eol.map(|eol| eol.apply(content));
+    let content = normalized.as_deref().unwrap_or(content);
+    let target = if file.is_symlink() {
+        if !follow_symlinks {
+            bail!(
+                "Refusing to write through symlink {} (--no-follow-symlinks)",
+                file.display()
+            );
+        }
+        std::fs::canonicalize(file)
+            .with_context(|| format!("Failed to resolve symlink: {}", file.display()))?
+    } else {
+        file.clone()
+    };
+
+    // 如果文件存在且不是禁止备份，先创建备份（备份为普通文件，内容取自链接目标）
     if !no_backup && file.exists() {
-        let backup_path = file.with_extension("bak");
+        let backup_path = backup_path(file);
         std::fs::copy(file, &backup_path)
             .with_context(|| format!("Failed to create backup: {}", backup_path.display()))?;
     }
 
-    let temp_file = file.with_extension("md.tmp");
-    std::fs::write(&temp_file, content)?;
-    std::fs::rename(&temp_file, file)?;
+    let bytes = match charset {
+        Some(encoding) => {
+            let had_bom = std::fs::read(&target)
+                .ok()
+                .and_then(|existing| Encoding::for_bom(&existing))
+                .is_some_and(|(bom_encoding, _)| bom_encoding == encoding);
+            encode_text(file, content, encoding, had_bom)?
+        }
+        None => content.as_bytes().to_vec(),
+    };
+
+    let temp_file = with_suffix(&target, ".tmp");
+    std::fs::write(&temp_file, bytes)?;
+    std::fs::rename(&temp_file, &target)?;
+    Ok(())
+}
+
+/// `--output-dir` 下与源文件对应的路径：相对路径直接拼接，绝对路径去掉根后拼接
+fn mirrored_path(dir: &Path, file: &Path) -> Result<PathBuf> {
+    use std::path::Component;
+    let mut dest = dir.to_path_buf();
+    for component in file.components() {
+        match component {
+            Component::Normal(part) => dest.push(part),
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+            Component::ParentDir => bail!(
+                "Cannot mirror {} under --output-dir: paths with '..' would escape the directory",
+                file.display()
+            ),
+        }
+    }
+    Ok(dest)
+}
+
+/// 将修改后的内容写到 `dest`（按需创建目录），源文件保持不变；字符集与 BOM 与源文件一致
+fn write_copy(
+    source: &Path,
+    dest: &Path,
+    content: &str,
+    charset: Option<&'static Encoding>,
+    eol: Option<Eol>,
+) -> Result<()> {
+    let normalized = eol.map(|eol| eol.apply(content));
+    let content = normalized.as_deref().unwrap_or(content);
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+    }
+    let bytes = match charset {
+        Some(encoding) => {
+            let had_bom = std::fs::read(source)
+                .ok()
+                .and_then(|existing| Encoding::for_bom(&existing))
+                .is_some_and(|(bom_encoding, _)| bom_encoding == encoding);
+            encode_text(source, content, encoding, had_bom)?
+        }
+        None => content.as_bytes().to_vec(),
+    };
+    std::fs::write(dest, bytes).with_context(|| format!("Failed to write {}", dest.display()))
+}
+
+/// Resolve a code block's linked path under `dir`, refusing paths that would escape it
+fn linked_path(dir: &Path, path: &str) -> Result<PathBuf> {
+    let relative = Path::new(path);
+    if relative.is_absolute() || relative.components().any(|c| matches!(c, std::path::Component::ParentDir)) {
+        bail!("Linked file '{}' must be a relative path inside {}", path, dir.display());
+    }
+    Ok(dir.join(relative))
+}
+
+/// 将链接到文件的代码块写到 `out_dir` 下；同一路径的多个代码块按文档顺序拼接
+fn tangle(content: &str, sections: &[parser::Section], out_dir: &Path, lang: Option<&str>) -> Result<()> {
+    let mut outputs: Vec<(&str, String)> = Vec::new();
+    for code in parser::code_files(sections) {
+        if lang.is_some_and(|lang| code.lang != Some(lang)) {
+            continue;
+        }
+        let (start, end) = parser::code_body_range(code.block);
+        match outputs.iter_mut().find(|(path, _)| *path == code.path) {
+            Some((_, body)) => body.push_str(&content[start..end]),
+            None => outputs.push((code.path, content[start..end].to_string())),
+        }
+    }
+    if outputs.is_empty() {
+        bail!("No code blocks linked to a file (add `file=path` to the info string or a `<!-- file: path -->` comment)");
+    }
+
+    for (path, body) in outputs {
+        let dest = linked_path(out_dir, path)?;
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+        }
+        std::fs::write(&dest, &body).with_context(|| format!("Failed to write {}", dest.display()))?;
+        println!("Wrote {} ({} lines)", dest.display(), body.lines().count());
+    }
     Ok(())
 }
 
+/// 用链接文件的当前内容替换各代码块的正文，返回新文档；已同步时原样返回
+fn weave(content: &str, sections: &[parser::Section], from: &Path, lang: Option<&str>) -> Result<String> {
+    let codes: Vec<_> = parser::code_files(sections)
+        .into_iter()
+        .filter(|code| lang.is_none_or(|lang| code.lang == Some(lang)))
+        .collect();
+    if codes.is_empty() {
+        bail!("No code blocks linked to a file (add `file=path` to the info string or a `<!-- file: path -->` comment)");
+    }
+
+    let mut replacements = Vec::new();
+    for code in &codes {
+        if codes.iter().filter(|other| other.path == code.path).count() > 1 {
+            bail!("'{}' is linked by several code blocks; weave needs one block per file", code.path);
+        }
+        let source = linked_path(from, code.path)?;
+        let mut body = std::fs::read_to_string(&source)
+            .with_context(|| format!("Failed to read linked file: {}", source.display()))?;
+        if body.lines().any(|line| line.trim().starts_with("```")) {
+            bail!("{} contains a ``` line, which would close the code block early", source.display());
+        }
+        if !body.is_empty() && !body.ends_with('\n') {
+            body.push('\n');
+        }
+        replacements.push((parser::code_body_range(code.block), body));
+    }
+
+    let mut new_content = content.to_string();
+    for ((start, end), body) in replacements.into_iter().rev() {
+        new_content.replace_range(start..end, &body);
+    }
+    Ok(new_content)
+}
+
 fn run() -> Result<()> {
     let cli = Cli::parse();
+    TRACE.store(cli.trace, Ordering::Relaxed);
+    parser::set_tab_width(cli.tab_width as usize);
+    parser::set_profile(cli.profile);
+    if let Some(map) = cli.exit_code_map.clone() {
+        let _ = EXIT_CODE_MAP.set(map);
+    }
+    let lock_timeout = (!cli.no_lock).then(|| Duration::from_secs(cli.lock_timeout));
+
+    let target = match &cli.command {
+        Commands::Patch { format, file, heading, index, .. } => Some(ErrorTarget {
+            format: *format,
+            file: Some(file.clone()),
+            heading: heading.clone(),
+            index: Some(*index),
+        }),
+        Commands::Get { format, file, heading, index, .. } => Some(ErrorTarget {
+            format: *format,
+            file: Some(file.clone()),
+            heading: Some(heading.clone()),
+            index: Some(*index),
+        }),
+        Commands::Parse { format, file, .. } | Commands::Weave { format, file, .. } => Some(ErrorTarget {
+            format: *format,
+            file: Some(file.clone()),
+            heading: None,
+            index: None,
+        }),
+        Commands::Apply { format, .. } | Commands::Plan { format, .. } => Some(ErrorTarget {
+            format: *format,
+            file: None,
+            heading: None,
+            index: None,
+        }),
+        _ => None,
+    };
+    if let Some(target) = target {
+        let _ = ERROR_TARGET.set(target);
+    }
 
     match cli.command {
         Commands::Patch {
             file,
             heading,
             index,
+            block_hash,
+            index_range,
+            line,
+            numbered,
+            recursive_index,
+            after_heading,
+            heading_block,
+            occurrence,
+            keep_going,
+            ignore_emoji,
+            plain_headings,
+            fuzzy_headings,
+            heading_regex,
+            under,
             op,
             content,
+            content_env,
+            dedent,
+            no_op_on_missing_content,
             fingerprint,
+            fingerprint_file,
+            fingerprint_ignore_case,
+            file_sha256,
             force,
+            interactive,
+            yes,
             no_backup,
+            no_follow_symlinks,
+            separator,
+            idempotency,
+            max_block_bytes,
+            repair_fences,
+            literal_content,
+            verify_structure,
+            merge_append,
+            match_style,
+            coarse_blocks,
+            sort_mode,
+            sort_key,
+            toc_depth,
+            to,
+            line_key,
+            region,
+            whole_file,
+            strip_comments,
+            flavor,
+            input_charset,
+            max_file_bytes,
+            normalize_eol,
+            emit_diff,
+            emit,
+            dry_run_json,
+            report_unchanged,
             format,
+            theme,
         } => {
+            let format = if dry_run_json { OutputFormat::Json } else { format };
+            let content = match &content_env {
+                Some(var) => match std::env::var(var) {
+                    Ok(value) => vec![value],
+                    Err(std::env::VarError::NotPresent) => bail!("Environment variable '{}' is not set", var),
+                    Err(std::env::VarError::NotUnicode(_)) => {
+                        bail!("Environment variable '{}' is not valid UTF-8", var)
+                    }
+                },
+                None => content,
+            };
+            let content: Vec<String> = if dedent {
+                content.iter().map(|c| patch::dedent(c)).collect()
+            } else {
+                content
+            };
+            let fingerprint = match &fingerprint_file {
+                Some(path) => Some(read_fingerprint_file(path)?),
+                None => fingerprint,
+            };
+
             // Validate content requirement
             let content = match op {
-                OperationType::Delete => None,
-                _ => match content {
-                    Some(c) => Some(c),
-                    None => bail!("Content is required for append/replace operations"),
-                },
+                OperationType::Delete
+                | OperationType::Sort
+                | OperationType::Toc
+                | OperationType::Dedup
+                | OperationType::Merge
+                | OperationType::Convert
+                | OperationType::TrimTrailing => None,
+                _ if !content.is_empty() => Some(patch::join_blocks(&content, separator)),
+                _ if no_op_on_missing_content => None,
+                _ => bail!("Content is required for append/replace/replace-body/list-append/ensure/insert-sorted/append-line-if-absent/definition-append operations"),
+            };
+
+            let mut heading_path = match &under {
+                // 正则模式下 --under 仍按字面匹配
+                Some(parent) if heading_regex => {
+                    parse_heading_path(parent)?.iter().map(|h| format!("^{}$", regex::escape(h))).collect()
+                }
+                Some(parent) => parse_heading_path(parent)?,
+                None => Vec::new(),
             };
+            // 可选的类型选择器后缀，如 `## API > code[1]`
+            let mut selector = None;
+            if let Some(h) = &heading {
+                let (path, parsed) = parser::split_selector(h);
+                let segments = if heading_regex { parse_heading_regex_path(path)? } else { parse_heading_path(path)? };
+                heading_path.extend(segments);
+                selector = parsed;
+            }
 
             let operation = PatchOperation {
                 file: file.clone(),
-                heading_path: parse_heading_path(&heading)?,
+                heading_path,
                 block_index: index,
                 operation: op.into(),
                 content,
                 fingerprint,
+                fingerprint_ignore_case,
+                line,
+                occurrence,
+                numbered: numbered.clone(),
+                recursive_index,
+                selector,
+                block_hash,
+                block_range: index_range.map(|r| (r[0], r[1])),
+                after_heading,
+                heading_block,
+                noop_on_missing_content: no_op_on_missing_content,
+                strip_comments,
+                separator,
+                idempotency,
+                max_block_bytes,
+                repair_fences,
+                literal_content,
+                verify_structure,
+                merge_append,
+                match_style,
+                coarse_blocks,
+                ignore_emoji,
+                plain_headings,
+                fuzzy_headings,
+                heading_match: if heading_regex { HeadingMatch::Regex } else { HeadingMatch::Exact },
+                sort_mode,
+                sort_key,
+                toc_depth,
+                convert_to: to,
+                line_key,
+                region,
+                whole_file,
+                flavor: flavor.unwrap_or_else(|| Flavor::detect(&file)),
+            };
+
+            // 可能写回时，读取之前加锁，直到写入完成
+            let _lock = match lock_timeout {
+                Some(timeout) if (force || interactive) && emit.is_none() && !dry_run_json => {
+                    Some(lock_file(&file, timeout)?)
+                }
+                _ => None,
+            };
+            let bytes = traced(&format!("read {}", file.display()), || read_bytes(&file, max_file_bytes))?;
+            // 哈希按读取到的原始字节计算，不再次读取文件
+            if let Some(expected) = &file_sha256 {
+                verify_file_sha256(&file, &bytes, expected)?;
+            }
+            let content_str = decode_text(&file, bytes, input_charset)?;
+            // 解析器按 LF 计算偏移：先统一为 LF，写入时再转换为目标风格
+            let content_str = if normalize_eol.is_some() { Eol::Lf.apply(&content_str) } else { content_str };
+            let fence_warnings = warn_unterminated_fences(&file, &content_str, repair_fences);
+            let result = traced("parse+resolve+apply", || patch::apply_operation(&content_str, &operation, force));
+            let result = match result {
+                Ok(result) => result,
+                Err(e) if keep_going && e.to_string().starts_with("Multiple sections found") => {
+                    // 歧义报告：列出所有候选 section 的完整路径，仍以歧义错误（退出码 4）结束
+                    let sections = parser::parse_sections_with(&content_str, operation.flavor)?;
+                    let report = parser::ambiguity_report(&content_str, &sections, &operation.heading_path[0]);
+                    bail!("{}\n{}", e, report);
+                }
+                Err(e) => {
+                    let Some(blocked) = e.downcast_ref::<patch::BlockedOperation>() else {
+                        return Err(e);
+                    };
+                    // --interactive：展示改动并询问是否应用（--yes 自动确认）
+                    if interactive {
+                        println!("Preview of the change:");
+                        output::print_result(&blocked.diff, format, theme, false, false);
+                        if !yes && !confirm("Apply? [y/N] ")? {
+                            bail!("Aborted: change not applied");
+                        }
+                        patch::apply_operation(&content_str, &operation, true)?
+                    } else {
+                        // 未授权的破坏性操作：先展示将要产生的改动，再以错误退出
+                        if !format.is_json() {
+                            println!("Blocked (not applied): preview of the change");
+                            output::print_result(&blocked.diff, format, theme, false, false);
+                        }
+                        return Err(e);
+                    }
+                }
             };
+            if let Some(path) = &emit_diff {
+                match &result {
+                    PatchResult::Applied { is_noop: true, .. } | PatchResult::DryRun { is_noop: true, .. } => {
+                        write_emitted_diff(path, &[])?
+                    }
+                    PatchResult::Applied { diff, .. } | PatchResult::DryRun { diff, .. } => {
+                        write_emitted_diff(path, std::slice::from_ref(diff))?
+                    }
+                }
+            }
 
-            let content_str = std::fs::read_to_string(&file)?;
-            let result = patch::apply_operation(&content_str, &operation, force)?;
+            // --emit：不写回文件，修改后的文档输出到 stdout（both 时 diff 输出到 stderr）
+            if let Some(emit) = emit {
+                // 授权检查已通过（未被阻止），因此可以直接生成修改后的内容
+                let result = match result {
+                    PatchResult::DryRun { .. } => patch::apply_operation(&content_str, &operation, true)?,
+                    applied => applied,
+                };
+                if let PatchResult::Applied { new_content, diff, warnings, .. } = result {
+                    print_warnings(&warnings, format);
+                    if emit == Emit::Both {
+                        eprint!("{}", diff);
+                    }
+                    print!("{}", new_content);
+                }
+                return Ok(());
+            }
 
-            let op_info = OperationInfo {
+            let mut op_info = OperationInfo {
                 file: file.clone(),
-                heading: heading.clone(),
+                heading: heading
+                    .or_else(|| numbered.map(|n| format!("section {}", n)))
+                    .unwrap_or_else(|| format!("line {}", line.unwrap_or_default())),
                 index,
-                operation: format!("{:?}", op).to_lowercase(),
+                operation: op.to_possible_value().map_or_else(String::new, |v| v.get_name().to_string()),
+                new_block_index: None,
+                warnings: fence_warnings,
+                new_content: None,
+                noop_reason: None,
             };
 
+            // --dry-run-json：不写回文件，JSON 结果中附带完整的候选文档
+            if dry_run_json {
+                let result = match result {
+                    PatchResult::DryRun { .. } => patch::apply_operation(&content_str, &operation, true)?,
+                    applied => applied,
+                };
+                if let PatchResult::Applied { new_content, diff, is_noop, new_block_index, warnings, .. } = result {
+                    print_warnings(&warnings, format);
+                    op_info.warnings.extend(warnings);
+                    op_info.new_block_index = new_block_index;
+                    op_info.new_content = Some(new_content);
+                    output::print_result_with_info(&diff, format, theme, false, Some(op_info), is_noop);
+                }
+                return Ok(());
+            }
+
             match result {
-                PatchResult::Applied { new_content, diff, is_noop } => {
-                    atomic_write(&file, &new_content, no_backup)?;
-                    output::print_result_with_info(&diff, format, true, Some(op_info), is_noop);
+                PatchResult::Applied { new_content, diff, is_noop, noop_reason, new_block_index, warnings } => {
+                    print_warnings(&warnings, format);
+                    op_info.warnings.extend(warnings);
+                    traced(&format!("write {}", file.display()), || {
+                        atomic_write(&file, &new_content, no_backup, !no_follow_symlinks, input_charset, normalize_eol)
+                    })?;
+                    op_info.new_block_index = new_block_index;
+                    op_info.noop_reason = noop_reason.filter(|_| report_unchanged);
+                    // JSON 格式下原因放在输出文档中，stdout 保持可解析
+                    let unchanged = op_info.noop_reason.clone().filter(|_| !format.is_json());
+                    output::print_result_with_info(&diff, format, theme, true, Some(op_info), is_noop);
+                    if let Some(reason) = unchanged {
+                        println!("Unchanged: {}", reason);
+                    }
                 }
-                PatchResult::DryRun { diff, is_noop } => {
-                    output::print_result_with_info(&diff, format, false, Some(op_info), is_noop);
+                PatchResult::DryRun { diff, is_noop, noop_reason, warnings } => {
+                    print_warnings(&warnings, format);
+                    op_info.warnings.extend(warnings);
+                    op_info.noop_reason = noop_reason.filter(|_| report_unchanged);
+                    let unchanged = op_info.noop_reason.clone().filter(|_| !format.is_json());
+                    output::print_result_with_info(&diff, format, theme, false, Some(op_info), is_noop);
+                    if let Some(reason) = unchanged {
+                        println!("Unchanged: {}", reason);
+                    }
                     if !force {
                         println!("\n(Run with --force to apply changes)");
                     }
@@ -236,21 +1510,316 @@ fn run() -> Result<()> {
             config,
             force,
             no_backup,
+            no_follow_symlinks,
+            print_count,
+            fail_on_noop,
+            emit_diff,
+            allow_overlap,
+            report_unchanged,
+            summary_only,
+            progress,
+            output_dir,
+            input_charset,
+            max_file_bytes,
+            normalize_eol,
+            format,
+            theme,
+        } => {
+            let format = if summary_only && !format.is_json() { OutputFormat::Json } else { format };
+            let operations = traced("load config", || load_config(&config))?;
+            let options = BatchOptions {
+                force,
+                format,
+                theme,
+                no_backup,
+                dry_run: false,
+                follow_symlinks: !no_follow_symlinks,
+                allow_overlap,
+                report_unchanged,
+                summary_only,
+                progress,
+                output_dir,
+                input_charset,
+                max_file_bytes,
+                normalize_eol,
+                lock_timeout,
+            };
+            let summary = apply_batch(operations, &options)?;
+            if let Some(path) = &emit_diff {
+                write_emitted_diff(path, &summary.diffs)?;
+            }
+            // JSON 格式下计数已在 files_modified 字段中
+            if print_count && !format.is_json() {
+                println!("Files modified: {}", summary.files_modified);
+            }
+            if fail_on_noop && summary.files_modified == 0 {
+                bail!("No changes: no file was modified (--fail-on-noop)");
+            }
+        }
+
+        Commands::Plan {
+            config,
+            dry_run_exit_code,
+            allow_overlap,
+            report_unchanged,
+            summary_only,
+            input_charset,
+            max_file_bytes,
+            format,
+            theme,
+        } => {
+            let format = if summary_only && !format.is_json() { OutputFormat::Json } else { format };
+            let operations = traced("load config", || load_config(&config))?;
+            let options = BatchOptions {
+                force: false,
+                format,
+                theme,
+                no_backup: true,
+                dry_run: true,
+                follow_symlinks: true,
+                allow_overlap,
+                report_unchanged,
+                summary_only,
+                progress: false,
+                output_dir: None,
+                input_charset,
+                max_file_bytes,
+                normalize_eol: None,
+                lock_timeout: None,
+            };
+            let summary = apply_batch(operations, &options)?;
+            if dry_run_exit_code && summary.changed > 0 {
+                bail!(
+                    "Changes pending: {} operation(s) would modify content (--dry-run-exit-code)",
+                    summary.changed
+                );
+            }
+        }
+
+        Commands::Parse {
+            file,
+            format,
+            flavor,
+            only_type,
+            coarse_blocks,
+        } => {
+            let content = std::fs::read_to_string(&file)?;
+            let flavor = flavor.unwrap_or_else(|| Flavor::detect(&file));
+            let mut sections = parser::parse_sections_with(&content, flavor)?;
+            if coarse_blocks {
+                parser::coarsen_paragraphs(&content, &mut sections);
+            }
+            output::print_sections(&sections, format, only_type.as_deref());
+        }
+
+        Commands::Revert { file } => {
+            let backup = backup_path(&file);
+            if !backup.exists() {
+                bail!("No backup found for {} (expected {})", file.display(), backup.display());
+            }
+            let content = std::fs::read_to_string(&backup)
+                .with_context(|| format!("Failed to read backup: {}", backup.display()))?;
+            // 恢复时不再创建新的备份，保留 .bak 以便再次恢复
+            atomic_write(&file, &content, true, true, None, None)?;
+            println!("Restored {} from {}", file.display(), backup.display());
+        }
+
+        Commands::ApplyDiff {
+            file,
+            diff,
+            reverse,
+            no_backup,
+        } => {
+            let _lock = lock_timeout.map(|timeout| lock_file(&file, timeout)).transpose()?;
+            let content = std::fs::read_to_string(&file)?;
+            let diff_text = std::fs::read_to_string(&diff)
+                .with_context(|| format!("Failed to read diff: {}", diff.display()))?;
+            let new_content =
+                patch::apply_unified_diff(&content, &diff_text, &file.to_string_lossy(), reverse)?;
+            if new_content == content {
+                println!("No changes for {}", file.display());
+            } else {
+                atomic_write(&file, &new_content, no_backup, true, None, None)?;
+                let verb = if reverse { "Reverse-applied" } else { "Applied" };
+                println!("{} {} to {}", verb, diff.display(), file.display());
+            }
+        }
+
+        Commands::Explain {
+            file,
+            heading,
+            index,
+            occurrence,
+        } => {
+            let content = std::fs::read_to_string(&file)?;
+            explain_address(&file, &content, &parse_heading_path(&heading)?, index, occurrence)?;
+        }
+
+        Commands::Get {
+            file,
+            heading,
+            index,
+            occurrence,
             format,
         } => {
-            let operations = load_config(&config)?;
-            apply_batch(operations, force, format, no_backup)?;
+            let content = std::fs::read_to_string(&file)?;
+            let sections = parser::parse_sections_with(&content, Flavor::detect(&file))?;
+            let section = parser::find_section(&sections, &parse_heading_path(&heading)?, occurrence)?;
+            let block = parser::get_block(section, index)?;
+            output::print_block(&content, section, index, block, format);
+        }
+
+        Commands::VerifyBlock {
+            file,
+            heading,
+            index,
+            occurrence,
+            fingerprint,
+            fingerprint_ignore_case,
+        } => {
+            let content = std::fs::read_to_string(&file)?;
+            let heading_path = parse_heading_path(&heading)?;
+            patch::verify_block(
+                &content,
+                &heading_path,
+                index,
+                occurrence,
+                &fingerprint,
+                fingerprint_ignore_case,
+                Flavor::detect(&file),
+            )?;
+            println!("Fingerprint matches: block {} under '{}'", index, heading);
+        }
+
+        Commands::Tangle { file, out_dir, lang } => {
+            let content = std::fs::read_to_string(&file)?;
+            let sections = parser::parse_sections_with(&content, Flavor::detect(&file))?;
+            tangle(&content, &sections, &out_dir, lang.as_deref())?;
         }
 
-        Commands::Plan { config, format } => {
-            let operations = load_config(&config)?;
-            apply_batch(operations, false, format, true)?;
+        Commands::Weave {
+            file,
+            from,
+            lang,
+            force,
+            no_backup,
+            format,
+        } => {
+            let _lock = lock_timeout.filter(|_| force).map(|timeout| lock_file(&file, timeout)).transpose()?;
+            let content = std::fs::read_to_string(&file)?;
+            let sections = parser::parse_sections_with(&content, Flavor::detect(&file))?;
+            let new_content = weave(&content, &sections, &from, lang.as_deref())?;
+            let is_noop = new_content == content;
+            if force && !is_noop {
+                atomic_write(&file, &new_content, no_backup, true, None, None)?;
+            }
+            let diff = output::format_diff(&content, &new_content, &file.to_string_lossy());
+            output::print_result(&diff, format, ColorTheme::default(), force, is_noop);
+            if !force && !is_noop {
+                println!("\n(Run with --force to apply changes)");
+            }
         }
     }
 
     Ok(())
 }
 
+/// 打印地址解析过程：候选 heading、最终选中的 section 以及目标 block
+fn explain_address(
+    file: &Path,
+    content: &str,
+    heading_path: &[String],
+    index: usize,
+    occurrence: Option<usize>,
+) -> Result<()> {
+    let line_of = |offset: usize| content[..offset].matches('\n').count() + 1;
+    let sections = parser::parse_sections_with(content, Flavor::detect(file))?;
+
+    println!("File: {} ({} sections)", file.display(), sections.len());
+    println!("Heading path: {:?}", heading_path);
+
+    let first_heading = heading_path[0].trim();
+    let candidates: Vec<&parser::Section> = sections
+        .iter()
+        .filter(|s| s.heading.trim() == first_heading)
+        .collect();
+    println!("Candidates for '{}': {}", first_heading, candidates.len());
+    for (n, candidate) in candidates.iter().enumerate() {
+        println!("  [{}] line {}: {}", n, line_of(candidate.heading_start), candidate.heading);
+    }
+
+    let reason = match (occurrence, candidates.len()) {
+        (Some(n), _) => format!("selected by --occurrence {}", n),
+        (None, 1) => "unique match".to_string(),
+        (None, _) if heading_path.len() > 1 => "first match (document order) under which the nested path resolves".to_string(),
+        (None, _) => "ambiguous".to_string(),
+    };
+
+    let section = parser::find_section(&sections, heading_path, occurrence)?;
+    println!(
+        "Chosen section: {} (line {}, {})",
+        section.heading,
+        line_of(section.heading_start),
+        reason
+    );
+
+    println!("Blocks ({}):", section.blocks.len());
+    for (n, block) in section.blocks.iter().enumerate() {
+        let marker = if n == index { "  <- selected" } else { "" };
+        println!(
+            "  [{}] {} bytes {}..{} lines {}-{}{}",
+            n,
+            block.block_type.kind(),
+            block.start,
+            block.end,
+            line_of(block.start),
+            line_of(block.end),
+            marker
+        );
+    }
+
+    let block = parser::get_block(section, index)?;
+    println!(
+        "Selected block: index {}, {} bytes {}..{}",
+        index,
+        block.block_type.kind(),
+        block.start,
+        block.end
+    );
+    println!("Preview:");
+    for line in block.content.lines().take(5) {
+        println!("  | {}", line);
+    }
+    if block.content.lines().count() > 5 {
+        println!("  | ...");
+    }
+
+    Ok(())
+}
+
+/// `--heading-regex` 的路径：以 `#` 标记（可带 `^` 前缀）开头的词开始新的一段，
+/// 不含标记时整个路径为一个正则。每段在读取文件之前编译，非法正则直接报错
+fn parse_heading_regex_path(path: &str) -> Result<Vec<String>> {
+    let mut segments: Vec<String> = Vec::new();
+    for word in path.split_whitespace() {
+        let marker = word.trim_start_matches('^');
+        let starts_segment = !marker.is_empty() && marker.chars().all(|c| c == '#');
+        match segments.last_mut() {
+            Some(current) if !starts_segment => {
+                current.push(' ');
+                current.push_str(word);
+            }
+            _ => segments.push(word.to_string()),
+        }
+    }
+    for segment in &segments {
+        if let Err(e) = regex::Regex::new(segment) {
+            bail!("Invalid heading regex '{}': {}", segment, e);
+        }
+    }
+    Ok(segments)
+}
+
 fn parse_heading_path(path: &str) -> Result<Vec<String>> {
     // Parse heading path like "# Title ## Subtitle" into ["# Title", "## Subtitle"]
     // Split by heading markers and reconstruct
@@ -285,31 +1854,276 @@ fn parse_heading_path(path: &str) -> Result<Vec<String>> {
     Ok(headings)
 }
 
-fn apply_batch(operations: Vec<OperationConfig>, force: bool, format: OutputFormat, no_backup: bool) -> Result<()> {
-    let mut all_diffs = Vec::new();
+/// 批量执行选项
+#[derive(Clone)]
+struct BatchOptions {
+    force: bool,
+    format: OutputFormat,
+    theme: ColorTheme,
+    no_backup: bool,
+    /// plan 模式：永远不写入文件，单个操作上的 `force` 仅用于破坏性操作的授权检查
+    dry_run: bool,
+    follow_symlinks: bool,
+    /// 允许同一文件上目标范围重叠的操作（按配置顺序依次执行）
+    allow_overlap: bool,
+    /// 输出每个 noop 操作的原因
+    report_unchanged: bool,
+    /// 只输出汇总计数（JSON），不输出逐个文件的明细
+    summary_only: bool,
+    /// 在 stderr 上显示进度条（stderr 不是终端时自动关闭）
+    progress: bool,
+    /// 写入到该目录下的镜像路径，而不是原地修改
+    output_dir: Option<PathBuf>,
+    /// 输入文件字符集（None 表示 UTF-8）
+    input_charset: Option<&'static Encoding>,
+    /// 读取前检查的输入文件大小上限
+    max_file_bytes: Option<u64>,
+    /// 写入时统一换行符风格
+    normalize_eol: Option<Eol>,
+    /// 文件锁的等待时间（None 表示不加锁）
+    lock_timeout: Option<Duration>,
+}
+
+/// 批量执行某一阶段的进度条；未启用或 stderr 不是终端时返回隐藏的进度条（所有调用均为空操作）
+fn progress_bar(enabled: bool, len: usize, phase: &'static str) -> ProgressBar {
+    if !enabled || !std::io::stderr().is_terminal() {
+        return ProgressBar::hidden();
+    }
+    let style = ProgressStyle::with_template("{msg:>10} [{bar:40}] {pos}/{len}")
+        .expect("valid progress template")
+        .progress_chars("=> ");
+    // 出错提前返回时也清除进度条，避免残留在终端上
+    ProgressBar::new(len as u64)
+        .with_style(style)
+        .with_message(phase)
+        .with_finish(ProgressFinish::AndClear)
+}
+
+/// 批量执行结果统计
+struct BatchSummary {
+    /// 内容会发生变化（非 noop）的操作数
+    changed: usize,
+    /// 有实际改动的操作的 diff（按执行顺序）
+    diffs: Vec<String>,
+    /// 实际被修改（写入）的文件数
+    files_modified: usize,
+}
+
+/// `jsonl` 流式输出中"已解析"的一行
+fn resolved_info(op_config: &OperationConfig) -> Result<OperationInfo> {
+    Ok(OperationInfo {
+        file: op_config.file.clone(),
+        heading: op_config.heading.join(" "),
+        index: op_config.index,
+        operation: serde_json::to_value(op_config.operation)?.as_str().unwrap_or_default().to_string(),
+        new_block_index: None,
+        warnings: Vec::new(),
+        new_content: None,
+        noop_reason: None,
+    })
+}
+
+/// 批量执行操作
+fn apply_batch(operations: Vec<OperationConfig>, options: &BatchOptions) -> Result<BatchSummary> {
+    let BatchOptions {
+        force,
+        format,
+        theme,
+        no_backup,
+        dry_run,
+        follow_symlinks,
+        allow_overlap,
+        report_unchanged,
+        summary_only,
+        progress,
+        ref output_dir,
+        input_charset,
+        max_file_bytes,
+        normalize_eol,
+        lock_timeout,
+    } = *options;
+
+    // 原地写入时，在读取之前为所有目标文件加锁；按规范化路径排序去重，
+    // 避免同一文件的不同写法重复加锁，也避免两个批量互相等待
+    let mut locks = Vec::new();
+    if let Some(timeout) = lock_timeout.filter(|_| !dry_run && output_dir.is_none()) {
+        let mut files: Vec<PathBuf> = operations
+            .iter()
+            .map(|o| std::fs::canonicalize(&o.file).unwrap_or_else(|_| o.file.clone()))
+            .collect();
+        files.sort();
+        files.dedup();
+        for file in &files {
+            locks.push(lock_file(file, timeout)?);
+        }
+    }
+
     let mut all_results = Vec::new();
 
-    // First pass: validate all operations
+    // 每个文件只读取一次，同一文件上的操作依次作用于其当前内容
+    let mut contents: HashMap<PathBuf, String> = HashMap::new();
+    let mut file_warnings: HashMap<PathBuf, Vec<String>> = HashMap::new();
+    let mut raw_bytes: HashMap<PathBuf, Vec<u8>> = HashMap::new();
+    let mut planned = Vec::new();
+    // 声明了 depends_on 的操作不在此处预先解析：其目标可能要由前面的操作创建
+    let mut deferred = Vec::new();
+    let depended: HashSet<&str> = operations.iter().flat_map(|o| o.depends_on.iter().map(String::as_str)).collect();
+
+    // First pass: validate all operations and resolve their target ranges
+    let bar = progress_bar(progress, operations.len(), "validating");
     for op_config in &operations {
-        let content = match std::fs::read_to_string(&op_config.file) {
-            Ok(c) => c,
-            Err(e) => {
-                bail!("Failed to read {}: {}", op_config.file.display(), e);
-            }
-        };
+        bar.inc(1);
+        if !contents.contains_key(&op_config.file) {
+            let read = traced(&format!("read {}", op_config.file.display()), || {
+                let bytes = read_bytes(&op_config.file, max_file_bytes)?;
+                // 只为需要校验哈希的文件保留原始字节
+                if operations.iter().any(|o| o.file == op_config.file && o.file_sha256.is_some()) {
+                    raw_bytes.insert(op_config.file.clone(), bytes.clone());
+                }
+                decode_text(&op_config.file, bytes, input_charset)
+            });
+            let content = match read {
+                Ok(c) if normalize_eol.is_some() => Eol::Lf.apply(&c),
+                Ok(c) => c,
+                Err(e) => {
+                    bail!("Failed to read {}: {}", op_config.file.display(), e);
+                }
+            };
+            let warnings = bar.suspend(|| warn_unterminated_fences(&op_config.file, &content, false));
+            file_warnings.insert(op_config.file.clone(), warnings);
+            contents.insert(op_config.file.clone(), content);
+        }
+        // 整个文件的乐观并发检查：在任何写入之前进行
+        if let Some(expected) = &op_config.file_sha256 {
+            verify_file_sha256(&op_config.file, &raw_bytes[&op_config.file], expected)?;
+        }
 
         let operation = PatchOperation {
             file: op_config.file.clone(),
             heading_path: op_config.heading.clone(),
             block_index: op_config.index,
             operation: op_config.operation.into(),
-            content: op_config.content.clone(),
+            content: op_config.content.as_ref().map(|c| c.join(1)),
             fingerprint: op_config.fingerprint.clone(),
+            fingerprint_ignore_case: op_config.fingerprint_ignore_case,
+            line: None,
+            occurrence: None,
+            numbered: None,
+            recursive_index: op_config.recursive_index,
+            selector: None,
+            block_hash: op_config.block_hash.clone(),
+            block_range: op_config.index_range.map(|[first, last]| (first, last)),
+            after_heading: false,
+            heading_block: op_config.heading_block,
+            noop_on_missing_content: false,
+            strip_comments: CommentMode::Keep,
+            separator: 1,
+            idempotency: op_config.idempotency,
+            max_block_bytes: None,
+            repair_fences: false,
+            literal_content: false,
+            verify_structure: false,
+            merge_append: None,
+            match_style: op_config.match_style,
+            coarse_blocks: false,
+            ignore_emoji: false,
+            plain_headings: false,
+            fuzzy_headings: false,
+            heading_match: HeadingMatch::Exact,
+            sort_mode: op_config.sort_mode,
+            sort_key: op_config.sort_key.clone(),
+            toc_depth: op_config.toc_depth,
+            convert_to: op_config.to,
+            line_key: op_config.line_key.clone(),
+            region: op_config.region.clone(),
+            whole_file: op_config.whole_file,
+            flavor: Flavor::detect(&op_config.file),
         };
+        if !op_config.depends_on.is_empty() {
+            deferred.push((op_config, operation));
+            continue;
+        }
+
+        let label = format!("parse+resolve op {} ({})", planned.len() + 1, op_config.file.display());
+        let range = traced(&label, || patch::target_range(&contents[&op_config.file], &operation)).map_err(|e| {
+            anyhow::anyhow!(
+                "Operation failed for {} (heading: {:?}): {}",
+                op_config.file.display(),
+                op_config.heading,
+                e
+            )
+        })?;
+        // jsonl：每个操作解析完成后立即输出一行，便于下游进程流式处理
+        if matches!(format, OutputFormat::Jsonl) {
+            let info = resolved_info(op_config)?;
+            bar.suspend(|| output::stream_resolved_operation(&info));
+        }
+        planned.push((op_config, operation, range));
+    }
+
+    // 冲突检测：同一文件上目标范围重叠的操作会互相覆盖
+    if !allow_overlap {
+        for (i, (a_config, _, a)) in planned.iter().enumerate() {
+            for (j, (b_config, _, b)) in planned.iter().enumerate().skip(i + 1) {
+                let overlaps = a == b || (a.0 < b.1 && b.0 < a.1);
+                if a_config.file == b_config.file && overlaps {
+                    bail!(
+                        "Conflicting operations on {}: operation {} (heading: {:?}, bytes {}..{}) \
+                         overlaps operation {} (heading: {:?}, bytes {}..{}). \
+                         Use --allow-overlap to apply both in order.",
+                        a_config.file.display(),
+                        i + 1,
+                        a_config.heading,
+                        a.0,
+                        a.1,
+                        j + 1,
+                        b_config.heading,
+                        b.0,
+                        b.1
+                    );
+                }
+            }
+        }
+    }
+
+    // 同一文件内自下而上执行，使前面目标的偏移和 block 索引不受后续修改影响
+    if !allow_overlap {
+        planned.sort_by_key(|(op_config, _, range)| {
+            let file_order = operations.iter().position(|o| o.file == op_config.file);
+            (file_order, std::cmp::Reverse(range.0))
+        });
+    }
+
+    bar.finish_and_clear();
+
+    let bar = progress_bar(progress, planned.len() + deferred.len(), "applying");
+    // 已落实（写入或本就无需修改）的操作 id，以及被依赖但只做了预览的操作
+    let mut settled: HashSet<&str> = HashSet::new();
+    let mut previews = Vec::new();
+    for (op_config, operation, _) in &planned {
+        bar.inc(1);
+        // 单个操作的 force/no_backup 优先于全局参数
+        let op_force = op_config.force.unwrap_or(force);
+        let op_no_backup = op_config.no_backup.unwrap_or(no_backup);
 
-        match patch::apply_operation(&content, &operation, force) {
+        let content = &contents[&op_config.file];
+        let label = format!("apply {:?} ({})", op_config.heading, op_config.file.display());
+        match traced(&label, || patch::apply_operation(content, operation, op_force)) {
             Ok(result) => {
-                all_results.push((op_config.file.clone(), result));
+                let (PatchResult::Applied { warnings, .. } | PatchResult::DryRun { warnings, .. }) = &result;
+                bar.suspend(|| print_warnings(warnings, format));
+                if let PatchResult::Applied { new_content, .. } = &result {
+                    contents.insert(op_config.file.clone(), new_content.clone());
+                }
+                if let Some(id) = op_config.id.as_deref().filter(|id| depended.contains(id)) {
+                    match &result {
+                        PatchResult::DryRun { is_noop: false, .. } => previews.push((op_config, operation)),
+                        _ => {
+                            settled.insert(id);
+                        }
+                    }
+                }
+                all_results.push((op_config.file.clone(), result, op_no_backup));
             }
             Err(e) => {
                 bail!(
@@ -322,31 +2136,186 @@ fn apply_batch(operations: Vec<OperationConfig>, force: bool, format: OutputForm
         }
     }
 
-    // If all validations pass and force is enabled, apply all changes atomically
-    if force {
-        for (file, result) in &all_results {
-            if let PatchResult::Applied { new_content, .. } = result {
-                atomic_write(file, new_content, no_backup)?;
+    // 依赖阶段：按配置顺序执行，每个操作都基于当前内容重新解析。
+    // 依赖只做了预览（未写入）时，基于包含预览改动的内容求值，且本操作也只预览
+    if !deferred.is_empty() {
+        let mut simulated = contents.clone();
+        for (op_config, operation) in previews {
+            if let PatchResult::Applied { new_content, .. } =
+                patch::apply_operation(&simulated[&op_config.file], operation, true)?
+            {
+                simulated.insert(op_config.file.clone(), new_content);
+            }
+        }
+        for (op_config, operation) in &deferred {
+            bar.inc(1);
+            let deps_settled = op_config.depends_on.iter().all(|dep| settled.contains(dep.as_str()));
+            let op_force = op_config.force.unwrap_or(force) && deps_settled;
+            let op_no_backup = op_config.no_backup.unwrap_or(no_backup);
+            if matches!(format, OutputFormat::Jsonl) {
+                let info = resolved_info(op_config)?;
+                bar.suspend(|| output::stream_resolved_operation(&info));
+            }
+
+            let base = if deps_settled { &contents[&op_config.file] } else { &simulated[&op_config.file] };
+            let label = format!("apply {:?} ({})", op_config.heading, op_config.file.display());
+            let result = traced(&label, || patch::apply_operation(base, operation, op_force)).map_err(|e| {
+                anyhow::anyhow!(
+                    "Operation failed for {} (heading: {:?}): {}",
+                    op_config.file.display(),
+                    op_config.heading,
+                    e
+                )
+            })?;
+            let (PatchResult::Applied { warnings, is_noop, .. } | PatchResult::DryRun { warnings, is_noop, .. }) =
+                &result;
+            bar.suspend(|| print_warnings(warnings, format));
+            if !is_noop {
+                if let PatchResult::Applied { new_content, .. } =
+                    patch::apply_operation(&simulated[&op_config.file], operation, true)?
+                {
+                    simulated.insert(op_config.file.clone(), new_content);
+                }
+            }
+            if *is_noop || matches!(result, PatchResult::Applied { .. }) {
+                settled.extend(op_config.id.as_deref());
+            }
+            if let PatchResult::Applied { new_content, .. } = &result {
+                contents.insert(op_config.file.clone(), new_content.clone());
+            }
+            all_results.push((op_config.file.clone(), result, op_no_backup));
+        }
+    }
+
+    bar.finish_and_clear();
+
+    // If all validations pass, write each modified file once with its final content
+    if !dry_run {
+        let mut written: Vec<&PathBuf> = Vec::new();
+        let mut to_write: Vec<&PathBuf> = all_results
+            .iter()
+            .filter(|(_, result, _)| matches!(result, PatchResult::Applied { .. }))
+            .map(|(file, _, _)| file)
+            .collect();
+        to_write.sort();
+        to_write.dedup();
+        let bar = progress_bar(progress, to_write.len(), "writing");
+        for (file, result, _) in &all_results {
+            if !matches!(result, PatchResult::Applied { .. }) || written.contains(&file) {
+                continue;
+            }
+            bar.inc(1);
+            if let Some(dir) = output_dir {
+                let dest = mirrored_path(dir, file)?;
+                traced(&format!("write {}", dest.display()), || {
+                    write_copy(file, &dest, &contents[file], input_charset, normalize_eol)
+                })?;
+                written.push(file);
+                continue;
             }
+            // 任一操作需要备份时即创建备份
+            let file_no_backup = all_results.iter().all(|(f, r, op_no_backup)| {
+                f != file || !matches!(r, PatchResult::Applied { .. }) || *op_no_backup
+            });
+            traced(&format!("write {}", file.display()), || {
+                atomic_write(file, &contents[file], file_no_backup, follow_symlinks, input_charset, normalize_eol)
+            })?;
+            written.push(file);
         }
+        bar.finish_and_clear();
     }
 
-    // Output results
-    for (file, result) in &all_results {
-        match result {
-            PatchResult::Applied { diff, .. } | PatchResult::DryRun { diff, .. } => {
-                all_diffs.push(format!("--- {} ---\n{}", file.display(), diff));
+    let any_applied = !dry_run
+        && all_results
+            .iter()
+            .any(|(_, result, _)| matches!(result, PatchResult::Applied { .. }));
+    let any_dry_run = dry_run
+        || all_results
+            .iter()
+            .any(|(_, result, _)| matches!(result, PatchResult::DryRun { .. }));
+
+    // Output results：按文件分组（按首次出现的顺序），每个文件单独渲染
+    let mut file_diffs: Vec<FileDiff> = Vec::new();
+    for (file, result, _) in &all_results {
+        let (PatchResult::Applied { diff, is_noop, warnings, noop_reason, .. }
+        | PatchResult::DryRun { diff, is_noop, warnings, noop_reason, .. }) = result;
+        let noop_reason = noop_reason.clone().filter(|_| report_unchanged);
+        match file_diffs.iter_mut().find(|f| &f.file == file) {
+            Some(entry) => {
+                if !is_noop {
+                    entry.diff.push_str(diff);
+                }
+                entry.is_noop &= *is_noop;
+                entry.warnings.extend(warnings.iter().cloned());
+                entry.noop_reasons.extend(noop_reason);
             }
+            None => file_diffs.push(FileDiff {
+                file: file.clone(),
+                diff: if *is_noop { String::new() } else { diff.clone() },
+                is_noop: *is_noop,
+                warnings: file_warnings.get(file).into_iter().flatten().chain(warnings).cloned().collect(),
+                noop_reasons: noop_reason.into_iter().collect(),
+            }),
         }
     }
+    let is_changed = |result: &PatchResult| match result {
+        PatchResult::Applied { is_noop, .. } | PatchResult::DryRun { is_noop, .. } => !is_noop,
+    };
+    let mut modified_files: Vec<&PathBuf> = all_results
+        .iter()
+        .filter(|(_, result, _)| {
+            !dry_run && matches!(result, PatchResult::Applied { .. }) && is_changed(result)
+        })
+        .map(|(file, _, _)| file)
+        .collect();
+    modified_files.sort();
+    modified_files.dedup();
+
+    if summary_only {
+        let counts = output::OperationCounts {
+            applied: all_results
+                .iter()
+                .filter(|(_, r, _)| !dry_run && matches!(r, PatchResult::Applied { is_noop: false, .. }))
+                .count(),
+            noop: all_results
+                .iter()
+                .filter(|(_, r, _)| matches!(r, PatchResult::Applied { is_noop: true, .. } | PatchResult::DryRun { is_noop: true, .. }))
+                .count(),
+            total: all_results.len(),
+            files_modified: modified_files.len(),
+        };
+        output::print_batch_summary(&file_diffs, counts, format);
+    } else {
+        output::print_batch_result(&file_diffs, format, theme, any_applied, modified_files.len());
+    }
 
-    let combined_diff = all_diffs.join("\n");
-    // Batch 操作暂简单处理，不传递 is_noop
-    output::print_result(&combined_diff, format, force, false);
+    // JSON 格式下原因已在 noop_reasons 中
+    if report_unchanged && !format.is_json() {
+        for (file, result, _) in &all_results {
+            if let PatchResult::Applied { noop_reason: Some(reason), .. }
+            | PatchResult::DryRun { noop_reason: Some(reason), .. } = result
+            {
+                println!("Unchanged: {}: {}", file.display(), reason);
+            }
+        }
+    }
 
-    if !force {
+    // JSON 输出保持为单个可解析的文档
+    if any_dry_run && !format.is_json() {
         println!("\n(Run with --force to apply changes)");
     }
 
-    Ok(())
+    let diffs: Vec<String> = all_results
+        .iter()
+        .filter(|(_, result, _)| is_changed(result))
+        .map(|(_, result, _)| match result {
+            PatchResult::Applied { diff, .. } | PatchResult::DryRun { diff, .. } => diff.clone(),
+        })
+        .collect();
+
+    Ok(BatchSummary {
+        changed: diffs.len(),
+        diffs,
+        files_modified: modified_files.len(),
+    })
 }
@@ -1,8 +1,10 @@
 use clap::ValueEnum;
-use colored::Colorize;
+use colored::{ColoredString, Colorize};
 use serde::Serialize;
 use std::path::PathBuf;
 
+use crate::parser::{block_hash, section_parents, Block, Section};
+
 #[derive(Clone, Copy, Debug, Default, ValueEnum)]
 pub enum OutputFormat {
     /// Unified diff format
@@ -10,10 +12,199 @@ pub enum OutputFormat {
     Diff,
     /// JSON format
     Json,
+    /// JSON Lines: one compact JSON object per line; batch commands stream one line per
+    /// operation as it is resolved, followed by one line per file
+    Jsonl,
     /// Short summary
     Short,
 }
 
+impl OutputFormat {
+    /// JSON 或 JSON Lines：stdout 只包含可解析的 JSON
+    pub fn is_json(self) -> bool {
+        matches!(self, OutputFormat::Json | OutputFormat::Jsonl)
+    }
+}
+
+/// 序列化为 JSON：`json` 格式缩进输出，`jsonl` 格式输出单行
+fn to_json<T: Serialize>(value: &T, format: OutputFormat) -> String {
+    if matches!(format, OutputFormat::Jsonl) {
+        serde_json::to_string(value).unwrap()
+    } else {
+        serde_json::to_string_pretty(value).unwrap()
+    }
+}
+
+/// Color theme for diff/short output
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum ColorTheme {
+    /// Green additions, red deletions
+    #[default]
+    Default,
+    /// Bright, bold colors for low-contrast terminals
+    HighContrast,
+    /// No colors: bold additions, underlined deletions
+    Monochrome,
+}
+
+impl ColorTheme {
+    fn addition(self, text: &str) -> ColoredString {
+        match self {
+            ColorTheme::Default => text.green(),
+            ColorTheme::HighContrast => text.bright_green().bold(),
+            ColorTheme::Monochrome => text.bold(),
+        }
+    }
+
+    fn deletion(self, text: &str) -> ColoredString {
+        match self {
+            ColorTheme::Default => text.red(),
+            ColorTheme::HighContrast => text.bright_red().bold(),
+            ColorTheme::Monochrome => text.underline(),
+        }
+    }
+
+    fn hunk(self, text: &str) -> ColoredString {
+        match self {
+            ColorTheme::Default => text.cyan(),
+            ColorTheme::HighContrast => text.bright_cyan().bold(),
+            ColorTheme::Monochrome => text.italic(),
+        }
+    }
+
+    fn applied(self, text: &str) -> ColoredString {
+        match self {
+            ColorTheme::Monochrome => text.bold(),
+            _ => self.addition(text),
+        }
+    }
+
+    fn planned(self, text: &str) -> ColoredString {
+        match self {
+            ColorTheme::Default => text.yellow(),
+            ColorTheme::HighContrast => text.bright_yellow().bold(),
+            ColorTheme::Monochrome => text.italic(),
+        }
+    }
+
+    fn file_header(self, text: &str) -> ColoredString {
+        match self {
+            ColorTheme::Default => text.magenta().bold(),
+            ColorTheme::HighContrast => text.bright_magenta().bold(),
+            ColorTheme::Monochrome => text.reversed(),
+        }
+    }
+}
+
+/// Section with its position in the heading tree, for `mdp parse`
+#[derive(Serialize)]
+struct SectionNode<'a> {
+    index: usize,
+    parent: Option<usize>,
+    heading: &'a str,
+    heading_level: u8,
+    heading_start: usize,
+    heading_end: usize,
+    blocks: Vec<BlockNode<'a>>,
+}
+
+/// Block with its (unfiltered) index in the section, usable as a patch address
+#[derive(Serialize)]
+struct BlockNode<'a> {
+    index: usize,
+    #[serde(flatten)]
+    block: &'a Block,
+}
+
+/// `get` 的 JSON 输出：block 及其所在 section，行号从 1 开始（含首尾两行）
+#[derive(Serialize)]
+struct ResolvedBlock<'a> {
+    heading: &'a str,
+    index: usize,
+    #[serde(flatten)]
+    block: &'a Block,
+    start_line: usize,
+    end_line: usize,
+    /// Content SHA-256, usable with `--block-hash`
+    sha256: String,
+}
+
+/// 打印解析出的 block：JSON 格式附带类型、字节范围与行号范围，其他格式只输出原始内容
+pub fn print_block(content: &str, section: &Section, index: usize, block: &Block, format: OutputFormat) {
+    if !format.is_json() {
+        println!("{}", block.content);
+        return;
+    }
+    let line_of = |offset: usize| content[..offset].matches('\n').count() + 1;
+    let resolved = ResolvedBlock {
+        heading: &section.heading,
+        index,
+        block,
+        start_line: line_of(block.start),
+        end_line: line_of(block.end),
+        sha256: block_hash(block),
+    };
+    println!("{}", to_json(&resolved, format));
+}
+
+/// 打印解析结果（JSON 或文本树）；`only_type` 只保留该类型的 block，索引保持不变
+pub fn print_sections(sections: &[Section], format: OutputFormat, only_type: Option<&str>) {
+    let parents = section_parents(sections);
+    match format {
+        OutputFormat::Json | OutputFormat::Jsonl => {
+            let nodes: Vec<SectionNode> = sections
+                .iter()
+                .enumerate()
+                .map(|(index, section)| SectionNode {
+                    index,
+                    parent: parents[index],
+                    heading: &section.heading,
+                    heading_level: section.heading_level,
+                    heading_start: section.heading_start,
+                    heading_end: section.heading_end,
+                    blocks: filtered_blocks(section, only_type)
+                        .into_iter()
+                        .map(|(index, block)| BlockNode { index, block })
+                        .collect(),
+                })
+                .collect();
+            if matches!(format, OutputFormat::Jsonl) {
+                for node in &nodes {
+                    println!("{}", to_json(node, format));
+                }
+            } else {
+                println!("{}", to_json(&nodes, format));
+            }
+        }
+        OutputFormat::Diff | OutputFormat::Short => {
+            for section in sections {
+                let indent = "  ".repeat(section.heading_level.saturating_sub(1) as usize);
+                println!("{}{}", indent, section.heading.bold());
+                for (n, block) in filtered_blocks(section, only_type) {
+                    println!(
+                        "{}  [{}] {} {}..{}",
+                        indent,
+                        n,
+                        block.block_type.kind(),
+                        block.start,
+                        block.end
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Section 中类型匹配的 block 及其原始索引
+fn filtered_blocks<'a>(section: &'a Section, only_type: Option<&str>) -> Vec<(usize, &'a Block)> {
+    section
+        .blocks
+        .iter()
+        .enumerate()
+        .filter(|(_, b)| only_type.is_none_or(|kind| b.block_type.kind() == kind))
+        .collect()
+}
+
 /// 操作信息，用于 JSON 输出
 #[derive(Debug, Clone)]
 pub struct OperationInfo {
@@ -21,6 +212,75 @@ pub struct OperationInfo {
     pub heading: String,
     pub index: usize,
     pub operation: String,
+    pub new_block_index: Option<usize>,
+    /// 非致命的提示（模糊匹配、未闭合代码块等）
+    pub warnings: Vec<String>,
+    /// 完整的候选文档，仅 `--dry-run-json` 时填充
+    pub new_content: Option<String>,
+    /// 无变化的原因，仅 `--report-unchanged` 时填充
+    pub noop_reason: Option<String>,
+}
+
+/// 批量操作中单个文件的 diff（该文件上所有操作的 diff 依次拼接）
+#[derive(Debug, Clone)]
+pub struct FileDiff {
+    pub file: PathBuf,
+    pub diff: String,
+    pub is_noop: bool,
+    pub warnings: Vec<String>,
+    /// 该文件上各个无变化操作的原因，仅 `--report-unchanged` 时填充
+    pub noop_reasons: Vec<String>,
+}
+
+/// 批量操作的 JSON 输出（按文件）
+#[derive(Serialize)]
+struct JsonBatchOutput {
+    success: bool,
+    applied: bool,
+    is_noop: bool,
+    /// Files actually written with changed content
+    files_modified: usize,
+    files: Vec<FileChange>,
+}
+
+#[derive(Serialize)]
+struct FileChange {
+    file: String,
+    status: String,
+    diff: String,
+    warnings: Vec<String>,
+    /// Why operations on this file changed nothing, only with `--report-unchanged`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    noop_reasons: Vec<String>,
+}
+
+/// 批量操作按结果分类的计数
+#[derive(Debug, Clone, Copy)]
+pub struct OperationCounts {
+    /// 已写入且内容有变化
+    pub applied: usize,
+    /// 内容无变化
+    pub noop: usize,
+    pub total: usize,
+    /// 实际写入（内容有变化）的文件数
+    pub files_modified: usize,
+}
+
+/// `--summary-only` 的 JSON 输出：只有汇总，没有逐个文件/操作的明细
+#[derive(Serialize)]
+struct JsonBatchSummary {
+    success: bool,
+    operations: usize,
+    applied: usize,
+    /// Would change content but were not written (dry-run)
+    pending: usize,
+    noop: usize,
+    files_touched: usize,
+    /// Files actually written with changed content
+    files_modified: usize,
+    bytes_added: usize,
+    bytes_removed: usize,
+    byte_delta: i64,
 }
 
 /// 成功操作的 JSON 输出
@@ -30,6 +290,11 @@ struct JsonSuccessOutput {
     applied: bool,
     is_noop: bool,
     changes: Vec<Change>,
+    /// Non-fatal notices, empty when the operation resolved cleanly
+    warnings: Vec<String>,
+    /// The complete would-be document, only present with `--dry-run-json`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    new_content: Option<String>,
 }
 
 /// 详细变更信息
@@ -40,6 +305,15 @@ struct Change {
     heading: String,
     index: usize,
     status: String,
+    new_block_index: Option<usize>,
+    /// Lines added / removed by the change (from the diff)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    additions: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deletions: Option<usize>,
+    /// Why the operation changed nothing, only with `--report-unchanged`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    noop_reason: Option<String>,
 }
 
 /// 错误 JSON 输出（Agent 可解析）
@@ -69,18 +343,18 @@ pub struct ErrorContext {
     pub fingerprint: Option<String>,
 }
 
-/// 打印错误（支持 JSON 格式）
+/// 打印错误到 stderr：JSON 格式输出结构化的错误对象，其余格式输出一行文本
 pub fn print_error(
     error: &anyhow::Error,
     format: OutputFormat,
-    exit_code: i32,
+    kind: &'static str,
     file: Option<&PathBuf>,
     heading: Option<&str>,
     index: Option<usize>,
 ) {
     match format {
-        OutputFormat::Json => {
-            let (code, message, suggestion) = classify_error_detail(error, exit_code);
+        OutputFormat::Json | OutputFormat::Jsonl => {
+            let (code, message, suggestion) = classify_error_detail(error, kind);
             let error_output = JsonErrorOutput {
                 success: false,
                 error: ErrorDetail {
@@ -95,40 +369,40 @@ pub fn print_error(
                     suggestion: suggestion.map(|s| s.to_string()),
                 },
             };
-            eprintln!("{}", serde_json::to_string_pretty(&error_output).unwrap());
-        }
-        _ => {
-            // 文本格式错误已经在 main 中打印
+            eprintln!("{}", to_json(&error_output, format));
         }
+        OutputFormat::Diff | OutputFormat::Short => eprintln!("Error: {}", error),
     }
 }
 
-/// 分类错误并返回 (code, message, suggestion)
-fn classify_error_detail(error: &anyhow::Error, exit_code: i32) -> (&'static str, String, Option<&'static str>) {
+/// 按错误类别（见 `EXIT_CODES`）返回 (code, message, suggestion)；
+/// 按类别而非退出码区分，`--exit-code-map` 不影响结果
+fn classify_error_detail(error: &anyhow::Error, kind: &'static str) -> (&'static str, String, Option<&'static str>) {
     let msg = error.to_string();
-    match exit_code {
-        2 => (
+    match kind {
+        "heading_not_found" => (
             "heading_not_found",
             msg.clone(),
             Some("Verify the heading exists or use nested path like '# Parent ## Child'"),
         ),
-        3 => (
+        "fingerprint_mismatch" => (
             "fingerprint_mismatch",
             msg.clone(),
             Some("The target block content has changed. Update fingerprint or verify the block index"),
         ),
-        4 => (
+        "ambiguous_heading" => (
             "ambiguous_heading",
             msg.clone(),
             Some("Multiple sections match. Use full path like '# Parent ## TargetHeading'"),
         ),
-        _ => {
+        "error" => {
             if msg.contains("file") || msg.contains("not found") {
                 ("file_not_found", msg, Some("Verify the file path exists"))
             } else {
                 ("general_error", msg, None)
             }
         }
+        _ => (kind, msg, None),
     }
 }
 
@@ -145,25 +419,113 @@ fn extract_fingerprint_from_error(error: &anyhow::Error) -> Option<String> {
     None
 }
 
-pub fn print_result(diff: &str, format: OutputFormat, applied: bool, is_noop: bool) {
-    print_result_with_info(diff, format, applied, None, is_noop);
+pub fn print_result(diff: &str, format: OutputFormat, theme: ColorTheme, applied: bool, is_noop: bool) {
+    print_result_with_info(diff, format, theme, applied, None, is_noop);
 }
 
 pub fn print_result_with_info(
     diff: &str,
     format: OutputFormat,
+    theme: ColorTheme,
     applied: bool,
     op_info: Option<OperationInfo>,
     is_noop: bool,
 ) {
     match format {
-        OutputFormat::Diff => print_diff(diff, is_noop),
-        OutputFormat::Json => print_json(diff, applied, op_info, is_noop),
-        OutputFormat::Short => print_short(diff, applied, is_noop),
+        OutputFormat::Diff => print_diff(diff, theme, is_noop),
+        OutputFormat::Json | OutputFormat::Jsonl => print_json(diff, format, applied, op_info, is_noop),
+        OutputFormat::Short => print_short(diff, theme, applied, is_noop),
     }
 }
 
-fn print_diff(diff: &str, is_noop: bool) {
+/// 批量结果：每个文件带独立的文件头，JSON/short 格式按文件给出结构化结果
+pub fn print_batch_result(
+    files: &[FileDiff],
+    format: OutputFormat,
+    theme: ColorTheme,
+    applied: bool,
+    files_modified: usize,
+) {
+    match format {
+        OutputFormat::Diff => {
+            for (n, file) in files.iter().enumerate() {
+                if n > 0 {
+                    println!();
+                }
+                println!("{}", theme.file_header(&format!("==> {} <==", file.file.display())));
+                print_diff(&file.diff, theme, file.is_noop);
+            }
+        }
+        OutputFormat::Json => {
+            let output = JsonBatchOutput {
+                success: true,
+                applied,
+                is_noop: files.iter().all(|f| f.is_noop),
+                files_modified,
+                files: files
+                    .iter()
+                    .map(|f| FileChange {
+                        file: f.file.to_string_lossy().to_string(),
+                        status: status_name(applied, f.is_noop).to_string(),
+                        diff: f.diff.clone(),
+                        warnings: f.warnings.clone(),
+                        noop_reasons: f.noop_reasons.clone(),
+                    })
+                    .collect(),
+            };
+            println!("{}", to_json(&output, format));
+        }
+        OutputFormat::Jsonl => {
+            for f in files {
+                let change = FileChange {
+                    file: f.file.to_string_lossy().to_string(),
+                    status: status_name(applied, f.is_noop).to_string(),
+                    diff: f.diff.clone(),
+                    warnings: f.warnings.clone(),
+                    noop_reasons: f.noop_reasons.clone(),
+                };
+                println!("{}", to_json(&change, format));
+            }
+        }
+        OutputFormat::Short => {
+            for file in files {
+                println!("{}: {}", file.file.display(), short_summary(&file.diff, theme, applied, file.is_noop));
+            }
+        }
+    }
+}
+
+/// 批量结果的汇总（`--summary-only`）
+pub fn print_batch_summary(files: &[FileDiff], counts: OperationCounts, format: OutputFormat) {
+    let stats: Vec<DiffStats> = files.iter().map(|f| DiffStats::of(&f.diff)).collect();
+    let bytes_added: usize = stats.iter().map(|s| s.added_bytes).sum();
+    let bytes_removed: usize = stats.iter().map(|s| s.removed_bytes).sum();
+    let summary = JsonBatchSummary {
+        success: true,
+        operations: counts.total,
+        applied: counts.applied,
+        pending: counts.total - counts.applied - counts.noop,
+        noop: counts.noop,
+        files_touched: files.iter().filter(|f| !f.is_noop).count(),
+        files_modified: counts.files_modified,
+        bytes_added,
+        bytes_removed,
+        byte_delta: bytes_added as i64 - bytes_removed as i64,
+    };
+    println!("{}", to_json(&summary, format));
+}
+
+fn status_name(applied: bool, is_noop: bool) -> &'static str {
+    if is_noop {
+        "noop"
+    } else if applied {
+        "applied"
+    } else {
+        "dry-run"
+    }
+}
+
+fn print_diff(diff: &str, theme: ColorTheme, is_noop: bool) {
     if is_noop {
         println!("{}", "(No changes - content already up to date)".dimmed());
         return;
@@ -171,35 +533,34 @@ fn print_diff(diff: &str, is_noop: bool) {
 
     for line in diff.lines() {
         if line.starts_with('+') && !line.starts_with("+++") {
-            println!("{}", line.green());
+            println!("{}", theme.addition(line));
         } else if line.starts_with('-') && !line.starts_with("---") {
-            println!("{}", line.red());
+            println!("{}", theme.deletion(line));
         } else if line.starts_with("@") {
-            println!("{}", line.cyan());
+            println!("{}", theme.hunk(line));
         } else {
             println!("{}", line);
         }
     }
 }
 
-fn print_json(_diff: &str, applied: bool, op_info: Option<OperationInfo>, is_noop: bool) {
-    let (file, operation, heading, index) = match op_info {
+fn print_json(diff: &str, format: OutputFormat, applied: bool, op_info: Option<OperationInfo>, is_noop: bool) {
+    let (file, operation, heading, index, new_block_index, warnings, new_content, noop_reason) = match op_info {
         Some(info) => (
             info.file.to_string_lossy().to_string(),
             info.operation,
             info.heading,
             info.index,
+            info.new_block_index,
+            info.warnings,
+            info.new_content,
+            info.noop_reason,
         ),
-        None => ("unknown".to_string(), "unknown".to_string(), "unknown".to_string(), 0),
+        None => ("unknown".to_string(), "unknown".to_string(), "unknown".to_string(), 0, None, Vec::new(), None, None),
     };
 
-    let status = if is_noop {
-        "noop"
-    } else if applied {
-        "applied"
-    } else {
-        "dry-run"
-    };
+    let status = status_name(applied, is_noop);
+    let stats = DiffStats::of(diff);
 
     let output = JsonSuccessOutput {
         success: true,
@@ -211,31 +572,90 @@ fn print_json(_diff: &str, applied: bool, op_info: Option<OperationInfo>, is_noo
             heading,
             index,
             status: status.to_string(),
+            new_block_index,
+            additions: Some(stats.additions),
+            deletions: Some(stats.deletions),
+            noop_reason,
         }],
+        warnings,
+        new_content,
+    };
+
+    println!("{}", to_json(&output, format));
+}
+
+/// `jsonl` 格式下批量操作解析完成时立即输出一行（status 为 `resolved`），并刷新 stdout
+pub fn stream_resolved_operation(info: &OperationInfo) {
+    use std::io::Write;
+    let change = Change {
+        file: info.file.to_string_lossy().to_string(),
+        operation: info.operation.clone(),
+        heading: info.heading.clone(),
+        index: info.index,
+        status: "resolved".to_string(),
+        new_block_index: None,
+        additions: None,
+        deletions: None,
+        noop_reason: None,
     };
+    println!("{}", to_json(&change, OutputFormat::Jsonl));
+    let _ = std::io::stdout().flush();
+}
 
-    println!("{}", serde_json::to_string_pretty(&output).unwrap());
+fn print_short(diff: &str, theme: ColorTheme, applied: bool, is_noop: bool) {
+    println!("{}", short_summary(diff, theme, applied, is_noop));
 }
 
-fn print_short(diff: &str, applied: bool, is_noop: bool) {
+fn short_summary(diff: &str, theme: ColorTheme, applied: bool, is_noop: bool) -> String {
     if is_noop {
-        println!("{}", "No changes".dimmed());
-        return;
+        return "No changes".dimmed().to_string();
     }
 
-    let additions = diff.lines().filter(|l| l.starts_with('+') && !l.starts_with("+++")).count();
-    let deletions = diff.lines().filter(|l| l.starts_with('-') && !l.starts_with("---")).count();
-
+    let stats = DiffStats::of(diff);
     let status = if applied {
-        "Applied".green()
+        theme.applied("Applied")
     } else {
-        "Planned".yellow()
+        theme.planned("Planned")
     };
 
-    println!("{}: +{} -{}", status, additions, deletions);
+    format!(
+        "{}: +{}/-{} lines (net {:+}), +{}/-{} bytes",
+        status,
+        stats.additions,
+        stats.deletions,
+        stats.additions as isize - stats.deletions as isize,
+        stats.added_bytes,
+        stats.removed_bytes
+    )
+}
+
+/// Size of a change, counted from the `+`/`-` lines of a unified diff
+/// (bytes include each line's newline)
+#[derive(Debug, Default, PartialEq, Eq)]
+struct DiffStats {
+    additions: usize,
+    deletions: usize,
+    added_bytes: usize,
+    removed_bytes: usize,
+}
+
+impl DiffStats {
+    fn of(diff: &str) -> Self {
+        let mut stats = DiffStats::default();
+        // 文件头（`---`/`+++`）在第一个 hunk 之前，hunk 内以 `---` 开头的行是被删除的内容
+        for line in diff.lines().skip_while(|l| !l.starts_with("@@")) {
+            if let Some(text) = line.strip_prefix('+') {
+                stats.additions += 1;
+                stats.added_bytes += text.len() + 1;
+            } else if let Some(text) = line.strip_prefix('-') {
+                stats.deletions += 1;
+                stats.removed_bytes += text.len() + 1;
+            }
+        }
+        stats
+    }
 }
 
-#[allow(dead_code)]
 pub fn format_diff(old: &str, new: &str, filename: &str) -> String {
     // 如果内容相同，返回空 diff
     if old == new {
@@ -277,23 +697,23 @@ pub fn format_diff(old: &str, new: &str, filename: &str) -> String {
     ));
 
     // Context before
-    for i in context_start..start {
-        diff.push_str(&format!(" {}\n", old_lines[i]));
+    for line in &old_lines[context_start..start] {
+        diff.push_str(&format!(" {}\n", line));
     }
 
     // Deletions
-    for i in start..old_end {
-        diff.push_str(&format!("-{}\n", old_lines[i]));
+    for line in &old_lines[start..old_end] {
+        diff.push_str(&format!("-{}\n", line));
     }
 
     // Additions
-    for i in start..new_end {
-        diff.push_str(&format!("+{}\n", new_lines[i]));
+    for line in &new_lines[start..new_end] {
+        diff.push_str(&format!("+{}\n", line));
     }
 
     // Context after
-    for i in old_end..(old_end + 3).min(old_lines.len()) {
-        diff.push_str(&format!(" {}\n", old_lines[i]));
+    for line in &old_lines[old_end..(old_end + 3).min(old_lines.len())] {
+        diff.push_str(&format!(" {}\n", line));
     }
 
     diff
@@ -1,50 +1,423 @@
 use anyhow::{bail, Result};
+use clap::ValueEnum;
 use regex::Regex;
+use serde::Serialize;
+use std::path::Path;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+/// Tab stop width used when measuring indentation (list continuation and nesting)
+static TAB_WIDTH: AtomicUsize = AtomicUsize::new(4);
+
+/// Set the tab stop width (default 4) used to expand tabs when measuring indentation
+pub fn set_tab_width(width: usize) {
+    TAB_WIDTH.store(width.max(1), Ordering::Relaxed);
+}
+
+/// Indentation of `line` in columns, expanding tabs to the next tab stop, so `"\t"`,
+/// `"    "` and `"  \t"` all measure 4 with the default width
+pub fn indent_width(line: &str) -> usize {
+    let tab = TAB_WIDTH.load(Ordering::Relaxed);
+    let mut width = 0;
+    for c in line.chars() {
+        match c {
+            ' ' => width += 1,
+            '\t' => width += tab - width % tab,
+            _ => break,
+        }
+    }
+    width
+}
+
+/// Parsing strictness: which block-level extensions and heuristics `parse_block` applies
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Profile {
+    /// CommonMark core: no tables; an HTML block must open with a tag or declaration
+    Commonmark,
+    /// GitHub Flavored Markdown: CommonMark plus pipe tables
+    Gfm,
+    /// mdp's own rules: pipe tables, definition lists, and any line starting with `<`
+    /// opens an HTML block
+    #[default]
+    Internal,
+}
+
+/// Block features enabled by a [`Profile`]
+#[derive(Clone, Copy, Debug)]
+pub struct ProfileFeatures {
+    /// Recognize pipe tables (header row followed by a delimiter row)
+    pub tables: bool,
+    /// Recognize definition lists (`term` followed by `: definition` lines)
+    pub definition_lists: bool,
+    /// Only `<tag`, `</tag`, `<!X` or `<?` opens an HTML block (so `<3` or `<https://…>`
+    /// start a paragraph); otherwise any line starting with `<` does
+    pub strict_html: bool,
+}
+
+impl Profile {
+    pub fn features(self) -> ProfileFeatures {
+        match self {
+            Profile::Commonmark => ProfileFeatures { tables: false, definition_lists: false, strict_html: true },
+            Profile::Gfm => ProfileFeatures { tables: true, definition_lists: false, strict_html: true },
+            Profile::Internal => ProfileFeatures { tables: true, definition_lists: true, strict_html: false },
+        }
+    }
+}
+
+/// Active parsing profile, stored as its discriminant
+static PROFILE: AtomicU8 = AtomicU8::new(Profile::Internal as u8);
+
+/// Set the parsing profile (default [`Profile::Internal`]) used by all subsequent parses
+pub fn set_profile(profile: Profile) {
+    PROFILE.store(profile as u8, Ordering::Relaxed);
+}
+
+fn profile() -> Profile {
+    match PROFILE.load(Ordering::Relaxed) {
+        0 => Profile::Commonmark,
+        1 => Profile::Gfm,
+        _ => Profile::Internal,
+    }
+}
+
+/// Markdown dialect used when splitting content into blocks
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Flavor {
+    /// Plain (CommonMark-like) Markdown
+    #[default]
+    Markdown,
+    /// MDX: `import`/`export` statements and JSX elements are kept as opaque blocks
+    Mdx,
+}
+
+impl Flavor {
+    /// Pick the flavor from the file extension (`.mdx` → MDX, otherwise Markdown)
+    pub fn detect(path: &Path) -> Flavor {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("mdx") => Flavor::Mdx,
+            _ => Flavor::Markdown,
+        }
+    }
+}
 
 /// Represents a block of content within a Markdown file
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Block {
     pub start: usize,      // Start offset in bytes
     pub end: usize,        // End offset in bytes
     pub content: String,   // Full content including delimiters
-    #[allow(dead_code)]
     pub block_type: BlockType,
 }
 
-#[derive(Debug, Clone)]
+/// Serialized as `{"type": "<kind>", ...}` using the names from [`BlockType::kind`]
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
 #[allow(dead_code)]
 pub enum BlockType {
     Paragraph,
     Heading { level: u8 },
+    #[serde(rename = "code")]
     CodeBlock { lang: Option<String> },
     List { ordered: bool },
+    #[serde(rename = "quote")]
     BlockQuote,
     Table,
     Html,
     ThematicBreak,
+    /// Reference-style link definition (`[id]: https://...`).
+    /// Each definition is its own block, even when several are consecutive,
+    /// so a single URL can be addressed and replaced individually.
+    LinkDefinition,
+    /// MDX `import`/`export` statement(s)
+    Esm,
+    /// MDX JSX element, kept verbatim up to its closing tag
+    Jsx,
+    /// Definition list: `term` lines each followed by `: definition` lines
+    DefinitionList,
+}
+
+impl BlockType {
+    /// All names returned by [`BlockType::kind`]
+    pub const KINDS: [&'static str; 12] = [
+        "paragraph",
+        "heading",
+        "code",
+        "list",
+        "quote",
+        "table",
+        "html",
+        "thematic_break",
+        "link_definition",
+        "esm",
+        "jsx",
+        "definition_list",
+    ];
+
+    /// Short, stable name of the block type
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BlockType::Paragraph => "paragraph",
+            BlockType::Heading { .. } => "heading",
+            BlockType::CodeBlock { .. } => "code",
+            BlockType::List { .. } => "list",
+            BlockType::BlockQuote => "quote",
+            BlockType::Table => "table",
+            BlockType::Html => "html",
+            BlockType::ThematicBreak => "thematic_break",
+            BlockType::LinkDefinition => "link_definition",
+            BlockType::Esm => "esm",
+            BlockType::Jsx => "jsx",
+            BlockType::DefinitionList => "definition_list",
+        }
+    }
+}
+
+/// Type-based block selector: `code[1]` is the second code block, `table` the first table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockSelector {
+    /// A [`BlockType::kind`] name
+    pub kind: String,
+    /// 0-based position among blocks of that kind
+    pub nth: usize,
+}
+
+/// Split a selector suffix off a heading path: `## API > code[0]` → (`## API`, `code[0]`).
+/// Text after the last ` > ` is only treated as a selector if it names a known block kind.
+pub fn split_selector(path: &str) -> (&str, Option<BlockSelector>) {
+    let selector_re = Regex::new(r"^([a-z_]+)(?:\[(\d+)\])?$").unwrap();
+    let Some((heading, selector)) = path.rsplit_once(" > ") else {
+        return (path, None);
+    };
+    match selector_re.captures(selector.trim()) {
+        Some(caps) if BlockType::KINDS.contains(&&caps[1]) => {
+            let nth = caps.get(2).map_or(0, |n| n.as_str().parse().unwrap_or(usize::MAX));
+            (heading, Some(BlockSelector { kind: caps[1].to_string(), nth }))
+        }
+        _ => (path, None),
+    }
+}
+
+/// Index of the block matched by `selector` within the section
+pub fn select_block(section: &Section, selector: &BlockSelector) -> Result<usize> {
+    let matches: Vec<usize> = section
+        .blocks
+        .iter()
+        .enumerate()
+        .filter(|(_, b)| b.block_type.kind() == selector.kind)
+        .map(|(idx, _)| idx)
+        .collect();
+    match matches.get(selector.nth) {
+        Some(&idx) => Ok(idx),
+        None => bail!(
+            "No {}[{}] block under '{}' (section has {} {} block(s))",
+            selector.kind,
+            selector.nth,
+            section.heading,
+            matches.len(),
+            selector.kind
+        ),
+    }
+}
+
+/// SHA-256 hex digest of a block's content, the address used by `--block-hash`
+pub fn block_hash(block: &Block) -> String {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(block.content.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Index of the block whose content hash starts with `prefix` (like a git short hash)
+pub fn select_block_by_hash(section: &Section, prefix: &str) -> Result<usize> {
+    if prefix.is_empty() || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("Invalid block hash prefix '{}': expected hex digits", prefix);
+    }
+    let prefix = prefix.to_ascii_lowercase();
+    let matches: Vec<usize> = section
+        .blocks
+        .iter()
+        .enumerate()
+        .filter(|(_, b)| block_hash(b).starts_with(&prefix))
+        .map(|(idx, _)| idx)
+        .collect();
+    match matches.as_slice() {
+        [idx] => Ok(*idx),
+        [] => bail!(
+            "Block hash mismatch: no block under '{}' has a hash starting with '{}'",
+            section.heading,
+            prefix
+        ),
+        _ => bail!(
+            "Block hash prefix '{}' matches blocks {:?} under '{}'; use a longer prefix",
+            prefix,
+            matches,
+            section.heading
+        ),
+    }
 }
 
 /// Represents a section under a heading
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Section {
     pub heading: String,
-    #[allow(dead_code)]
     pub heading_level: u8,
-    #[allow(dead_code)]
     pub heading_start: usize,
-    #[allow(dead_code)]
+    /// End of the heading text, *before* the line's trailing newline (block offsets follow
+    /// the same convention). Use [`heading_line_end`] to insert on the next line.
     pub heading_end: usize,
     pub blocks: Vec<Block>,
+    /// Byte ranges of blocks marked `<!-- mdp:ignore -->` (directive included); they are
+    /// left out of `blocks` and cannot be edited
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ignored: Vec<(usize, usize)>,
 }
 
-/// Parse markdown content and find all sections
+/// Whether `text` is exactly the `<!-- mdp:ignore -->` directive
+fn is_ignore_directive(text: &str) -> bool {
+    let directive_re = Regex::new(r"^<!--\s*mdp:ignore\s*-->$").unwrap();
+    directive_re.is_match(text.trim())
+}
+
+/// Index of each section's parent section (the nearest preceding section with a lower level)
+pub fn section_parents(sections: &[Section]) -> Vec<Option<usize>> {
+    let mut parents = Vec::with_capacity(sections.len());
+    let mut stack: Vec<usize> = Vec::new();
+    for (idx, section) in sections.iter().enumerate() {
+        while let Some(&top) = stack.last() {
+            if sections[top].heading_level < section.heading_level {
+                break;
+            }
+            stack.pop();
+        }
+        parents.push(stack.last().copied());
+        stack.push(idx);
+    }
+    parents
+}
+
+/// Full heading path of a section from its top-level ancestor, e.g. `# Guide ## Setup ### Notes`
+pub fn section_path(sections: &[Section], idx: usize) -> String {
+    let parents = section_parents(sections);
+    let mut chain = vec![idx];
+    while let Some(parent) = parents[*chain.last().unwrap()] {
+        chain.push(parent);
+    }
+    chain.iter().rev().map(|&i| sections[i].heading.trim()).collect::<Vec<_>>().join(" ")
+}
+
+/// Report of every section matching an ambiguous heading: line, full disambiguating path
+/// and the `--occurrence` that selects it
+pub fn ambiguity_report(content: &str, sections: &[Section], heading: &str) -> String {
+    let heading = heading.trim();
+    let mut report = format!("Candidates for '{}':", heading);
+    let matches = sections.iter().enumerate().filter(|(_, s)| s.heading.trim() == heading);
+    for (occurrence, (idx, section)) in matches.enumerate() {
+        let line = content[..section.heading_start].matches('\n').count() + 1;
+        report.push_str(&format!(
+            "\n  line {}: -H '{}' (or --occurrence {})",
+            line,
+            section_path(sections, idx),
+            occurrence
+        ));
+    }
+    report
+}
+
+/// Parse plain Markdown content and find all sections
+#[cfg(test)]
 pub fn parse_sections(content: &str) -> Result<Vec<Section>> {
+    parse_sections_with(content, Flavor::Markdown)
+}
+
+/// Coarse block granularity: merge each run of consecutive paragraphs (separated only by
+/// blank lines) into a single paragraph block, so the prose body is addressed as one unit.
+/// Code blocks, tables, lists and other block types stay distinct.
+pub fn coarsen_paragraphs(content: &str, sections: &mut [Section]) {
+    for section in sections {
+        let mut merged: Vec<Block> = Vec::with_capacity(section.blocks.len());
+        for block in section.blocks.drain(..) {
+            match merged.last_mut() {
+                Some(prev)
+                    if matches!(prev.block_type, BlockType::Paragraph)
+                        && matches!(block.block_type, BlockType::Paragraph) =>
+                {
+                    prev.end = block.end;
+                    prev.content = content[prev.start..prev.end].to_string();
+                }
+                _ => merged.push(block),
+            }
+        }
+        section.blocks = merged;
+    }
+}
+
+/// Byte range of the managed region `name`: from the line after `<!-- mdp:begin name -->`
+/// to the start of the `<!-- mdp:end name -->` line. Markers inside code fences are ignored.
+pub fn region_range(content: &str, name: &str) -> Result<(usize, usize)> {
+    let marker_re = Regex::new(r"^<!--\s*mdp:(begin|end)\s+(\S+)\s*-->$").unwrap();
+    let mut begin = None;
+    let mut fence: Option<&str> = None;
+    let mut offset = 0;
+    for line in content.split_inclusive('\n') {
+        let line_start = offset;
+        offset += line.len();
+        let trimmed = line.trim();
+        match fence {
+            Some(open) if closes_fence(trimmed, open) => {
+                fence = None;
+                continue;
+            }
+            Some(_) => continue,
+            None if code_fence(trimmed).is_some() => {
+                fence = code_fence(trimmed);
+                continue;
+            }
+            None => {}
+        }
+        let Some(caps) = marker_re.captures(trimmed).filter(|c| &c[2] == name) else {
+            continue;
+        };
+        match (&caps[1], begin) {
+            ("begin", None) => begin = Some(offset),
+            ("begin", Some(_)) => bail!("Region '{}' is opened twice", name),
+            ("end", Some(start)) => return Ok((start, line_start)),
+            _ => bail!("Region '{}' ends before it begins", name),
+        }
+    }
+    match begin {
+        Some(_) => bail!("Region '{}' is not closed: missing `<!-- mdp:end {} -->`", name, name),
+        None => bail!("Region '{}' not found: missing `<!-- mdp:begin {} -->`", name, name),
+    }
+}
+
+/// Bound resolution to a region: keep only the blocks inside `range`, and only the
+/// sections that start inside it or still have blocks in it
+pub fn restrict_to_region(content: &str, sections: &mut Vec<Section>, (start, end): (usize, usize)) {
+    for section in sections.iter_mut() {
+        section.blocks.retain_mut(|b| {
+            // 与标记行粘连的 block（如段落紧接 `<!-- mdp:end -->`）裁剪到区域边界
+            let (b_start, b_end) = (b.start.max(start), b.end.min(end));
+            if b_start >= b_end || content[b_start..b_end].trim().is_empty() {
+                return false;
+            }
+            if (b_start, b_end) != (b.start, b.end) {
+                b.content = content[b_start..b_end].trim_end_matches('\n').to_string();
+                b.start = b_start;
+                b.end = b_start + b.content.len();
+            }
+            true
+        });
+    }
+    sections.retain(|s| (start..end).contains(&s.heading_start) || !s.blocks.is_empty());
+}
+
+/// Parse content of the given flavor and find all sections
+pub fn parse_sections_with(content: &str, flavor: Flavor) -> Result<Vec<Section>> {
     let mut sections = Vec::new();
     let lines: Vec<&str> = content.lines().collect();
     
     let mut current_section: Option<Section> = None;
     let mut i = 0;
     let mut current_offset = 0;
+    // 与代码块一样，原样 HTML 块（如 <pre>）内部的 `#` 行不是 heading
+    let in_raw_html = raw_html_lines(&lines);
 
     while i < lines.len() {
         let line = lines[i];
@@ -52,10 +425,13 @@ pub fn parse_sections(content: &str) -> Result<Vec<Section>> {
         let line_end = current_offset + line.len();
         
         // Check if this is a heading
-        if let Some(caps) = Regex::new(r"^(#{1,6})\s+(.+)$").unwrap().captures(line) {
-            let hashes = caps.get(1).unwrap().as_str();
+        let heading = if in_raw_html[i] { None } else { heading_parts(line) };
+        if let Some((hashes, title)) = heading {
             let level = hashes.len() as u8;
-            let heading_text = format!("{} {}", hashes, caps.get(2).unwrap().as_str());
+            let heading_text = match strip_closing_hashes(title) {
+                "" => hashes.to_string(),
+                title => format!("{} {}", hashes, title),
+            };
 
             // Close previous section
             if let Some(section) = current_section.take() {
@@ -69,11 +445,23 @@ pub fn parse_sections(content: &str) -> Result<Vec<Section>> {
                 heading_start: line_start,
                 heading_end: line_end,
                 blocks: Vec::new(),
+                ignored: Vec::new(),
             });
         } else if let Some(ref mut section) = current_section {
             // Parse block in this section
-            if let Some((block, next_i)) = parse_block(&lines, i, current_offset)? {
-                section.blocks.push(block);
+            if let Some((block, next_i)) = parse_block(&lines, i, current_offset, flavor)? {
+                // `<!-- mdp:ignore -->` 单独成块时作用于下一个 block，与之粘连时作用于所在 block
+                let pending = section.blocks.last().filter(|b| is_ignore_directive(&b.content)).map(|b| b.start);
+                if let Some(start) = pending {
+                    section.blocks.pop();
+                    section.ignored.push((start, block.end));
+                } else if block.content.lines().next().is_some_and(is_ignore_directive)
+                    && !is_ignore_directive(&block.content)
+                {
+                    section.ignored.push((block.start, block.end));
+                } else {
+                    section.blocks.push(block);
+                }
                 // Adjust current_offset for next iteration
                 let lines_consumed = next_i - i;
                 for j in 0..lines_consumed {
@@ -93,76 +481,687 @@ pub fn parse_sections(content: &str) -> Result<Vec<Section>> {
         sections.push(section);
     }
 
+    check_block_order(&sections)?;
     Ok(sections)
 }
 
+/// Parser invariant: every block has `end >= start` and the blocks of a section are
+/// strictly ordered by `start`. A violation is a parser bug; reporting it here is
+/// better than a slicing panic (or an edit at the wrong offset) later on.
+fn check_block_order(sections: &[Section]) -> Result<()> {
+    for section in sections {
+        for (idx, block) in section.blocks.iter().enumerate() {
+            if block.end < block.start {
+                bail!(
+                    "Internal parser error: block {} of '{}' ends before it starts ({}..{}); please report this bug",
+                    idx, section.heading, block.start, block.end
+                );
+            }
+        }
+        for (idx, pair) in section.blocks.windows(2).enumerate() {
+            if pair[1].start <= pair[0].start {
+                bail!(
+                    "Internal parser error: blocks {} and {} of '{}' share or reverse start offset {}; please report this bug",
+                    idx, idx + 1, section.heading, pair[1].start
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How heading path segments are compared with `Section.heading`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HeadingMatch {
+    /// Exact text (ignoring surrounding whitespace)
+    #[default]
+    Exact,
+    /// Each segment is a regex matched against the heading line (e.g. `^## v\d+\.\d+`)
+    Regex,
+}
+
+/// One compiled heading path segment
+struct SegmentMatcher<'p> {
+    text: &'p str,
+    regex: Option<Regex>,
+}
+
+impl<'p> SegmentMatcher<'p> {
+    fn compile(segments: &'p [String], mode: HeadingMatch) -> Result<Vec<Self>> {
+        segments
+            .iter()
+            .map(|segment| {
+                let text = segment.trim();
+                let regex = match mode {
+                    HeadingMatch::Exact => None,
+                    HeadingMatch::Regex => Some(
+                        Regex::new(text).map_err(|e| anyhow::anyhow!("Invalid heading regex '{}': {}", text, e))?,
+                    ),
+                };
+                Ok(SegmentMatcher { text, regex })
+            })
+            .collect()
+    }
+
+    fn matches(&self, heading: &str) -> bool {
+        match &self.regex {
+            Some(regex) => regex.is_match(heading.trim()),
+            None => heading.trim() == self.text,
+        }
+    }
+}
+
 /// Find a section by heading path, supporting nested headings
 /// heading_path: ["# Parent", "## Child", "### GrandChild"]
 /// 从第一个 heading 开始，逐级向下查找
-pub fn find_section<'a>(sections: &'a [Section], heading_path: &[String]) -> Result<&'a Section> {
+///
+/// `occurrence` selects the Nth (0-based, document order) match of the first
+/// heading instead of reporting ambiguity.
+pub fn find_section<'a>(
+    sections: &'a [Section],
+    heading_path: &[String],
+    occurrence: Option<usize>,
+) -> Result<&'a Section> {
+    find_section_with(sections, heading_path, occurrence, HeadingMatch::Exact)
+}
+
+/// [`find_section`] with a choice of segment comparison; ambiguity rules are the same
+pub fn find_section_with<'a>(
+    sections: &'a [Section],
+    heading_path: &[String],
+    occurrence: Option<usize>,
+    mode: HeadingMatch,
+) -> Result<&'a Section> {
     if heading_path.is_empty() {
         bail!("Heading path cannot be empty");
     }
+    let matchers = SegmentMatcher::compile(heading_path, mode)?;
 
-    // 第一级：找到所有匹配的顶级 heading
-    let first_heading = heading_path[0].trim();
-    let first_level = first_heading.chars().take_while(|&c| c == '#').count() as u8;
-    
-    let candidates: Vec<&Section> = sections
+    // 第一级：找到所有匹配的顶级 heading（保留其在 sections 中的索引）
+    let first_heading = matchers[0].text;
+
+    let candidates: Vec<usize> = sections
         .iter()
-        .filter(|s| s.heading.trim() == first_heading)
+        .enumerate()
+        .filter(|(_, s)| matchers[0].matches(&s.heading))
+        .map(|(idx, _)| idx)
         .collect();
 
     if candidates.is_empty() {
         bail!("Heading not found: {}", first_heading);
     }
 
-    // 如果只找一级，但有多个匹配，报错提示歧义
-    if heading_path.len() == 1 {
-        if candidates.len() > 1 {
-            bail!(
-                "Multiple sections found for heading '{}'. Please provide a more specific path like '# Parent ## {}'.",
-                first_heading, 
-                first_heading.trim_start_matches('#').trim()
-            );
+    let starts: &[usize] = match occurrence {
+        Some(n) => match candidates.get(n) {
+            Some(candidate) => std::slice::from_ref(candidate),
+            None => bail!(
+                "Occurrence {} out of range: heading '{}' matches {} section(s)",
+                n,
+                first_heading,
+                candidates.len()
+            ),
+        },
+        None => {
+            // 如果只找一级，但有多个匹配，报错提示歧义
+            if heading_path.len() == 1 && candidates.len() > 1 {
+                bail!(
+                    "Multiple sections found for heading '{}'. Please provide a more specific path like '# Parent ## {}'.",
+                    first_heading, 
+                    first_heading.trim_start_matches('#').trim()
+                );
+            }
+            &candidates
+        }
+    };
+
+    // 多级路径：按文档顺序依次尝试各候选，取第一个能完整解析路径的（稳定的 tie-break）
+    let mut first_error = None;
+    for &start in starts {
+        match walk_heading_path(sections, start, &matchers[1..]) {
+            Ok(idx) => return Ok(&sections[idx]),
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
         }
-        return Ok(candidates[0]);
     }
+    Err(first_error.expect("at least one candidate"))
+}
 
-    // 多级路径：需要按顺序找到匹配的嵌套结构
-    // 由于 sections 是按文档顺序排列的，我们可以利用这一点
-    let mut current_section = candidates[0];
-    let mut section_idx = sections.iter().position(|s| s.heading == current_section.heading).unwrap();
+/// Tolerant fallback for a heading path that does not match exactly: each component
+/// that has no exact match is compared ignoring case and runs of whitespace (also
+/// between the `#` marks and the title). Returns the corrected path when every
+/// component resolves to a single distinct heading and at least one was corrected.
+pub fn fuzzy_heading_path(sections: &[Section], heading_path: &[String]) -> Option<Vec<String>> {
+    correct_heading_path(sections, heading_path, normalize_heading)
+}
 
-    for i in 1..heading_path.len() {
-        let target_heading = heading_path[i].trim();
-        let _target_level = target_heading.chars().take_while(|&c| c == '#').count() as u8;
+/// Like [`fuzzy_heading_path`], but also ignoring emoji: `:shortcode:`s are mapped to
+/// their literal emoji and leading emoji are stripped, so `## Launch` finds
+/// `## :rocket: Launch` and `## 🚀 Launch`
+pub fn emoji_heading_path(sections: &[Section], heading_path: &[String]) -> Option<Vec<String>> {
+    correct_heading_path(sections, heading_path, normalize_heading_emoji)
+}
 
-        // 从当前 section 之后开始查找
-        let mut found = false;
-        for (idx, section) in sections.iter().enumerate().skip(section_idx + 1) {
-            let section_level = section.heading.chars().take_while(|&c| c == '#').count() as u8;
-            
-            // 如果遇到同级的 heading，说明已经离开了当前 section 的范围
-            if section_level <= first_level {
-                break;
+fn correct_heading_path(
+    sections: &[Section],
+    heading_path: &[String],
+    normalize: fn(&str) -> String,
+) -> Option<Vec<String>> {
+    let mut corrected = Vec::with_capacity(heading_path.len());
+    let mut changed = false;
+    for component in heading_path {
+        let wanted = component.trim();
+        if sections.iter().any(|s| s.heading.trim() == wanted) {
+            corrected.push(wanted.to_string());
+            continue;
+        }
+        let key = normalize(wanted);
+        let mut matches: Vec<&str> = sections
+            .iter()
+            .map(|s| s.heading.trim())
+            .filter(|h| normalize(h) == key)
+            .collect();
+        matches.sort_unstable();
+        matches.dedup();
+        match matches.as_slice() {
+            [single] => {
+                corrected.push(single.to_string());
+                changed = true;
             }
-            
-            // 匹配目标 heading
-            if section.heading.trim() == target_heading {
-                current_section = section;
-                section_idx = idx;
-                found = true;
-                break;
+            _ => return None,
+        }
+    }
+    changed.then_some(corrected)
+}
+
+/// `##  API   usage` -> `## api usage`
+fn normalize_heading(heading: &str) -> String {
+    let title = heading.trim_start_matches('#');
+    let hashes = heading.len() - title.len();
+    let words: Vec<String> = title.split_whitespace().map(str::to_lowercase).collect();
+    format!("{} {}", "#".repeat(hashes), words.join(" "))
+}
+
+/// GitHub shortcodes commonly used in headings and their literal emoji
+const EMOJI_SHORTCODES: &[(&str, &str)] = &[
+    ("rocket", "🚀"),
+    ("sparkles", "✨"),
+    ("tada", "🎉"),
+    ("bug", "🐛"),
+    ("memo", "📝"),
+    ("books", "📚"),
+    ("book", "📖"),
+    ("package", "📦"),
+    ("wrench", "🔧"),
+    ("hammer", "🔨"),
+    ("gear", "⚙️"),
+    ("zap", "⚡"),
+    ("fire", "🔥"),
+    ("bulb", "💡"),
+    ("star", "⭐"),
+    ("art", "🎨"),
+    ("lock", "🔒"),
+    ("link", "🔗"),
+    ("mag", "🔍"),
+    ("construction", "🚧"),
+    ("warning", "⚠️"),
+    ("x", "❌"),
+    ("white_check_mark", "✅"),
+    ("heavy_check_mark", "✔️"),
+    ("question", "❓"),
+    ("information_source", "ℹ️"),
+    ("recycle", "♻️"),
+    ("chart_with_upwards_trend", "📈"),
+    ("globe_with_meridians", "🌐"),
+    ("test_tube", "🧪"),
+];
+
+fn is_emoji_char(c: char) -> bool {
+    matches!(
+        c as u32,
+        0x1F000..=0x1FAFF | 0x2600..=0x27BF | 0x2300..=0x23FF | 0x2B00..=0x2BFF | 0x2139 | 0x200D | 0xFE0F
+    )
+}
+
+/// `## :rocket:  Launch 🚀` -> `## launch 🚀`: shortcodes become literal emoji, leading emoji
+/// (and unknown leading shortcodes) are dropped, then case and whitespace are normalized
+fn normalize_heading_emoji(heading: &str) -> String {
+    let title = heading.trim_start_matches('#');
+    let hashes = &heading[..heading.len() - title.len()];
+
+    let mut literal = title.to_string();
+    for (code, emoji) in EMOJI_SHORTCODES {
+        literal = literal.replace(&format!(":{}:", code), emoji);
+    }
+
+    let mut rest = literal.trim_start();
+    loop {
+        let stripped = rest.trim_start_matches(is_emoji_char).trim_start();
+        let stripped = match stripped.strip_prefix(':').and_then(|s| s.split_once(':')) {
+            Some((code, after)) if !code.is_empty() && !code.contains(char::is_whitespace) => after.trim_start(),
+            _ => stripped,
+        };
+        if stripped.len() == rest.len() {
+            break;
+        }
+        rest = stripped;
+    }
+    normalize_heading(&format!("{} {}", hashes, rest))
+}
+
+/// Like [`fuzzy_heading_path`], but also ignoring inline formatting: emphasis, code and
+/// strikethrough markers and link targets, so `## Bold` finds `## **Bold**`
+pub fn plain_heading_path(sections: &[Section], heading_path: &[String]) -> Option<Vec<String>> {
+    correct_heading_path(sections, heading_path, normalize_heading_plain)
+}
+
+/// `## **Use** the [CLI](cli.md)` -> `## use the cli`
+fn normalize_heading_plain(heading: &str) -> String {
+    let link_re = Regex::new(r"!?\[([^\]]*)\]\([^)]*\)").unwrap();
+    // 仅去掉词首/词尾的 `_`，保留 snake_case 中的下划线
+    let underscore_re = Regex::new(r"(^|\s)_+|_+(\s|$)").unwrap();
+    let plain = link_re.replace_all(heading, "$1");
+    let plain = plain.replace(['*', '`'], "").replace("~~", "");
+    normalize_heading(&underscore_re.replace_all(&plain, "$1$2"))
+}
+
+/// Split an ATX heading line into its `#` run and raw title. This is the single
+/// definition of a heading line: 1-6 `#`s, whitespace, then a title with at least one
+/// non-whitespace character. `#`, `###### ` and `#no-space` are not headings.
+fn heading_parts(line: &str) -> Option<(&str, &str)> {
+    let level = line.len() - line.trim_start_matches('#').len();
+    if !(1..=6).contains(&level) {
+        return None;
+    }
+    let (hashes, rest) = line.split_at(level);
+    let title = rest.trim_start();
+    if title.len() == rest.len() || title.trim_end().is_empty() {
+        return None;
+    }
+    Some((hashes, title))
+}
+
+/// Whether `line` starts a section (see [`heading_parts`])
+pub fn is_heading_line(line: &str) -> bool {
+    heading_parts(line).is_some()
+}
+
+/// Drop an optional closing sequence (`## Title ##` -> `Title`): trailing `#`s preceded
+/// by whitespace, or a title made only of `#`s (CommonMark ATX headings)
+fn strip_closing_hashes(title: &str) -> &str {
+    let trimmed = title.trim_end();
+    let without = trimmed.trim_end_matches('#');
+    if without.is_empty() {
+        return "";
+    }
+    if without.len() < trimmed.len() && without.ends_with([' ', '\t']) {
+        return without.trim_end();
+    }
+    trimmed
+}
+
+/// Resolve the remaining path components beneath `section_idx`, each one a direct
+/// child of the section matched by the previous component: a component that skips a
+/// level (`# A ### C`) does not reach into an intermediate heading (`## B`) of that level
+fn walk_heading_path(sections: &[Section], mut section_idx: usize, rest: &[SegmentMatcher]) -> Result<usize> {
+    for target in rest {
+        let target_heading = target.text;
+        let scope_level = sections[section_idx].heading_level;
+
+        // 从当前 section 之后开始查找，遇到同级或更高级 heading 即离开当前范围
+        let subtree: Vec<(usize, &Section)> = sections
+            .iter()
+            .enumerate()
+            .skip(section_idx + 1)
+            .take_while(|(_, s)| s.heading_level > scope_level)
+            .collect();
+        // 直接子节点：此前的 heading 级别都不比它浅（否则它嵌套在中间 heading 之下）
+        let mut shallowest = u8::MAX;
+        let mut found = None;
+        let mut nested = None;
+        for &(idx, s) in &subtree {
+            if target.matches(&s.heading) {
+                if s.heading_level <= shallowest {
+                    found = Some(idx);
+                    break;
+                }
+                nested.get_or_insert(idx);
+            }
+            shallowest = shallowest.min(s.heading_level);
+        }
+
+        match (found, nested) {
+            (Some(idx), _) => section_idx = idx,
+            (None, Some(idx)) => {
+                // 指出实际包含该 heading 的中间 heading，便于补全路径
+                let level = sections[idx].heading_level;
+                let parent = sections[..idx].iter().rev().find(|s| s.heading_level < level).expect("nested under a heading");
+                bail!(
+                    "Subheading not found: {} is not a direct child of '{}' (it is nested under '{}'; include that heading in the path)",
+                    target_heading,
+                    sections[section_idx].heading.trim(),
+                    parent.heading.trim()
+                );
+            }
+            (None, None) => bail!("Subheading not found: {}", target_heading),
+        }
+    }
+    Ok(section_idx)
+}
+
+/// Parse content that starts inside a section into blocks, stopping at the first heading.
+/// Offsets are relative to `content`.
+pub fn parse_blocks(content: &str, flavor: Flavor) -> Result<Vec<Block>> {
+    let lines: Vec<&str> = content.lines().collect();
+    let mut blocks = Vec::new();
+    let mut i = 0;
+    let mut offset = 0;
+
+    while i < lines.len() && !is_heading_line(lines[i]) {
+        if let Some((block, next_i)) = parse_block(&lines, i, offset, flavor)? {
+            offset += lines[i..next_i].iter().map(|l| l.len() + 1).sum::<usize>();
+            blocks.push(block);
+            i = next_i;
+            continue;
+        }
+        offset += lines[i].len() + 1;
+        i += 1;
+    }
+
+    Ok(blocks)
+}
+
+/// Resolve a block index counted across a section and all of its descendant
+/// subsections in document order. Returns `(section_index, block_index)`.
+pub fn find_block_recursive(sections: &[Section], section_idx: usize, index: usize) -> Result<(usize, usize)> {
+    let level = sections[section_idx].heading_level;
+    let mut remaining = index;
+    let mut total = 0;
+
+    // 后续 section 中级别更深的均属于该 section 的子树
+    for (idx, section) in sections.iter().enumerate().skip(section_idx) {
+        if idx > section_idx && section.heading_level <= level {
+            break;
+        }
+        if remaining < section.blocks.len() {
+            return Ok((idx, remaining));
+        }
+        remaining -= section.blocks.len();
+        total += section.blocks.len();
+    }
+
+    bail!(
+        "Block index {} out of range (section and its subsections have {} blocks)",
+        index,
+        total
+    )
+}
+
+/// Find a section by its outline number, e.g. `2.1.3` is the 3rd child of the
+/// 1st child of the 2nd top-level section (ordinals are 1-based).
+/// Returns the section index.
+pub fn find_section_by_number(sections: &[Section], number: &str) -> Result<usize> {
+    let parents = section_parents(sections);
+    let mut current: Option<usize> = None;
+
+    for (depth, part) in number.split('.').enumerate() {
+        let ordinal: usize = match part.trim().parse() {
+            Ok(n) if n >= 1 => n,
+            _ => bail!("Invalid section number '{}': expected ordinals like 2.1.3", number),
+        };
+        let children: Vec<usize> = (0..sections.len()).filter(|&idx| parents[idx] == current).collect();
+        match children.get(ordinal - 1) {
+            Some(&idx) => current = Some(idx),
+            None => bail!(
+                "Heading not found: section number {} (level {} has only {} section(s))",
+                number,
+                depth + 1,
+                children.len()
+            ),
+        }
+    }
+
+    match current {
+        Some(idx) => Ok(idx),
+        None => bail!("Invalid section number '{}'", number),
+    }
+}
+
+/// Find the block containing the given document line (1-based).
+/// Returns `(section_index, block_index)`.
+pub fn find_block_by_line(sections: &[Section], content: &str, line: usize) -> Result<(usize, usize)> {
+    if line == 0 {
+        bail!("Line numbers are 1-based");
+    }
+
+    // 计算目标行的起始字节偏移（与 parse_sections 的偏移计算保持一致）
+    let mut offset = 0;
+    let mut lines = content.lines();
+    for _ in 1..line {
+        match lines.next() {
+            Some(l) => offset += l.len() + 1,
+            None => bail!("Line {} is beyond the end of the document", line),
+        }
+    }
+    if lines.next().is_none() {
+        bail!("Line {} is beyond the end of the document", line);
+    }
+
+    for (section_idx, section) in sections.iter().enumerate() {
+        if let Some(block_idx) = section
+            .blocks
+            .iter()
+            .position(|b| b.start <= offset && offset <= b.end)
+        {
+            return Ok((section_idx, block_idx));
+        }
+        if section.ignored.iter().any(|&(start, end)| start <= offset && offset <= end) {
+            bail!("Block at line {} is marked <!-- mdp:ignore --> and cannot be targeted", line);
+        }
+    }
+
+    bail!("No addressable block at line {} (blank line, heading, or before the first heading)", line)
+}
+
+/// A single item of a list block
+#[derive(Debug, Clone)]
+pub struct ListItem {
+    /// Item marker including trailing whitespace, e.g. `- ` or `3. `
+    pub marker: String,
+    /// Item text after the marker, including continuation lines
+    pub text: String,
+}
+
+/// Split a list block into its top-level items.
+/// Returns the items and whether the list is loose (items separated by blank lines).
+pub fn list_items(content: &str) -> (Vec<ListItem>, bool) {
+    let marker_re = Regex::new(r"^(\s*)([-*+]|\d+[.)])(\s+)").unwrap();
+    let base_indent = indent_width(content);
+
+    let mut items: Vec<ListItem> = Vec::new();
+    let mut loose = false;
+    for line in content.lines() {
+        let caps = marker_re
+            .captures(line)
+            .filter(|caps| indent_width(caps.get(1).unwrap().as_str()) == base_indent);
+        match (caps, items.last_mut()) {
+            (Some(caps), _) => {
+                if let Some(prev) = items.last_mut() {
+                    if prev.text.ends_with('\n') {
+                        loose = true;
+                    }
+                    prev.text = prev.text.trim_end_matches('\n').to_string();
+                }
+                let marker = caps.get(0).unwrap().as_str();
+                items.push(ListItem {
+                    marker: marker.to_string(),
+                    text: line[marker.len()..].to_string(),
+                });
+            }
+            (None, Some(item)) => {
+                item.text.push('\n');
+                if !line.trim().is_empty() {
+                    item.text.push_str(line);
+                }
+            }
+            (None, None) => {}
+        }
+    }
+    if let Some(last) = items.last_mut() {
+        last.text = last.text.trim_end_matches('\n').to_string();
+    }
+    (items, loose)
+}
+
+/// Opening fence of a fenced code block: a run of at least three backticks or tildes
+/// (after indentation), e.g. "```" or "~~~~"
+pub fn code_fence(line: &str) -> Option<&str> {
+    let trimmed = line.trim_start();
+    let fence_char = trimmed.chars().next().filter(|c| matches!(c, '`' | '~'))?;
+    let run = trimmed.len() - trimmed.trim_start_matches(fence_char).len();
+    (run >= 3).then(|| &trimmed[..run])
+}
+
+/// Whether `line` closes a code block opened with `fence`: the same character, at least
+/// as many times, and nothing else on the line
+pub fn closes_fence(line: &str, fence: &str) -> bool {
+    let trimmed = line.trim();
+    let fence_char = fence.chars().next().unwrap_or('`');
+    trimmed.len() >= fence.len() && trimmed.chars().all(|c| c == fence_char)
+}
+
+/// The code fence still open at the end of `content`: its line number (1-based) and marker
+fn open_fence_at_end(content: &str) -> Option<(usize, &str)> {
+    let mut open: Option<(usize, &str)> = None;
+    for (i, line) in content.lines().enumerate() {
+        match open {
+            None => open = code_fence(line).map(|fence| (i + 1, fence)),
+            Some((_, fence)) if closes_fence(line, fence) => open = None,
+            Some(_) => {}
+        }
+    }
+    open
+}
+
+/// Line numbers (1-based) of code fences that are opened but never closed
+pub fn unterminated_fences(content: &str) -> Vec<usize> {
+    open_fence_at_end(content).map(|(line, _)| line).into_iter().collect()
+}
+
+/// Close an unterminated code fence right before the next heading line
+/// (or at the end of the document), so later sections are not swallowed
+pub fn repair_fences(content: &str) -> String {
+    let Some((open_line, fence)) = open_fence_at_end(content) else {
+        return content.to_string();
+    };
+
+    let mut repaired = String::with_capacity(content.len() + 4);
+    let mut closed = false;
+    for (i, line) in content.lines().enumerate() {
+        if !closed && i + 1 > open_line && is_heading_line(line) {
+            repaired.push_str(fence);
+            repaired.push('\n');
+            closed = true;
+        }
+        repaired.push_str(line);
+        repaired.push('\n');
+    }
+    if !content.ends_with('\n') {
+        repaired.pop();
+    }
+    if !closed {
+        if !repaired.ends_with('\n') {
+            repaired.push('\n');
+        }
+        repaired.push_str(fence);
+        repaired.push('\n');
+    }
+    repaired
+}
+
+/// Backslash-escape lines that would parse as ATX headings (`# x` → `\# x`), leaving
+/// code fences and raw HTML blocks untouched, so the text stays literal prose
+pub fn escape_heading_lines(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    let in_raw_html = raw_html_lines(&lines);
+    let mut fence: Option<&str> = None;
+
+    let mut escaped = String::with_capacity(text.len());
+    for (i, line) in text.split_inclusive('\n').enumerate() {
+        if let Some(open) = fence {
+            if closes_fence(line, open) {
+                fence = None;
             }
+        } else if let Some(open) = code_fence(line) {
+            fence = Some(open);
+        } else if !in_raw_html[i] && is_heading_line(line) {
+            escaped.push('\\');
         }
+        escaped.push_str(line);
+    }
+    escaped
+}
+
+/// Byte ranges of all HTML comments (`<!-- ... -->`), including multi-line ones.
+/// An unterminated comment runs to the end of the document.
+pub fn html_comment_ranges(content: &str) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    let mut pos = 0;
+    while let Some(open) = content[pos..].find("<!--") {
+        let start = pos + open;
+        let end = match content[start + 4..].find("-->") {
+            Some(close) => start + 4 + close + 3,
+            None => content.len(),
+        };
+        ranges.push((start, end));
+        pos = end;
+    }
+    ranges
+}
 
-        if !found {
-            bail!("Subheading not found: {}", target_heading);
+/// Remove all HTML comments from the document
+pub fn strip_html_comments(content: &str) -> String {
+    text_without_ranges(content, 0, content.len(), &html_comment_ranges(content))
+}
+
+/// Text of `content[start..end]` with the given byte ranges removed
+pub fn text_without_ranges(content: &str, start: usize, end: usize, ranges: &[(usize, usize)]) -> String {
+    let mut text = String::new();
+    let mut pos = start;
+    for &(r_start, r_end) in ranges {
+        if r_end <= pos || r_start >= end {
+            continue;
         }
+        if r_start > pos {
+            text.push_str(&content[pos..r_start]);
+        }
+        pos = r_end.min(end);
+    }
+    if pos < end {
+        text.push_str(&content[pos..end]);
     }
+    text
+}
 
-    Ok(current_section)
+/// Offset just past the heading line's newline (`heading_end` itself stops before it),
+/// i.e. where content inserted directly below the heading starts on a fresh line.
+/// Equals the content length when the heading is the last line without a newline.
+pub fn heading_line_end(content: &str, section: &Section) -> usize {
+    match content[section.heading_end..].find('\n') {
+        Some(pos) => section.heading_end + pos + 1,
+        None => content.len(),
+    }
+}
+
+/// Synthetic block spanning the section's heading line, so the heading can be
+/// fingerprinted, replaced or deleted like any other block
+pub fn heading_block(content: &str, section: &Section) -> Block {
+    Block {
+        start: section.heading_start,
+        end: section.heading_end,
+        content: content[section.heading_start..section.heading_end].to_string(),
+        block_type: BlockType::Heading { level: section.heading_level },
+    }
 }
 
 /// Get a block by index within a section
@@ -177,11 +1176,21 @@ pub fn get_block(section: &Section, index: usize) -> Result<&Block> {
     Ok(&section.blocks[index])
 }
 
+/// Get the consecutive blocks `first..=last` of a section
+pub fn get_blocks(section: &Section, first: usize, last: usize) -> Result<&[Block]> {
+    if first > last {
+        bail!("Invalid block range {}..{}: the first index is after the last", first, last);
+    }
+    get_block(section, last)?;
+    Ok(&section.blocks[first..=last])
+}
+
 /// Parse a block starting at the given line
 fn parse_block(
     lines: &[&str],
     start: usize,
     start_offset: usize,
+    flavor: Flavor,
 ) -> Result<Option<(Block, usize)>> {
     if start >= lines.len() {
         return Ok(None);
@@ -193,48 +1202,419 @@ fn parse_block(
     if line.is_empty() {
         return Ok(None);
     }
+    let features = profile().features();
+
+    // Code block
+    if code_fence(line).is_some() {
+        return parse_code_block(lines, start, start_offset);
+    }
+
+    // MDX：import/export 语句与 JSX 元素原样保留
+    if flavor == Flavor::Mdx {
+        if is_esm_line(lines[start]) {
+            return parse_esm(lines, start, start_offset);
+        }
+        if is_jsx_line(lines[start]) {
+            return parse_jsx(lines, start, start_offset);
+        }
+    }
+
+    // Link reference definition
+    if Regex::new(r"^\[.+\]:\s+\S").unwrap().is_match(line) {
+        let end_offset = start_offset + lines[start].len();
+        return Ok(Some((
+            Block {
+                start: start_offset,
+                end: end_offset,
+                content: lines[start].to_string(),
+                block_type: BlockType::LinkDefinition,
+            },
+            start + 1,
+        )));
+    }
+
+    // Table：需要表头行 + 分隔行，仅含 `|` 的单行文本仍是段落
+    if features.tables && line.contains('|') && lines.get(start + 1).is_some_and(|next| is_table_delimiter(next)) {
+        return parse_table(lines, start, start_offset);
+    }
+
+    // Block quote
+    if line.starts_with('>') {
+        return parse_block_quote(lines, start, start_offset);
+    }
+
+    // Thematic break（需先于列表判断，`* * *` 和 `- - -` 不是列表）
+    if is_thematic_break(lines[start]) {
+        let end_offset = start_offset + lines[start].len();
+        return Ok(Some((
+            Block {
+                start: start_offset,
+                end: end_offset,
+                content: lines[start].to_string(),
+                block_type: BlockType::ThematicBreak,
+            },
+            start + 1,
+        )));
+    }
+
+    // List
+    if Regex::new(r"^([-*+]|\d+\.)\s").unwrap().is_match(line) {
+        return parse_list(lines, start, start_offset);
+    }
+
+    // HTML block
+    if line.starts_with('<')
+        && !line.starts_with("<!--")
+        && (!features.strict_html || is_html_tag_start(line))
+    {
+        return parse_html_block(lines, start, start_offset);
+    }
+
+    // Definition list：术语行之后紧跟 `:` 开头的定义行
+    if features.definition_lists && is_definition_start(lines, start) {
+        return parse_definition_list(lines, start, start_offset);
+    }
+
+    // Default: paragraph
+    parse_paragraph(lines, start, start_offset, flavor)
+}
+
+/// `: definition` line of a definition list
+fn is_definition_line(line: &str) -> bool {
+    let line = line.trim_start();
+    line == ":" || line.starts_with(": ") || line.starts_with(":\t")
+}
+
+/// A term line (not itself a definition) directly followed by a definition line
+fn is_definition_start(lines: &[&str], start: usize) -> bool {
+    !lines[start].trim().is_empty()
+        && !is_definition_line(lines[start])
+        && lines.get(start + 1).is_some_and(|next| is_definition_line(next))
+}
+
+/// Definition list entries run while each term is followed by definition lines;
+/// entries may be separated by a single blank line
+fn parse_definition_list(
+    lines: &[&str],
+    start: usize,
+    start_offset: usize,
+) -> Result<Option<(Block, usize)>> {
+    let mut end = start;
+    loop {
+        // 术语行及其后所有定义行
+        end += 1;
+        while end < lines.len() && is_definition_line(lines[end]) {
+            end += 1;
+        }
+        if end < lines.len() && is_definition_start(lines, end) {
+            continue;
+        }
+        if end + 1 < lines.len() && lines[end].trim().is_empty() && is_definition_start(lines, end + 1) {
+            end += 1;
+            continue;
+        }
+        break;
+    }
+
+    let content = lines[start..end].join("\n");
+    Ok(Some((
+        Block {
+            start: start_offset,
+            end: start_offset + content.len(),
+            content,
+            block_type: BlockType::DefinitionList,
+        },
+        end,
+    )))
+}
+
+/// One term of a definition list with its definitions (without the `: ` prefix)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DefinitionItem {
+    pub term: String,
+    pub definitions: Vec<String>,
+}
+
+/// Split definition list content (or a `term\n: definition` snippet) into its entries.
+/// Returns the entries and whether they are separated by blank lines.
+pub fn definition_items(content: &str) -> (Vec<DefinitionItem>, bool) {
+    let mut items: Vec<DefinitionItem> = Vec::new();
+    let mut loose = false;
+    for line in content.lines() {
+        if line.trim().is_empty() {
+            loose |= !items.is_empty();
+        } else if is_definition_line(line) {
+            let text = line.trim_start()[1..].trim().to_string();
+            if let Some(item) = items.last_mut() {
+                item.definitions.push(text);
+            }
+        } else {
+            items.push(DefinitionItem {
+                term: line.trim().to_string(),
+                definitions: Vec::new(),
+            });
+        }
+    }
+    (items, loose)
+}
+
+/// A fenced code block linked to a source file, through a `file=` attribute in its info
+/// string (```` ```rust file=src/lib.rs ````) or a `<!-- file: src/lib.rs -->` comment
+/// right before it
+#[derive(Debug, Clone, Copy)]
+pub struct CodeFile<'a> {
+    pub path: &'a str,
+    /// First word of the info string, if it is not an attribute
+    pub lang: Option<&'a str>,
+    pub block: &'a Block,
+}
+
+/// All code blocks linked to a source file, in document order
+pub fn code_files(sections: &[Section]) -> Vec<CodeFile<'_>> {
+    let comment_re = Regex::new(r"^<!--\s*file:\s*(\S+)\s*-->$").unwrap();
+    let mut files = Vec::new();
+    for section in sections {
+        for (index, block) in section.blocks.iter().enumerate() {
+            let BlockType::CodeBlock { lang: info } = &block.block_type else {
+                continue;
+            };
+            let info = info.as_deref().unwrap_or_default();
+            let lang = info.split_whitespace().next().filter(|word| !word.contains('='));
+            let attribute = info.split_whitespace().find_map(|word| word.strip_prefix("file="));
+            let comment = index
+                .checked_sub(1)
+                .and_then(|prev| comment_re.captures(section.blocks[prev].content.trim()))
+                .map(|caps| caps.get(1).unwrap().as_str());
+            if let Some(path) = attribute.map(|p| p.trim_matches(['"', '\''])).or(comment) {
+                files.push(CodeFile { path, lang, block });
+            }
+        }
+    }
+    files
+}
+
+/// Byte range (in the document) of a code block's body: the lines between the fences,
+/// including the newline before the closing fence
+pub fn code_body_range(block: &Block) -> (usize, usize) {
+    let content = &block.content;
+    let body_start = content.find('\n').map_or(content.len(), |n| n + 1);
+    let closing = content[body_start..]
+        .rfind('\n')
+        .map_or(body_start, |n| body_start + n + 1);
+    let body_end = if content.len() > body_start && content[closing..].trim() == "```" {
+        closing
+    } else {
+        content.len()
+    };
+    (block.start + body_start, block.start + body_end.max(body_start))
+}
+
+/// Strict HTML block start: an opening or closing tag name, a declaration (`<!DOCTYPE`)
+/// or a processing instruction (`<?xml`)
+fn is_html_tag_start(line: &str) -> bool {
+    let rest = line.strip_prefix("</").or_else(|| line.strip_prefix('<')).unwrap_or(line);
+    let mut chars = rest.chars();
+    match chars.next() {
+        Some('?') => true,
+        Some('!') => chars.next().is_some_and(|c| c.is_ascii_alphabetic()),
+        Some(c) if c.is_ascii_alphabetic() => {
+            // 标签名之后只能是空白、`>` 或 `/`（排除 `<https://…>` 这类自动链接）
+            let after = rest.trim_start_matches(|c: char| c.is_ascii_alphanumeric() || c == '-');
+            matches!(after.chars().next(), None | Some('>' | '/' | ' ' | '\t'))
+        }
+        _ => false,
+    }
+}
+
+/// Tags whose HTML blocks are kept verbatim up to the closing tag, blank lines included
+/// (CommonMark HTML block type 1)
+const RAW_HTML_TAGS: [&str; 4] = ["pre", "script", "style", "textarea"];
+
+/// Closing tag of the raw HTML block opened on this line, if any (e.g. `</pre>`)
+fn raw_html_close(line: &str) -> Option<String> {
+    let rest = line.trim_start().strip_prefix('<')?.to_ascii_lowercase();
+    RAW_HTML_TAGS.iter().find_map(|tag| {
+        let after = rest.strip_prefix(tag)?;
+        matches!(after.chars().next(), None | Some('>' | ' ' | '\t')).then(|| format!("</{}>", tag))
+    })
+}
+
+/// For each line, whether it belongs to a raw HTML block (opening through closing tag line)
+fn raw_html_lines(lines: &[&str]) -> Vec<bool> {
+    let mut mask = vec![false; lines.len()];
+    let mut fence: Option<&str> = None;
+    let mut i = 0;
+    while i < lines.len() {
+        // 代码块中的 `<pre>` 只是文本
+        if let Some(open) = fence {
+            if closes_fence(lines[i], open) {
+                fence = None;
+            }
+        } else if let Some(open) = code_fence(lines[i]) {
+            fence = Some(open);
+        } else if let Some(close) = raw_html_close(lines[i]) {
+            let end = (i..lines.len())
+                .find(|&j| lines[j].to_ascii_lowercase().contains(&close))
+                .unwrap_or(lines.len() - 1);
+            mask[i..=end].iter_mut().for_each(|m| *m = true);
+            i = end + 1;
+            continue;
+        }
+        i += 1;
+    }
+    mask
+}
+
+/// MDX ESM statement (`import ...` / `export ...` at the start of a line)
+fn is_esm_line(line: &str) -> bool {
+    line.starts_with("import ") || line.starts_with("export ")
+}
+
+/// MDX JSX element or fragment start (`<Component`, `<div`, `<>`), excluding comments
+fn is_jsx_line(line: &str) -> bool {
+    let line = line.trim_start();
+    let mut chars = line.chars();
+    chars.next() == Some('<')
+        && matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '>')
+}
+
+/// ESM statements run until a blank line
+fn parse_esm(
+    lines: &[&str],
+    start: usize,
+    start_offset: usize,
+) -> Result<Option<(Block, usize)>> {
+    let mut end = start;
+    let mut content = String::new();
+    let mut current_offset = start_offset;
+
+    while end < lines.len() && !lines[end].trim().is_empty() {
+        if !content.is_empty() {
+            content.push('\n');
+            current_offset += 1;
+        }
+        content.push_str(lines[end]);
+        current_offset += lines[end].len();
+        end += 1;
+    }
+
+    Ok(Some((
+        Block {
+            start: start_offset,
+            end: current_offset,
+            content,
+            block_type: BlockType::Esm,
+        },
+        end,
+    )))
+}
+
+/// JSX elements run until every opened tag is closed (self-closing tags open nothing),
+/// so blank lines and Markdown inside a component stay part of the block.
+/// An unclosed element stops before the next heading.
+fn parse_jsx(
+    lines: &[&str],
+    start: usize,
+    start_offset: usize,
+) -> Result<Option<(Block, usize)>> {
+    let tag_re = Regex::new(r"<(/?)([A-Za-z][\w.:-]*)?(?:\{[^}]*\}|[^<>])*?(/?)>").unwrap();
+    let mut end = start;
+    let mut content = String::new();
+    let mut current_offset = start_offset;
+
+    while end < lines.len() {
+        let line = lines[end];
+        if end > start && is_heading_line(line) {
+            break;
+        }
+        if !content.is_empty() {
+            content.push('\n');
+            current_offset += 1;
+        }
+        content.push_str(line);
+        current_offset += line.len();
+        end += 1;
+
+        // 以累积内容重新计算嵌套深度，以支持跨行的标签属性
+        let mut depth: i32 = 0;
+        let mut last_tag_end = 0;
+        for caps in tag_re.captures_iter(&content) {
+            last_tag_end = caps.get(0).unwrap().end();
+            if !caps[1].is_empty() {
+                depth -= 1;
+            } else if caps[3].is_empty() {
+                depth += 1;
+            }
+        }
+        let pending_tag = content[last_tag_end..].contains('<');
+        if depth <= 0 && !pending_tag {
+            break;
+        }
+    }
+
+    trim_trailing_blank_lines(&mut content, &mut current_offset);
+    Ok(Some((
+        Block {
+            start: start_offset,
+            end: current_offset,
+            content,
+            block_type: BlockType::Jsx,
+        },
+        end,
+    )))
+}
 
-    // Code block
-    if line.starts_with("```") {
-        return parse_code_block(lines, start, start_offset);
+/// Thematic break per CommonMark: up to 3 spaces of indentation, then 3 or more
+/// of the same `-`, `*` or `_`, optionally separated by spaces or tabs
+fn is_thematic_break(line: &str) -> bool {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    if indent > 3 {
+        return false;
     }
 
-    // Table
-    if line.contains('|') {
-        return parse_table(lines, start, start_offset);
+    let mut marks = line.chars().filter(|c| *c != ' ' && *c != '\t');
+    let Some(first) = marks.next() else {
+        return false;
+    };
+    if !matches!(first, '-' | '*' | '_') {
+        return false;
     }
 
-    // Block quote
-    if line.starts_with('>') {
-        return parse_block_quote(lines, start, start_offset);
+    let mut count = 1;
+    for c in marks {
+        if c != first {
+            return false;
+        }
+        count += 1;
     }
+    count >= 3
+}
 
-    // List
-    if Regex::new(r"^([-*+]|\d+\.)\s").unwrap().is_match(line) {
-        return parse_list(lines, start, start_offset);
-    }
+/// Table delimiter row, e.g. `|---|:--:|` or `--- | ---`
+fn is_table_delimiter(line: &str) -> bool {
+    let line = line.trim();
+    line.contains('-')
+        && Regex::new(r"^\|?\s*:?-+:?\s*(\|\s*:?-+:?\s*)*\|?$").unwrap().is_match(line)
+}
 
-    // HTML block
-    if line.starts_with('<') && !line.starts_with("<!--") {
-        return parse_html_block(lines, start, start_offset);
-    }
+/// Setext heading underline (`===` or `---` with no inner spaces)
+fn is_setext_underline(line: &str) -> bool {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    let marks = line.trim();
+    indent <= 3
+        && !marks.is_empty()
+        && (marks.chars().all(|c| c == '=') || marks.chars().all(|c| c == '-'))
+}
 
-    // Thematic break
-    if Regex::new(r"^([-*_]){3,}\s*$").unwrap().is_match(line) {
-        let end_offset = start_offset + lines[start].len();
-        return Ok(Some((
-            Block {
-                start: start_offset,
-                end: end_offset,
-                content: lines[start].to_string(),
-                block_type: BlockType::ThematicBreak,
-            },
-            start + 1,
-        )));
+/// Drop trailing blank lines swallowed by a multi-line block, keeping `end` consistent with `content`
+fn trim_trailing_blank_lines(content: &mut String, end: &mut usize) {
+    while let Some(pos) = content.rfind('\n') {
+        if !content[pos + 1..].trim().is_empty() {
+            break;
+        }
+        *end -= content.len() - pos;
+        content.truncate(pos);
     }
-
-    // Default: paragraph
-    parse_paragraph(lines, start, start_offset)
 }
 
 fn parse_code_block(
@@ -243,8 +1623,10 @@ fn parse_code_block(
     start_offset: usize,
 ) -> Result<Option<(Block, usize)>> {
     let first_line = lines[start];
+    let fence = code_fence(first_line).unwrap_or("```");
     let lang = first_line
-        .trim_start_matches('`')
+        .trim_start()
+        .trim_start_matches(fence)
         .trim()
         .to_string();
     let lang = if lang.is_empty() { None } else { Some(lang) };
@@ -257,7 +1639,7 @@ fn parse_code_block(
         content.push('\n');
         content.push_str(lines[end]);
         
-        if lines[end].trim() == "```" {
+        if closes_fence(lines[end], fence) {
             current_offset += lines[end].len();
             break;
         }
@@ -265,6 +1647,14 @@ fn parse_code_block(
         end += 1;
     }
 
+    // 未闭合的代码块延伸到文档末尾：block 不包含最后一行的换行符
+    let next = if end >= lines.len() {
+        current_offset -= 1;
+        lines.len()
+    } else {
+        end + 1
+    };
+
     Ok(Some((
         Block {
             start: start_offset,
@@ -272,7 +1662,7 @@ fn parse_code_block(
             content,
             block_type: BlockType::CodeBlock { lang },
         },
-        end + 1,
+        next,
     )))
 }
 
@@ -282,32 +1672,20 @@ fn parse_table(
     start_offset: usize,
 ) -> Result<Option<(Block, usize)>> {
     let mut end = start;
-    let mut content = String::new();
-    let mut current_offset = start_offset;
 
-    while end < lines.len() {
-        let line = lines[end];
-        if !line.contains('|') && !line.trim().is_empty() {
-            break;
-        }
-        if !content.is_empty() {
-            content.push('\n');
-            current_offset += 1;
-        }
-        content.push_str(line);
-        current_offset += line.len();
+    // 表格行连续出现，遇到空行或不含 `|` 的行结束
+    while end < lines.len() && lines[end].contains('|') && !lines[end].trim().is_empty() {
         end += 1;
-
-        // Empty line ends the table
-        if line.trim().is_empty() {
-            break;
-        }
     }
 
+    // content 是原文中连续行的精确切片，end 偏移直接由其长度得出
+    let content = lines[start..end].join("\n");
+    let end_offset = start_offset + content.len();
+
     Ok(Some((
         Block {
             start: start_offset,
-            end: current_offset,
+            end: end_offset,
             content,
             block_type: BlockType::Table,
         },
@@ -338,6 +1716,8 @@ fn parse_block_quote(
         end += 1;
     }
 
+    trim_trailing_blank_lines(&mut content, &mut current_offset);
+
     Ok(Some((
         Block {
             start: start_offset,
@@ -360,13 +1740,15 @@ fn parse_list(
     let mut end = start;
     let mut content = String::new();
     let mut current_offset = start_offset;
+    let list_item_re = Regex::new(r"^([-*+]|\d+\.)\s").unwrap();
 
     while end < lines.len() {
         let line = lines[end];
         
         // Check if this is a new list item or continuation
-        let is_list_item = Regex::new(r"^([-*+]|\d+\.)\s").unwrap().is_match(line.trim());
-        let is_indented = line.starts_with("  ") || line.starts_with("\t") || line.trim().is_empty();
+        let is_list_item = list_item_re.is_match(line.trim());
+        // 缩进按列宽计算（tab 展开到下一个制表位），混用 tab/空格的续行不会被拆开
+        let is_indented = indent_width(line) >= 2 || line.trim().is_empty();
 
         if !is_list_item && !is_indented && !line.trim().is_empty() {
             break;
@@ -381,6 +1763,8 @@ fn parse_list(
         end += 1;
     }
 
+    trim_trailing_blank_lines(&mut content, &mut current_offset);
+
     Ok(Some((
         Block {
             start: start_offset,
@@ -402,6 +1786,32 @@ fn parse_html_block(
     let mut current_offset = start_offset;
     let mut tag_stack = 0;
 
+    // <pre> 等原样块：直到闭合标签所在行，中间可有空行
+    if let Some(close) = raw_html_close(lines[start]) {
+        while end < lines.len() {
+            let line = lines[end];
+            if !content.is_empty() {
+                content.push('\n');
+                current_offset += 1;
+            }
+            content.push_str(line);
+            current_offset += line.len();
+            end += 1;
+            if line.to_ascii_lowercase().contains(&close) {
+                break;
+            }
+        }
+        return Ok(Some((
+            Block {
+                start: start_offset,
+                end: current_offset,
+                content,
+                block_type: BlockType::Html,
+            },
+            end,
+        )));
+    }
+
     // Simple HTML block parsing - just grab until we hit an empty line
     // or close the initial tag
     while end < lines.len() {
@@ -443,22 +1853,35 @@ fn parse_paragraph(
     lines: &[&str],
     start: usize,
     start_offset: usize,
+    flavor: Flavor,
 ) -> Result<Option<(Block, usize)>> {
     let mut end = start;
     let mut content = String::new();
     let mut current_offset = start_offset;
+    let list_item_re = Regex::new(r"^([-*+]|\d+\.)\s").unwrap();
 
     while end < lines.len() {
         let line = lines[end];
         if line.trim().is_empty() {
             break;
         }
+        // Setext 下划线（紧跟段落文本的 `---` / `===`）属于该段落，而不是 thematic break。
+        // Setext heading 不作为 section heading 处理。
+        if !content.is_empty() && is_setext_underline(line) {
+            content.push('\n');
+            content.push_str(line);
+            current_offset += 1 + line.len();
+            end += 1;
+            break;
+        }
         // Stop at certain block-starting patterns
-        if line.starts_with("```") 
-            || line.starts_with("#") 
+        if code_fence(line).is_some()
+            || is_heading_line(line)
             || line.starts_with(">")
-            || Regex::new(r"^([-*+]|\d+\.)\s").unwrap().is_match(line)
-            || Regex::new(r"^([-*_]){3,}\s*$").unwrap().is_match(line)
+            || list_item_re.is_match(line)
+            || is_thematic_break(line)
+            || raw_html_close(line).is_some()
+            || (flavor == Flavor::Mdx && (is_esm_line(line) || is_jsx_line(line)))
         {
             break;
         }
@@ -507,5 +1930,544 @@ mod tests {
         let sections = parse_sections(content).unwrap();
         assert_eq!(sections[0].blocks.len(), 1);
         assert!(matches!(sections[0].blocks[0].block_type, BlockType::CodeBlock { .. }));
+
+        // `~~~` 围栏同样是代码块，只有同类、不短于开头的围栏才能关闭它
+        let content = "# Title\n\n~~~python\n```\n# not a heading\n~~~\n\nAfter.\n";
+        let sections = parse_sections(content).unwrap();
+        assert_eq!(sections.len(), 1);
+        assert_eq!(sections[0].blocks.len(), 2);
+        assert!(matches!(&sections[0].blocks[0].block_type, BlockType::CodeBlock { lang: Some(l) } if l == "python"));
+        assert_eq!(sections[0].blocks[0].content, "~~~python\n```\n# not a heading\n~~~");
+    }
+
+    #[test]
+    fn test_parse_link_definitions() {
+        let content = "# Links\n\nSee [docs][d].\n\n[d]: https://example.com/docs\n[api]: https://example.com/api \"API\"\n";
+        let sections = parse_sections(content).unwrap();
+        let blocks = &sections[0].blocks;
+        assert_eq!(blocks.len(), 3);
+        assert!(matches!(blocks[0].block_type, BlockType::Paragraph));
+        assert!(matches!(blocks[1].block_type, BlockType::LinkDefinition));
+        assert!(matches!(blocks[2].block_type, BlockType::LinkDefinition));
+        assert_eq!(blocks[1].content, "[d]: https://example.com/docs");
+        assert_eq!(&content[blocks[2].start..blocks[2].end], blocks[2].content);
+    }
+
+    #[test]
+    fn test_find_block_by_line() {
+        let content = "# Title\n\nIntro line one\nIntro line two\n\n## Code\n\n```sh\necho hi\n```\n";
+        let sections = parse_sections(content).unwrap();
+
+        // 段落内部的第二行
+        assert_eq!(find_block_by_line(&sections, content, 4).unwrap(), (0, 0));
+        // 代码块内部
+        assert_eq!(find_block_by_line(&sections, content, 9).unwrap(), (1, 0));
+        // 空行和 heading 行不属于任何 block
+        assert!(find_block_by_line(&sections, content, 2).is_err());
+        assert!(find_block_by_line(&sections, content, 6).is_err());
+        assert!(find_block_by_line(&sections, content, 42).is_err());
+    }
+
+    #[test]
+    fn test_thematic_break_variants() {
+        for rule in ["---", "* * *", "___", "  - - -", "*****"] {
+            let content = format!("# Title\n\nBefore.\n\n{}\n\nAfter.\n", rule);
+            let sections = parse_sections(&content).unwrap();
+            let blocks = &sections[0].blocks;
+            assert_eq!(blocks.len(), 3, "rule {:?}", rule);
+            assert!(matches!(blocks[1].block_type, BlockType::ThematicBreak), "rule {:?}", rule);
+            assert_eq!(blocks[1].content, rule);
+        }
+
+        // 段落内紧跟的 `***` 结束段落并成为 thematic break
+        let sections = parse_sections("# T\n\nText\n***\n").unwrap();
+        assert_eq!(sections[0].blocks.len(), 2);
+        assert!(matches!(sections[0].blocks[1].block_type, BlockType::ThematicBreak));
+
+        // 紧跟段落的 `---` 是 setext 下划线，归属于段落
+        let sections = parse_sections("# T\n\nSetext title\n---\n").unwrap();
+        assert_eq!(sections[0].blocks.len(), 1);
+        assert!(matches!(sections[0].blocks[0].block_type, BlockType::Paragraph));
+        assert_eq!(sections[0].blocks[0].content, "Setext title\n---");
+    }
+
+    #[test]
+    fn test_unterminated_fence_repair() {
+        let content = "# A\n\n```sh\necho hi\n\n## B\n\nText\n";
+        assert_eq!(unterminated_fences(content), vec![3]);
+        assert_eq!(parse_sections(content).unwrap().len(), 1);
+
+        let repaired = repair_fences(content);
+        assert_eq!(repaired, "# A\n\n```sh\necho hi\n\n```\n## B\n\nText\n");
+        assert!(unterminated_fences(&repaired).is_empty());
+        let sections = parse_sections(&repaired).unwrap();
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[1].blocks[0].content, "Text");
+
+        // 已闭合的代码块不需要修复
+        let closed = "# A\n\n```\nx\n```\n";
+        assert!(unterminated_fences(closed).is_empty());
+        assert_eq!(repair_fences(closed), closed);
+
+        // 嵌套的四反引号围栏与 `~~~` 围栏都是闭合的
+        let nested = "# T\n\n````md\n```\ninner\n```\n````\n\nPara.\n";
+        assert!(unterminated_fences(nested).is_empty());
+        assert_eq!(repair_fences(nested), nested);
+        let tilde = "# T\n\n~~~sh\n```\necho hi\n~~~\n\nPara.\n";
+        assert!(unterminated_fences(tilde).is_empty());
+        assert_eq!(repair_fences(tilde), tilde);
+
+        // 未闭合时用同样的围栏补齐
+        assert_eq!(repair_fences("# T\n\n~~~\nx\n"), "# T\n\n~~~\nx\n~~~\n");
+    }
+
+    #[test]
+    fn test_section_parents() {
+        let content = "# A\n\n## B\n\n### C\n\n## D\n\n# E\n";
+        let sections = parse_sections(content).unwrap();
+        assert_eq!(section_parents(&sections), vec![None, Some(0), Some(1), Some(0), None]);
+    }
+
+    #[test]
+    fn test_coarsen_paragraphs() {
+        let content = "# Doc\n\nFirst para.\n\nSecond para.\n\n```sh\nls\n```\n\nThird para.\n";
+        let mut sections = parse_sections(content).unwrap();
+        assert_eq!(sections[0].blocks.len(), 4);
+        coarsen_paragraphs(content, &mut sections);
+        let kinds: Vec<&str> = sections[0].blocks.iter().map(|b| b.block_type.kind()).collect();
+        assert_eq!(kinds, ["paragraph", "code", "paragraph"]);
+        assert_eq!(sections[0].blocks[0].content, "First para.\n\nSecond para.");
+    }
+
+    #[test]
+    fn test_find_section_by_regex() {
+        let content = "# Changelog\n\n## v1.2\n\nnew\n\n## v1.1\n\nold\n\n## Unreleased\n\nwip\n";
+        let sections = parse_sections(content).unwrap();
+        let find = |pattern: &str, occurrence| {
+            find_section_with(&sections, &[pattern.to_string()], occurrence, HeadingMatch::Regex)
+        };
+        assert_eq!(find(r"^## Unrel", None).unwrap().blocks[0].content, "wip");
+        // 多个版本匹配时与精确匹配一样报告歧义，可用 occurrence 选择
+        let err = find(r"^## v\d+\.\d+$", None).unwrap_err();
+        assert!(err.to_string().contains("Multiple sections found"), "{}", err);
+        assert_eq!(find(r"^## v\d+\.\d+$", Some(1)).unwrap().blocks[0].content, "old");
+        assert!(find(r"^## v(", None).unwrap_err().to_string().contains("Invalid heading regex"));
+
+        let path = vec![r"^# Change".to_string(), r"v1\.1".to_string()];
+        let section = find_section_with(&sections, &path, None, HeadingMatch::Regex).unwrap();
+        assert_eq!(section.heading, "## v1.1");
+    }
+
+    #[test]
+    fn test_ambiguity_report() {
+        let content = "# Guide\n\n## Notes\n\na\n\n# Reference\n\n## Notes\n\nb\n";
+        let sections = parse_sections(content).unwrap();
+        assert_eq!(section_path(&sections, 3), "# Reference ## Notes");
+        assert_eq!(
+            ambiguity_report(content, &sections, "## Notes"),
+            "Candidates for '## Notes':\n  line 3: -H '# Guide ## Notes' (or --occurrence 0)\n  \
+             line 9: -H '# Reference ## Notes' (or --occurrence 1)"
+        );
+    }
+
+    #[test]
+    fn test_find_section_by_occurrence() {
+        let content = "## Notes\n\nfirst\n\n## Notes\n\nsecond\n\n## Notes\n\nthird\n";
+        let sections = parse_sections(content).unwrap();
+        let path = vec!["## Notes".to_string()];
+
+        assert!(find_section(&sections, &path, None).is_err());
+        let section = find_section(&sections, &path, Some(1)).unwrap();
+        assert_eq!(section.blocks[0].content, "second");
+        assert!(find_section(&sections, &path, Some(3)).is_err());
+    }
+
+    #[test]
+    fn test_find_block_recursive() {
+        let content = "# A
+
+a1
+
+## B
+
+b1
+
+### C
+
+c1
+
+c2
+
+# D
+
+d1
+";
+        let sections = parse_sections(content).unwrap();
+
+        assert_eq!(find_block_recursive(&sections, 0, 0).unwrap(), (0, 0));
+        assert_eq!(find_block_recursive(&sections, 0, 1).unwrap(), (1, 0));
+        // 孙级 section 中的第二个 block
+        assert_eq!(find_block_recursive(&sections, 0, 3).unwrap(), (2, 1));
+        // 不会越过同级 heading
+        assert!(find_block_recursive(&sections, 0, 4).is_err());
+    }
+
+    #[test]
+    fn test_parse_mdx_blocks() {
+        let content = "import { Chart } from './chart'\nexport const meta = { title: 'x' }\n\n# Doc\n\nIntro text\n<Chart data={[1, 2]} />\nMore prose\n\n<Note kind=\"tip\">\n\nInside **note**\n\n</Note>\n\n<Callout\n  title=\"Multi\"\n  onClose={() => done()}\n/>\n\nAfter\n";
+        let sections = parse_sections_with(content, Flavor::Mdx).unwrap();
+        let kinds: Vec<&str> = sections[0].blocks.iter().map(|b| b.block_type.kind()).collect();
+        assert_eq!(kinds, vec!["paragraph", "jsx", "paragraph", "jsx", "jsx", "paragraph"]);
+        assert_eq!(sections[0].blocks[1].content, "<Chart data={[1, 2]} />");
+        assert_eq!(sections[0].blocks[3].content, "<Note kind=\"tip\">\n\nInside **note**\n\n</Note>");
+        assert!(sections[0].blocks[4].content.ends_with("/>"));
+        for block in &sections[0].blocks {
+            assert_eq!(&content[block.start..block.end], block.content);
+        }
+
+        // 普通 Markdown 模式下行为不变
+        let sections = parse_sections(content).unwrap();
+        assert_eq!(sections[0].blocks[0].content, "Intro text\n<Chart data={[1, 2]} />\nMore prose");
+    }
+
+    #[test]
+    fn test_table_requires_delimiter_row() {
+        let content = "# T\n\na | b means either\n\n| Name | Value |\n|------|:-----:|\n| x    | 1     |\n\nAfter\n";
+        let sections = parse_sections(content).unwrap();
+        let blocks = &sections[0].blocks;
+
+        assert!(matches!(blocks[0].block_type, BlockType::Paragraph));
+        assert_eq!(blocks[0].content, "a | b means either");
+
+        assert!(matches!(blocks[1].block_type, BlockType::Table));
+        assert_eq!(blocks[1].start, content.find("| Name").unwrap());
+        assert_eq!(blocks[1].end, content.find("\n\nAfter").unwrap());
+        assert_eq!(&content[blocks[1].start..blocks[1].end], blocks[1].content);
+
+        assert_eq!(blocks[2].content, "After");
+    }
+
+    #[test]
+    fn test_block_selectors() {
+        let content = "## API\n\nIntro\n\n```sh\nfirst\n```\n\n| a | b |\n|---|---|\n\n```rust\nsecond\n```\n";
+        let sections = parse_sections(content).unwrap();
+
+        let (heading, selector) = split_selector("## API > code[1]");
+        assert_eq!(heading, "## API");
+        let selector = selector.unwrap();
+        assert_eq!(sections[0].blocks[select_block(&sections[0], &selector).unwrap()].content, "```rust\nsecond\n```");
+
+        let (_, selector) = split_selector("## API > table");
+        assert_eq!(select_block(&sections[0], &selector.unwrap()).unwrap(), 2);
+
+        assert!(select_block(&sections[0], &split_selector("## API > code[2]").1.unwrap()).is_err());
+        // 未知类型不视为选择器
+        assert_eq!(split_selector("## A > B"), ("## A > B", None));
+    }
+
+    #[test]
+    fn test_tab_and_space_indented_lists_match() {
+        let kinds = |content: &str| -> Vec<(String, usize)> {
+            let sections = parse_sections(content).unwrap();
+            sections[0].blocks.iter().map(|b| (b.block_type.kind().to_string(), b.content.lines().count())).collect()
+        };
+        let spaces = "# L\n\n- a\n    - nested\n    continued\n- b\n\nAfter.\n";
+        let tabs = "# L\n\n- a\n\t- nested\n\tcontinued\n- b\n\nAfter.\n";
+        let mixed = "# L\n\n- a\n  \t- nested\n \tcontinued\n- b\n\nAfter.\n";
+        assert_eq!(kinds(spaces), vec![("list".to_string(), 4), ("paragraph".to_string(), 1)]);
+        assert_eq!(kinds(tabs), kinds(spaces));
+        assert_eq!(kinds(mixed), kinds(spaces));
+
+        assert_eq!(indent_width("\tx"), 4);
+        assert_eq!(indent_width("  \tx"), 4);
+        assert_eq!(indent_width(" x"), 1);
+        let (items, _) = list_items("- a\n\t- nested\n- b");
+        assert_eq!(items.len(), 2);
+    }
+
+    #[test]
+    fn test_emoji_heading_path() {
+        let content = "# Doc\n\n## :rocket: Launch\n\ngo\n\n## 🐛 Known issues :warning:\n\nnone\n";
+        let sections = parse_sections(content).unwrap();
+        assert_eq!(
+            emoji_heading_path(&sections, &["## Launch".to_string()]),
+            Some(vec!["## :rocket: Launch".to_string()])
+        );
+        assert_eq!(
+            emoji_heading_path(&sections, &["## 🚀 Launch".to_string()]),
+            Some(vec!["## :rocket: Launch".to_string()])
+        );
+        // 非开头的 shortcode 与字面 emoji 互相对应
+        assert_eq!(
+            emoji_heading_path(&sections, &["## :bug: Known issues ⚠️".to_string()]),
+            Some(vec!["## 🐛 Known issues :warning:".to_string()])
+        );
+        assert_eq!(fuzzy_heading_path(&sections, &["## Launch".to_string()]), None);
+    }
+
+    #[test]
+    fn test_heading_line_end() {
+        let content = "# Doc\n\n## API\ntext\n## End";
+        let sections = parse_sections(content).unwrap();
+        assert_eq!(&content[..sections[1].heading_end], "# Doc\n\n## API");
+        assert_eq!(&content[heading_line_end(content, &sections[1])..], "text\n## End");
+        assert_eq!(heading_line_end(content, &sections[2]), content.len());
+    }
+
+    #[test]
+    fn test_heading_block() {
+        let content = "# Doc\n\n## API\n\ntext\n";
+        let sections = parse_sections(content).unwrap();
+        let block = heading_block(content, &sections[1]);
+        assert_eq!(block.content, "## API");
+        assert_eq!(&content[block.start..block.end], "## API");
+        assert!(matches!(block.block_type, BlockType::Heading { level: 2 }));
+    }
+
+    #[test]
+    fn test_fuzzy_heading_path() {
+        let content = "# Guide\n\n## API Usage\n\ntext\n\n## Api  Notes\n\nmore\n";
+        let sections = parse_sections(content).unwrap();
+        let path = vec!["# guide".to_string(), "##api   usage".to_string()];
+        assert_eq!(
+            fuzzy_heading_path(&sections, &path),
+            Some(vec!["# Guide".to_string(), "## API Usage".to_string()])
+        );
+        // 精确匹配无需修正；无法唯一确定时不做猜测
+        assert_eq!(fuzzy_heading_path(&sections, &["## API Usage".to_string()]), None);
+        assert_eq!(fuzzy_heading_path(&sections, &["## Missing".to_string()]), None);
+    }
+
+    #[test]
+    fn test_find_section_by_number() {
+        let content = "# One\n\n## Setup\n\na\n\n## Usage\n\nb\n\n# Two\n\n## Setup\n\nc\n\n### Linux\n\nd\n";
+        let sections = parse_sections(content).unwrap();
+
+        assert_eq!(sections[find_section_by_number(&sections, "1.1").unwrap()].blocks[0].content, "a");
+        assert_eq!(sections[find_section_by_number(&sections, "2.1").unwrap()].blocks[0].content, "c");
+        assert_eq!(sections[find_section_by_number(&sections, "2.1.1").unwrap()].heading, "### Linux");
+
+        assert!(find_section_by_number(&sections, "3").is_err());
+        assert!(find_section_by_number(&sections, "2.2").is_err());
+        assert!(find_section_by_number(&sections, "1.0").is_err());
+    }
+
+    #[test]
+    fn test_pre_block_hides_heading_lines() {
+        let content = "# Real\n\nRun this:\n<pre>\n# Shell Comment\n\nls -la\n</pre>\n\nAfter.\n";
+        let sections = parse_sections(content).unwrap();
+        assert_eq!(sections.len(), 1);
+        assert_eq!(sections[0].blocks.len(), 3);
+        assert_eq!(sections[0].blocks[1].content, "<pre>\n# Shell Comment\n\nls -la\n</pre>");
+        assert_eq!(sections[0].blocks[2].content, "After.");
+
+        // 第一个 heading 之前的 <pre> 同样不产生 section
+        let sections = parse_sections("<pre>\n# Shell Comment\n</pre>\n\n# Real\n\nText\n").unwrap();
+        assert_eq!(sections.len(), 1);
+        assert_eq!(sections[0].heading, "# Real");
+
+        // 代码块（`~~~` 或更长的反引号围栏）中的 `<pre>` 只是文本
+        for fenced in ["~~~html\n<pre>\n~~~", "````md\n```\n<pre>\n```\n````"] {
+            let sections = parse_sections(&format!("# A\n\n{}\n\n## B\n\nText\n", fenced)).unwrap();
+            assert_eq!(sections.len(), 2, "{}", fenced);
+            assert_eq!(sections[1].heading, "## B");
+        }
+    }
+
+    #[test]
+    fn test_escape_heading_lines() {
+        let text = "# Not a heading\nplain\n```sh\n# comment\n```\n## Also literal";
+        assert_eq!(
+            escape_heading_lines(text),
+            "\\# Not a heading\nplain\n```sh\n# comment\n```\n\\## Also literal"
+        );
+        assert_eq!(escape_heading_lines("#hashtag\n"), "#hashtag\n");
+
+        // `~~~` 与嵌套的四反引号围栏中的内容保持原样
+        let text = "~~~sh\n# comment\n~~~\n````md\n```\n# inner\n```\n# still code\n````\n# After";
+        assert_eq!(
+            escape_heading_lines(text),
+            "~~~sh\n# comment\n~~~\n````md\n```\n# inner\n```\n# still code\n````\n\\# After"
+        );
+    }
+
+    #[test]
+    fn test_nested_path_tries_each_candidate_in_order() {
+        // 只有第二个 "# Release" 含有 "## Notes"
+        let content = "# Release\n\nv1\n\n# Release\n\nv2\n\n## Notes\n\nSecond notes\n";
+        let sections = parse_sections(content).unwrap();
+        let path = vec!["# Release".to_string(), "## Notes".to_string()];
+        assert_eq!(find_section(&sections, &path, None).unwrap().blocks[0].content, "Second notes");
+        assert!(find_section(&sections, &path, Some(0)).is_err());
+
+        // 每一级只在上一级匹配到的 section 内查找
+        let content = "# A\n\n## B\n\nb\n\n## D\n\n### C\n\nc\n";
+        let sections = parse_sections(content).unwrap();
+        let path: Vec<String> = ["# A", "## B", "### C"].iter().map(|s| s.to_string()).collect();
+        assert!(find_section(&sections, &path, None).is_err());
+        let path: Vec<String> = ["# A", "## D", "### C"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(find_section(&sections, &path, None).unwrap().blocks[0].content, "c");
+    }
+
+    #[test]
+    fn test_skipped_level_path_stays_at_direct_children() {
+        // "# A ### C" 不会进入 "## B" 之下
+        let content = "# A\n\n## B\n\n### C\n\nunder b\n";
+        let sections = parse_sections(content).unwrap();
+        let path: Vec<String> = ["# A", "### C"].iter().map(|s| s.to_string()).collect();
+        let err = find_section(&sections, &path, None).unwrap_err().to_string();
+        assert!(err.contains("Subheading not found"), "{}", err);
+        assert!(err.contains("nested under '## B'"), "{}", err);
+
+        // 跳级的直接子节点仍可匹配，即使其后还有更浅的同级 section
+        let content = "# A\n\n### C\n\ndirect\n\n## B\n\n### C\n\nunder b\n";
+        let sections = parse_sections(content).unwrap();
+        assert_eq!(find_section(&sections, &path, None).unwrap().blocks[0].content, "direct");
+    }
+
+    #[test]
+    fn test_heading_closing_hashes_and_plain_match() {
+        let content = "# Doc #\n\n## **Bold** ##\n\nText\n\n## C# ##\n\n## ###\n\n## Use `snake_case`\n";
+        let sections = parse_sections(content).unwrap();
+        let headings: Vec<&str> = sections.iter().map(|s| s.heading.as_str()).collect();
+        // `#` 紧贴文字时不是结束序列
+        assert_eq!(headings, ["# Doc", "## **Bold**", "## C#", "##", "## Use `snake_case`"]);
+
+        let path = |h: &str| vec![h.to_string()];
+        assert_eq!(plain_heading_path(&sections, &path("## Bold")), Some(path("## **Bold**")));
+        assert_eq!(
+            plain_heading_path(&sections, &path("## use snake_case")),
+            Some(path("## Use `snake_case`"))
+        );
+        assert_eq!(plain_heading_path(&sections, &path("## Italic")), None);
+    }
+
+    #[test]
+    fn test_strict_html_tag_start() {
+        for line in ["<div>", "<div class=\"x\">", "</p>", "<br/>", "<!DOCTYPE html>", "<?xml version=\"1.0\"?>"] {
+            assert!(is_html_tag_start(line), "{}", line);
+        }
+        for line in ["<3 markdown", "<https://example.com>", "<a@b.c>", "< div>"] {
+            assert!(!is_html_tag_start(line), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_block_order_invariant() {
+        // 相邻的"近乎为空"的 block：注释、分隔线、空代码块、单字符段落
+        let content = "# Doc\n<!-- -->\n---\n```\n```\n.\n\n-\n\n> \n|a|\n|-|\n";
+        let mut sections = parse_sections(content).unwrap();
+        let blocks = &sections[0].blocks;
+        assert!(!blocks.is_empty());
+        assert!(blocks.iter().all(|b| b.end >= b.start));
+        assert!(blocks.windows(2).all(|p| p[0].start < p[1].start));
+
+        // 手工构造违反不变量的 section：报告内部错误而不是 panic
+        let first = sections[0].blocks[0].clone();
+        sections[0].blocks.push(first);
+        let err = check_block_order(&sections).unwrap_err().to_string();
+        assert!(err.contains("Internal parser error"), "{}", err);
+    }
+
+    #[test]
+    fn test_heading_line_classification() {
+        for line in ["# A", "###### A", "#\tA", "## A ##", "## ##"] {
+            assert!(is_heading_line(line), "{:?}", line);
+        }
+        for line in ["#", "###### ", "#  \t", "#no-space", "####### A", " # A"] {
+            assert!(!is_heading_line(line), "{:?}", line);
+        }
+
+        // 非 heading 的 `#` 行是段落文本：既不开启 section，也不打断或丢失段落
+        let content = "# Doc\n\nText\n#no-space\n###### \n#\n\n#tag\n";
+        let sections = parse_sections(content).unwrap();
+        assert_eq!(sections.len(), 1);
+        let blocks: Vec<&str> = sections[0].blocks.iter().map(|b| b.content.as_str()).collect();
+        assert_eq!(blocks, ["Text\n#no-space\n###### \n#", "#tag"]);
+        assert_eq!(parse_blocks("Text\n###### \nMore\n", Flavor::Markdown).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_region_range_and_restriction() {
+        let content = "# Doc\n\nHand.\n\n<!-- mdp:begin api -->\n- a\n\n## Gen\n\nText.\n<!-- mdp:end api -->\n\n## After\n\nMore.\n";
+        let (start, end) = region_range(content, "api").unwrap();
+        assert_eq!(&content[start..end], "- a\n\n## Gen\n\nText.\n");
+
+        let mut sections = parse_sections(content).unwrap();
+        restrict_to_region(content, &mut sections, (start, end));
+        let kept: Vec<_> = sections.iter().map(|s| (s.heading.as_str(), s.blocks.len())).collect();
+        assert_eq!(kept, [("# Doc", 1), ("## Gen", 1)]);
+        assert_eq!(sections[0].blocks[0].content, "- a");
+
+        assert!(region_range(content, "missing").unwrap_err().to_string().contains("not found"));
+        let unclosed = "<!-- mdp:begin x -->\ntext\n";
+        assert!(region_range(unclosed, "x").unwrap_err().to_string().contains("not closed"));
+        // 代码块中的标记不算
+        let fenced = "```\n<!-- mdp:begin x -->\n```\n<!-- mdp:end x -->\n";
+        assert!(region_range(fenced, "x").is_err());
+    }
+
+    #[test]
+    fn test_select_block_by_hash() {
+        let content = "# Doc\n\nAlpha\n\nBeta\n\nAlpha\n";
+        let sections = parse_sections(content).unwrap();
+        let section = &sections[0];
+        let beta = block_hash(&section.blocks[1]);
+        assert_eq!(beta.len(), 64);
+
+        assert_eq!(select_block_by_hash(section, &beta[..7]).unwrap(), 1);
+        assert_eq!(select_block_by_hash(section, &beta[..7].to_uppercase()).unwrap(), 1);
+
+        // 两个相同内容的 block 哈希相同：任意前缀都有歧义
+        let alpha = block_hash(&section.blocks[0]);
+        let err = select_block_by_hash(section, &alpha[..12]).unwrap_err().to_string();
+        assert!(err.contains("matches blocks [0, 2]"), "{}", err);
+
+        let unmatched = "0123456789abcdef"
+            .chars()
+            .find(|&c| !alpha.starts_with(c) && !beta.starts_with(c))
+            .unwrap();
+        let err = select_block_by_hash(section, &unmatched.to_string()).unwrap_err().to_string();
+        assert!(err.contains("Block hash mismatch"), "{}", err);
+        assert!(select_block_by_hash(section, "xyz").is_err());
+    }
+
+    #[test]
+    fn test_ignore_directive_excludes_block() {
+        let content = "# Doc\n\nZero.\n\n<!-- mdp:ignore -->\n\nHand-kept.\n\nOne.\n\n<!--mdp:ignore-->\n- glued\n\nTwo.\n";
+        let sections = parse_sections(content).unwrap();
+        let blocks: Vec<&str> = sections[0].blocks.iter().map(|b| b.content.as_str()).collect();
+        assert_eq!(blocks, ["Zero.", "One.", "Two."]);
+        assert_eq!(sections[0].ignored.len(), 2);
+        let (start, end) = sections[0].ignored[0];
+        assert_eq!(&content[start..end], "<!-- mdp:ignore -->\n\nHand-kept.");
+
+        // 按行号定位到被忽略的 block 时报错
+        let err = find_block_by_line(&sections, content, 7).unwrap_err().to_string();
+        assert!(err.contains("marked <!-- mdp:ignore -->"), "{}", err);
+        assert_eq!(find_block_by_line(&sections, content, 9).unwrap(), (0, 1));
+    }
+
+    #[test]
+    fn test_definition_list_block() {
+        let content = "# Glossary\n\nBlock\n: A unit of content.\n\nSection\n: A heading and its blocks.\n: Sections nest by level.\n\nTrailing paragraph.\n";
+        let sections = parse_sections(content).unwrap();
+        let blocks = &sections[0].blocks;
+        assert_eq!(blocks.len(), 2);
+        assert!(matches!(blocks[0].block_type, BlockType::DefinitionList));
+        assert!(matches!(blocks[1].block_type, BlockType::Paragraph));
+
+        let (items, loose) = definition_items(&blocks[0].content);
+        assert!(loose);
+        assert_eq!(
+            items,
+            [
+                DefinitionItem { term: "Block".into(), definitions: vec!["A unit of content.".into()] },
+                DefinitionItem {
+                    term: "Section".into(),
+                    definitions: vec!["A heading and its blocks.".into(), "Sections nest by level.".into()],
+                },
+            ]
+        );
     }
 }
@@ -1,14 +1,31 @@
 use anyhow::{bail, Result};
-use regex::Regex;
+use clap::ValueEnum;
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
 use std::path::PathBuf;
 
-use crate::parser::{find_section, get_block, parse_sections, Block};
+use crate::parser::{
+    find_block_by_line, find_block_recursive, find_section, find_section_by_number, find_section_with, HeadingMatch, fuzzy_heading_path, emoji_heading_path, plain_heading_path, select_block, select_block_by_hash, BlockSelector, get_block, get_blocks, heading_block, heading_line_end, coarsen_paragraphs, escape_heading_lines, html_comment_ranges, list_items, definition_items, region_range, restrict_to_region, parse_blocks, parse_sections_with,
+    closes_fence, code_fence, repair_fences, strip_html_comments, text_without_ranges, Block, BlockType, Flavor, Section,
+};
 
 #[derive(Clone, Copy, Debug)]
 pub enum Operation {
     Append,
     Replace,
     Delete,
+    Sort,
+    ReplaceBody,
+    ListAppend,
+    Toc,
+    Dedup,
+    Merge,
+    Ensure,
+    InsertSorted,
+    Convert,
+    AppendLineIfAbsent,
+    DefinitionAppend,
+    TrimTrailing,
 }
 
 impl From<crate::config::OperationType> for Operation {
@@ -17,11 +34,82 @@ impl From<crate::config::OperationType> for Operation {
             crate::config::OperationType::Append => Operation::Append,
             crate::config::OperationType::Replace => Operation::Replace,
             crate::config::OperationType::Delete => Operation::Delete,
+            crate::config::OperationType::Sort => Operation::Sort,
+            crate::config::OperationType::ReplaceBody => Operation::ReplaceBody,
+            crate::config::OperationType::ListAppend => Operation::ListAppend,
+            crate::config::OperationType::Toc => Operation::Toc,
+            crate::config::OperationType::Dedup => Operation::Dedup,
+            crate::config::OperationType::Merge => Operation::Merge,
+            crate::config::OperationType::Ensure => Operation::Ensure,
+            crate::config::OperationType::InsertSorted => Operation::InsertSorted,
+            crate::config::OperationType::Convert => Operation::Convert,
+            crate::config::OperationType::AppendLineIfAbsent => Operation::AppendLineIfAbsent,
+            crate::config::OperationType::DefinitionAppend => Operation::DefinitionAppend,
+            crate::config::OperationType::TrimTrailing => Operation::TrimTrailing,
         }
     }
 }
 
-#[derive(Debug)]
+/// How HTML comments (`<!-- ... -->`) are treated
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum CommentMode {
+    /// Comments are part of block content
+    #[default]
+    Keep,
+    /// Ignore comments when fingerprint-matching, but keep them in the output
+    Match,
+    /// Remove comments from the written output as well
+    All,
+}
+
+/// How list items are compared by the sort operation
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SortMode {
+    /// Byte-wise lexicographic order
+    #[default]
+    Lexical,
+    /// Lexicographic order ignoring case
+    CaseInsensitive,
+}
+
+/// Destination block type of the convert operation
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConvertTarget {
+    /// Bullet list, one item per line (or per item)
+    List,
+    /// Plain paragraph, one line per item
+    Paragraph,
+    /// Block quote
+    Quote,
+}
+
+impl ConvertTarget {
+    fn kind(self) -> &'static str {
+        match self {
+            ConvertTarget::List => "list",
+            ConvertTarget::Paragraph => "paragraph",
+            ConvertTarget::Quote => "quote",
+        }
+    }
+}
+
+/// How append decides that its content is already present
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Idempotency {
+    /// The content already exists as standalone block(s) after the target
+    /// (or as the tail of the target block, for tight appends such as list items)
+    #[default]
+    Exact,
+    /// The content appears anywhere from the target block onward
+    Contains,
+    /// Always append
+    Off,
+}
+
+#[derive(Debug, Clone)]
 pub struct PatchOperation {
     pub file: PathBuf,
     pub heading_path: Vec<String>,
@@ -29,243 +117,2144 @@ pub struct PatchOperation {
     pub operation: Operation,
     pub content: Option<String>,
     pub fingerprint: Option<String>,
+    /// Compile the fingerprint regex case-insensitively
+    pub fingerprint_ignore_case: bool,
+    /// Document line (1-based) addressing; bypasses heading_path/block_index when set
+    pub line: Option<usize>,
+    /// Select the Nth match of an ambiguous heading instead of erroring
+    pub occurrence: Option<usize>,
+    /// Outline number addressing (`2.1.3`); replaces heading_path when set
+    pub numbered: Option<String>,
+    /// Count block_index across the section and all its descendant subsections
+    pub recursive_index: bool,
+    /// Select the block by type (e.g. `code[1]`) instead of block_index
+    pub selector: Option<BlockSelector>,
+    /// Select the block whose content SHA-256 starts with this hex prefix
+    pub block_hash: Option<String>,
+    /// Delete the blocks `first..=last` in one edit instead of the block at `block_index`
+    pub block_range: Option<(usize, usize)>,
+    /// Append directly after the heading line instead of after a block (ignores block_index)
+    pub after_heading: bool,
+    /// Target the heading line itself instead of a block (replace/delete only; ignores block_index)
+    pub heading_block: bool,
+    /// Treat append/replace with empty or whitespace-only content as a no-op
+    pub noop_on_missing_content: bool,
+    pub strip_comments: CommentMode,
+    /// Blank lines inserted before appended content
+    pub separator: usize,
+    pub idempotency: Idempotency,
+    /// Refuse to operate on target blocks larger than this many bytes
+    pub max_block_bytes: Option<usize>,
+    /// Close unterminated code fences before processing
+    pub repair_fences: bool,
+    /// Escape heading-like lines in `content` and refuse results that gain a heading
+    pub literal_content: bool,
+    /// Re-parse the result and require an unchanged outline with the new content in place
+    pub verify_structure: bool,
+    /// Append only: update a block at least this similar to the content (0.0–1.0)
+    /// instead of appending a near-duplicate
+    pub merge_append: Option<f64>,
+    /// Append only: format the content like the target block (a list item for lists,
+    /// `> ` lines for quotes)
+    pub match_style: bool,
+    /// Treat each run of consecutive paragraphs as a single block
+    pub coarse_blocks: bool,
+    /// Match headings ignoring leading emoji and `:shortcode:`/literal emoji differences
+    pub ignore_emoji: bool,
+    /// Match headings ignoring inline formatting (emphasis, code, links)
+    pub plain_headings: bool,
+    /// Fall back to a heading that matches ignoring case and whitespace (with a warning)
+    pub fuzzy_headings: bool,
+    /// Compare heading path segments exactly or as regexes
+    pub heading_match: HeadingMatch,
+    pub sort_mode: SortMode,
+    /// Regex extracting the sort key from each item (first capture group, or the whole match)
+    pub sort_key: Option<String>,
+    /// Deepest heading level listed by the toc operation (1-6)
+    pub toc_depth: u8,
+    /// Destination block type of the convert operation
+    pub convert_to: Option<ConvertTarget>,
+    /// append-line-if-absent: regex identifying an existing line (default: the same line)
+    pub line_key: Option<String>,
+    /// Managed region (`<!-- mdp:begin name -->` ... `<!-- mdp:end name -->`) the
+    /// operation is confined to
+    pub region: Option<String>,
+    /// Apply to the whole document instead of a section (trim-trailing only);
+    /// `heading_path` is ignored
+    pub whole_file: bool,
+    pub flavor: Flavor,
 }
 
 pub enum PatchResult {
-    Applied { new_content: String, diff: String, is_noop: bool },
-    DryRun { diff: String, is_noop: bool },
+    Applied {
+        new_content: String,
+        diff: String,
+        is_noop: bool,
+        /// Why the operation left the content unchanged (set only when `is_noop`)
+        noop_reason: Option<String>,
+        /// Index of the inserted/replaced block in the new content (None for delete)
+        new_block_index: Option<usize>,
+        /// Non-fatal notices raised while resolving the target (e.g. a fuzzy heading match)
+        warnings: Vec<String>,
+    },
+    DryRun {
+        diff: String,
+        is_noop: bool,
+        noop_reason: Option<String>,
+        warnings: Vec<String>,
+    },
+}
+
+/// A destructive operation refused for lack of `--force` or a fingerprint.
+/// Carries the diff it would have produced so callers can show a preview.
+#[derive(Debug)]
+pub struct BlockedOperation {
+    pub message: &'static str,
+    pub diff: String,
 }
 
+impl std::fmt::Display for BlockedOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.message)
+    }
+}
+
+impl std::error::Error for BlockedOperation {}
+
 pub fn apply_operation(
     content: &str,
     operation: &PatchOperation,
     force: bool,
 ) -> Result<PatchResult> {
+    let mut warnings = Vec::new();
+    let mut result = apply_operation_collecting(content, operation, force, &mut warnings)?;
+    let (PatchResult::Applied { warnings: slot, .. } | PatchResult::DryRun { warnings: slot, .. }) = &mut result;
+    *slot = warnings;
+    Ok(result)
+}
+
+fn apply_operation_collecting(
+    content: &str,
+    operation: &PatchOperation,
+    force: bool,
+    warnings: &mut Vec<String>,
+) -> Result<PatchResult> {
+    if operation.region.is_some() && operation.strip_comments == CommentMode::All {
+        bail!("--region cannot be combined with --strip-comments all, which would remove the region markers");
+    }
+
+    // 预处理（去除注释、修复代码块）在副本上进行，diff 仍与原始内容对比
+    let original = content;
+    let mut working = None;
+    if operation.strip_comments == CommentMode::All {
+        working = Some(strip_html_comments(original));
+    }
+    if operation.repair_fences {
+        working = Some(repair_fences(working.as_deref().unwrap_or(original)));
+    }
+    let content = working.as_deref().unwrap_or(original);
+
+    // 字面内容：`#` 开头的行转义为普通文本，避免意外产生新的 heading
+    let literal;
+    let operation = if operation.literal_content {
+        literal = PatchOperation {
+            content: operation.content.as_deref().map(escape_heading_lines),
+            ..operation.clone()
+        };
+        &literal
+    } else {
+        operation
+    };
+
+    // 整个文件范围：不解析 heading 路径
+    if operation.whole_file {
+        if !matches!(operation.operation, Operation::TrimTrailing) {
+            bail!("--whole-file only applies to trim-trailing operations");
+        }
+        let (start, end) = match &operation.region {
+            Some(region) => region_range(content, region)?,
+            None => (0, content.len()),
+        };
+        let new_content = apply_trim_trailing(content, start, end);
+        return finish_operation(original, new_content, operation, 0, 0, force);
+    }
+
     // Parse the markdown to find sections and blocks
-    let sections = parse_sections(content)?;
+    let sections = parse_target_sections(content, operation)?;
+
+    // Resolve the target section and block index
+    let (section_idx, block_index) = resolve_target(content, &sections, operation, warnings)?;
 
-    // Find the target section
-    let section = find_section(&sections, &operation.heading_path)?;
+    // 动态生成的内容为空时跳过（目标地址仍需有效）
+    if operation.noop_on_missing_content
+        && matches!(operation.operation, Operation::Append | Operation::Replace)
+        && operation.content.as_deref().is_none_or(|c| c.trim().is_empty())
+    {
+        return finish_operation(original, original.to_string(), operation, section_idx, block_index, force);
+    }
 
-    // Get the target block
-    let block = get_block(section, operation.block_index)?;
+    // 目录：已有标记时原地刷新，否则插入到目标位置
+    if matches!(operation.operation, Operation::Toc) {
+        let new_content = apply_toc(content, &sections, section_idx, block_index, operation)?;
+        return finish_operation(original, new_content, operation, section_idx, block_index, force);
+    }
 
-    // === 定位层：fingerprint 是定位条件，不匹配 = 找不到目标 ===
-    // 注意：fingerprint 检查独立于 --force，force 不能绕过定位失败
-    if let Some(ref fingerprint) = operation.fingerprint {
-        let regex = Regex::new(fingerprint)?;
-        if !regex.is_match(&block.content) {
-            bail!(
-                "Fingerprint mismatch: block at index {} does not match pattern '{}'. \
-                 Target block content does not meet identification criteria.",
-                operation.block_index, fingerprint
-            );
+    // 去重：删除与本 section 中更早 block 完全相同的 block
+    if matches!(operation.operation, Operation::Dedup) {
+        let new_content = apply_dedup(content, &sections[section_idx]);
+        if !force && new_content != content {
+            return Err(BlockedOperation {
+                message: "Destructive operation requires authorization: provide --force \
+                          to remove duplicate blocks.",
+                diff: operation_diff(original, &new_content, operation),
+            }
+            .into());
         }
+        return finish_operation(original, new_content, operation, section_idx, 0, force);
     }
 
-    // === 权限层：破坏性操作需要明确的执行授权 ===
-    // 两种授权方式：
-    // 1. 提供 fingerprint（通过内容验证表明知道自己在改什么）
-    // 2. 提供 --force（明确接受风险）
-    match operation.operation {
-        Operation::Replace | Operation::Delete if operation.fingerprint.is_none() && !force => {
-            bail!(
-                "Destructive operation requires authorization: provide either \
-                 --force flag or a fingerprint to verify the target block."
-            );
+    // 合并：将本 section 中相邻的同类 block 合并为一个
+    if matches!(operation.operation, Operation::Merge) {
+        let new_content = apply_merge(content, &sections[section_idx]);
+        if !force && new_content != content {
+            return Err(BlockedOperation {
+                message: "Destructive operation requires authorization: provide --force \
+                          to merge adjacent blocks.",
+                diff: operation_diff(original, &new_content, operation),
+            }
+            .into());
         }
-        _ => {}
+        return finish_operation(original, new_content, operation, section_idx, 0, force);
     }
 
-    // Generate the new content
-    let new_content = match operation.operation {
-        Operation::Append => apply_append(content, block, operation.content.as_deref())?,
-        Operation::Replace => apply_replace(content, block, operation.content.as_deref())?,
-        Operation::Delete => apply_delete(content, block)?,
-    };
+    // 去除行尾空白：heading 行与整个 section 正文（含子 section）
+    if matches!(operation.operation, Operation::TrimTrailing) {
+        let (body_start, end) = section_body_range(content, &sections, section_idx, operation);
+        let start = if operation.region.is_some() { body_start } else { sections[section_idx].heading_start };
+        let new_content = apply_trim_trailing(content, start, end);
+        return finish_operation(original, new_content, operation, section_idx, 0, force);
+    }
 
-    // Generate diff - clean filename for display (remove leading ./ or /)
-    let filename = operation.file.to_string_lossy();
-    let clean_filename = filename.trim_start_matches("./").trim_start_matches('/');
-    let diff = generate_diff(content, &new_content, clean_filename);
+    if operation.merge_append.is_some() && !matches!(operation.operation, Operation::Append) {
+        bail!("--merge-append only applies to append operations");
+    }
+    if operation.match_style && !matches!(operation.operation, Operation::Append) {
+        bail!("--match-style only applies to append operations");
+    }
 
-    // Noop 检测：内容无变化（幂等性生效）
-    let is_noop = content == new_content;
+    // 紧跟 heading 插入：不依赖目标 block，空 section 也适用
+    if operation.after_heading {
+        if !matches!(operation.operation, Operation::Append) {
+            bail!("--after-heading only applies to append operations");
+        }
+        let new_content = apply_after_heading(
+            content,
+            &sections[section_idx],
+            operation.content.as_deref(),
+            operation.separator,
+        )?;
+        return finish_operation(original, new_content, operation, section_idx, 0, force);
+    }
 
-    if force {
-        Ok(PatchResult::Applied { new_content, diff, is_noop })
-    } else {
-        Ok(PatchResult::DryRun { diff, is_noop })
+    // 整体替换 section 正文：从 heading 之后到下一个同级（或更高级）heading
+    if matches!(operation.operation, Operation::ReplaceBody) {
+        let (body_start, body_end) = section_body_range(content, &sections, section_idx, operation);
+        let body = &content[body_start..body_end];
+
+        if let Some(ref fingerprint) = operation.fingerprint {
+            if !fingerprint_regex(fingerprint, operation.fingerprint_ignore_case)?.is_match(body.trim()) {
+                bail!(
+                    "Fingerprint mismatch: body of '{}' does not match pattern '{}'.",
+                    sections[section_idx].heading, fingerprint
+                );
+            }
+        }
+
+        let new_content = apply_replace_body(content, body_start, body_end, operation.content.as_deref())?;
+        if operation.fingerprint.is_none() && !force {
+            return Err(BlockedOperation {
+                message: "Destructive operation requires authorization: provide either \
+                          --force flag or a fingerprint to verify the section body.",
+                diff: operation_diff(original, &new_content, operation),
+            }
+            .into());
+        }
+        return finish_operation(original, new_content, operation, section_idx, 0, force);
     }
-}
 
-fn apply_append(content: &str, block: &Block, new_content: Option<&str>) -> Result<String> {
-    let insert_content = match new_content {
-        Some(c) => c,
-        None => bail!("Append operation requires content"),
-    };
+    // 确保存在：fingerprint 匹配的 block 原地替换，否则追加到 section 末尾
+    if matches!(operation.operation, Operation::Ensure) {
+        let (new_content, block_index) = apply_ensure(content, &sections[section_idx], operation)?;
+        return finish_operation(original, new_content, operation, section_idx, block_index, force);
+    }
 
-    // 幂等性检查：如果内容已存在，直接返回原内容
-    let block_and_after = &content[block.start..];
-    if block_and_after.contains(insert_content) {
-        return Ok(content.to_string());
+    // 连续多个 block 一次删除：合并为一个范围，始终需要 --force
+    if let Some((first, last)) = operation.block_range {
+        if !matches!(operation.operation, Operation::Delete) {
+            bail!("--index-range only applies to delete operations");
+        }
+        let blocks = get_blocks(&sections[section_idx], first, last)?;
+        // fingerprint 校验范围内的第一个 block
+        if let Some(ref fingerprint) = operation.fingerprint {
+            let regex = fingerprint_regex(fingerprint, operation.fingerprint_ignore_case)?;
+            check_fingerprint(content, &blocks[0], first, &regex, operation.strip_comments)?;
+        }
+        let new_content = apply_delete(content, blocks[0].start, blocks[blocks.len() - 1].end)?;
+        if !force {
+            return Err(BlockedOperation {
+                message: "Destructive operation requires authorization: provide --force \
+                          to delete a range of blocks.",
+                diff: operation_diff(original, &new_content, operation),
+            }
+            .into());
+        }
+        return finish_operation(original, new_content, operation, section_idx, first, force);
     }
 
-    let before = &content[..block.end];
-    let after = &content[block.end..];
+    // Get the target block（--heading-block 时为 heading 行本身）
+    let heading;
+    let block = if operation.heading_block {
+        if !matches!(operation.operation, Operation::Replace | Operation::Delete) {
+            bail!("--heading-block only applies to replace and delete operations");
+        }
+        heading = heading_block(content, &sections[section_idx]);
+        &heading
+    } else {
+        get_block(&sections[section_idx], block_index)?
+    };
 
-    // 在内容前加换行，确保格式正确
-    let insert_with_newline = format!("\n{}", insert_content);
+    // 防护：异常巨大的 block 通常意味着输入格式错误（例如未闭合的代码块）
+    if let Some(max) = operation.max_block_bytes {
+        let size = block.end - block.start;
+        if size > max {
+            bail!(
+                "Target block at index {} is {} bytes, exceeding --max-block-bytes {}. \
+                 The input may be malformed (e.g. an unterminated code fence).",
+                block_index, size, max
+            );
+        }
+    }
 
-    Ok(format!("{}{}{}", before, insert_with_newline, after))
-}
+    // === 定位层：fingerprint 是定位条件，不匹配 = 找不到目标 ===
+    // 注意：fingerprint 检查独立于 --force，force 不能绕过定位失败
+    if let Some(ref fingerprint) = operation.fingerprint {
+        let regex = fingerprint_regex(fingerprint, operation.fingerprint_ignore_case)?;
+        check_fingerprint(content, block, block_index, &regex, operation.strip_comments)?;
+    }
 
-fn apply_replace(content: &str, block: &Block, new_content: Option<&str>) -> Result<String> {
-    let replacement = match new_content {
-        Some(c) => c,
-        None => bail!("Replace operation requires content"),
+    // Generate the new content
+    let new_content = match operation.operation {
+        Operation::Append => {
+            let appended = if operation.match_style {
+                apply_append_matching_style(content, block, operation)?
+            } else {
+                apply_append(
+                    content,
+                    block,
+                    operation.content.as_deref(),
+                    operation.separator,
+                    operation.idempotency,
+                    operation.flavor,
+                )?
+            };
+            match operation.merge_append {
+                Some(threshold) if appended != content => {
+                    merge_similar_block(content, &sections[section_idx], block_index, operation, threshold, warnings)?
+                        .unwrap_or(appended)
+                }
+                _ => appended,
+            }
+        }
+        Operation::Replace => apply_replace(
+            content,
+            block,
+            &sections[section_idx].heading,
+            operation.content.as_deref(),
+        )?,
+        Operation::Delete => apply_delete(content, block.start, block.end)?,
+        Operation::Sort => apply_sort(
+            content,
+            block,
+            operation.sort_mode,
+            operation.sort_key.as_deref(),
+        )?,
+        Operation::ListAppend => apply_list_append(content, block, operation.content.as_deref())?,
+        Operation::InsertSorted => apply_insert_sorted(
+            content,
+            &sections[section_idx],
+            block_index,
+            operation.content.as_deref(),
+            operation.separator,
+            operation.sort_mode,
+            operation.sort_key.as_deref(),
+        )?,
+        Operation::Convert => apply_convert(content, block, operation.convert_to)?,
+        Operation::AppendLineIfAbsent => {
+            apply_append_line(content, block, operation.content.as_deref(), operation.line_key.as_deref())?
+        }
+        Operation::DefinitionAppend => apply_definition_append(content, block, operation.content.as_deref())?,
+        Operation::ReplaceBody
+        | Operation::Toc
+        | Operation::Dedup
+        | Operation::Merge
+        | Operation::Ensure
+        | Operation::TrimTrailing => {
+            unreachable!("handled before block lookup")
+        }
     };
 
-    let before = &content[..block.start];
-    let after = &content[block.end..];
+    // === 权限层：破坏性操作需要明确的执行授权 ===
+    // 两种授权方式：
+    // 1. 提供 fingerprint（通过内容验证表明知道自己在改什么）
+    // 2. 提供 --force（明确接受风险）
+    // 未授权时仍返回 diff 预览，便于用户决定是否加 --force
+    match operation.operation {
+        Operation::Replace | Operation::Delete if operation.fingerprint.is_none() && !force => {
+            return Err(BlockedOperation {
+                message: "Destructive operation requires authorization: provide either \
+                          --force flag or a fingerprint to verify the target block.",
+                diff: operation_diff(original, &new_content, operation),
+            }
+            .into());
+        }
+        // 转换会重写整个 block，始终需要 --force
+        Operation::Convert if !force && new_content != content => {
+            return Err(BlockedOperation {
+                message: "Destructive operation requires authorization: provide --force \
+                          to convert the target block.",
+                diff: operation_diff(original, &new_content, operation),
+            }
+            .into());
+        }
+        _ => {}
+    }
 
-    Ok(format!("{}{}{}", before, replacement, after))
+    finish_operation(original, new_content, operation, section_idx, block_index, force)
 }
 
-fn apply_delete(content: &str, block: &Block) -> Result<String> {
-    let before = &content[..block.start];
-    let after = &content[block.end..];
-
-    // Clean up extra newlines that might result from deletion
-    let result = format!("{}{}", before, after);
-    
-    // Remove consecutive blank lines caused by deletion
-    let cleaned = Regex::new(r"\n{3,}")?.replace_all(&result, "\n\n");
-    
-    Ok(cleaned.to_string())
+/// Join several content blocks into one insertion, separated by `separator` blank lines.
+/// A single block is used verbatim.
+pub fn join_blocks(blocks: &[String], separator: usize) -> String {
+    if let [single] = blocks {
+        return single.clone();
+    }
+    blocks
+        .iter()
+        .map(|b| b.trim_matches('\n'))
+        .collect::<Vec<_>>()
+        .join(&"\n".repeat(separator + 1))
 }
 
-fn generate_diff(original: &str, modified: &str, filename: &str) -> String {
+/// Remove the longest leading whitespace shared by all non-blank lines (like Python's
+/// `textwrap.dedent`); whitespace-only lines become empty
+pub fn dedent(text: &str) -> String {
+    let common = text
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| &l[..l.len() - l.trim_start().len()])
+        .reduce(|a, b| {
+            let len = a.bytes().zip(b.bytes()).take_while(|(x, y)| x == y).count();
+            &a[..len]
+        })
+        .unwrap_or("");
 
+    text.split_inclusive('\n')
+        .map(|line| match line.strip_prefix(common) {
+            _ if line.trim().is_empty() => &line[line.trim_end_matches(['\n', '\r']).len()..],
+            Some(rest) => rest,
+            None => line,
+        })
+        .collect()
+}
 
-    // Simple line-based diff
-    let original_lines: Vec<&str> = original.lines().collect();
-    let modified_lines: Vec<&str> = modified.lines().collect();
+/// Generate diff - clean filename for display (remove leading ./ or /)
+fn operation_diff(original: &str, new_content: &str, operation: &PatchOperation) -> String {
+    let filename = operation.file.to_string_lossy();
+    let clean_filename = filename.trim_start_matches("./").trim_start_matches('/');
+    generate_diff(original, new_content, clean_filename)
+}
 
-    let mut diff = format!("--- a/{}\n+++ b/{}\n", filename, filename);
+/// 按操作的 flavor 与 block 粒度（`coarse_blocks`）解析，使索引与定位保持一致
+fn parse_target_sections(content: &str, operation: &PatchOperation) -> Result<Vec<Section>> {
+    let mut sections = parse_sections_with(content, operation.flavor)?;
+    if operation.coarse_blocks {
+        coarsen_paragraphs(content, &mut sections);
+    }
+    if let Some(region) = &operation.region {
+        restrict_to_region(content, &mut sections, region_range(content, region)?);
+    }
+    Ok(sections)
+}
 
-    // Use a simple LCS-based diff
-    let lcs = compute_lcs(&original_lines, &modified_lines);
+/// Byte range `(start, end)` of the content an operation would modify, without applying it.
+/// Insertions right after a heading yield an empty range at the end of the heading line.
+pub fn target_range(content: &str, operation: &PatchOperation) -> Result<(usize, usize)> {
+    if operation.whole_file {
+        return match &operation.region {
+            Some(region) => region_range(content, region),
+            None => Ok((0, content.len())),
+        };
+    }
+    let sections = parse_target_sections(content, operation)?;
+    let (section_idx, block_index) = resolve_target(content, &sections, operation, &mut Vec::new())?;
 
-    let mut i = 0;
-    let mut j = 0;
-    let mut lcs_idx = 0;
+    if matches!(operation.operation, Operation::Toc) {
+        if let Some(range) = toc_range(content) {
+            return Ok(range);
+        }
+    }
+    if operation.after_heading {
+        let heading_end = sections[section_idx].heading_end;
+        return Ok((heading_end, heading_end));
+    }
+    if matches!(
+        operation.operation,
+        Operation::ReplaceBody | Operation::Dedup | Operation::Merge | Operation::Ensure
+    ) {
+        return Ok(section_body_range(content, &sections, section_idx, operation));
+    }
+    if matches!(operation.operation, Operation::TrimTrailing) {
+        let (body_start, end) = section_body_range(content, &sections, section_idx, operation);
+        let start = if operation.region.is_some() { body_start } else { sections[section_idx].heading_start };
+        return Ok((start, end));
+    }
+    if operation.heading_block {
+        let section = &sections[section_idx];
+        return Ok((section.heading_start, section.heading_end));
+    }
+    if let Some((first, last)) = operation.block_range {
+        let blocks = get_blocks(&sections[section_idx], first, last)?;
+        return Ok((blocks[0].start, blocks[blocks.len() - 1].end));
+    }
 
-    while i < original_lines.len() || j < modified_lines.len() {
-        if lcs_idx < lcs.len() {
-            if i < original_lines.len() 
-                && j < modified_lines.len()
-                && original_lines[i] == modified_lines[j]
-                && original_lines[i] == lcs[lcs_idx]
-            {
-                // Unchanged line
-                diff.push_str(&format!(" {}\n", original_lines[i]));
-                i += 1;
-                j += 1;
-                lcs_idx += 1;
-            } else if i < original_lines.len() 
-                && (lcs_idx >= lcs.len() || original_lines[i] != lcs[lcs_idx])
-            {
-                // Deleted line
-                diff.push_str(&format!("-{}\n", original_lines[i]));
-                i += 1;
+    let block = get_block(&sections[section_idx], block_index)?;
+    // 在兄弟 block 之间插入：范围从目标 block 延伸到 section 末尾
+    if matches!(operation.operation, Operation::InsertSorted) && !matches!(block.block_type, BlockType::List { .. }) {
+        return Ok((block.start, section_body_range(content, &sections, section_idx, operation).1));
+    }
+    Ok((block.start, block.end))
+}
+
+/// 解析目标 section 与 block 索引：按行号，或按 heading 路径（可递归计数）
+///
+/// A heading path without an exact match falls back to a case/whitespace-insensitive
+/// match; using that fallback is reported through `warnings`.
+fn resolve_target(
+    content: &str,
+    sections: &[Section],
+    operation: &PatchOperation,
+    warnings: &mut Vec<String>,
+) -> Result<(usize, usize)> {
+    match operation.line {
+        Some(line) => find_block_by_line(sections, content, line),
+        None => {
+            let section_idx = match &operation.numbered {
+                Some(number) => find_section_by_number(sections, number)?,
+                None => {
+                    let found = find_section_with(
+                        sections,
+                        &operation.heading_path,
+                        operation.occurrence,
+                        operation.heading_match,
+                    );
+                    let section = match found {
+                        Ok(section) => section,
+                        // 正则模式下不做额外的容错匹配
+                        Err(e) if operation.heading_match == HeadingMatch::Regex => return Err(e),
+                        Err(e) => {
+                            // --ignore-emoji / --plain-headings 是显式选择的匹配方式，不产生警告；模糊匹配则提示
+                            let explicit_path = if operation.ignore_emoji {
+                                emoji_heading_path(sections, &operation.heading_path)
+                            } else if operation.plain_headings {
+                                plain_heading_path(sections, &operation.heading_path)
+                            } else {
+                                None
+                            };
+                            let (path, fuzzy) = match explicit_path {
+                                Some(path) => (path, false),
+                                None => match fuzzy_heading_path(sections, &operation.heading_path) {
+                                    Some(path) if operation.fuzzy_headings => (path, true),
+                                    // 未启用 --fuzzy-headings 时只作为提示，不替用户改写目标
+                                    Some(path) => bail!(
+                                        "{} (did you mean '{}'? pass --fuzzy-headings to accept it)",
+                                        e,
+                                        path.join(" ")
+                                    ),
+                                    None => return Err(e),
+                                },
+                            };
+                            let section = find_section(sections, &path, operation.occurrence).map_err(|_| e)?;
+                            if fuzzy {
+                                warnings.push(format!(
+                                    "Fuzzy heading match: '{}' resolved to '{}'",
+                                    operation.heading_path.join(" "),
+                                    path.join(" ")
+                                ));
+                            }
+                            section
+                        }
+                    };
+                    sections
+                        .iter()
+                        .position(|s| std::ptr::eq(s, section))
+                        .unwrap()
+                }
+            };
+            if let Some(selector) = &operation.selector {
+                Ok((section_idx, select_block(&sections[section_idx], selector)?))
+            } else if let Some(prefix) = &operation.block_hash {
+                Ok((section_idx, select_block_by_hash(&sections[section_idx], prefix)?))
+            } else if operation.recursive_index {
+                find_block_recursive(sections, section_idx, operation.block_index)
             } else {
-                // Added line
-                diff.push_str(&format!("+{}\n", modified_lines[j]));
-                j += 1;
+                Ok((section_idx, operation.block_index))
             }
-        } else if i < original_lines.len() {
-            // Remaining deletions
-            diff.push_str(&format!("-{}\n", original_lines[i]));
-            i += 1;
-        } else {
-            // Remaining additions
-            diff.push_str(&format!("+{}\n", modified_lines[j]));
-            j += 1;
         }
     }
+}
 
-    diff
+/// Section 正文范围：从 heading 行末到下一个同级（或更高级）heading
+/// （操作限定了 region 时截断到区域之内）
+fn section_body_range(
+    content: &str,
+    sections: &[Section],
+    section_idx: usize,
+    operation: &PatchOperation,
+) -> (usize, usize) {
+    let level = sections[section_idx].heading_level;
+    let body_start = sections[section_idx].heading_end;
+    let body_end = sections[section_idx + 1..]
+        .iter()
+        .find(|s| s.heading_level <= level)
+        .map_or(content.len(), |s| s.heading_start);
+    match operation.region.as_deref().and_then(|region| region_range(content, region).ok()) {
+        Some((start, end)) => (body_start.max(start), body_end.min(end).max(body_start.max(start))),
+        None => (body_start, body_end),
+    }
 }
 
-fn compute_lcs<'a>(a: &[&'a str], b: &[&'a str]) -> Vec<&'a str> {
-    let m = a.len();
-    let n = b.len();
-    
-    if m == 0 || n == 0 {
-        return Vec::new();
+/// 生成 diff 与 noop 标记，并在实际执行时定位新 block
+fn finish_operation(
+    original: &str,
+    new_content: String,
+    operation: &PatchOperation,
+    section_idx: usize,
+    block_index: usize,
+    force: bool,
+) -> Result<PatchResult> {
+    // 字面内容模式下再解析一次，确认没有产生新的 heading
+    if operation.literal_content {
+        let before = parse_sections_with(original, operation.flavor)?.len();
+        let after = parse_sections_with(&new_content, operation.flavor)?.len();
+        if after > before {
+            bail!(
+                "Content would create {} new heading(s) despite --literal-content; refusing to apply",
+                after - before
+            );
+        }
     }
 
-    // Use dynamic programming for LCS
-    let mut dp = vec![vec![0; n + 1]; m + 1];
+    if let Some(region) = &operation.region {
+        check_region_edit(original, &new_content, region)?;
+    }
+    check_ignored_edit(original, &new_content, operation)?;
 
-    for i in 1..=m {
-        for j in 1..=n {
-            if a[i - 1] == b[j - 1] {
-                dp[i][j] = dp[i - 1][j - 1] + 1;
-            } else {
-                dp[i][j] = dp[i - 1][j].max(dp[i][j - 1]);
-            }
-        }
+    if operation.verify_structure && original != new_content {
+        verify_structure(original, &new_content, operation, section_idx, block_index)?;
     }
 
-    // Backtrack to find LCS
-    let mut lcs = Vec::new();
-    let mut i = m;
-    let mut j = n;
+    let diff = operation_diff(original, &new_content, operation);
 
-    while i > 0 && j > 0 {
-        if a[i - 1] == b[j - 1] {
-            lcs.push(a[i - 1]);
-            i -= 1;
-            j -= 1;
-        } else if dp[i - 1][j] > dp[i][j - 1] {
-            i -= 1;
+    // Noop 检测：内容无变化（幂等性生效）
+    let is_noop = original == new_content;
+    let noop_reason = is_noop.then(|| noop_reason(original, operation, section_idx, block_index));
+
+    if force {
+        let new_block_index = locate_new_block(&new_content, operation, section_idx, block_index)?;
+        Ok(PatchResult::Applied { new_content, diff, is_noop, noop_reason, new_block_index, warnings: Vec::new() })
+    } else {
+        Ok(PatchResult::DryRun { diff, is_noop, noop_reason, warnings: Vec::new() })
+    }
+}
+
+/// 受管区域：改动（与原文的首尾公共部分之间）必须完全落在区域内
+fn check_region_edit(original: &str, new_content: &str, region: &str) -> Result<()> {
+    if original == new_content {
+        return Ok(());
+    }
+    let line_of = |offset: usize| original[..offset].matches('\n').count() + 1;
+    let (start, end) = region_range(original, region)?;
+    let (prefix, changed_end) = changed_span(original, new_content);
+    if prefix < start || changed_end > end {
+        bail!(
+            "Edit outside region '{}': the change touches lines {}-{}, outside its markers on lines {} and {}",
+            region,
+            line_of(prefix),
+            line_of(changed_end.max(prefix)),
+            line_of(start) - 1,
+            line_of(end)
+        );
+    }
+    Ok(())
+}
+
+/// 标记为 `<!-- mdp:ignore -->` 的 block 受保护：改动不得触及
+fn check_ignored_edit(original: &str, new_content: &str, operation: &PatchOperation) -> Result<()> {
+    if original == new_content || !original.contains("mdp:ignore") {
+        return Ok(());
+    }
+    let line_of = |offset: usize| original[..offset].matches('\n').count() + 1;
+    let (prefix, changed_end) = changed_span(original, new_content);
+    let sections = parse_sections_with(original, operation.flavor)?;
+    // 纯插入只有落在被忽略的 block 内部才算触及
+    let touched = sections.iter().flat_map(|s| &s.ignored).find(|&&(start, end)| {
+        if prefix == changed_end {
+            start < prefix && prefix < end
         } else {
-            j -= 1;
+            prefix < end && changed_end > start
         }
+    });
+    if let Some(&(start, end)) = touched {
+        bail!(
+            "Edit touches the block on lines {}-{}, which is marked <!-- mdp:ignore --> and cannot be changed",
+            line_of(start),
+            line_of(end)
+        );
     }
+    Ok(())
+}
 
-    lcs.reverse();
-    lcs
+/// 改动范围：原文中首尾公共部分之间的 `(start, end)`，两端都落在字符边界上
+fn changed_span(original: &str, new_content: &str) -> (usize, usize) {
+    let mut prefix = original.bytes().zip(new_content.bytes()).take_while(|(a, b)| a == b).count();
+    // 公共前缀可能止于多字节字符中间，退回到该字符的起点
+    while !original.is_char_boundary(prefix) {
+        prefix -= 1;
+    }
+    let suffix = original.as_bytes()[prefix..]
+        .iter()
+        .rev()
+        .zip(new_content.as_bytes()[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut end = original.len() - suffix;
+    while !original.is_char_boundary(end) {
+        end += 1;
+    }
+    (prefix, end)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// 自检：重新解析修改后的文档，确认 heading 大纲不变（编辑 heading 本身除外），
+/// 且新内容作为目标 section 的 block 存在
+fn verify_structure(
+    original: &str,
+    new_content: &str,
+    operation: &PatchOperation,
+    section_idx: usize,
+    block_index: usize,
+) -> Result<()> {
+    let outline = |content: &str| -> Result<Vec<String>> {
+        Ok(parse_target_sections(content, operation)?.into_iter().map(|s| s.heading).collect())
+    };
+    let (before, after) = (outline(original)?, outline(new_content)?);
+    if !operation.heading_block && before != after {
+        bail!(
+            "Structure verification failed: the edit changes the document outline \
+             ({} heading(s) before, {} after); the content probably contains a heading line",
+            before.len(),
+            after.len()
+        );
+    }
 
-    #[test]
-    fn test_apply_append() {
-        let content = "# Title\n\nFirst paragraph.\n\nSecond paragraph.\n";
-        // block.end 指向段落结束后的位置（包含换行）
-        let block = Block {
-            start: 9,
-            end: 26, // "First paragraph." 结束于 25, 加上换行符
-            content: "First paragraph.".to_string(),
-            block_type: crate::parser::BlockType::Paragraph,
+    let expects_block = matches!(
+        operation.operation,
+        Operation::Append
+            | Operation::Replace
+            | Operation::ListAppend
+            | Operation::Ensure
+            | Operation::InsertSorted
+            | Operation::AppendLineIfAbsent
+            | Operation::DefinitionAppend
+    ) && operation.content.as_deref().is_some_and(|c| !c.trim().is_empty());
+    if expects_block && !operation.heading_block {
+        let found = match locate_new_block(new_content, operation, section_idx, block_index)? {
+            Some(idx) => parse_target_sections(new_content, operation)?
+                .get(section_idx)
+                .is_some_and(|s| idx < s.blocks.len()),
+            None => false,
         };
-        
-        let result = apply_append(content, &block, Some("New content")).unwrap();
+        if !found {
+            bail!(
+                "Structure verification failed: the new content is not a block of '{}' after re-parsing",
+                before[section_idx]
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Explain why an operation left `content` unchanged, pointing at the relevant line
+fn noop_reason(content: &str, operation: &PatchOperation, section_idx: usize, block_index: usize) -> String {
+    let line_of = |offset: usize| content[..offset].matches('\n').count() + 1;
+    if operation.whole_file {
+        return "trim-trailing skipped: no line ends with whitespace".to_string();
+    }
+    let sections = parse_target_sections(content, operation).unwrap_or_default();
+    let Some(section) = sections.get(section_idx) else {
+        return "target content is unchanged".to_string();
+    };
+    let block = section.blocks.get(block_index);
+    let block_line = block.map_or(line_of(section.heading_end), |b| line_of(b.start));
+    let inserted = operation
+        .content
+        .as_deref()
+        .map(|c| c.trim_matches('\n'))
+        .filter(|c| !c.trim().is_empty());
+
+    match operation.operation {
+        Operation::Append | Operation::Replace if inserted.is_none() && operation.noop_on_missing_content => {
+            "skipped: no content supplied (--no-op-on-missing-content)".to_string()
+        }
+        Operation::Append | Operation::ListAppend | Operation::InsertSorted => {
+            let name = match operation.operation {
+                Operation::Append => "append",
+                Operation::ListAppend => "list-append",
+                _ => "insert-sorted",
+            };
+            // 从目标位置开始查找已存在的内容
+            let from = match block {
+                Some(b) if !operation.after_heading => b.start,
+                _ => section.heading_end,
+            };
+            match inserted.and_then(|c| content[from..].find(c)) {
+                Some(pos) => format!("{} skipped: content already present at line {}", name, line_of(from + pos)),
+                None => format!("{} skipped: content already present", name),
+            }
+        }
+        Operation::Replace => format!("replace skipped: block at line {} already has this content", block_line),
+        Operation::AppendLineIfAbsent => {
+            let line = inserted.unwrap_or("").trim_matches('\n');
+            let key = line_key_regex(line, operation.line_key.as_deref()).ok();
+            let existing = block.zip(key).and_then(|(b, key)| {
+                matching_line(b, line, &key).ok().flatten().map(|n| line_of(b.start) + n)
+            });
+            match existing {
+                Some(line) => format!("append-line-if-absent skipped: line {} already matches", line),
+                None => "append-line-if-absent skipped: a matching line is already present".to_string(),
+            }
+        }
+        Operation::DefinitionAppend => {
+            let term = inserted.and_then(|c| definition_items(c).0.into_iter().next()).map(|item| item.term);
+            let existing = block.zip(term.as_deref()).and_then(|(b, term)| {
+                b.content.lines().position(|l| l.trim() == term).map(|n| line_of(b.start) + n)
+            });
+            match (term, existing) {
+                (Some(term), Some(line)) => {
+                    format!("definition-append skipped: '{}' is already defined at line {}", term, line)
+                }
+                _ => "definition-append skipped: entry already present".to_string(),
+            }
+        }
+        Operation::Convert => format!(
+            "convert skipped: block at line {} is already a {}",
+            block_line,
+            operation.convert_to.map_or("block", ConvertTarget::kind)
+        ),
+        Operation::Ensure => format!("ensure skipped: block at line {} already has this content", block_line),
+        Operation::ReplaceBody => format!(
+            "replace-body skipped: body of '{}' already matches",
+            section.heading
+        ),
+        Operation::Sort => format!("sort skipped: list at line {} is already in order", block_line),
+        Operation::Toc => "toc skipped: table of contents is up to date".to_string(),
+        Operation::Dedup => format!("dedup skipped: no duplicate blocks under '{}'", section.heading),
+        Operation::Merge => format!("merge skipped: no adjacent blocks of the same type under '{}'", section.heading),
+        Operation::TrimTrailing => {
+            format!("trim-trailing skipped: no line under '{}' ends with whitespace", section.heading)
+        }
+        Operation::Delete => format!("delete made no changes at line {}", block_line),
+    }
+}
+
+/// Check that the addressed block matches `fingerprint` without modifying anything
+pub fn verify_block(
+    content: &str,
+    heading_path: &[String],
+    block_index: usize,
+    occurrence: Option<usize>,
+    fingerprint: &str,
+    ignore_case: bool,
+    flavor: Flavor,
+) -> Result<()> {
+    let sections = parse_sections_with(content, flavor)?;
+    let section = find_section(&sections, heading_path, occurrence)?;
+    let block = get_block(section, block_index)?;
+    let regex = fingerprint_regex(fingerprint, ignore_case)?;
+    check_fingerprint(content, block, block_index, &regex, CommentMode::Keep)
+}
+
+fn fingerprint_regex(fingerprint: &str, ignore_case: bool) -> Result<Regex> {
+    Ok(RegexBuilder::new(fingerprint).case_insensitive(ignore_case).build()?)
+}
+
+fn check_fingerprint(
+    content: &str,
+    block: &Block,
+    block_index: usize,
+    regex: &Regex,
+    strip_comments: CommentMode,
+) -> Result<()> {
+    if !fingerprint_matches(content, block, regex, strip_comments) {
+        bail!(
+            "Fingerprint mismatch: block at index {} does not match pattern '{}'. \
+             Target block content does not meet identification criteria.",
+            block_index, regex
+        );
+    }
+    Ok(())
+}
+
+fn fingerprint_matches(content: &str, block: &Block, regex: &Regex, strip_comments: CommentMode) -> bool {
+    if strip_comments == CommentMode::Match {
+        // 按整个文档计算注释范围，以正确处理跨越多个 block 的多行注释
+        let comments = html_comment_ranges(content);
+        regex.is_match(&text_without_ranges(content, block.start, block.end, &comments))
+    } else {
+        regex.is_match(&block.content)
+    }
+}
+
+/// 重新解析修改后的内容，定位新插入（或替换）的 block 在 section 中的索引
+fn locate_new_block(
+    new_content: &str,
+    operation: &PatchOperation,
+    section_idx: usize,
+    block_index: usize,
+) -> Result<Option<usize>> {
+    let inserted = match (operation.operation, operation.content.as_deref()) {
+        (Operation::Delete | Operation::Toc | Operation::Dedup | Operation::Merge | Operation::TrimTrailing, _)
+        | (Operation::Append, None) => {
+            return Ok(None)
+        }
+        _ if operation.heading_block => return Ok(None),
+        (Operation::Append | Operation::Ensure | Operation::InsertSorted, Some(c)) => c.trim(),
+        _ => return Ok(Some(block_index)),
+    };
+
+    // 目标 section 之前的内容未被修改，因此 section 序号保持不变
+    let sections = parse_target_sections(new_content, operation)?;
+    let Some(section) = sections.get(section_idx) else {
+        return Ok(None);
+    };
+    // 插入列表条目时目标 list block 本身就是新内容所在的 block
+    if matches!(operation.operation, Operation::InsertSorted)
+        && section.blocks.get(block_index).is_some_and(|b| matches!(b.block_type, BlockType::List { .. }))
+    {
+        return Ok(Some(block_index));
+    }
+
+    // 按整块内容比较（多个 block 时取第一个），避免子串误匹配到目标 block 本身
+    let first = parse_blocks(inserted, operation.flavor)
+        .ok()
+        .and_then(|blocks| blocks.first().map(|b| b.content.trim().to_string()))
+        .unwrap_or_else(|| inserted.to_string());
+    // 普通追加的新 block 在目标之后；其余情况（heading 之后、原地替换或合并）可能就是目标位置
+    let start = match operation.operation {
+        Operation::Append if !operation.after_heading && operation.merge_append.is_none() => block_index + 1,
+        _ => block_index,
+    };
+    if let Some(idx) = section.blocks.iter().skip(start).position(|b| b.content.trim() == first) {
+        return Ok(Some(start + idx));
+    }
+    // 追加内容并入了目标 block（例如 separator 为 0 时紧跟在段落后）
+    Ok(section
+        .blocks
+        .get(block_index)
+        .filter(|b| b.content.trim_end().ends_with(inserted))
+        .map(|_| block_index))
+}
+
+/// Insert content right after the section's heading line, before its first block
+fn apply_after_heading(
+    content: &str,
+    section: &Section,
+    new_content: Option<&str>,
+    separator: usize,
+) -> Result<String> {
+    let insert_content = match new_content {
+        Some(c) => c,
+        None => bail!("Append operation requires content"),
+    };
+
+    // heading_end 位于 heading 行的换行符之前，从下一行开始插入，避免内容粘在 heading 行上
+    let line_end = heading_line_end(content, section);
+    let has_newline = content[..line_end].ends_with('\n');
+    let after = &content[line_end..];
+    let rest = after.trim_start_matches('\n');
+
+    // 幂等性检查：heading 之后的前几个完整 block 已是该内容（不按前缀匹配）
+    let trimmed = insert_content.trim();
+    let present = section.blocks.first().is_some_and(|first| {
+        section.blocks.iter().any(|b| content[first.start..b.end].trim() == trimmed)
+    });
+    if present {
+        return Ok(content.to_string());
+    }
+
+    let mut result = content[..line_end].to_string();
+    if !has_newline {
+        result.push('\n');
+    }
+    result.push_str(&"\n".repeat(separator));
+    result.push_str(insert_content);
+
+    if rest.is_empty() {
+        // 空 section（位于文档末尾）：保留原有的结尾换行
+        if has_newline {
+            result.push('\n');
+        }
+        return Ok(result);
+    }
+
+    // 保留 heading 与原首个 block 之间的空行（至少一个）
+    let gap = &after[..after.len() - rest.len()];
+    result.push('\n');
+    result.push_str(if gap.is_empty() { "\n" } else { gap });
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Replace the first block of `section` matching the fingerprint, or append after the
+/// section's last block when none matches. Returns the new content and the index of the
+/// replaced block (or of the block the content was appended after).
+///
+/// The new content must match the fingerprint itself, so that a rerun finds the block it
+/// inserted instead of appending a second copy.
+fn apply_ensure(content: &str, section: &Section, operation: &PatchOperation) -> Result<(String, usize)> {
+    let Some(fingerprint) = operation.fingerprint.as_deref() else {
+        bail!("Ensure operation requires a fingerprint (-p) to identify the managed block");
+    };
+    let Some(new_content) = operation.content.as_deref() else {
+        bail!("Ensure operation requires content");
+    };
+    let regex = fingerprint_regex(fingerprint, operation.fingerprint_ignore_case)?;
+    if !regex.is_match(new_content) {
+        bail!(
+            "Ensure content does not match fingerprint '{}'; a rerun could not find the inserted block",
+            fingerprint
+        );
+    }
+
+    let matched = section
+        .blocks
+        .iter()
+        .position(|b| fingerprint_matches(content, b, &regex, operation.strip_comments));
+    match (matched, section.blocks.len().checked_sub(1)) {
+        (Some(idx), _) => Ok((
+            apply_replace(content, &section.blocks[idx], &section.heading, Some(new_content))?,
+            idx,
+        )),
+        (None, Some(last)) => Ok((
+            apply_append(
+                content,
+                &section.blocks[last],
+                Some(new_content),
+                operation.separator,
+                operation.idempotency,
+                operation.flavor,
+            )?,
+            last,
+        )),
+        // 空 section：紧跟 heading 插入
+        (None, None) => Ok((
+            apply_after_heading(content, section, Some(new_content), operation.separator)?,
+            0,
+        )),
+    }
+}
+
+/// `separator` is the number of blank lines inserted between the target block and the new content
+fn apply_append(
+    content: &str,
+    block: &Block,
+    new_content: Option<&str>,
+    separator: usize,
+    idempotency: Idempotency,
+    flavor: Flavor,
+) -> Result<String> {
+    let insert_content = match new_content {
+        Some(c) => c,
+        None => bail!("Append operation requires content"),
+    };
+
+    // 幂等性检查：如果内容已存在，直接返回原内容（与分隔空行数量无关）
+    if already_appended(content, block, insert_content, idempotency, flavor)? {
+        return Ok(content.to_string());
+    }
+
+    let before = &content[..block.end];
+    let after = &content[block.end..];
+
+    // 在内容前加换行及分隔空行，确保格式正确
+    let insert_with_newline = format!("{}{}", "\n".repeat(separator + 1), insert_content);
+
+    // 追加到文件末尾：以恰好一个换行结束，不留下末尾空行
+    if after.trim().is_empty() {
+        return Ok(format!("{}{}\n", before, insert_with_newline.trim_end_matches('\n')));
+    }
+
+    Ok(format!("{}{}{}", before, insert_with_newline, after))
+}
+
+/// Append formatted like the target block: a new item of a list (renumbering ordered
+/// lists), `> ` lines after a quote; other block types get a plain append
+fn apply_append_matching_style(content: &str, block: &Block, operation: &PatchOperation) -> Result<String> {
+    let Some(text) = operation.content.as_deref() else {
+        bail!("Append operation requires content");
+    };
+    let quoted;
+    let text = match block.block_type {
+        BlockType::List { .. } => return insert_list_item(content, block, text, None),
+        // 已经是引用格式的内容保持原样
+        BlockType::BlockQuote if !text.trim_start().starts_with('>') => {
+            quoted = text
+                .trim_matches('\n')
+                .lines()
+                .map(|line| if line.trim().is_empty() { ">".to_string() } else { format!("> {}", line) })
+                .collect::<Vec<_>>()
+                .join("\n");
+            quoted.as_str()
+        }
+        _ => text,
+    };
+    apply_append(content, block, Some(text), operation.separator, operation.idempotency, operation.flavor)
+}
+
+const TOC_START: &str = "<!-- mdp:toc -->";
+const TOC_END: &str = "<!-- mdp:/toc -->";
+
+/// Byte range of an existing `<!-- mdp:toc -->` ... `<!-- mdp:/toc -->` region, markers included
+fn toc_range(content: &str) -> Option<(usize, usize)> {
+    let start = content.find(TOC_START)?;
+    let end = content[start..].find(TOC_END)? + start + TOC_END.len();
+    Some((start, end))
+}
+
+/// Refresh the TOC between its markers, or insert a new one at the target location
+fn apply_toc(
+    content: &str,
+    sections: &[Section],
+    section_idx: usize,
+    block_index: usize,
+    operation: &PatchOperation,
+) -> Result<String> {
+    let toc = format!("{}\n{}{}", TOC_START, build_toc(sections, operation.toc_depth), TOC_END);
+
+    if let Some((start, end)) = toc_range(content) {
+        return Ok(format!("{}{}{}", &content[..start], toc, &content[end..]));
+    }
+    if operation.after_heading {
+        return apply_after_heading(content, &sections[section_idx], Some(&toc), operation.separator);
+    }
+    let block = get_block(&sections[section_idx], block_index)?;
+    apply_append(content, block, Some(&toc), operation.separator, Idempotency::Off, operation.flavor)
+}
+
+/// Nested bullet list linking every heading up to `depth`, one line per heading
+fn build_toc(sections: &[Section], depth: u8) -> String {
+    let headings: Vec<&Section> = sections.iter().filter(|s| s.heading_level <= depth).collect();
+    let min_level = headings.iter().map(|s| s.heading_level).min().unwrap_or(1);
+
+    let mut seen: std::collections::HashMap<String, usize> = std::collections::HashMap::new();
+    let mut toc = String::new();
+    for section in headings {
+        let title = heading_title(&section.heading);
+        // 与 GitHub 一致：重复的锚点依次追加 -1、-2
+        let slug = heading_slug(title);
+        let count = seen.entry(slug.clone()).or_insert(0);
+        let anchor = if *count == 0 { slug } else { format!("{}-{}", slug, count) };
+        *count += 1;
+
+        let indent = "  ".repeat((section.heading_level - min_level) as usize);
+        toc.push_str(&format!("{}- [{}](#{})\n", indent, title, anchor));
+    }
+    toc
+}
+
+/// Heading text without the `#` markers (including optional closing ones)
+fn heading_title(heading: &str) -> &str {
+    heading.trim_start_matches('#').trim().trim_end_matches('#').trim_end()
+}
+
+/// GitHub-style heading anchor: lowercase, punctuation dropped, spaces to hyphens
+fn heading_slug(title: &str) -> String {
+    title
+        .to_lowercase()
+        .chars()
+        .filter_map(|c| match c {
+            ' ' => Some('-'),
+            c if c.is_alphanumeric() || c == '-' || c == '_' => Some(c),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Replace everything between `body_start` (end of the heading line) and `body_end`
+fn apply_replace_body(
+    content: &str,
+    body_start: usize,
+    body_end: usize,
+    new_content: Option<&str>,
+) -> Result<String> {
+    let body = match new_content {
+        Some(c) => c.trim_matches('\n'),
+        None => bail!("Replace-body operation requires content"),
+    };
+
+    // 幂等性检查：正文已与新内容一致
+    if content[body_start..body_end].trim_matches('\n') == body {
+        return Ok(content.to_string());
+    }
+
+    let before = &content[..body_start];
+    let after = &content[body_end..];
+    // 后面还有 heading 时保留一个空行分隔；正文从行首开始（区域内）时不再补空行
+    let lead = if before.ends_with('\n') { "" } else { "\n\n" };
+    let tail = if after.is_empty() { "\n" } else { "\n\n" };
+    Ok(format!("{}{}{}{}{}", before, lead, body, tail, after))
+}
+
+fn already_appended(
+    content: &str,
+    block: &Block,
+    insert_content: &str,
+    idempotency: Idempotency,
+    flavor: Flavor,
+) -> Result<bool> {
+    let block_and_after = &content[block.start..];
+    match idempotency {
+        Idempotency::Off => Ok(false),
+        Idempotency::Contains => Ok(block_and_after.contains(insert_content)),
+        Idempotency::Exact => {
+            // 重新解析目标 block 之后的内容，逐 block 比较
+            let insert = insert_content.trim_matches('\n');
+            let blocks = parse_blocks(block_and_after, flavor)?;
+
+            // 紧凑追加（如列表项）会并入目标 block
+            if blocks.first().is_some_and(|b| b.content.ends_with(&format!("\n{}", insert))) {
+                return Ok(true);
+            }
+
+            // 插入内容可能由多个 block 组成：起止均需落在 block 边界上
+            Ok(blocks.iter().skip(1).any(|first| {
+                block_and_after[first.start..].starts_with(insert)
+                    && blocks.iter().any(|b| b.end == first.start + insert.len())
+            }))
+        }
+    }
+}
+
+/// `{{block}}` expands to the block's current content and `{{heading}}` to the section title
+fn apply_replace(content: &str, block: &Block, heading: &str, new_content: Option<&str>) -> Result<String> {
+    let Some(template) = new_content else {
+        bail!("Replace operation requires content");
+    };
+    // 一次性替换两个占位符，block 内容中出现的 `{{heading}}` 原样保留
+    let placeholder_re = Regex::new(r"\{\{(block|heading)\}\}")?;
+    let replacement = placeholder_re.replace_all(template, |caps: &regex::Captures| match &caps[1] {
+        "block" => block.content.clone(),
+        _ => heading_title(heading).to_string(),
+    });
+
+    // Block.end 不包含结尾换行（所有 block 类型一致），因此 after 以换行开头或位于文档末尾。
+    // 去掉替换内容末尾的换行，保证与后续内容之间恰好一个换行。
+    let replacement = replacement.trim_end_matches('\n');
+    let before = &content[..block.start];
+    let after = &content[block.end..];
+    let separator = if after.is_empty() || after.starts_with('\n') { "" } else { "\n" };
+
+    Ok(format!("{}{}{}{}", before, replacement, separator, after))
+}
+
+/// `--merge-append`: replace the block (from the target onward) most similar to the
+/// content, if its similarity reaches `threshold`, rather than appending a near-duplicate.
+/// Returns `None` when no block is similar enough.
+fn merge_similar_block(
+    content: &str,
+    section: &Section,
+    block_index: usize,
+    operation: &PatchOperation,
+    threshold: f64,
+    warnings: &mut Vec<String>,
+) -> Result<Option<String>> {
+    let new_content = operation.content.as_deref().unwrap_or("").trim_matches('\n');
+    let best = section.blocks[block_index..]
+        .iter()
+        .map(|b| (b, similarity(&b.content, new_content)))
+        .filter(|(_, score)| *score >= threshold)
+        .max_by(|a, b| a.1.total_cmp(&b.1));
+    let Some((similar, score)) = best else {
+        return Ok(None);
+    };
+
+    warnings.push(format!(
+        "Merge-append: updated the similar block at line {} (similarity {:.2}) instead of appending",
+        content[..similar.start].matches('\n').count() + 1,
+        score
+    ));
+    apply_replace(content, similar, &section.heading, Some(new_content)).map(Some)
+}
+
+/// Word-level similarity: `2 * LCS / (len_a + len_b)` over whitespace-separated tokens,
+/// 1.0 for identical token sequences
+fn similarity(a: &str, b: &str) -> f64 {
+    let a: Vec<&str> = a.split_whitespace().collect();
+    let b: Vec<&str> = b.split_whitespace().collect();
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+    2.0 * compute_lcs(&a, &b).len() as f64 / (a.len() + b.len()) as f64
+}
+
+/// Regex identifying an existing copy of `line`: `key`, or the exact (trimmed) line
+fn line_key_regex(line: &str, key: Option<&str>) -> Result<Regex> {
+    let pattern = match key {
+        Some(key) => key.to_string(),
+        None => format!(r"^\s*{}\s*$", regex::escape(line.trim())),
+    };
+    Ok(Regex::new(&pattern)?)
+}
+
+/// Index of the first line of `block` matching `key`. List items are also tried with
+/// their marker replaced by the marker of `line` (or removed, if `line` has none), since
+/// the new item is rendered with the list's own marker.
+fn matching_line(block: &Block, line: &str, key: &Regex) -> Result<Option<usize>> {
+    let marker_re = Regex::new(r"^\s*([-*+]|\d+[.)])\s+")?;
+    let marker = marker_re.find(line).map_or("", |m| m.as_str());
+    let is_list = matches!(block.block_type, BlockType::List { .. });
+    Ok(block.content.lines().position(|l| {
+        key.is_match(l)
+            || (is_list && marker_re.find(l).is_some_and(|m| key.is_match(&format!("{}{}", marker, &l[m.end()..]))))
+    }))
+}
+
+/// Append a single line to a list (as a new item) or paragraph block, unless a line of
+/// the block already matches `key`. The new line must match `key` itself, so that a
+/// rerun finds it.
+fn apply_append_line(content: &str, block: &Block, new_content: Option<&str>, key: Option<&str>) -> Result<String> {
+    let line = match new_content.map(|c| c.trim_matches('\n')) {
+        Some(line) if !line.contains('\n') => line,
+        Some(_) => bail!("Append-line-if-absent operation requires a single line of content"),
+        None => bail!("Append-line-if-absent operation requires content"),
+    };
+    let key_re = line_key_regex(line, key)?;
+    if !key_re.is_match(line) {
+        bail!(
+            "Line '{}' does not match --line-key '{}'; a rerun would append it again",
+            line,
+            key_re.as_str()
+        );
+    }
+
+    // 逐行检查：任意一行匹配即视为已存在
+    if matching_line(block, line, &key_re)?.is_some() {
+        return Ok(content.to_string());
+    }
+
+    match block.block_type {
+        BlockType::List { .. } => insert_list_item(content, block, line, None),
+        BlockType::Paragraph => Ok(format!("{}\n{}{}", &content[..block.end], line, &content[block.end..])),
+        _ => bail!(
+            "Append-line-if-absent operation requires a list or paragraph block, but the target is a {} block",
+            block.block_type.kind()
+        ),
+    }
+}
+
+/// Append a `term\n: definition` entry to a definition list block. An existing entry
+/// with the same term and definitions makes this a no-op; the same term with different
+/// definitions is an error.
+fn apply_definition_append(content: &str, block: &Block, new_content: Option<&str>) -> Result<String> {
+    if !matches!(block.block_type, BlockType::DefinitionList) {
+        bail!(
+            "Definition-append operation requires a definition list block, but the target is a {} block",
+            block.block_type.kind()
+        );
+    }
+    let Some(new_content) = new_content else {
+        bail!("Definition-append operation requires content");
+    };
+    let entry = match definition_items(new_content).0.as_slice() {
+        [entry] if !entry.definitions.is_empty() => entry.clone(),
+        _ => bail!("Definition-append content must be a single entry: a term line followed by `: definition` lines"),
+    };
+
+    let (items, loose) = definition_items(&block.content);
+    if let Some(existing) = items.iter().find(|item| item.term == entry.term) {
+        if existing.definitions == entry.definitions {
+            return Ok(content.to_string());
+        }
+        bail!(
+            "Term '{}' is already defined with a different definition; use replace to change it",
+            entry.term
+        );
+    }
+
+    let rendered: Vec<String> = std::iter::once(entry.term.clone())
+        .chain(entry.definitions.iter().map(|d| format!(": {}", d)))
+        .collect();
+    let gap = if loose { "\n\n" } else { "\n" };
+    Ok(format!("{}{}{}{}", &content[..block.end], gap, rendered.join("\n"), &content[block.end..]))
+}
+
+/// Re-render a paragraph, list or quote block as another of those types. The text is
+/// split into lines (list items, quote lines without `>`), then each line becomes a `- `
+/// item, a paragraph line or a `> ` line. Bullet characters such as `•` are dropped.
+fn apply_convert(content: &str, block: &Block, target: Option<ConvertTarget>) -> Result<String> {
+    let Some(target) = target else {
+        bail!("Convert operation requires a destination type (--to list|paragraph|quote)");
+    };
+    let current = match block.block_type {
+        BlockType::Paragraph => ConvertTarget::Paragraph,
+        BlockType::List { .. } => ConvertTarget::List,
+        BlockType::BlockQuote => ConvertTarget::Quote,
+        _ => bail!(
+            "Convert operation requires a paragraph, list or quote block, but the target is a {} block",
+            block.block_type.kind()
+        ),
+    };
+    if current == target {
+        return Ok(content.to_string());
+    }
+
+    let lines: Vec<String> = match current {
+        // 多行条目合并为一行
+        ConvertTarget::List => list_items(&block.content)
+            .0
+            .iter()
+            .map(|item| item.text.lines().map(str::trim).collect::<Vec<_>>().join(" "))
+            .collect(),
+        ConvertTarget::Quote => block
+            .content
+            .lines()
+            .map(|l| {
+                let l = l.trim_start();
+                let l = l.strip_prefix('>').unwrap_or(l);
+                l.strip_prefix(' ').unwrap_or(l).trim_end().to_string()
+            })
+            .collect(),
+        ConvertTarget::Paragraph => block.content.lines().map(|l| l.trim().to_string()).collect(),
+    };
+    let bullet_re = Regex::new(r"^[•·‣▪◦]\s*")?;
+    let lines = lines.iter().filter(|l| !l.is_empty());
+
+    let rendered = match target {
+        ConvertTarget::List => lines
+            .map(|l| format!("- {}", bullet_re.replace(l, "")))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        ConvertTarget::Paragraph => lines.map(String::as_str).collect::<Vec<_>>().join("\n"),
+        ConvertTarget::Quote => lines.map(|l| format!("> {}", l)).collect::<Vec<_>>().join("\n"),
+    };
+    Ok(format!("{}{}{}", &content[..block.start], rendered, &content[block.end..]))
+}
+
+/// Strip trailing spaces and tabs from every line in `start..end`, leaving fenced code
+/// untouched (whitespace there may be significant). A trailing run of two or more
+/// spaces on a non-blank line is a hard line break and is kept.
+fn apply_trim_trailing(content: &str, start: usize, end: usize) -> String {
+    let mut trimmed = String::with_capacity(content.len());
+    trimmed.push_str(&content[..start]);
+    let mut fence: Option<&str> = None;
+    for line in content[start..end].split_inclusive('\n') {
+        let body = line.trim_end_matches(['\n', '\r']);
+        match fence {
+            Some(open) if closes_fence(body, open) => fence = None,
+            Some(_) => {
+                trimmed.push_str(line);
+                continue;
+            }
+            None => fence = code_fence(body),
+        }
+        let text = body.trim_end_matches([' ', '\t']);
+        let trailing = &body[text.len()..];
+        let hard_break = !text.trim().is_empty() && fence.is_none() && trailing.len() >= 2 && trailing.bytes().all(|b| b == b' ');
+        trimmed.push_str(if hard_break { body } else { text });
+        trimmed.push_str(&line[body.len()..]);
+    }
+    trimmed.push_str(&content[end..]);
+    trimmed
+}
+
+/// Remove every block whose content equals an earlier block of the section (first one kept),
+/// together with the blank lines separating it from the preceding block
+fn apply_dedup(content: &str, section: &Section) -> String {
+    let mut seen: Vec<&str> = Vec::new();
+    let mut removed: Vec<(usize, usize)> = Vec::new();
+    for (idx, block) in section.blocks.iter().enumerate() {
+        if seen.contains(&block.content.as_str()) {
+            // 重复 block 不可能是第一个，idx >= 1
+            removed.push((section.blocks[idx - 1].end, block.end));
+        } else {
+            seen.push(&block.content);
+        }
+    }
+
+    let mut result = content.to_string();
+    for &(start, end) in removed.iter().rev() {
+        result.replace_range(start..end, "");
+    }
+    result
+}
+
+/// 合并相邻的同类 block：段落与列表以单个换行相连，同语言的代码块合并到一个围栏内。
+/// 其他类型的 block 保持不变。
+fn apply_merge(content: &str, section: &Section) -> String {
+    let mergeable = |a: &Block, b: &Block| match (&a.block_type, &b.block_type) {
+        (BlockType::Paragraph, BlockType::Paragraph) => true,
+        (BlockType::List { ordered: x }, BlockType::List { ordered: y }) => x == y,
+        (BlockType::CodeBlock { lang: x }, BlockType::CodeBlock { lang: y }) => x == y,
+        _ => false,
+    };
+
+    // 依次收集可合并的连续 block
+    let mut runs: Vec<&[Block]> = Vec::new();
+    let mut start = 0;
+    for idx in 1..=section.blocks.len() {
+        if idx == section.blocks.len() || !mergeable(&section.blocks[idx - 1], &section.blocks[idx]) {
+            if idx - start > 1 {
+                runs.push(&section.blocks[start..idx]);
+            }
+            start = idx;
+        }
+    }
+
+    let mut result = content.to_string();
+    for run in runs.iter().rev() {
+        let text = |b: &Block| content[b.start..b.end].trim_end_matches('\n');
+        let merged = if matches!(run[0].block_type, BlockType::CodeBlock { .. }) {
+            // 保留第一个代码块的开始围栏与最后一个的结束围栏，中间只拼接代码内容
+            let mut lines: Vec<&str> = Vec::new();
+            for (n, block) in run.iter().enumerate() {
+                let block_lines: Vec<&str> = text(block).lines().collect();
+                let from = if n == 0 { 0 } else { 1 };
+                let to = if n == run.len() - 1 { block_lines.len() } else { block_lines.len() - 1 };
+                lines.extend_from_slice(&block_lines[from..to]);
+            }
+            lines.join("\n")
+        } else {
+            run.iter().map(text).collect::<Vec<_>>().join("\n")
+        };
+        result.replace_range(run[0].start..run[run.len() - 1].end, &merged);
+    }
+    result
+}
+
+fn apply_delete(content: &str, start: usize, end: usize) -> Result<String> {
+    let before = &content[..start];
+    let after = &content[end..];
+
+    // Clean up extra newlines that might result from deletion
+    let result = format!("{}{}", before, after);
+    
+    // Remove consecutive blank lines caused by deletion
+    let cleaned = Regex::new(r"\n{3,}")?.replace_all(&result, "\n\n");
+    
+    Ok(cleaned.to_string())
+}
+
+/// 对列表 block 的条目排序；已排序时保持原样（幂等）
+fn apply_sort(content: &str, block: &Block, mode: SortMode, key: Option<&str>) -> Result<String> {
+    if !matches!(block.block_type, BlockType::List { .. }) {
+        bail!(
+            "Sort operation requires a list block, but the target is a {} block",
+            block.block_type.kind()
+        );
+    }
+
+    let sort_key = sort_key_fn(mode, key)?;
+
+    let (items, loose) = list_items(&block.content);
+    let mut order: Vec<usize> = (0..items.len()).collect();
+    order.sort_by_key(|&i| sort_key(&items[i].text));
+
+    if order.iter().enumerate().all(|(pos, &i)| pos == i) {
+        return Ok(content.to_string());
+    }
+
+    // 标记（包括有序列表的编号）保持原位置，只移动条目内容
+    let item_separator = if loose { "\n\n" } else { "\n" };
+    let sorted = order
+        .iter()
+        .enumerate()
+        .map(|(pos, &i)| format!("{}{}", items[pos].marker, items[i].text))
+        .collect::<Vec<_>>()
+        .join(item_separator);
+
+    Ok(format!("{}{}{}", &content[..block.start], sorted, &content[block.end..]))
+}
+
+/// Sort key of an item or block: its first line, or the `key` regex's first capture
+/// group (whole match without groups) on that line
+fn sort_key_fn(mode: SortMode, key: Option<&str>) -> Result<impl Fn(&str) -> String> {
+    let key_re = key.map(Regex::new).transpose()?;
+    Ok(move |text: &str| -> String {
+        let first_line = text.lines().next().unwrap_or("");
+        let key = match key_re.as_ref().and_then(|re| re.captures(first_line)) {
+            Some(caps) => caps.get(1).or(caps.get(0)).unwrap().as_str().to_string(),
+            None => first_line.to_string(),
+        };
+        match mode {
+            SortMode::Lexical => key,
+            SortMode::CaseInsensitive => key.to_lowercase(),
+        }
+    })
+}
+
+/// Insert content where it keeps the target sorted: as an item of a list block, or,
+/// for any other target, as a block among the siblings from the target block to the
+/// end of the section. The new entry goes before the first entry with a greater key.
+fn apply_insert_sorted(
+    content: &str,
+    section: &Section,
+    block_index: usize,
+    new_content: Option<&str>,
+    separator: usize,
+    mode: SortMode,
+    key: Option<&str>,
+) -> Result<String> {
+    let Some(new_content) = new_content else {
+        bail!("Insert-sorted operation requires content");
+    };
+    let sort_key = sort_key_fn(mode, key)?;
+    let block = get_block(section, block_index)?;
+    if matches!(block.block_type, BlockType::List { .. }) {
+        return insert_list_item(content, block, new_content, Some(&sort_key));
+    }
+
+    let text = new_content.trim_matches('\n');
+    let siblings = &section.blocks[block_index..];
+    // 幂等性检查：相同的 block 已存在
+    if siblings.iter().any(|b| b.content.trim() == text.trim()) {
+        return Ok(content.to_string());
+    }
+
+    let gap = "\n".repeat(separator + 1);
+    let new_key = sort_key(text);
+    match siblings.iter().find(|b| sort_key(&b.content) > new_key) {
+        Some(next) => Ok(format!("{}{}{}{}", &content[..next.start], text, gap, &content[next.start..])),
+        None => {
+            let last = siblings.last().unwrap_or(block);
+            Ok(format!("{}{}{}{}", &content[..last.end], gap, text, &content[last.end..]))
+        }
+    }
+}
+
+/// Append an item to a list block; ordered lists are renumbered sequentially
+/// from the first item's number. A marker in the new content (`3. `, `- `) is ignored.
+fn apply_list_append(content: &str, block: &Block, new_content: Option<&str>) -> Result<String> {
+    let Some(new_content) = new_content else {
+        bail!("List-append operation requires content");
+    };
+    insert_list_item(content, block, new_content, None)
+}
+
+/// Add an item to a list block, at the end or (with `sort_key`) before the first item
+/// with a greater key, renumbering ordered lists
+fn insert_list_item(
+    content: &str,
+    block: &Block,
+    new_content: &str,
+    sort_key: Option<&dyn Fn(&str) -> String>,
+) -> Result<String> {
+    let ordered = match block.block_type {
+        BlockType::List { ordered } => ordered,
+        _ => bail!(
+            "List-append operation requires a list block, but the target is a {} block",
+            block.block_type.kind()
+        ),
+    };
+    // 只去掉首尾空行与缩进，保留行尾硬换行（两个空格）
+    let text = new_content.trim_start().trim_end_matches('\n');
+    let marker_re = Regex::new(r"^([-*+]|\d+[.)])\s+")?;
+    let text = marker_re.replace(text, "");
+
+    let (items, loose) = list_items(&block.content);
+
+    // 幂等性检查：条目文本已存在
+    if items.iter().any(|item| item.text.trim() == text.trim()) {
+        return Ok(content.to_string());
+    }
+
+    let mut entries: Vec<(String, String)> = items
+        .iter()
+        .map(|item| (item.marker.clone(), item.text.clone()))
+        .collect();
+    let last_marker = entries.last().map(|(m, _)| m.clone()).unwrap_or_else(|| "- ".to_string());
+    let position = match sort_key {
+        Some(key) => items.iter().position(|item| key(&item.text) > key(&text)).unwrap_or(items.len()),
+        None => items.len(),
+    };
+    entries.insert(position, (last_marker, text.into_owned()));
+
+    if ordered {
+        // 以首个条目的编号为起点，保留其分隔符（`.` 或 `)`）与缩进
+        let first = entries[0].0.clone();
+        let indent = &first[..first.len() - first.trim_start().len()];
+        let digits: String = first.trim_start().chars().take_while(|c| c.is_ascii_digit()).collect();
+        let start: usize = digits.parse().unwrap_or(1);
+        let delimiter = first.trim_start()[digits.len()..].chars().next().unwrap_or('.');
+        for (n, (marker, _)) in entries.iter_mut().enumerate() {
+            let spacing = &marker[marker.trim_end().len()..];
+            *marker = format!("{}{}{}{}", indent, start + n, delimiter, spacing);
+        }
+    }
+
+    let item_separator = if loose { "\n\n" } else { "\n" };
+    let list = entries
+        .iter()
+        .map(|(marker, text)| format!("{}{}", marker, text))
+        .collect::<Vec<_>>()
+        .join(item_separator);
+
+    Ok(format!("{}{}{}", &content[..block.start], list, &content[block.end..]))
+}
+
+/// Lines of context around each change in a hunk
+const DIFF_CONTEXT: usize = 3;
+
+/// Unified diff with `@@` hunks, applicable with `git apply` / `patch -p1`
+fn generate_diff(original: &str, modified: &str, filename: &str) -> String {
+    // 行内保留换行符，以便区分文件末尾是否有换行
+    let original_lines: Vec<&str> = original.split_inclusive('\n').collect();
+    let modified_lines: Vec<&str> = modified.split_inclusive('\n').collect();
+
+    let mut diff = format!("--- a/{}\n+++ b/{}\n", filename, filename);
+
+    // Use a simple LCS-based diff to build the edit script
+    let lcs = compute_lcs(&original_lines, &modified_lines);
+    let mut script: Vec<(char, &str)> = Vec::new();
+
+    let mut i = 0;
+    let mut j = 0;
+    let mut lcs_idx = 0;
+
+    while i < original_lines.len() || j < modified_lines.len() {
+        if lcs_idx < lcs.len() {
+            if i < original_lines.len()
+                && j < modified_lines.len()
+                && original_lines[i] == modified_lines[j]
+                && original_lines[i] == lcs[lcs_idx]
+            {
+                // Unchanged line
+                script.push((' ', original_lines[i]));
+                i += 1;
+                j += 1;
+                lcs_idx += 1;
+            } else if i < original_lines.len() && original_lines[i] != lcs[lcs_idx] {
+                // Deleted line
+                script.push(('-', original_lines[i]));
+                i += 1;
+            } else {
+                // Added line
+                script.push(('+', modified_lines[j]));
+                j += 1;
+            }
+        } else if i < original_lines.len() {
+            // Remaining deletions
+            script.push(('-', original_lines[i]));
+            i += 1;
+        } else {
+            // Remaining additions
+            script.push(('+', modified_lines[j]));
+            j += 1;
+        }
+    }
+
+    // 将相距不超过 2 * DIFF_CONTEXT 行的改动合并为同一个 hunk
+    let changes: Vec<usize> = script
+        .iter()
+        .enumerate()
+        .filter(|(_, (tag, _))| *tag != ' ')
+        .map(|(idx, _)| idx)
+        .collect();
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for &idx in &changes {
+        let start = idx.saturating_sub(DIFF_CONTEXT);
+        let end = (idx + DIFF_CONTEXT + 1).min(script.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    for (start, end) in hunks {
+        let old_before = script[..start].iter().filter(|(tag, _)| *tag != '+').count();
+        let new_before = script[..start].iter().filter(|(tag, _)| *tag != '-').count();
+        let old_count = script[start..end].iter().filter(|(tag, _)| *tag != '+').count();
+        let new_count = script[start..end].iter().filter(|(tag, _)| *tag != '-').count();
+        // 空范围的起始行号指向其前一行
+        let old_start = if old_count == 0 { old_before } else { old_before + 1 };
+        let new_start = if new_count == 0 { new_before } else { new_before + 1 };
+
+        diff.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            old_start, old_count, new_start, new_count
+        ));
+        for (tag, line) in &script[start..end] {
+            match line.strip_suffix('\n') {
+                Some(text) => diff.push_str(&format!("{}{}\n", tag, text)),
+                None => diff.push_str(&format!("{}{}\n\\ No newline at end of file\n", tag, line)),
+            }
+        }
+    }
+
+    diff
+}
+
+/// One hunk of a unified diff; lines keep their trailing newline (if any)
+struct Hunk {
+    old_start: usize,
+    new_start: usize,
+    old_lines: Vec<String>,
+    new_lines: Vec<String>,
+}
+
+/// Split a unified diff into per-file hunk lists, keyed by the `+++` path (without `b/`)
+fn parse_unified_diff(diff: &str) -> Result<Vec<(String, Vec<Hunk>)>> {
+    let header_re = Regex::new(r"^@@ -(\d+)(?:,(\d+))? \+(\d+)(?:,(\d+))? @@").unwrap();
+    let count = |m: Option<regex::Match>| m.map_or(Ok(1), |m| m.as_str().parse::<usize>());
+
+    let mut files: Vec<(String, Vec<Hunk>)> = Vec::new();
+    // hunk 剩余的旧/新行数；两者归零前，`---` / `+++` 开头的行属于 hunk 正文
+    let (mut old_left, mut new_left) = (0, 0);
+    // 上一行归属（旧、新），用于处理 `\ No newline at end of file`
+    let mut last = (false, false);
+
+    for line in diff.lines() {
+        if old_left == 0 && new_left == 0 {
+            if let Some(path) = line.strip_prefix("+++ ") {
+                let path = path.split('\t').next().unwrap_or(path).trim();
+                files.push((path.strip_prefix("b/").unwrap_or(path).to_string(), Vec::new()));
+                continue;
+            }
+            if let Some(caps) = header_re.captures(line) {
+                if files.is_empty() {
+                    files.push((String::new(), Vec::new()));
+                }
+                old_left = count(caps.get(2))?;
+                new_left = count(caps.get(4))?;
+                files.last_mut().unwrap().1.push(Hunk {
+                    old_start: caps[1].parse()?,
+                    new_start: caps[3].parse()?,
+                    old_lines: Vec::new(),
+                    new_lines: Vec::new(),
+                });
+                continue;
+            }
+        }
+
+        let Some(hunk) = files.last_mut().and_then(|(_, hunks)| hunks.last_mut()) else {
+            continue;
+        };
+        if line.starts_with('\\') {
+            if last.0 {
+                if let Some(l) = hunk.old_lines.last_mut() {
+                    l.pop();
+                }
+            }
+            if last.1 {
+                if let Some(l) = hunk.new_lines.last_mut() {
+                    l.pop();
+                }
+            }
+            continue;
+        }
+        if old_left == 0 && new_left == 0 {
+            continue;
+        }
+
+        // 部分工具会去掉空上下文行的前导空格
+        let (tag, text) = match line.chars().next() {
+            Some(tag @ (' ' | '-' | '+')) => (tag, &line[1..]),
+            None => (' ', ""),
+            Some(_) => bail!("Malformed diff line: {}", line),
+        };
+        let text = format!("{}\n", text);
+        last = (tag != '+', tag != '-');
+        if last.0 {
+            if old_left == 0 {
+                bail!("Malformed diff: hunk has more lines than its header declares");
+            }
+            old_left -= 1;
+            hunk.old_lines.push(text.clone());
+        }
+        if last.1 {
+            if new_left == 0 {
+                bail!("Malformed diff: hunk has more lines than its header declares");
+            }
+            new_left -= 1;
+            hunk.new_lines.push(text);
+        }
+    }
+
+    if old_left != 0 || new_left != 0 {
+        bail!("Malformed diff: last hunk is truncated");
+    }
+    Ok(files)
+}
+
+/// Apply a unified diff (as emitted by `--emit-diff`) to `content`, or undo it with `reverse`.
+/// When the diff covers several files, only the part whose `+++` path matches `filename` is used.
+pub fn apply_unified_diff(content: &str, diff: &str, filename: &str, reverse: bool) -> Result<String> {
+    let files = parse_unified_diff(diff)?;
+    let clean_filename = filename.trim_start_matches("./").trim_start_matches('/');
+    let hunks = match files.as_slice() {
+        [] => return Ok(content.to_string()),
+        [(_, hunks)] => hunks,
+        _ => match files.iter().find(|(path, _)| path == clean_filename) {
+            Some((_, hunks)) => hunks,
+            None => bail!("Diff does not contain changes for {}", filename),
+        },
+    };
+
+    let lines: Vec<&str> = content.split_inclusive('\n').collect();
+    let mut result = String::with_capacity(content.len());
+    let mut cursor = 0;
+
+    for (n, hunk) in hunks.iter().enumerate() {
+        let (start, from, to) = if reverse {
+            (hunk.new_start, &hunk.new_lines, &hunk.old_lines)
+        } else {
+            (hunk.old_start, &hunk.old_lines, &hunk.new_lines)
+        };
+        // 空范围的起始行号指向其前一行
+        let at = if from.is_empty() { start } else { start.saturating_sub(1) };
+        let matches = at >= cursor
+            && at + from.len() <= lines.len()
+            && lines[at..at + from.len()].iter().zip(from).all(|(a, b)| a == b);
+        if !matches {
+            bail!(
+                "Hunk {} does not apply to {}: content at line {} has changed",
+                n + 1,
+                filename,
+                at + 1
+            );
+        }
+
+        result.extend(lines[cursor..at].iter().copied());
+        result.extend(to.iter().map(String::as_str));
+        cursor = at + from.len();
+    }
+    result.extend(lines[cursor..].iter().copied());
+
+    Ok(result)
+}
+
+fn compute_lcs<'a>(a: &[&'a str], b: &[&'a str]) -> Vec<&'a str> {
+    let m = a.len();
+    let n = b.len();
+    
+    if m == 0 || n == 0 {
+        return Vec::new();
+    }
+
+    // Use dynamic programming for LCS
+    let mut dp = vec![vec![0; n + 1]; m + 1];
+
+    for i in 1..=m {
+        for j in 1..=n {
+            if a[i - 1] == b[j - 1] {
+                dp[i][j] = dp[i - 1][j - 1] + 1;
+            } else {
+                dp[i][j] = dp[i - 1][j].max(dp[i][j - 1]);
+            }
+        }
+    }
+
+    // Backtrack to find LCS
+    let mut lcs = Vec::new();
+    let mut i = m;
+    let mut j = n;
+
+    while i > 0 && j > 0 {
+        if a[i - 1] == b[j - 1] {
+            lcs.push(a[i - 1]);
+            i -= 1;
+            j -= 1;
+        } else if dp[i - 1][j] > dp[i][j - 1] {
+            i -= 1;
+        } else {
+            j -= 1;
+        }
+    }
+
+    lcs.reverse();
+    lcs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_sections;
+
+    fn operation(heading: &str, index: usize, op: Operation, content: Option<&str>) -> PatchOperation {
+        PatchOperation {
+            file: PathBuf::from("doc.md"),
+            heading_path: vec![heading.to_string()],
+            block_index: index,
+            operation: op,
+            content: content.map(|c| c.to_string()),
+            fingerprint: None,
+            fingerprint_ignore_case: false,
+            line: None,
+            occurrence: None,
+            numbered: None,
+            recursive_index: false,
+            selector: None,
+            block_hash: None,
+            block_range: None,
+            after_heading: false,
+            heading_block: false,
+            noop_on_missing_content: false,
+            strip_comments: CommentMode::Keep,
+            separator: 1,
+            idempotency: Idempotency::Exact,
+            max_block_bytes: None,
+            repair_fences: false,
+            literal_content: false,
+            verify_structure: false,
+            merge_append: None,
+            match_style: false,
+            coarse_blocks: false,
+            ignore_emoji: false,
+            plain_headings: false,
+            fuzzy_headings: false,
+            heading_match: HeadingMatch::Exact,
+            sort_mode: SortMode::Lexical,
+            sort_key: None,
+            toc_depth: 6,
+            convert_to: None,
+            line_key: None,
+            region: None,
+            whole_file: false,
+            flavor: Flavor::Markdown,
+        }
+    }
+
+    #[test]
+    fn test_apply_append() {
+        let content = "# Title\n\nFirst paragraph.\n\nSecond paragraph.\n";
+        // block.end 指向段落最后一个字符之后（不含换行，与 parser 一致）
+        let block = Block {
+            start: 9,
+            end: 25, // "First paragraph." 结束于 25
+            content: "First paragraph.".to_string(),
+            block_type: crate::parser::BlockType::Paragraph,
+        };
+        
+        let result = apply_append(content, &block, Some("New content"), 1, Idempotency::Exact, Flavor::Markdown).unwrap();
         // 追加后应包含 "First paragraph.\nNew content"
         assert!(result.contains("First paragraph."));
         assert!(result.contains("New content"));
         assert!(result.contains("Second paragraph."));
         
         // 幂等性测试：再次追加相同内容应无变化
-        let result2 = apply_append(&result, &block, Some("New content")).unwrap();
+        let result2 = apply_append(&result, &block, Some("New content"), 1, Idempotency::Exact, Flavor::Markdown).unwrap();
         assert_eq!(result, result2);
     }
 
+    #[test]
+    fn test_new_block_index_after_append() {
+        let content = "# Title\n\nFirst.\n\n```\ncode\n```\n";
+        let op = operation("# Title", 1, Operation::Append, Some("Appended paragraph."));
+
+        let result = apply_operation(content, &op, true).unwrap();
+        let PatchResult::Applied { new_content, new_block_index, .. } = result else {
+            panic!("expected applied result");
+        };
+        assert_eq!(new_block_index, Some(2));
+
+        let sections = parse_sections(&new_content).unwrap();
+        let block = get_block(&sections[0], 2).unwrap();
+        assert_eq!(block.content, "Appended paragraph.");
+
+        // 目标 block 包含追加内容的子串时，新 block 仍是其后的那一个
+        let content = "# Menu\n\nbanana split\n";
+        let op = operation("# Menu", 0, Operation::Append, Some("banana"));
+        let PatchResult::Applied { new_block_index, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(new_block_index, Some(1));
+    }
+
+    #[test]
+    fn test_append_separator() {
+        let content = "# Title\n\n- a\n- b\n\nAfter.\n";
+        let block = parse_sections(content).unwrap()[0].blocks[0].clone();
+
+        let tight = apply_append(content, &block, Some("- c"), 0, Idempotency::Exact, Flavor::Markdown).unwrap();
+        assert_eq!(tight, "# Title\n\n- a\n- b\n- c\n\nAfter.\n");
+
+        let loose = apply_append(content, &block, Some("Note."), 2, Idempotency::Exact, Flavor::Markdown).unwrap();
+        assert_eq!(loose, "# Title\n\n- a\n- b\n\n\nNote.\n\nAfter.\n");
+
+        // 幂等性与分隔空行数量无关
+        assert_eq!(apply_append(&loose, &block, Some("Note."), 1, Idempotency::Exact, Flavor::Markdown).unwrap(), loose);
+    }
+
+    #[test]
+    fn test_max_block_bytes_guard() {
+        // 未闭合的代码块会吞掉文档剩余内容
+        let content = "# Title\n\n```\nunterminated\n\n## Next\n\nMore text here.\n";
+        let mut op = operation("# Title", 0, Operation::Replace, Some("x"));
+        op.max_block_bytes = Some(16);
+
+        let err = apply_operation(content, &op, true).err().unwrap();
+        assert!(err.to_string().contains("--max-block-bytes"), "{}", err);
+
+        op.max_block_bytes = Some(1024);
+        assert!(apply_operation(content, &op, true).is_ok());
+    }
+
+    #[test]
+    fn test_sort_glossary_list() {
+        let content = "# Glossary\n\n- zeta: last\n- Alpha: first\n- beta: second\n  continued\n\nAfter.\n";
+        let mut op = operation("# Glossary", 0, Operation::Sort, None);
+        op.sort_mode = SortMode::CaseInsensitive;
+
+        let PatchResult::Applied { new_content, is_noop, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert!(!is_noop);
+        assert_eq!(
+            new_content,
+            "# Glossary\n\n- Alpha: first\n- beta: second\n  continued\n- zeta: last\n\nAfter.\n"
+        );
+
+        // 已排序时再次执行为 noop
+        let PatchResult::Applied { is_noop, .. } = apply_operation(&new_content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert!(is_noop);
+    }
+
+    #[test]
+    fn test_sort_ordered_list_by_key() {
+        let content = "# Releases\n\n1. v2 (2024)\n2. v1 (2022)\n3. v3 (2023)\n";
+        let mut op = operation("# Releases", 0, Operation::Sort, None);
+        op.sort_key = Some(r"\((\d+)\)".to_string());
+
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(new_content, "# Releases\n\n1. v1 (2022)\n2. v3 (2023)\n3. v2 (2024)\n");
+
+        // 非列表 block 报错
+        let err = apply_operation("# T\n\nText\n", &operation("# T", 0, Operation::Sort, None), true);
+        assert!(err.is_err());
+    }
+
     #[test]
     fn test_apply_replace() {
         let content = "# Title\n\nOld content.\n\nOther text.\n";
@@ -276,7 +2265,7 @@ mod tests {
             block_type: crate::parser::BlockType::Paragraph,
         };
         
-        let result = apply_replace(content, &block, Some("New content.")).unwrap();
+        let result = apply_replace(content, &block, "# Title", Some("New content.")).unwrap();
         assert!(result.contains("New content."));
         assert!(!result.contains("Old content."));
     }
@@ -291,8 +2280,830 @@ mod tests {
             block_type: crate::parser::BlockType::Paragraph,
         };
         
-        let result = apply_delete(content, &block).unwrap();
+        let result = apply_delete(content, block.start, block.end).unwrap();
         assert!(!result.contains("Delete me."));
         assert!(result.contains("Keep me."));
     }
+
+    #[test]
+    fn test_fingerprint_ignores_comments_in_match_mode() {
+        let content = "# Title\n\nPublic <!-- internal\nnote --> text\n";
+        let mut op = operation("# Title", 0, Operation::Replace, Some("Updated"));
+        op.fingerprint = Some(r"^Public\s+text$".to_string());
+
+        // 默认模式下注释参与匹配
+        assert!(apply_operation(content, &op, true).is_err());
+
+        op.strip_comments = CommentMode::Match;
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(new_content, "# Title\n\nUpdated\n");
+    }
+
+    #[test]
+    fn test_strip_comments_from_output() {
+        let content = "# Title\n\n<!-- internal -->\nKeep me.\n";
+        let mut op = operation("# Title", 0, Operation::Append, Some("\nAdded."));
+        op.strip_comments = CommentMode::All;
+
+        let PatchResult::Applied { new_content, diff, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert!(!new_content.contains("internal"));
+        assert!(new_content.contains("Keep me."));
+        assert!(diff.contains("-<!-- internal -->"));
+    }
+
+    #[test]
+    fn test_append_after_heading() {
+        let content = "# Title\n\nFirst.\n\n## Empty\n\n## Last\n";
+        let mut op = operation("# Title", 0, Operation::Append, Some("> Note"));
+        op.after_heading = true;
+
+        let result = apply_operation(content, &op, true).unwrap();
+        let PatchResult::Applied { new_content, new_block_index, .. } = result else {
+            panic!("expected applied result");
+        };
+        assert_eq!(new_content, "# Title\n\n> Note\n\nFirst.\n\n## Empty\n\n## Last\n");
+        assert_eq!(new_block_index, Some(0));
+
+        // 幂等
+        let PatchResult::Applied { is_noop, .. } = apply_operation(&new_content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert!(is_noop);
+
+        // 空 section（中间与末尾）
+        op.heading_path = vec!["## Empty".to_string()];
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(new_content, "# Title\n\nFirst.\n\n## Empty\n\n> Note\n\n## Last\n");
+
+        op.heading_path = vec!["## Last".to_string()];
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(new_content, "# Title\n\nFirst.\n\n## Empty\n\n## Last\n\n> Note\n");
+
+        // 首个 block 仅以该内容开头时不算已存在
+        let mut op = operation("# Title", 0, Operation::Append, Some("Note"));
+        op.after_heading = true;
+        let PatchResult::Applied { new_content, is_noop, .. } =
+            apply_operation("# Title\n\nNotes follow below.\n", &op, true).unwrap()
+        else {
+            panic!("expected applied result");
+        };
+        assert!(!is_noop);
+        assert_eq!(new_content, "# Title\n\nNote\n\nNotes follow below.\n");
+    }
+
+    #[test]
+    fn test_after_heading_starts_on_fresh_line() {
+        // heading_end 不含换行：插入内容必须从新的一行开始，不能粘在 heading 上
+        let mut op = operation("## API", 0, Operation::Append, Some("Inserted"));
+        op.after_heading = true;
+
+        for (content, expected) in [
+            ("# Doc\n\n## API\ntext\n", "# Doc\n\n## API\n\nInserted\n\ntext\n"),
+            ("# Doc\n\n## API", "# Doc\n\n## API\n\nInserted"),
+            ("# Doc\n\n## API\n", "# Doc\n\n## API\n\nInserted\n"),
+        ] {
+            let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+                panic!("expected applied result");
+            };
+            assert_eq!(new_content, expected);
+            assert!(new_content.lines().any(|line| line == "Inserted"), "{:?}", new_content);
+        }
+    }
+
+    #[test]
+    fn test_replace_body() {
+        let content = "# Doc\n\n## API\n\nOld intro.\n\n| a | b |\n|---|---|\n\n### Old sub\n\nGone.\n\n## Next\n\nKeep me.\n";
+        let op = operation("## API", 0, Operation::ReplaceBody, Some("| x |\n|---|\n"));
+
+        // 未授权时拒绝
+        assert!(apply_operation(content, &op, false).is_err());
+
+        let PatchResult::Applied { new_content, is_noop, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert!(!is_noop);
+        assert_eq!(new_content, "# Doc\n\n## API\n\n| x |\n|---|\n\n## Next\n\nKeep me.\n");
+
+        // 幂等
+        let PatchResult::Applied { is_noop, .. } = apply_operation(&new_content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert!(is_noop);
+
+        // fingerprint 作用于整个正文
+        let mut op = operation("## Next", 0, Operation::ReplaceBody, Some("Replaced."));
+        op.fingerprint = Some("Old intro".to_string());
+        assert!(apply_operation(content, &op, false).is_err());
+        op.fingerprint = Some("Keep me".to_string());
+        let PatchResult::DryRun { diff, .. } = apply_operation(content, &op, false).unwrap() else {
+            panic!("expected dry run");
+        };
+        assert!(diff.contains("+Replaced."));
+    }
+
+    #[test]
+    fn test_append_idempotency_modes() {
+        let content = "# Title\n\nRun the tests before release.\n";
+        let block = parse_sections(content).unwrap()[0].blocks[0].clone();
+        let append = |mode| apply_append(content, &block, Some("the tests"), 1, mode, Flavor::Markdown).unwrap();
+
+        // contains 会因子串误判为已存在
+        assert_eq!(append(Idempotency::Contains), content);
+        // exact 只认独立的 block
+        let appended = append(Idempotency::Exact);
+        assert_eq!(appended, "# Title\n\nRun the tests before release.\n\nthe tests\n");
+        assert_eq!(
+            apply_append(&appended, &block, Some("the tests"), 1, Idempotency::Exact, Flavor::Markdown).unwrap(),
+            appended
+        );
+        // off 总是追加
+        let twice = apply_append(&appended, &block, Some("the tests"), 1, Idempotency::Off, Flavor::Markdown).unwrap();
+        assert_eq!(twice.matches("the tests").count(), 3);
+
+        // 紧凑追加的列表项并入列表后仍视为已存在
+        let list = "# Title\n\n- a\n- b\n";
+        let block = parse_sections(list).unwrap()[0].blocks[0].clone();
+        let tight = apply_append(list, &block, Some("- c"), 0, Idempotency::Exact, Flavor::Markdown).unwrap();
+        let block = parse_sections(&tight).unwrap()[0].blocks[0].clone();
+        assert_eq!(apply_append(&tight, &block, Some("- c"), 0, Idempotency::Exact, Flavor::Markdown).unwrap(), tight);
+    }
+
+    #[test]
+    fn test_apply_unified_diff_round_trip() {
+        let original = "# Doc\n\nOne\n\nTwo\n\nThree\n\nFour\n\nFive\n\nSix\n\nSeven";
+        let modified = "# Doc\n\nUno\n\nTwo\n\nThree\n\nFour\n\nFive\n\nSix\n\nSeven\n\n--- added\n";
+        let diff = generate_diff(original, modified, "doc.md");
+
+        assert_eq!(apply_unified_diff(original, &diff, "doc.md", false).unwrap(), modified);
+        assert_eq!(apply_unified_diff(modified, &diff, "doc.md", true).unwrap(), original);
+
+        // 内容已变化时拒绝应用
+        assert!(apply_unified_diff("# Doc\n\nChanged\n", &diff, "doc.md", false).is_err());
+        // 无改动的 diff 只有文件头
+        let empty = generate_diff(original, original, "doc.md");
+        assert_eq!(apply_unified_diff(original, &empty, "doc.md", true).unwrap(), original);
+    }
+
+    #[test]
+    fn test_generate_diff_hunks() {
+        let original = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12";
+        let modified = "1\nTWO\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n";
+        let diff = generate_diff(original, modified, "doc.md");
+        assert_eq!(
+            diff,
+            "--- a/doc.md\n+++ b/doc.md\n\
+             @@ -1,5 +1,5 @@\n 1\n-2\n+TWO\n 3\n 4\n 5\n\
+             @@ -9,4 +9,4 @@\n 9\n 10\n 11\n-12\n\\ No newline at end of file\n+12\n"
+        );
+    }
+
+    #[test]
+    fn test_replace_paragraph_followed_by_heading() {
+        let content = "# Title\n\nOld text\n## Next\n\nBody\n";
+        let block = parse_sections(content).unwrap()[0].blocks[0].clone();
+        assert_eq!(&content[block.end..block.end + 1], "\n");
+
+        for replacement in ["New text", "New text\n", "New text\n\n"] {
+            let result = apply_replace(content, &block, "# Title", Some(replacement)).unwrap();
+            assert_eq!(result, "# Title\n\nNew text\n## Next\n\nBody\n");
+            assert_eq!(parse_sections(&result).unwrap()[1].heading, "## Next");
+        }
+
+        // 文档末尾没有换行的 block
+        let content = "# Title\n\nOld text";
+        let block = parse_sections(content).unwrap()[0].blocks[0].clone();
+        assert_eq!(apply_replace(content, &block, "# Title", Some("New\n")).unwrap(), "# Title\n\nNew");
+    }
+
+    #[test]
+    fn test_dedent() {
+        assert_eq!(dedent("    def f():\n        pass\n  \n    f()\n"), "def f():\n    pass\n\nf()\n");
+        // 没有共同缩进时保持不变
+        assert_eq!(dedent("a\n  b"), "a\n  b");
+        // 制表符与空格不混为共同前缀
+        assert_eq!(dedent("\tx\n    y"), "\tx\n    y");
+    }
+
+    #[test]
+    fn test_dedup_section() {
+        let content = "# Notes\n\nSame.\n\nOther.\n\nSame.\n\n- a\n\nSame.\n\n# Next\n\nSame.\n";
+        let op = operation("# Notes", 0, Operation::Dedup, None);
+
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        // 仅在目标 section 内去重，保留第一次出现及不同的 block
+        assert_eq!(new_content, "# Notes\n\nSame.\n\nOther.\n\n- a\n\n# Next\n\nSame.\n");
+
+        // 破坏性操作：未 force 时拒绝；无重复时为 noop
+        assert!(apply_operation(content, &op, false).is_err());
+        let PatchResult::DryRun { is_noop, .. } = apply_operation(&new_content, &op, false).unwrap() else {
+            panic!("expected dry run");
+        };
+        assert!(is_noop);
+    }
+
+    #[test]
+    fn test_merge_adjacent_blocks() {
+        let content = "# Notes\n\nFirst part.\n\nSecond part.\n\n```sh\na\n```\n\n```sh\nb\n```\n\n```py\nc\n```\n";
+        let op = operation("# Notes", 0, Operation::Merge, None);
+
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        // 不同语言的代码块保持独立
+        assert_eq!(
+            new_content,
+            "# Notes\n\nFirst part.\nSecond part.\n\n```sh\na\nb\n```\n\n```py\nc\n```\n"
+        );
+
+        assert!(apply_operation(content, &op, false).is_err());
+        let PatchResult::DryRun { is_noop, .. } = apply_operation(&new_content, &op, false).unwrap() else {
+            panic!("expected dry run");
+        };
+        assert!(is_noop);
+    }
+
+    #[test]
+    fn test_ensure_replaces_or_appends() {
+        let content = "# Status\n\nIntro.\n\nBuild: failing\n\n## Next\n";
+        let mut op = operation("# Status", 0, Operation::Ensure, Some("Build: passing"));
+        op.fingerprint = Some("^Build:".to_string());
+
+        let PatchResult::Applied { new_content, new_block_index, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(new_content, "# Status\n\nIntro.\n\nBuild: passing\n\n## Next\n");
+        assert_eq!(new_block_index, Some(1));
+
+        // 不存在匹配 block 时追加到 section 末尾，再次执行为 noop
+        let content = "# Status\n\nIntro.\n\n## Next\n";
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(new_content, "# Status\n\nIntro.\n\nBuild: passing\n\n## Next\n");
+        let PatchResult::DryRun { is_noop, .. } = apply_operation(&new_content, &op, false).unwrap() else {
+            panic!("expected dry run");
+        };
+        assert!(is_noop);
+
+        op.content = Some("Tests: passing".to_string());
+        assert!(apply_operation(content, &op, true).is_err());
+    }
+
+    #[test]
+    fn test_insert_sorted_into_list_middle() {
+        let content = "# Glossary\n\n1. Apple\n2. Cherry\n3. Date\n";
+        let op = operation("# Glossary", 0, Operation::InsertSorted, Some("Banana"));
+
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(new_content, "# Glossary\n\n1. Apple\n2. Banana\n3. Cherry\n4. Date\n");
+
+        let PatchResult::DryRun { is_noop, .. } = apply_operation(&new_content, &op, false).unwrap() else {
+            panic!("expected dry run");
+        };
+        assert!(is_noop);
+    }
+
+    #[test]
+    fn test_insert_sorted_among_sibling_blocks() {
+        let content = "# Refs\n\nIntro.\n\n[alpha]: a\n\n[gamma]: g\n";
+        let op = operation("# Refs", 1, Operation::InsertSorted, Some("[beta]: b"));
+
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(new_content, "# Refs\n\nIntro.\n\n[alpha]: a\n\n[beta]: b\n\n[gamma]: g\n");
+    }
+
+    #[test]
+    fn test_merge_append_updates_near_duplicate() {
+        let content = "# Release\n\nVersion 1.2 adds streaming output and a progress bar.\n\n## Next\n";
+        let mut op = operation(
+            "# Release",
+            0,
+            Operation::Append,
+            Some("Version 1.2 adds streaming output, a progress bar and merge."),
+        );
+        op.merge_append = Some(0.6);
+
+        let PatchResult::Applied { new_content, warnings, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(
+            new_content,
+            "# Release\n\nVersion 1.2 adds streaming output, a progress bar and merge.\n\n## Next\n"
+        );
+        assert!(warnings[0].starts_with("Merge-append: updated the similar block at line 3"), "{:?}", warnings);
+
+        // 明显不同的内容照常追加
+        op.content = Some("Thanks to all contributors.".to_string());
+        let PatchResult::Applied { new_content, warnings, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert!(new_content.contains("progress bar.\n\nThanks to all contributors.\n"));
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_convert_paragraph_to_list_and_back() {
+        let content = "# Todo\n\n• Write docs\n• Ship it\n";
+        let mut op = operation("# Todo", 0, Operation::Convert, None);
+        op.convert_to = Some(ConvertTarget::List);
+
+        assert!(apply_operation(content, &op, false).is_err());
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(new_content, "# Todo\n\n- Write docs\n- Ship it\n");
+
+        op.convert_to = Some(ConvertTarget::Paragraph);
+        let PatchResult::Applied { new_content, .. } = apply_operation(&new_content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(new_content, "# Todo\n\nWrite docs\nShip it\n");
+
+        // 已是目标类型时为 noop
+        let PatchResult::DryRun { is_noop, .. } = apply_operation(&new_content, &op, false).unwrap() else {
+            panic!("expected dry run");
+        };
+        assert!(is_noop);
+    }
+
+    #[test]
+    fn test_append_line_if_absent() {
+        let content = "# Platforms\n\n- Linux (x86_64)\n- macOS (arm64)\n";
+        let mut op = operation("# Platforms", 0, Operation::AppendLineIfAbsent, Some("- FreeBSD (x86_64)"));
+        op.line_key = Some(r"^- FreeBSD\b".to_string());
+
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(new_content, "# Platforms\n\n- Linux (x86_64)\n- macOS (arm64)\n- FreeBSD (x86_64)\n");
+
+        // 已有匹配 key 的行（即使内容不同）时为 noop
+        op.content = Some("- FreeBSD (arm64)".to_string());
+        let PatchResult::DryRun { is_noop, noop_reason, .. } = apply_operation(&new_content, &op, false).unwrap() else {
+            panic!("expected dry run");
+        };
+        assert!(is_noop);
+        assert_eq!(noop_reason.as_deref(), Some("append-line-if-absent skipped: line 5 already matches"));
+
+        // 列表沿用自身的标记；重跑时按渲染后的行匹配 key
+        let content = "# Platforms\n\n* Linux\n* macOS\n";
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(new_content, "# Platforms\n\n* Linux\n* macOS\n* FreeBSD (arm64)\n");
+        let PatchResult::DryRun { is_noop, noop_reason, .. } = apply_operation(&new_content, &op, false).unwrap() else {
+            panic!("expected dry run");
+        };
+        assert!(is_noop);
+        assert_eq!(noop_reason.as_deref(), Some("append-line-if-absent skipped: line 5 already matches"));
+    }
+
+    #[test]
+    fn test_definition_append_is_idempotent() {
+        let content = "# Glossary\n\nBlock\n: A unit of content.\n\nNext paragraph.\n";
+        let entry = "Section\n: A heading and its blocks.";
+        let op = operation("# Glossary", 0, Operation::DefinitionAppend, Some(entry));
+
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(
+            new_content,
+            "# Glossary\n\nBlock\n: A unit of content.\nSection\n: A heading and its blocks.\n\nNext paragraph.\n"
+        );
+
+        let PatchResult::DryRun { is_noop, noop_reason, .. } = apply_operation(&new_content, &op, false).unwrap() else {
+            panic!("expected dry run");
+        };
+        assert!(is_noop);
+        assert_eq!(
+            noop_reason.as_deref(),
+            Some("definition-append skipped: 'Section' is already defined at line 5")
+        );
+
+        // 同名术语、不同定义时报错
+        let conflicting = operation("# Glossary", 0, Operation::DefinitionAppend, Some("Block\n: Something else."));
+        assert!(apply_operation(&new_content, &conflicting, true).is_err());
+    }
+
+    #[test]
+    fn test_region_confines_operation() {
+        let content = "# Notes\n\nHand-written.\n\n<!-- mdp:begin gen -->\n- one\n<!-- mdp:end gen -->\n\nFooter.\n";
+        let mut op = operation("# Notes", 0, Operation::ListAppend, Some("- two"));
+        op.region = Some("gen".to_string());
+
+        // 索引相对于区域：block 0 是区域内的列表
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(
+            new_content,
+            "# Notes\n\nHand-written.\n\n<!-- mdp:begin gen -->\n- one\n- two\n<!-- mdp:end gen -->\n\nFooter.\n"
+        );
+
+        // 区域外的 block 无法定位
+        op.block_index = 1;
+        assert!(apply_operation(content, &op, true).is_err());
+
+        // replace-body 截断到区域之内
+        let mut body = operation("# Notes", 0, Operation::ReplaceBody, Some("- fresh"));
+        body.region = Some("gen".to_string());
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &body, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert!(new_content.starts_with("# Notes\n\nHand-written.\n\n<!-- mdp:begin gen -->\n- fresh\n"));
+        assert!(new_content.ends_with("<!-- mdp:end gen -->\n\nFooter.\n"));
+
+        // 能定位但改动越出区域（区域外的 heading 本身）时拒绝
+        let mut heading = operation("# Notes", 0, Operation::Replace, Some("# Renamed"));
+        heading.heading_block = true;
+        heading.region = Some("gen".to_string());
+        let err = apply_operation(content, &heading, true).err().unwrap().to_string();
+        assert!(err.contains("Edit outside region 'gen'"), "{}", err);
+    }
+
+    #[test]
+    fn test_region_edit_with_multibyte_characters() {
+        // 公共前缀止于 "é" 与 "è" 的第二个字节之前：不能在字符中间切分
+        let content = "<!-- mdp:begin gen -->\n# Café\n\nText.\n<!-- mdp:end gen -->\n";
+        let mut op = operation("# Café", 0, Operation::Replace, Some("# Cafè"));
+        op.heading_block = true;
+        op.region = Some("gen".to_string());
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(new_content, "<!-- mdp:begin gen -->\n# Cafè\n\nText.\n<!-- mdp:end gen -->\n");
+
+        assert_eq!(changed_span("aé", "aè"), (1, 3));
+        assert_eq!(changed_span("éa", "èa"), (0, 2));
+    }
+
+    #[test]
+    fn test_fingerprint_ignore_case() {
+        let content = "# TODO list\n\nTODO: write docs\n";
+        let mut op = operation("# TODO list", 0, Operation::Replace, Some("Done: write docs"));
+        op.fingerprint = Some("^todo:".to_string());
+        assert!(apply_operation(content, &op, false).is_err());
+
+        op.fingerprint_ignore_case = true;
+        let PatchResult::DryRun { is_noop, .. } = apply_operation(content, &op, false).unwrap() else {
+            panic!("expected dry run");
+        };
+        assert!(!is_noop);
+
+        // heading fingerprint 同样适用
+        let mut heading = operation("# TODO list", 0, Operation::Replace, Some("# Done list"));
+        heading.heading_block = true;
+        heading.fingerprint = Some("todo list".to_string());
+        assert!(apply_operation(content, &heading, false).is_err());
+        heading.fingerprint_ignore_case = true;
+        assert!(apply_operation(content, &heading, false).is_ok());
+    }
+
+    #[test]
+    fn test_trim_trailing_section() {
+        let content = "# Doc \n\nIntro.\t\n\n## A\n\nText.\t\n\n```\ncode  \n```\n\n## B\n\nKeep. \n";
+        let op = operation("## A", 0, Operation::TrimTrailing, None);
+        let PatchResult::Applied { new_content, is_noop, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied");
+        };
+        assert!(!is_noop);
+        // 只处理目标 section；代码块内的空白保留
+        assert_eq!(
+            new_content,
+            "# Doc \n\nIntro.\t\n\n## A\n\nText.\n\n```\ncode  \n```\n\n## B\n\nKeep. \n"
+        );
+
+        let PatchResult::Applied { is_noop, noop_reason, .. } = apply_operation(&new_content, &op, true).unwrap() else {
+            panic!("expected applied");
+        };
+        assert!(is_noop);
+        assert_eq!(noop_reason.as_deref(), Some("trim-trailing skipped: no line under '## A' ends with whitespace"));
+
+        let mut whole = operation("", 0, Operation::TrimTrailing, None);
+        whole.heading_path = Vec::new();
+        whole.whole_file = true;
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &whole, true).unwrap() else {
+            panic!("expected applied");
+        };
+        assert_eq!(
+            new_content,
+            "# Doc\n\nIntro.\n\n## A\n\nText.\n\n```\ncode  \n```\n\n## B\n\nKeep.\n"
+        );
+
+        // 硬换行（两个及以上空格）保留；`~~~` 代码块内的空白同样保留
+        let content = "# Doc\n\nLine one  \nLine two \n  \n~~~\ncode \t\n~~~\n";
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &whole, true).unwrap() else {
+            panic!("expected applied");
+        };
+        assert_eq!(new_content, "# Doc\n\nLine one  \nLine two\n\n~~~\ncode \t\n~~~\n");
+    }
+
+    #[test]
+    fn test_delete_block_range() {
+        let content = "# Doc\n\n## A\n\nZero.\n\nOne.\n\n- two\n\nThree.\n\nFour.\n\n## B\n\nSibling.\n";
+        let mut op = operation("## A", 0, Operation::Delete, None);
+        op.block_range = Some((1, 3));
+
+        // 未授权时只返回预览
+        let err = apply_operation(content, &op, false).err().unwrap();
+        assert!(err.downcast_ref::<BlockedOperation>().is_some());
+
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied");
+        };
+        assert_eq!(new_content, "# Doc\n\n## A\n\nZero.\n\nFour.\n\n## B\n\nSibling.\n");
+
+        op.block_range = Some((3, 5));
+        let err = apply_operation(content, &op, true).err().unwrap().to_string();
+        assert!(err.contains("Block index 5 out of range"), "{}", err);
+        op.block_range = Some((3, 1));
+        assert!(apply_operation(content, &op, true).is_err());
+    }
+
+    #[test]
+    fn test_append_at_eof_ends_with_single_newline() {
+        let op = operation("# T", 0, Operation::Append, Some("Added\n"));
+        for content in ["# T\n\nText\n", "# T\n\nText\n\n\n", "# T\n\nText"] {
+            let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+                panic!("expected applied");
+            };
+            assert_eq!(new_content, "# T\n\nText\n\nAdded\n", "{:?}", content);
+        }
+
+        // 不在文件末尾时保持原有的后续内容
+        let content = "# T\n\nText\n\n## Next\n";
+        let op = operation("# T", 0, Operation::Append, Some("Added"));
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied");
+        };
+        assert_eq!(new_content, "# T\n\nText\n\nAdded\n\n## Next\n");
+
+        // 行尾的硬换行（两个空格）保留
+        let op = operation("# T", 0, Operation::Append, Some("Line one  \nLine two  \n"));
+        let PatchResult::Applied { new_content, .. } = apply_operation("# T\n\nText\n", &op, true).unwrap() else {
+            panic!("expected applied");
+        };
+        assert_eq!(new_content, "# T\n\nText\n\nLine one  \nLine two  \n");
+    }
+
+    #[test]
+    fn test_append_match_style() {
+        let mut op = operation("# Todo", 0, Operation::Append, Some("Write docs"));
+        op.match_style = true;
+        let applied = |content: &str, op: &PatchOperation| match apply_operation(content, op, true).unwrap() {
+            PatchResult::Applied { new_content, .. } => new_content,
+            PatchResult::DryRun { .. } => panic!("expected applied"),
+        };
+
+        let content = "# Todo\n\n* Fix bug\n* Ship\n";
+        let new_content = applied(content, &op);
+        assert_eq!(new_content, "# Todo\n\n* Fix bug\n* Ship\n* Write docs\n");
+        // 幂等
+        assert_eq!(applied(&new_content, &op), new_content);
+
+        // 有序列表续编号
+        let content = "# Todo\n\n3. Fix bug\n4. Ship\n\nAfter.\n";
+        assert_eq!(applied(content, &op), "# Todo\n\n3. Fix bug\n4. Ship\n5. Write docs\n\nAfter.\n");
+
+        let content = "# Todo\n\n> Quoted.\n";
+        assert_eq!(applied(content, &op), "# Todo\n\n> Quoted.\n\n> Write docs\n");
+
+        // 未设置时仍是普通段落
+        op.match_style = false;
+        assert_eq!(applied("# Todo\n\n- Ship\n", &op), "# Todo\n\n- Ship\n\nWrite docs\n");
+    }
+
+    #[test]
+    fn test_coarse_blocks_replace_whole_prose() {
+        let content = "# Doc\n\nFirst para.\n\nSecond para.\n\n| a |\n|---|\n| 1 |\n";
+        let mut op = operation("# Doc", 0, Operation::Replace, Some("Only para."));
+        op.coarse_blocks = true;
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(new_content, "# Doc\n\nOnly para.\n\n| a |\n|---|\n| 1 |\n");
+    }
+
+    #[test]
+    fn test_verify_structure() {
+        let content = "# Doc\n\n## Notes\n\nFirst.\n\n## Next\n\nMore.\n";
+        let mut op = operation("## Notes", 0, Operation::Append, Some("# Heading-like line"));
+        op.verify_structure = true;
+        let err = apply_operation(content, &op, true).err().unwrap();
+        assert!(err.to_string().contains("Structure verification failed"), "{}", err);
+
+        op.content = Some("Second.".to_string());
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert!(new_content.contains("First.\n\nSecond.\n\n## Next"));
+    }
+
+    #[test]
+    fn test_literal_content_escapes_headings() {
+        let content = "# Title\n\nIntro.\n";
+        let mut op = operation("# Title", 0, Operation::Append, Some("# 1 is the answer\n\n```sh\n# keep\n```"));
+        op.literal_content = true;
+
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(new_content, "# Title\n\nIntro.\n\n\\# 1 is the answer\n\n```sh\n# keep\n```\n");
+        assert_eq!(parse_sections_with(&new_content, Flavor::Markdown).unwrap().len(), 1);
+
+        // 再次执行保持幂等
+        let PatchResult::Applied { is_noop, .. } = apply_operation(&new_content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert!(is_noop);
+    }
+
+    #[test]
+    fn test_heading_block_fingerprint_and_replace() {
+        let content = "# Doc\n\n## Old Name\n\nBody\n";
+        let mut op = operation("## Old Name", 0, Operation::Replace, Some("## New Name"));
+        op.heading_block = true;
+        op.fingerprint = Some("^## Old".to_string());
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(new_content, "# Doc\n\n## New Name\n\nBody\n");
+
+        // fingerprint 针对 heading 行本身进行匹配
+        op.fingerprint = Some("Body".to_string());
+        let err = apply_operation(content, &op, true).err().unwrap();
+        assert!(err.to_string().contains("Fingerprint mismatch"), "{}", err);
+
+        op.fingerprint = None;
+        op.operation = Operation::Append;
+        assert!(apply_operation(content, &op, true).is_err());
+    }
+
+    #[test]
+    fn test_fuzzy_heading_match_warns() {
+        let content = "# Doc\n\n## API Usage\n\nOld\n";
+        let mut op = operation("## api  usage", 0, Operation::Replace, Some("New"));
+        // 未显式启用时只提示，不改写
+        let err = apply_operation(content, &op, true).err().unwrap().to_string();
+        assert!(err.contains("did you mean '## API Usage'?"), "{}", err);
+
+        op.fuzzy_headings = true;
+        let PatchResult::Applied { new_content, warnings, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(new_content, "# Doc\n\n## API Usage\n\nNew\n");
+        assert_eq!(warnings, vec!["Fuzzy heading match: '## api  usage' resolved to '## API Usage'"]);
+
+        op.heading_path = vec!["## API Usage".to_string()];
+        let PatchResult::Applied { warnings, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_noop_reason() {
+        let content = "# Title\n\nIntro.\n\nAlready here.\n";
+        let op = operation("# Title", 0, Operation::Append, Some("Already here."));
+        let PatchResult::DryRun { is_noop, noop_reason, .. } = apply_operation(content, &op, false).unwrap() else {
+            panic!("expected dry run");
+        };
+        assert!(is_noop);
+        assert_eq!(noop_reason.as_deref(), Some("append skipped: content already present at line 5"));
+
+        let op = operation("# Title", 0, Operation::Replace, Some("Intro."));
+        let PatchResult::Applied { noop_reason, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(noop_reason.as_deref(), Some("replace skipped: block at line 3 already has this content"));
+
+        // 有改动时不给出原因
+        let op = operation("# Title", 0, Operation::Append, Some("New."));
+        let PatchResult::DryRun { noop_reason, .. } = apply_operation(content, &op, false).unwrap() else {
+            panic!("expected dry run");
+        };
+        assert!(noop_reason.is_none());
+    }
+
+    #[test]
+    fn test_replace_template_keeps_block() {
+        let content = "# Title\n\n## API\n\nOld text.\n";
+        let op = operation("## API", 0, Operation::Replace, Some("NOTE: {{heading}} updated\n\n{{block}}"));
+
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(new_content, "# Title\n\n## API\n\nNOTE: API updated\n\nOld text.\n");
+
+        // block 中的占位符文本不会被再次展开
+        let content = "# Title\n\n## API\n\nUse {{heading}} in templates.\n";
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(new_content, "# Title\n\n## API\n\nNOTE: API updated\n\nUse {{heading}} in templates.\n");
+    }
+
+    #[test]
+    fn test_toc_insert_and_refresh() {
+        let content = "# Guide\n\nIntro.\n\n## Setup\n\nA\n\n### Linux\n\nB\n";
+        let mut op = operation("# Guide", 0, Operation::Toc, None);
+        op.toc_depth = 2;
+
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert!(new_content.starts_with(
+            "# Guide\n\nIntro.\n\n<!-- mdp:toc -->\n- [Guide](#guide)\n  - [Setup](#setup)\n<!-- mdp:/toc -->\n\n## Setup"
+        ));
+
+        // 重复运行为 noop；新增 heading 后原地更新
+        let PatchResult::Applied { is_noop, .. } = apply_operation(&new_content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert!(is_noop);
+
+        let extended = format!("{}\n## Setup\n\nAgain\n", new_content);
+        let PatchResult::Applied { new_content, .. } = apply_operation(&extended, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert!(new_content.contains("  - [Setup](#setup)\n  - [Setup](#setup-1)\n<!-- mdp:/toc -->"));
+        assert_eq!(new_content.matches("<!-- mdp:toc -->").count(), 1);
+    }
+
+    #[test]
+    fn test_list_append_renumbers() {
+        let content = "# Steps\n\n1. Install\n3. Configure\n3. Run\n\nDone.\n";
+        let op = operation("# Steps", 0, Operation::ListAppend, Some("7. Verify"));
+
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(new_content, "# Steps\n\n1. Install\n2. Configure\n3. Run\n4. Verify\n\nDone.\n");
+
+        // 幂等：条目已存在
+        let PatchResult::Applied { is_noop, .. } = apply_operation(&new_content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert!(is_noop);
+
+        // 无序列表沿用最后一个标记；非列表 block 报错
+        let content = "# Todo\n\n* a\n* b\n";
+        let op = operation("# Todo", 0, Operation::ListAppend, Some("c"));
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(new_content, "# Todo\n\n* a\n* b\n* c\n");
+        assert!(apply_operation("# T\n\nText\n", &operation("# T", 0, Operation::ListAppend, Some("x")), true).is_err());
+    }
+
+    #[test]
+    fn test_noop_on_missing_content() {
+        let content = "# Title\n\nBody\n";
+        for (op, text) in [(Operation::Append, Some("  \n")), (Operation::Replace, None)] {
+            let mut op = operation("# Title", 0, op, text);
+            op.noop_on_missing_content = true;
+            let PatchResult::Applied { new_content, is_noop, .. } = apply_operation(content, &op, true).unwrap() else {
+                panic!("expected applied result");
+            };
+            assert!(is_noop);
+            assert_eq!(new_content, content);
+        }
+    }
+
+    #[test]
+    fn test_hard_line_breaks_preserved() {
+        let content = "# Title\n\nFirst line  \nsecond line\n\nOther  \nparagraph\n";
+        let sections = parse_sections(content).unwrap();
+        assert_eq!(sections[0].blocks[0].content, "First line  \nsecond line");
+
+        // 替换为带硬换行的内容
+        let op = operation("# Title", 0, Operation::Replace, Some("New line  \nwith break  \n"));
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert_eq!(new_content, "# Title\n\nNew line  \nwith break  \n\nOther  \nparagraph\n");
+
+        // 其他操作不改写相邻 block 中的硬换行
+        let op = operation("# Title", 0, Operation::Append, Some("Tail  \nend"));
+        let PatchResult::Applied { new_content, .. } = apply_operation(content, &op, true).unwrap() else {
+            panic!("expected applied result");
+        };
+        assert!(new_content.contains("First line  \nsecond line"));
+        assert!(new_content.contains("Tail  \nend\n\nOther  \nparagraph"));
+    }
 }
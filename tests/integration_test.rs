@@ -3,8 +3,9 @@
 //! 运行: cargo test --test integration_test
 
 use std::fs;
-use std::path::PathBuf;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::io::Write;
+use std::process::{Command, Stdio};
 
 /// 获取 mdp 二进制路径
 fn mdp_bin() -> PathBuf {
@@ -29,6 +30,11 @@ fn mdp_bin() -> PathBuf {
     path
 }
 
+/// 文件名后追加后缀得到的路径（备份为 `<file>.bak`，临时文件为 `<file>.tmp`）
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    PathBuf::from(format!("{}{}", path.display(), suffix))
+}
+
 /// 创建临时 markdown 文件（使用线程安全的唯一名称）
 fn create_test_file(content: &str) -> PathBuf {
     use std::sync::atomic::{AtomicU64, Ordering};
@@ -56,7 +62,7 @@ fn run_mdp(args: &[&str]) -> (i32, String, String) {
     let output = Command::new(&bin)
         .args(args)
         .output()
-        .expect(&format!("Failed to execute {:?}", bin));
+        .unwrap_or_else(|_| panic!("Failed to execute {:?}", bin));
     
     let exit_code = output.status.code().unwrap_or(-1);
     let stdout = String::from_utf8_lossy(&output.stdout).to_string();
@@ -65,6 +71,22 @@ fn run_mdp(args: &[&str]) -> (i32, String, String) {
     (exit_code, stdout, stderr)
 }
 
+/// 运行 mdp 命令并设置额外的环境变量，返回 (exit_code, stdout, stderr)
+fn run_mdp_with_env(args: &[&str], envs: &[(&str, &str)]) -> (i32, String, String) {
+    let bin = mdp_bin();
+    let output = Command::new(&bin)
+        .args(args)
+        .envs(envs.iter().copied())
+        .output()
+        .unwrap_or_else(|_| panic!("Failed to execute {:?}", bin));
+
+    let exit_code = output.status.code().unwrap_or(-1);
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+
+    (exit_code, stdout, stderr)
+}
+
 // ============================================================================
 // 测试：幂等性 (关键特性)
 // ============================================================================
@@ -117,7 +139,7 @@ fn test_backup_created_on_destructive_operation() {
     let content = "# Doc\n\n## Section\n\nOriginal\n";
     let file_path = create_test_file(content);
     let file_str = file_path.to_str().unwrap();
-    let backup_path = file_path.with_extension("bak");
+    let backup_path = with_suffix(&file_path, ".bak");
     
     // 执行 replace 操作
     let (code, _, _) = run_mdp(&[
@@ -148,7 +170,7 @@ fn test_no_backup_with_flag() {
     let content = "# Doc\n\n## Section\n\nOriginal\n";
     let file_path = create_test_file(content);
     let file_str = file_path.to_str().unwrap();
-    let backup_path = file_path.with_extension("bak");
+    let backup_path = with_suffix(&file_path, ".bak");
     
     // 使用 --no-backup
     let (code, _, _) = run_mdp(&[
@@ -212,7 +234,7 @@ fn test_noop_detection_in_json_output() {
     
     // 清理
     let _ = fs::remove_file(&file_path);
-    let _ = fs::remove_file(file_path.with_extension("bak"));
+    let _ = fs::remove_file(with_suffix(&file_path, ".bak"));
 }
 
 // ============================================================================
@@ -386,7 +408,7 @@ fn test_atomic_replace() {
     assert!(!result.contains("Original"));
     
     // 检查没有遗留的临时文件
-    let temp_file = file_path.with_extension("md.tmp");
+    let temp_file = with_suffix(&file_path, ".tmp");
     assert!(!temp_file.exists(), "Temp file should be cleaned up");
     
     // 清理
@@ -424,3 +446,2814 @@ fn test_json_output() {
     // 清理
     let _ = fs::remove_file(&file_path);
 }
+
+#[test]
+fn test_json_reports_new_block_index() {
+    let content = "# Doc\n\n## IndexSection\n\nFirst\n\nSecond\n";
+    let file_path = create_test_file(content);
+    let file_str = file_path.to_str().unwrap();
+
+    let (code, stdout, _) = run_mdp(&[
+        "patch",
+        "-f", file_str,
+        "-H", "## IndexSection",
+        "-i", "1",
+        "--op", "append",
+        "-c", "Third",
+        "--force",
+        "--no-backup",
+        "-F", "json"
+    ]);
+
+    assert_eq!(code, 0);
+    assert!(stdout.contains("\"new_block_index\": 2"), "JSON should report the new block index: {}", stdout);
+
+    // 使用报告的 index 继续定位新 block
+    let (code2, _, _) = run_mdp(&[
+        "patch",
+        "-f", file_str,
+        "-H", "## IndexSection",
+        "-i", "2",
+        "--op", "replace",
+        "-c", "Third (edited)",
+        "-p", "^Third$",
+        "--force",
+        "--no-backup"
+    ]);
+    assert_eq!(code2, 0, "Reported index should address the appended block");
+
+    let result = fs::read_to_string(&file_path).unwrap();
+    assert!(result.contains("Third (edited)"));
+
+    // 清理
+    let _ = fs::remove_file(&file_path);
+}
+
+// ============================================================================
+// 测试：链接引用定义
+// ============================================================================
+
+#[test]
+fn test_replace_link_definition() {
+    let content = "# Doc\n\n## References\n\nSee [home][h] and [repo][r].\n\n[h]: https://old.example.com\n[r]: https://github.com/example/repo\n";
+    let file_path = create_test_file(content);
+    let file_str = file_path.to_str().unwrap();
+
+    let (code, _, _) = run_mdp(&[
+        "patch",
+        "-f", file_str,
+        "-H", "## References",
+        "-i", "1",
+        "--op", "replace",
+        "-c", "[h]: https://new.example.com",
+        "-p", r"^\[h\]:",
+        "--force",
+        "--no-backup"
+    ]);
+    assert_eq!(code, 0);
+
+    let result = fs::read_to_string(&file_path).unwrap();
+    assert!(result.contains("[h]: https://new.example.com\n[r]: https://github.com/example/repo"));
+    assert!(!result.contains("old.example.com"));
+    assert!(result.contains("See [home][h] and [repo][r]."));
+
+    // 清理
+    let _ = fs::remove_file(&file_path);
+}
+
+// ============================================================================
+// 测试：按行号寻址
+// ============================================================================
+
+#[test]
+fn test_line_addressing() {
+    let content = "# Doc\n\n## Section\n\nFirst paragraph\n\n```\nold code\n```\n";
+    let file_path = create_test_file(content);
+    let file_str = file_path.to_str().unwrap();
+
+    // 第 8 行位于代码块内部
+    let (code, _, _) = run_mdp(&[
+        "patch",
+        "-f", file_str,
+        "--line", "8",
+        "--op", "replace",
+        "-c", "```\nnew code\n```",
+        "-p", "old code",
+        "--force",
+        "--no-backup"
+    ]);
+    assert_eq!(code, 0);
+
+    let result = fs::read_to_string(&file_path).unwrap();
+    assert!(result.contains("```\nnew code\n```"));
+    assert!(result.contains("First paragraph"));
+
+    // --line 与 --heading 互斥
+    let (code2, _, _) = run_mdp(&[
+        "patch",
+        "-f", file_str,
+        "-H", "## Section",
+        "--line", "5",
+        "--op", "append",
+        "-c", "x"
+    ]);
+    assert_ne!(code2, 0);
+
+    // 清理
+    let _ = fs::remove_file(&file_path);
+}
+
+// ============================================================================
+// 测试：按大纲编号寻址
+// ============================================================================
+
+#[test]
+fn test_numbered_addressing() {
+    let content = "# One\n\n## Setup\n\nOne setup\n\n# Two\n\n## Setup\n\nTwo setup\n";
+    let file_path = create_test_file(content);
+    let file_str = file_path.to_str().unwrap();
+
+    // 2.1 即第二个一级标题下的第一个子标题
+    let (code, _, _) = run_mdp(&[
+        "patch",
+        "-f", file_str,
+        "--numbered", "2.1",
+        "--op", "append",
+        "-c", "Appended",
+        "--force",
+        "--no-backup"
+    ]);
+    assert_eq!(code, 0);
+
+    let result = fs::read_to_string(&file_path).unwrap();
+    assert!(result.contains("Two setup\n\nAppended"));
+    assert!(!result.contains("One setup\n\nAppended"));
+
+    // 越界编号按“标题未找到”处理
+    let (code2, _, stderr) = run_mdp(&[
+        "patch",
+        "-f", file_str,
+        "--numbered", "1.3",
+        "--op", "append",
+        "-c", "x"
+    ]);
+    assert_eq!(code2, 2);
+    assert!(stderr.contains("section number 1.3"));
+
+    // 清理
+    let _ = fs::remove_file(&file_path);
+}
+
+// ============================================================================
+// 测试：批量操作中的单操作 force/no_backup
+// ============================================================================
+
+#[test]
+fn test_per_operation_force_in_config() {
+    let forced = create_test_file("# Doc\n\n## Forced\n\nOld forced\n");
+    let planned = create_test_file("# Doc\n\n## Planned\n\nOld planned\n");
+    let config = create_test_file("");
+    let config = config.with_extension("yaml");
+    fs::write(
+        &config,
+        format!(
+            "operations:\n  - file: {}\n    heading: [\"## Forced\"]\n    operation: replace\n    content: New forced\n    force: true\n    no_backup: true\n  - file: {}\n    heading: [\"## Planned\"]\n    operation: append\n    content: New planned\n",
+            forced.display(),
+            planned.display()
+        ),
+    )
+    .unwrap();
+
+    let (code, stdout, _) = run_mdp(&["apply", config.to_str().unwrap()]);
+    assert_eq!(code, 0);
+    assert!(stdout.contains("Run with --force"), "Unforced operations remain dry-run");
+
+    let forced_result = fs::read_to_string(&forced).unwrap();
+    assert!(forced_result.contains("New forced"), "Forced operation should be applied");
+    assert!(!with_suffix(&forced, ".bak").exists(), "Per-op no_backup should skip backup");
+
+    let planned_result = fs::read_to_string(&planned).unwrap();
+    assert!(!planned_result.contains("New planned"), "Unforced operation should not be applied");
+
+    // plan 忽略单操作的 force
+    fs::write(&forced, "# Doc\n\n## Forced\n\nOld forced\n").unwrap();
+    let (code2, _, _) = run_mdp(&["plan", config.to_str().unwrap()]);
+    assert_eq!(code2, 0);
+    assert!(fs::read_to_string(&forced).unwrap().contains("Old forced"));
+
+    // 清理
+    let _ = fs::remove_file(&forced);
+    let _ = fs::remove_file(&planned);
+    let _ = fs::remove_file(&config);
+}
+
+#[test]
+fn test_occurrence_selects_ambiguous_heading() {
+    let content = "# A\n\n## Dup\n\none\n\n# B\n\n## Dup\n\ntwo\n\n# C\n\n## Dup\n\nthree\n";
+    let file_path = create_test_file(content);
+    let file_str = file_path.to_str().unwrap();
+
+    let (code, _, _) = run_mdp(&[
+        "patch",
+        "-f", file_str,
+        "-H", "## Dup",
+        "--occurrence", "1",
+        "--op", "replace",
+        "-c", "TWO",
+        "-p", "^two$",
+        "--force",
+        "--no-backup"
+    ]);
+    assert_eq!(code, 0, "--occurrence should bypass the ambiguity error");
+
+    let result = fs::read_to_string(&file_path).unwrap();
+    assert!(result.contains("one\n") && result.contains("TWO\n") && result.contains("three\n"));
+
+    // 清理
+    let _ = fs::remove_file(&file_path);
+}
+
+// ============================================================================
+// 测试：explain 子命令
+// ============================================================================
+
+#[test]
+fn test_explain_shows_resolution() {
+    let content = "# A\n\n## B\n\nfirst\n\n```sh\nsecond\n```\n\n# C\n\n## B\n\nother\n";
+    let file_path = create_test_file(content);
+    let file_str = file_path.to_str().unwrap();
+
+    let (code, stdout, _) = run_mdp(&["explain", "-f", file_str, "-H", "# A ## B", "-i", "1"]);
+    assert_eq!(code, 0);
+    assert!(stdout.contains("Chosen section: ## B (line 3"), "{}", stdout);
+    assert!(stdout.contains("[1] code bytes 18..34 lines 7-9  <- selected"), "{}", stdout);
+    assert!(stdout.contains("  | second"), "{}", stdout);
+
+    // explain 不修改文件
+    assert_eq!(fs::read_to_string(&file_path).unwrap(), content);
+
+    // 歧义时仍然以退出码 4 报错
+    let (code2, stdout2, _) = run_mdp(&["explain", "-f", file_str, "-H", "## B"]);
+    assert_eq!(code2, 4);
+    assert!(stdout2.contains("Candidates for '## B': 2"));
+
+    // 清理
+    let _ = fs::remove_file(&file_path);
+}
+
+// ============================================================================
+// 测试：颜色主题
+// ============================================================================
+
+#[test]
+fn test_monochrome_theme_uses_no_colors() {
+    let content = "# Doc\n\n## Theme\n\nOld line\n";
+    let file_path = create_test_file(content);
+    let file_str = file_path.to_str().unwrap();
+    let args = [
+        "patch",
+        "-f", file_str,
+        "-H", "## Theme",
+        "--op", "replace",
+        "-c", "New line",
+        "-p", "Old",
+    ];
+
+    let (code, stdout, _) = run_mdp_with_env(&[&args[..], &["--theme", "monochrome"]].concat(), &[("CLICOLOR_FORCE", "1")]);
+    assert_eq!(code, 0);
+    // 没有前景色 (30-37, 90-97)，但新增/删除行仍有不同样式
+    let has_foreground = (30..=37).chain(90..=97).any(|code| {
+        stdout.contains(&format!("[{}m", code)) || stdout.contains(&format!(";{}m", code))
+    });
+    assert!(!has_foreground, "{:?}", stdout);
+    assert!(stdout.contains("\x1b[1m+New line"), "{:?}", stdout);
+    assert!(stdout.contains("\x1b[4m-Old line"), "{:?}", stdout);
+
+    // 默认主题使用颜色
+    let (_, stdout_default, _) = run_mdp_with_env(&args, &[("CLICOLOR_FORCE", "1")]);
+    assert!(stdout_default.contains("\x1b[32m+New line"), "{:?}", stdout_default);
+
+    // 清理
+    let _ = fs::remove_file(&file_path);
+}
+
+#[test]
+fn test_batch_output_has_per_file_headers() {
+    let first = create_test_file("# Doc\n\n## A\n\nOne\n");
+    let second = create_test_file("# Doc\n\n## B\n\nTwo\n");
+    let config = write_batch_config(&format!(
+        "  - file: {}\n    heading: [\"## A\"]\n    operation: append\n    content: Added one\n  - file: {}\n    heading: [\"## B\"]\n    operation: append\n    content: Added two\n",
+        first.display(),
+        second.display()
+    ));
+
+    let (code, stdout, _) = run_mdp_with_env(&["plan", config.to_str().unwrap()], &[("CLICOLOR_FORCE", "1")]);
+    assert_eq!(code, 0);
+    // 每个文件一个带样式的文件头，其后是该文件自己的 diff
+    for (file, added) in [(&first, "+Added one"), (&second, "+Added two")] {
+        let header = format!("==> {} <==", file.display());
+        let line = stdout.lines().find(|l| l.contains(&header)).unwrap_or_else(|| panic!("{:?}", stdout));
+        assert!(line.starts_with("\x1b["), "header should be styled: {:?}", line);
+        let section = &stdout[stdout.find(&header).unwrap()..];
+        assert!(section.contains(added), "{:?}", section);
+    }
+    let first_section = &stdout[..stdout.find(&format!("==> {} <==", second.display())).unwrap()];
+    assert!(!first_section.contains("Added two"));
+
+    // JSON 按文件给出结构化结果
+    let (_, stdout, _) = run_mdp(&["plan", config.to_str().unwrap(), "-F", "json"]);
+    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(json["files"].as_array().unwrap().len(), 2);
+    assert_eq!(json["files"][0]["status"], "dry-run");
+
+    // 清理
+    let _ = fs::remove_file(&first);
+    let _ = fs::remove_file(&second);
+    let _ = fs::remove_file(&config);
+}
+
+// ============================================================================
+// 测试：--input-charset 转码读写
+// ============================================================================
+
+#[test]
+fn test_latin1_round_trip() {
+    let file = create_test_file("");
+    // "# Menü\n\nCafé\n" 的 Latin-1 编码
+    fs::write(&file, b"# Men\xfc\n\nCaf\xe9\n").unwrap();
+    let file_str = file.to_str().unwrap();
+
+    // 未指定字符集时按 UTF-8 读取失败
+    let (code, _, _) = run_mdp(&["patch", "-f", file_str, "-H", "# Menü", "--op", "append", "-c", "x"]);
+    assert_ne!(code, 0);
+
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "# Menü", "--op", "append", "-c", "Crème brûlée",
+        "--input-charset", "latin1", "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(fs::read(&file).unwrap(), b"# Men\xfc\n\nCaf\xe9\n\nCr\xe8me br\xfbl\xe9e\n");
+
+    // 非法字节序列：错误信息包含字符集名称
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "# Menü", "--op", "append", "-c", "x", "--input-charset", "utf-8",
+    ]);
+    assert_ne!(code, 0);
+    assert!(stderr.contains("as UTF-8: invalid byte sequence"), "{}", stderr);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：revert 子命令
+// ============================================================================
+
+#[test]
+fn test_revert_restores_backup() {
+    let content = "# Doc\n\n## Revert\n\nOriginal\n";
+    let file_path = create_test_file(content);
+    let file_str = file_path.to_str().unwrap();
+    let backup_path = with_suffix(&file_path, ".bak");
+
+    // 没有备份时报错
+    let (code, _, stderr) = run_mdp(&["revert", "-f", file_str]);
+    assert_eq!(code, 1);
+    assert!(stderr.contains("No backup found"), "{}", stderr);
+
+    let (code, _, _) = run_mdp(&[
+        "patch",
+        "-f", file_str,
+        "-H", "## Revert",
+        "--op", "replace",
+        "-c", "Changed",
+        "-p", "Original",
+        "--force"
+    ]);
+    assert_eq!(code, 0);
+    assert!(fs::read_to_string(&file_path).unwrap().contains("Changed"));
+
+    let (code, stdout, _) = run_mdp(&["revert", "-f", file_str]);
+    assert_eq!(code, 0);
+    assert!(stdout.contains("Restored"));
+    assert_eq!(fs::read_to_string(&file_path).unwrap(), content);
+
+    // 清理
+    let _ = fs::remove_file(&file_path);
+    let _ = fs::remove_file(&backup_path);
+}
+
+// ============================================================================
+// 测试：未闭合代码块
+// ============================================================================
+
+#[test]
+fn test_unterminated_fence_warning_and_repair() {
+    let content = "# Doc\n\n```sh\necho hi\n\n## Later\n\nText\n";
+    let file_path = create_test_file(content);
+    let file_str = file_path.to_str().unwrap();
+
+    // 不修复时：## Later 被代码块吞掉，并输出警告
+    let (code, _, stderr) = run_mdp(&[
+        "patch",
+        "-f", file_str,
+        "-H", "## Later",
+        "--op", "append",
+        "-c", "More"
+    ]);
+    assert_eq!(code, 2);
+    assert!(stderr.contains("unterminated code fence"), "{}", stderr);
+    assert!(stderr.contains(":3;"), "{}", stderr);
+
+    // 修复后 section 正确分隔
+    let (code, _, _) = run_mdp(&[
+        "patch",
+        "-f", file_str,
+        "-H", "## Later",
+        "--op", "append",
+        "-c", "More",
+        "--repair-fences",
+        "--force",
+        "--no-backup"
+    ]);
+    assert_eq!(code, 0);
+    let result = fs::read_to_string(&file_path).unwrap();
+    assert_eq!(result, "# Doc\n\n```sh\necho hi\n\n```\n## Later\n\nText\n\nMore\n");
+
+    // 嵌套的四反引号围栏与 `~~~` 围栏已闭合：无警告，修复不改动代码块
+    for fenced in ["````md\n```\ninner\n```\n````", "~~~sh\n```\necho hi\n~~~"] {
+        fs::write(&file_path, format!("# T\n\n{}\n\nPara.\n", fenced)).unwrap();
+        let (code, _, stderr) = run_mdp(&[
+            "patch", "-f", file_str, "-H", "# T", "-i", "1", "--op", "append", "-c", "More", "--repair-fences", "--force", "--no-backup",
+        ]);
+        assert_eq!(code, 0, "{}", stderr);
+        assert!(!stderr.contains("unterminated code fence"), "{}", stderr);
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), format!("# T\n\n{}\n\nPara.\n\nMore\n", fenced));
+    }
+
+    // 清理
+    let _ = fs::remove_file(&file_path);
+}
+
+// ============================================================================
+// 测试：parse 子命令
+// ============================================================================
+
+#[test]
+fn test_parse_json_output() {
+    let content = "# Title\n\nIntro\n\n## Code\n\n```rust\nfn main() {}\n```\n";
+    let file_path = create_test_file(content);
+    let file_str = file_path.to_str().unwrap();
+
+    let (code, stdout, _) = run_mdp(&["parse", "-f", file_str, "--format", "json"]);
+    assert_eq!(code, 0);
+
+    let sections: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(sections[0]["heading"], "# Title");
+    assert_eq!(sections[0]["heading_level"], 1);
+    assert_eq!(sections[0]["parent"], serde_json::Value::Null);
+    assert_eq!(sections[0]["blocks"][0]["block_type"]["type"], "paragraph");
+    assert_eq!(sections[0]["blocks"][0]["start"], 9);
+    assert_eq!(sections[0]["blocks"][0]["end"], 14);
+
+    assert_eq!(sections[1]["heading_level"], 2);
+    assert_eq!(sections[1]["parent"], 0);
+    assert_eq!(sections[1]["blocks"][0]["block_type"]["type"], "code");
+    assert_eq!(sections[1]["blocks"][0]["block_type"]["lang"], "rust");
+    assert_eq!(sections[1]["blocks"][0]["start"], 25);
+
+    // 清理
+    let _ = fs::remove_file(&file_path);
+}
+
+#[test]
+fn test_parse_only_type_filter() {
+    let content = "# Guide\n\nIntro\n\n```sh\nls\n```\n\n- item\n\n## API\n\nText\n\n```rust\nfn f() {}\n```\n";
+    let file_path = create_test_file(content);
+    let file_str = file_path.to_str().unwrap();
+
+    let (code, stdout, _) = run_mdp(&["parse", "-f", file_str, "--format", "json", "--only-type", "code"]);
+    assert_eq!(code, 0);
+
+    let sections: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    for (section, lang, index) in [(0, "sh", 1), (1, "rust", 1)] {
+        let blocks = sections[section]["blocks"].as_array().unwrap();
+        assert_eq!(blocks.len(), 1, "{}", stdout);
+        assert_eq!(blocks[0]["block_type"]["lang"], lang);
+        // 保留原始索引，可直接用于 patch 寻址
+        assert_eq!(blocks[0]["index"], index);
+    }
+
+    // 文本输出同样只列出代码块
+    let (_, stdout, _) = run_mdp(&["parse", "-f", file_str, "--only-type", "code"]);
+    assert!(stdout.contains("[1] code"), "{}", stdout);
+    assert!(!stdout.contains("paragraph") && !stdout.contains("list"), "{}", stdout);
+
+    // 未知类型被拒绝
+    let (code, _, _) = run_mdp(&["parse", "-f", file_str, "--only-type", "codeblock"]);
+    assert_ne!(code, 0);
+
+    // 清理
+    let _ = fs::remove_file(&file_path);
+}
+
+#[test]
+fn test_under_resolves_relative_heading() {
+    let content = "# Doc A\n\n## Child\n\nContent A\n\n# Doc B\n\n## Child\n\nContent B\n";
+    let file_path = create_test_file(content);
+    let file_str = file_path.to_str().unwrap();
+
+    let (code, _, _) = run_mdp(&[
+        "patch",
+        "-f", file_str,
+        "-H", "## Child",
+        "--under", "# Doc B",
+        "--op", "append",
+        "-c", "Added to B",
+        "--force",
+        "--no-backup"
+    ]);
+    assert_eq!(code, 0, "--under should disambiguate like a nested path");
+
+    let result = fs::read_to_string(&file_path).unwrap();
+    assert!(result.find("Added to B").unwrap() > result.find("Content B").unwrap());
+
+    // 清理
+    let _ = fs::remove_file(&file_path);
+}
+
+// ============================================================================
+// 测试：符号链接
+// ============================================================================
+
+#[cfg(unix)]
+#[test]
+fn test_patch_through_symlink() {
+    let real_path = create_test_file("# Doc\n\n## Linked\n\nOriginal\n");
+    let link_path = real_path.with_extension("link.md");
+    std::os::unix::fs::symlink(&real_path, &link_path).unwrap();
+    let link_str = link_path.to_str().unwrap();
+
+    let (code, _, _) = run_mdp(&[
+        "patch",
+        "-f", link_str,
+        "-H", "## Linked",
+        "--op", "append",
+        "-c", "Through link",
+        "--force"
+    ]);
+    assert_eq!(code, 0);
+
+    assert!(fs::symlink_metadata(&link_path).unwrap().file_type().is_symlink(), "Symlink should remain intact");
+    assert!(fs::read_to_string(&real_path).unwrap().contains("Through link"), "Real file should be updated");
+    let backup_path = with_suffix(&link_path, ".bak");
+    assert!(fs::read_to_string(&backup_path).unwrap().contains("Original"));
+
+    // --no-follow-symlinks 拒绝写入
+    let (code2, _, stderr) = run_mdp(&[
+        "patch",
+        "-f", link_str,
+        "-H", "## Linked",
+        "--op", "append",
+        "-c", "Refused",
+        "--force",
+        "--no-follow-symlinks"
+    ]);
+    assert_ne!(code2, 0);
+    assert!(stderr.contains("symlink"), "{}", stderr);
+    assert!(!fs::read_to_string(&real_path).unwrap().contains("Refused"));
+
+    // 清理
+    let _ = fs::remove_file(&link_path);
+    let _ = fs::remove_file(&real_path);
+    let _ = fs::remove_file(&backup_path);
+}
+
+// ============================================================================
+// 测试：批量修改计数
+// ============================================================================
+
+#[test]
+fn test_print_count_and_fail_on_noop() {
+    let changed = create_test_file("# Doc\n\n## Count\n\nOriginal\n");
+    let unchanged = create_test_file("# Doc\n\n## Count\n\nOriginal\n\nAlready here\n");
+    let config = create_test_file("").with_extension("yaml");
+    fs::write(
+        &config,
+        format!(
+            "operations:\n  - file: {}\n    heading: [\"## Count\"]\n    operation: append\n    content: Already here\n  - file: {}\n    heading: [\"## Count\"]\n    operation: append\n    content: Already here\n",
+            changed.display(),
+            unchanged.display()
+        ),
+    )
+    .unwrap();
+    let config_str = config.to_str().unwrap();
+
+    let (code, stdout, _) = run_mdp(&["apply", config_str, "--force", "--no-backup", "--print-count"]);
+    assert_eq!(code, 0);
+    assert!(stdout.contains("Files modified: 1"), "{}", stdout);
+
+    // 第二次执行全部为 noop
+    let (code2, stdout2, _) = run_mdp(&["apply", config_str, "--force", "--no-backup", "--print-count", "--fail-on-noop"]);
+    assert_eq!(code2, 10);
+    assert!(stdout2.contains("Files modified: 0"), "{}", stdout2);
+
+    // JSON 格式：计数写入 files_modified，stdout 仍可解析
+    fs::write(&changed, "# Doc\n\n## Count\n\nOriginal\n").unwrap();
+    let (code, stdout, stderr) = run_mdp(&["apply", config_str, "--force", "--no-backup", "--print-count", "--format", "json"]);
+    assert_eq!(code, 0, "{}", stderr);
+    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(json["files_modified"], 1);
+    let (code, stdout, _) = run_mdp(&["apply", config_str, "--force", "--no-backup", "--print-count", "--summary-only"]);
+    assert_eq!(code, 0);
+    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(json["files_modified"], 0);
+
+    // 清理
+    let _ = fs::remove_file(&changed);
+    let _ = fs::remove_file(&unchanged);
+    let _ = fs::remove_file(&config);
+}
+
+// ============================================================================
+// 测试：递归索引
+// ============================================================================
+
+#[test]
+fn test_recursive_index_reaches_sub_subsection() {
+    let content = "# Guide\n\nIntro\n\n## Setup\n\nInstall it\n\n### Linux\n\nUse apt\n\n# Other\n\nUnrelated\n";
+    let file = create_test_file(content);
+    let file_str = file.to_str().unwrap();
+
+    // 不开启递归时，# Guide 只有一个 block
+    let (code, _, stderr) = run_mdp(&["patch", "-f", file_str, "-H", "# Guide", "-i", "2", "--op", "replace", "-c", "Use dnf", "--force"]);
+    assert_ne!(code, 0);
+    assert!(stderr.contains("out of range"), "{}", stderr);
+
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "# Guide", "-i", "2", "--recursive-index",
+        "--op", "replace", "-c", "Use dnf", "-p", "apt", "--no-backup", "--force",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+
+    let result = fs::read_to_string(&file).unwrap();
+    assert!(result.contains("### Linux\n\nUse dnf\n"));
+    assert!(result.contains("Install it"));
+    assert!(result.contains("Unrelated"));
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：仅校验 fingerprint
+// ============================================================================
+
+#[test]
+fn test_verify_block_fingerprint() {
+    let content = "# Doc\n\n## Config\n\nversion = 1.2.0\n";
+    let file = create_test_file(content);
+    let file_str = file.to_str().unwrap();
+
+    let (code, stdout, _) = run_mdp(&["verify-block", "-f", file_str, "-H", "## Config", "-i", "0", "-p", r"version = 1\.2"]);
+    assert_eq!(code, 0);
+    assert!(stdout.contains("Fingerprint matches"));
+    assert!(!stdout.contains("---"), "should not print a diff");
+
+    let (code, _, stderr) = run_mdp(&["verify-block", "-f", file_str, "-H", "## Config", "-i", "0", "-p", r"version = 2\."]);
+    assert_eq!(code, 3, "{}", stderr);
+
+    // 与 patch 一致，--fingerprint-ignore-case 忽略大小写
+    let args = ["verify-block", "-f", file_str, "-H", "## Config", "-i", "0", "-p", r"VERSION = 1\.2"];
+    let (code, _, _) = run_mdp(&args);
+    assert_eq!(code, 3);
+    let (code, _, stderr) = run_mdp(&[&args[..], &["--fingerprint-ignore-case"]].concat());
+    assert_eq!(code, 0, "{}", stderr);
+
+    // 文件不应被修改，也不应生成备份
+    assert_eq!(fs::read_to_string(&file).unwrap(), content);
+    assert!(!with_suffix(&file, ".bak").exists());
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：非 .md 扩展名的备份与临时文件命名
+// ============================================================================
+
+#[test]
+fn test_backup_naming_for_other_extensions() {
+    for ext in ["markdown", "mdx"] {
+        let md_path = create_test_file("# Doc\n\n## Ext\n\nOriginal\n");
+        let file_path = md_path.with_extension(ext);
+        fs::rename(&md_path, &file_path).unwrap();
+        let file_str = file_path.to_str().unwrap();
+
+        let (code, _, stderr) = run_mdp(&[
+            "patch",
+            "-f", file_str,
+            "-H", "## Ext",
+            "--op", "replace",
+            "-c", "Replaced",
+            "--force"
+        ]);
+        assert_eq!(code, 0, "{}", stderr);
+        assert!(fs::read_to_string(&file_path).unwrap().contains("Replaced"));
+
+        let backup_path = with_suffix(&file_path, ".bak");
+        assert!(backup_path.exists(), "Backup should be {}", backup_path.display());
+        assert!(fs::read_to_string(&backup_path).unwrap().contains("Original"));
+        assert!(!file_path.with_extension("bak").exists());
+        assert!(!with_suffix(&file_path, ".tmp").exists());
+
+        // revert 使用相同的命名规则
+        let (code, _, _) = run_mdp(&["revert", "-f", file_str]);
+        assert_eq!(code, 0);
+        assert!(fs::read_to_string(&file_path).unwrap().contains("Original"));
+
+        // 清理
+        let _ = fs::remove_file(&file_path);
+        let _ = fs::remove_file(&backup_path);
+    }
+}
+
+// ============================================================================
+// 测试：MDX
+// ============================================================================
+
+#[test]
+fn test_mdx_imports_and_components() {
+    let content = "import Banner from './banner'\n\n# Page\n\n## Usage\n\nSome prose\n<Banner tone=\"info\" />\n\nClosing words\n";
+    let md_path = create_test_file(content);
+    let file_path = md_path.with_extension("mdx");
+    fs::rename(&md_path, &file_path).unwrap();
+    let file_str = file_path.to_str().unwrap();
+
+    let (code, stdout, _) = run_mdp(&["parse", "-f", file_str, "--format", "json"]);
+    assert_eq!(code, 0);
+    assert!(stdout.contains("\"type\": \"jsx\""), "{}", stdout);
+
+    // 组件是独立的 block（index 1），替换段落不会影响它
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Usage", "-i", "0",
+        "--op", "replace", "-c", "New prose", "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    let result = fs::read_to_string(&file_path).unwrap();
+    assert!(result.starts_with("import Banner from './banner'\n"));
+    assert!(result.contains("New prose\n<Banner tone=\"info\" />\n"), "{}", result);
+
+    // 强制按普通 Markdown 解析时，组件并入段落
+    let (_, stdout, _) = run_mdp(&["parse", "-f", file_str, "--format", "json", "--flavor", "markdown"]);
+    assert!(!stdout.contains("\"jsx\""));
+
+    // 清理
+    let _ = fs::remove_file(&file_path);
+}
+
+// ============================================================================
+// 测试：整体替换 section 正文
+// ============================================================================
+
+#[test]
+fn test_replace_body_keeps_siblings() {
+    let content = "# Doc\n\n## Generated\n\nOld one.\n\n- old\n- list\n\n## Manual\n\nHand written.\n";
+    let file = create_test_file(content);
+    let file_str = file.to_str().unwrap();
+
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Generated",
+        "--op", "replace-body", "-c", "Fresh output.", "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+
+    let result = fs::read_to_string(&file).unwrap();
+    assert_eq!(result, "# Doc\n\n## Generated\n\nFresh output.\n\n## Manual\n\nHand written.\n");
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：一次追加多个 block
+// ============================================================================
+
+#[test]
+fn test_append_multiple_content_blocks() {
+    let file = create_test_file("# Doc\n\n## Usage\n\nIntro.\n");
+    let file_str = file.to_str().unwrap();
+    let args = [
+        "patch", "-f", file_str, "-H", "## Usage", "--op", "append",
+        "-c", "Run it:", "-c", "```sh\nmdp --help\n```", "--force", "--no-backup",
+    ];
+
+    let (code, _, stderr) = run_mdp(&args);
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(
+        fs::read_to_string(&file).unwrap(),
+        "# Doc\n\n## Usage\n\nIntro.\n\nRun it:\n\n```sh\nmdp --help\n```\n"
+    );
+    let (_, stdout, _) = run_mdp(&["parse", "-f", file_str]);
+    assert!(stdout.contains("[1] paragraph") && stdout.contains("[2] code"), "{}", stdout);
+
+    // 整组内容已存在：幂等
+    let (code, stdout, _) = run_mdp(&args);
+    assert_eq!(code, 0);
+    assert!(stdout.contains("No changes"), "{}", stdout);
+
+    // YAML 中 content 可以是列表
+    let config = write_batch_config(&format!(
+        "  - file: {}\n    heading: [\"## Usage\"]\n    index: 2\n    operation: append\n    content:\n      - \"Then:\"\n      - \"- step one\\n- step two\"\n",
+        file.display()
+    ));
+    let (code, _, stderr) = run_mdp(&["apply", config.to_str().unwrap(), "--force", "--no-backup"]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert!(fs::read_to_string(&file).unwrap().ends_with("```\n\nThen:\n\n- step one\n- step two\n"));
+
+    // 清理
+    let _ = fs::remove_file(&file);
+    let _ = fs::remove_file(&config);
+}
+
+// ============================================================================
+// 测试：--dedent 去除内容的共同缩进
+// ============================================================================
+
+#[test]
+fn test_dedent_content() {
+    let file = create_test_file("# Doc\n\n## Notes\n\nIntro.\n");
+    let file_str = file.to_str().unwrap();
+
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Notes", "--op", "append",
+        "-c", "    - first\n      continued\n    - second", "--dedent", "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(
+        fs::read_to_string(&file).unwrap(),
+        "# Doc\n\n## Notes\n\nIntro.\n\n- first\n  continued\n- second\n"
+    );
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：--file-sha256 整个文件的前置条件
+// ============================================================================
+
+/// 文件内容的 SHA-256（hex），借助系统的 sha256sum
+fn sha256_of(path: &Path) -> String {
+    let output = Command::new("sha256sum").arg(path).output().expect("Failed to run sha256sum");
+    String::from_utf8_lossy(&output.stdout).split_whitespace().next().unwrap().to_string()
+}
+
+#[test]
+fn test_file_sha256_precondition() {
+    let file = create_test_file("# Doc\n\n## Shared\n\nOriginal\n");
+    let file_str = file.to_str().unwrap();
+    let hash = sha256_of(&file);
+
+    // 文件被他人修改后，旧 hash 不再匹配：专用退出码 5，文件保持不变
+    fs::write(&file, "# Doc\n\n## Shared\n\nEdited elsewhere\n").unwrap();
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Shared", "--op", "append", "-c", "Mine",
+        "--file-sha256", &hash, "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 5, "{}", stderr);
+    assert!(stderr.contains("File hash mismatch"), "{}", stderr);
+    assert!(!fs::read_to_string(&file).unwrap().contains("Mine"));
+
+    // 使用最新 hash（大小写不敏感）即可应用
+    let hash = sha256_of(&file).to_uppercase();
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Shared", "--op", "append", "-c", "Mine",
+        "--file-sha256", &hash, "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert!(fs::read_to_string(&file).unwrap().contains("Mine"));
+
+    // 批量配置中的 file_sha256
+    let config = write_batch_config(&format!(
+        "  - file: {}\n    heading: [\"## Shared\"]\n    operation: append\n    content: Batch\n    file_sha256: {}\n",
+        file.display(),
+        hash
+    ));
+    let (code, _, _) = run_mdp(&["apply", config.to_str().unwrap(), "--force", "--no-backup"]);
+    assert_eq!(code, 5);
+    let _ = fs::remove_file(&config);
+    let config = write_batch_config(&format!(
+        "  - file: {}\n    heading: [\"## Shared\"]\n    operation: append\n    content: Batch\n    file_sha256: {}\n",
+        file.display(),
+        sha256_of(&file)
+    ));
+    let (code, _, stderr) = run_mdp(&["apply", config.to_str().unwrap(), "--force", "--no-backup"]);
+    assert_eq!(code, 0, "{}", stderr);
+
+    // hash 针对磁盘上的原始字节，而不是换行统一之后的内容
+    fs::write(&file, "# Doc\r\n\r\n## Shared\r\n\r\nText\r\n").unwrap();
+    let hash = sha256_of(&file);
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Shared", "--op", "append", "-c", "Mine",
+        "--file-sha256", &hash, "--normalize-eol", "crlf", "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+    let _ = fs::remove_file(&config);
+}
+
+// ============================================================================
+// 测试：--interactive 确认破坏性操作
+// ============================================================================
+
+/// 运行 mdp 并通过 stdin 输入 `input`
+fn run_mdp_with_input(args: &[&str], input: &str) -> (i32, String) {
+    let mut child = Command::new(mdp_bin())
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Failed to execute mdp");
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    (output.status.code().unwrap_or(-1), String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+#[test]
+fn test_interactive_confirmation() {
+    let content = "# Doc\n\n## Old\n\nOld text\n";
+    let file = create_test_file(content);
+    let file_str = file.to_str().unwrap();
+    let args = [
+        "patch", "-f", file_str, "-H", "## Old", "--op", "replace", "-c", "New text",
+        "--interactive", "--no-backup",
+    ];
+
+    // 回答 n 或直接 EOF：不应用
+    for input in ["n\n", ""] {
+        let (code, stdout) = run_mdp_with_input(&args, input);
+        assert_ne!(code, 0);
+        assert!(stdout.contains("+New text") && stdout.contains("Apply? [y/N]"), "{}", stdout);
+        assert_eq!(fs::read_to_string(&file).unwrap(), content);
+    }
+
+    // 回答 y：应用
+    let (code, _) = run_mdp_with_input(&args, "y\n");
+    assert_eq!(code, 0);
+    assert_eq!(fs::read_to_string(&file).unwrap(), "# Doc\n\n## Old\n\nNew text\n");
+
+    // --yes 不再询问
+    let (code, stdout) = run_mdp_with_input(
+        &["patch", "-f", file_str, "-H", "## Old", "--op", "delete", "--interactive", "--yes", "--no-backup"],
+        "",
+    );
+    assert_eq!(code, 0);
+    assert!(!stdout.contains("Apply?"));
+    assert!(!fs::read_to_string(&file).unwrap().contains("New text"));
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：dedup 删除重复 block
+// ============================================================================
+
+#[test]
+fn test_dedup_removes_repeated_paragraphs() {
+    let file = create_test_file("# Doc\n\n## Changelog\n\nAdded X.\n\nFixed Y.\n\nAdded X.\n\n## End\n\nBye.\n");
+    let file_str = file.to_str().unwrap();
+    let args = ["patch", "-f", file_str, "-H", "## Changelog", "--op", "dedup", "--no-backup"];
+
+    // 未 force 时只预览
+    let (code, stdout, _) = run_mdp(&args);
+    assert_ne!(code, 0);
+    assert!(stdout.contains("-Added X."), "{}", stdout);
+
+    let (code, _, stderr) = run_mdp(&[&args[..], &["--force"]].concat());
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(
+        fs::read_to_string(&file).unwrap(),
+        "# Doc\n\n## Changelog\n\nAdded X.\n\nFixed Y.\n\n## End\n\nBye.\n"
+    );
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：--literal-content 防止内容产生新 heading
+// ============================================================================
+
+#[test]
+fn test_literal_content_with_leading_hash() {
+    let file = create_test_file("# Doc\n\n## Chat\n\nHello.\n\n## Next\n\nMore.\n");
+    let file_str = file.to_str().unwrap();
+
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Chat", "--op", "append",
+        "-c", "## not a section, just shouting", "--literal-content", "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+
+    let result = fs::read_to_string(&file).unwrap();
+    assert!(result.contains("Hello.\n\n\\## not a section, just shouting\n\n## Next"));
+
+    // 结构未变：仍然只有 3 个 section
+    let (_, stdout, _) = run_mdp(&["parse", "-f", file_str]);
+    assert_eq!(stdout.lines().filter(|l| l.trim_start().starts_with('#')).count(), 3, "{}", stdout);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：--report-unchanged 输出 noop 原因
+// ============================================================================
+
+#[test]
+fn test_report_unchanged_reason() {
+    let file = create_test_file("# Doc\n\n## Notes\n\nFirst.\n\nSecond.\n");
+    let file_str = file.to_str().unwrap();
+
+    let (code, stdout, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Notes", "--op", "append",
+        "-c", "Second.", "--force", "--no-backup", "--report-unchanged",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert!(stdout.contains("Unchanged: append skipped: content already present at line 7"), "{}", stdout);
+
+    // 批量模式按文件报告
+    let config = write_batch_config(&format!(
+        "  - file: {}\n    heading: [\"## Notes\"]\n    operation: append\n    content: Second.\n",
+        file.display()
+    ));
+    let (code, stdout, _) = run_mdp(&["plan", config.to_str().unwrap(), "--report-unchanged"]);
+    assert_eq!(code, 0);
+    assert!(stdout.contains(&format!("Unchanged: {}: append skipped", file.display())), "{}", stdout);
+
+    // JSON 格式：原因写入 JSON，stdout 仍是单个可解析的文档
+    let (code, stdout, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Notes", "--op", "append",
+        "-c", "Second.", "--force", "--no-backup", "--report-unchanged", "--format", "json",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(json["changes"][0]["noop_reason"], "append skipped: content already present at line 7");
+    let (code, stdout, _) = run_mdp(&["plan", config.to_str().unwrap(), "--report-unchanged", "--format", "json"]);
+    assert_eq!(code, 0);
+    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert!(json["files"][0]["noop_reasons"][0].as_str().unwrap().starts_with("append skipped"), "{}", stdout);
+    let (code, stdout, _) = run_mdp(&["plan", config.to_str().unwrap(), "--report-unchanged", "--format", "jsonl"]);
+    assert_eq!(code, 0);
+    for line in stdout.lines() {
+        serde_json::from_str::<serde_json::Value>(line).unwrap();
+    }
+
+    // 未开启时不输出原因
+    let (_, stdout, _) = run_mdp(&["patch", "-f", file_str, "-H", "## Notes", "--op", "append", "-c", "Second."]);
+    assert!(!stdout.contains("Unchanged:"));
+
+    // 清理
+    let _ = fs::remove_file(&file);
+    let _ = fs::remove_file(&config);
+}
+
+// ============================================================================
+// 测试：替换内容模板
+// ============================================================================
+
+#[test]
+fn test_replace_with_block_template() {
+    let file = create_test_file("# Doc\n\n## Usage\n\nRun `mdp`.\n");
+    let file_str = file.to_str().unwrap();
+
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Usage", "--op", "replace",
+        "-c", "> Warning: {{heading}} changed in 0.2\n\n{{block}}", "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(
+        fs::read_to_string(&file).unwrap(),
+        "# Doc\n\n## Usage\n\n> Warning: Usage changed in 0.2\n\nRun `mdp`.\n"
+    );
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：目录生成与更新
+// ============================================================================
+
+#[test]
+fn test_toc_updates_after_new_heading() {
+    let file = create_test_file("# Doc\n\n## Install\n\nSteps.\n");
+    let file_str = file.to_str().unwrap();
+    let args = [
+        "patch", "-f", file_str, "-H", "# Doc", "--after-heading",
+        "--op", "toc", "--force", "--no-backup",
+    ];
+
+    let (code, _, stderr) = run_mdp(&args);
+    assert_eq!(code, 0, "{}", stderr);
+    let result = fs::read_to_string(&file).unwrap();
+    assert_eq!(
+        result,
+        "# Doc\n\n<!-- mdp:toc -->\n- [Doc](#doc)\n  - [Install](#install)\n<!-- mdp:/toc -->\n\n## Install\n\nSteps.\n"
+    );
+
+    // 新增 heading 后重新运行：目录原地更新
+    fs::write(&file, format!("{}\n## Usage Notes\n\nRun it.\n", result)).unwrap();
+    let (code, _, stderr) = run_mdp(&args);
+    assert_eq!(code, 0, "{}", stderr);
+    let result = fs::read_to_string(&file).unwrap();
+    assert!(result.contains("  - [Install](#install)\n  - [Usage Notes](#usage-notes)\n<!-- mdp:/toc -->"));
+    assert_eq!(result.matches("<!-- mdp:toc -->").count(), 1);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：导出 diff 文件
+// ============================================================================
+
+#[test]
+fn test_emit_diff_applies_with_patch() {
+    let dir = tempfile::tempdir().unwrap();
+    let original = "# Doc\n\n## A\n\nAlpha\n\n## B\n\nOne\n\nTwo\n\nThree\n\nFour\n\nFive\n\n## C\n\nGamma\n";
+    fs::write(dir.path().join("doc.md"), original).unwrap();
+
+    let mdp = |args: &[&str]| {
+        Command::new(mdp_bin())
+            .args(args)
+            .current_dir(dir.path())
+            .output()
+            .expect("Failed to execute mdp")
+    };
+
+    // dry run：只导出 diff，不修改文件
+    let output = mdp(&["patch", "-f", "doc.md", "-H", "## C", "--op", "replace", "-c", "Delta", "-p", "Gamma", "--emit-diff", "change.diff"]);
+    assert!(output.status.success());
+    assert_eq!(fs::read_to_string(dir.path().join("doc.md")).unwrap(), original);
+    let diff = fs::read_to_string(dir.path().join("change.diff")).unwrap();
+    assert!(diff.starts_with("--- a/doc.md\n+++ b/doc.md\n@@ "), "{}", diff);
+    assert!(!diff.contains("Alpha"), "hunks should only carry nearby context: {}", diff);
+
+    let status = Command::new("patch")
+        .args(["-p1", "-i", "change.diff"])
+        .current_dir(dir.path())
+        .status()
+        .expect("Failed to run patch");
+    assert!(status.success());
+    let patched = fs::read_to_string(dir.path().join("doc.md")).unwrap();
+    assert_eq!(patched, original.replace("Gamma", "Delta"));
+}
+
+// ============================================================================
+// 测试：apply-diff 正向/反向应用导出的 diff
+// ============================================================================
+
+#[test]
+fn test_apply_diff_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let original = "# Doc\n\n## Notes\n\nFirst\n\nSecond\n";
+    let doc = dir.path().join("doc.md");
+    let diff = dir.path().join("change.diff");
+    fs::write(&doc, original).unwrap();
+    let (doc_str, diff_str) = (doc.to_str().unwrap(), diff.to_str().unwrap());
+
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", doc_str, "-H", "## Notes", "-i", "1", "--op", "append",
+        "-c", "Third", "--force", "--no-backup", "--emit-diff", diff_str,
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    let modified = fs::read_to_string(&doc).unwrap();
+    assert_ne!(modified, original);
+
+    // 反向应用恢复原文
+    let (code, stdout, stderr) = run_mdp(&["apply-diff", "-f", doc_str, "--diff", diff_str, "--reverse", "--no-backup"]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert!(stdout.contains("Reverse-applied"));
+    assert_eq!(fs::read_to_string(&doc).unwrap(), original);
+
+    // 再次正向应用得到修改后的内容
+    let (code, _, stderr) = run_mdp(&["apply-diff", "-f", doc_str, "--diff", diff_str, "--no-backup"]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(fs::read_to_string(&doc).unwrap(), modified);
+
+    // 上下文已变化时拒绝应用
+    fs::write(&doc, "# Doc\n\n## Notes\n\nRewritten\n").unwrap();
+    let (code, _, stderr) = run_mdp(&["apply-diff", "-f", doc_str, "--diff", diff_str, "--reverse"]);
+    assert_ne!(code, 0);
+    assert!(stderr.contains("does not apply"), "{}", stderr);
+}
+
+// ============================================================================
+// 测试：批量操作的目标重叠检测
+// ============================================================================
+
+fn write_batch_config(ops: &str) -> PathBuf {
+    let config = create_test_file("").with_extension("yaml");
+    fs::write(&config, format!("operations:\n{}", ops)).unwrap();
+    config
+}
+
+#[test]
+fn test_batch_overlapping_operations_conflict() {
+    let content = "# Doc\n\n## Items\n\nFirst\n\nSecond\n";
+    let file = create_test_file(content);
+    let op = format!(
+        "  - file: {}\n    heading: [\"## Items\"]\n    index: 0\n    operation: delete\n",
+        file.display()
+    );
+    let config = write_batch_config(&format!("{}{}", op, op));
+    let config_str = config.to_str().unwrap();
+
+    let (code, _, stderr) = run_mdp(&["apply", config_str, "--force", "--no-backup"]);
+    assert_ne!(code, 0);
+    assert!(stderr.contains("Conflicting operations"), "{}", stderr);
+    assert!(stderr.contains("operation 1") && stderr.contains("operation 2"), "{}", stderr);
+    assert_eq!(fs::read_to_string(&file).unwrap(), content, "Nothing should be written on conflict");
+
+    // 清理
+    let _ = fs::remove_file(&file);
+    let _ = fs::remove_file(&config);
+}
+
+#[test]
+fn test_batch_disjoint_operations_on_same_file() {
+    let file = create_test_file("# Doc\n\n## Items\n\nFirst\n\nSecond\n\n## Other\n\nThird\n");
+    let op = |heading: &str, index: usize, operation: &str, content: &str| {
+        format!(
+            "  - file: {}\n    heading: [\"{}\"]\n    index: {}\n    operation: {}\n{}",
+            file.display(),
+            heading,
+            index,
+            operation,
+            content
+        )
+    };
+    let config = write_batch_config(&[
+        op("## Items", 0, "replace", "    content: One\n"),
+        op("## Items", 1, "append", "    content: Appended\n"),
+        op("## Other", 0, "delete", ""),
+    ]
+    .concat());
+    let config_str = config.to_str().unwrap();
+
+    let (code, _, stderr) = run_mdp(&["apply", config_str, "--force", "--no-backup"]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(
+        fs::read_to_string(&file).unwrap(),
+        "# Doc\n\n## Items\n\nOne\n\nSecond\n\nAppended\n\n## Other\n\n"
+    );
+
+    // 清理
+    let _ = fs::remove_file(&file);
+    let _ = fs::remove_file(&config);
+}
+
+// ============================================================================
+// 测试：plan 的变更退出码
+// ============================================================================
+
+#[test]
+fn test_plan_dry_run_exit_code() {
+    let file = create_test_file("# Doc\n\n## Status\n\nCurrent\n\nSynced\n");
+    let op = |index: usize, content: &str| {
+        format!(
+            "  - file: {}\n    heading: [\"## Status\"]\n    index: {}\n    operation: append\n    content: {}\n",
+            file.display(),
+            index,
+            content
+        )
+    };
+
+    // 全部为 noop：退出码 0
+    let in_sync = write_batch_config(&op(0, "Synced"));
+    let (code, _, _) = run_mdp(&["plan", in_sync.to_str().unwrap(), "--dry-run-exit-code"]);
+    assert_eq!(code, 0);
+
+    // 至少一个操作会修改内容：退出码 20
+    let drifted = write_batch_config(&[op(0, "Synced"), op(1, "Pending")].concat());
+    let (code, _, stderr) = run_mdp(&["plan", drifted.to_str().unwrap(), "--dry-run-exit-code"]);
+    assert_eq!(code, 20, "{}", stderr);
+
+    // 不加参数时仍为 0
+    let (code, _, _) = run_mdp(&["plan", drifted.to_str().unwrap()]);
+    assert_eq!(code, 0);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+    let _ = fs::remove_file(&in_sync);
+    let _ = fs::remove_file(&drifted);
+}
+
+// ============================================================================
+// 测试：空内容视为 noop
+// ============================================================================
+
+#[test]
+fn test_no_op_on_missing_content() {
+    let content = "# Doc\n\n## Generated\n\nKeep me\n";
+    let file = create_test_file(content);
+    let file_str = file.to_str().unwrap();
+
+    let (code, stdout, _) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Generated", "--op", "replace", "-c", "   ",
+        "--no-op-on-missing-content", "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0);
+    assert!(stdout.contains("No changes"), "{}", stdout);
+
+    let (code, _, _) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Generated", "--op", "replace",
+        "--no-op-on-missing-content", "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0);
+    assert_eq!(fs::read_to_string(&file).unwrap(), content);
+
+    // 不加参数时缺少内容仍报错
+    let (code, _, _) = run_mdp(&["patch", "-f", file_str, "-H", "## Generated", "--op", "replace", "--force"]);
+    assert_ne!(code, 0);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：--trace 计时输出
+// ============================================================================
+
+#[test]
+fn test_trace_reports_timings() {
+    let content = "# Doc\n\n## Traced\n\nOriginal\n";
+    let plain = create_test_file(content);
+    let traced = create_test_file(content);
+    fn patch_args(file: &str) -> Vec<&str> {
+        vec!["patch", "-f", file, "-H", "## Traced", "--op", "append", "-c", "Added", "--force", "--no-backup"]
+    }
+
+    let (code, _, stderr) = run_mdp(&patch_args(plain.to_str().unwrap()));
+    assert_eq!(code, 0);
+    assert!(!stderr.contains("[trace]"));
+
+    let (code, _, stderr) = run_mdp(&[patch_args(traced.to_str().unwrap()), vec!["--trace"]].concat());
+    assert_eq!(code, 0);
+    for phase in ["read", "parse+resolve+apply", "write"] {
+        assert!(stderr.contains(&format!("[trace] {}", phase)), "{}", stderr);
+    }
+    assert!(stderr.lines().filter(|l| l.starts_with("[trace]")).all(|l| l.ends_with(" ms")));
+
+    // 结果与不加 --trace 时一致
+    assert_eq!(fs::read_to_string(&plain).unwrap(), fs::read_to_string(&traced).unwrap());
+
+    // 清理
+    let _ = fs::remove_file(&plain);
+    let _ = fs::remove_file(&traced);
+}
+
+// ============================================================================
+// 测试：按类型选择 block
+// ============================================================================
+
+#[test]
+fn test_patch_with_block_selector() {
+    let content = "# Doc\n\n## API\n\nIntro\n\n```sh\nold-one\n```\n\n```sh\nold-two\n```\n";
+    let file = create_test_file(content);
+    let file_str = file.to_str().unwrap();
+
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## API > code[1]",
+        "--op", "replace", "-c", "```sh\nnew-two\n```", "-p", "old-two", "--no-backup", "--force",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    let result = fs::read_to_string(&file).unwrap();
+    assert!(result.contains("old-one") && result.contains("new-two") && !result.contains("old-two"));
+
+    let (code, _, stderr) = run_mdp(&["patch", "-f", file_str, "-H", "## API > table", "--op", "delete", "--force"]);
+    assert_ne!(code, 0);
+    assert!(stderr.contains("No table[0] block"), "{}", stderr);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：未授权操作的 diff 预览
+// ============================================================================
+
+#[test]
+fn test_blocked_replace_shows_preview() {
+    let content = "# Doc\n\n## Target\n\nOld text\n";
+    let file = create_test_file(content);
+    let file_str = file.to_str().unwrap();
+
+    let (code, stdout, stderr) = run_mdp(&["patch", "-f", file_str, "-H", "## Target", "--op", "replace", "-c", "New text"]);
+    assert_ne!(code, 0);
+    assert!(stderr.contains("requires authorization"), "{}", stderr);
+    assert!(stdout.contains("Blocked"), "{}", stdout);
+    assert!(stdout.contains("-Old text") && stdout.contains("+New text"), "{}", stdout);
+    assert_eq!(fs::read_to_string(&file).unwrap(), content);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：JSON 输出中的警告
+// ============================================================================
+
+#[test]
+fn test_json_output_reports_fuzzy_match_warning() {
+    let content = "# Doc\n\n## API Usage\n\nIntro\n";
+    let file = create_test_file(content);
+    let file_str = file.to_str().unwrap();
+
+    // 默认不做模糊匹配，只在错误中给出建议
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## api  usage", "--op", "append", "-c", "More",
+        "--no-backup", "--force",
+    ]);
+    assert_eq!(code, 2, "{}", stderr);
+    assert!(stderr.contains("did you mean '## API Usage'? pass --fuzzy-headings to accept it"), "{}", stderr);
+    assert_eq!(fs::read_to_string(&file).unwrap(), content);
+
+    let (code, stdout, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## api  usage", "--fuzzy-headings", "--op", "append", "-c", "More",
+        "--no-backup", "--force", "--format", "json",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(json["warnings"][0], "Fuzzy heading match: '## api usage' resolved to '## API Usage'");
+    assert!(fs::read_to_string(&file).unwrap().contains("Intro\n\nMore"));
+
+    // 精确匹配时 warnings 为空数组
+    let (_, stdout, _) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## API Usage", "--op", "append", "-c", "Last",
+        "--no-backup", "--force", "--format", "json",
+    ]);
+    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(json["warnings"], serde_json::json!([]));
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：--heading-block 将 heading 行作为 block 操作
+// ============================================================================
+
+#[test]
+fn test_heading_block_replace_with_fingerprint() {
+    let content = "# Doc\n\n## Setup\n\nSteps\n";
+    let file = create_test_file(content);
+    let file_str = file.to_str().unwrap();
+
+    // fingerprint 匹配 heading 行，作为授权
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Setup", "--heading-block",
+        "--op", "replace", "-c", "## Installation", "-p", "^## Setup$", "--no-backup", "--force",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(fs::read_to_string(&file).unwrap(), "# Doc\n\n## Installation\n\nSteps\n");
+
+    // fingerprint 不匹配 heading 时以退出码 3 失败
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Installation", "--heading-block",
+        "--op", "delete", "-p", "Steps", "--no-backup",
+    ]);
+    assert_eq!(code, 3, "{}", stderr);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：--progress 在非终端环境下不输出进度条
+// ============================================================================
+
+#[test]
+fn test_progress_is_silent_without_tty() {
+    let file = create_test_file("# Doc\n\n## A\n\nOne\n");
+    let config = write_batch_config(&format!(
+        "  - file: {}\n    heading: [\"## A\"]\n    operation: append\n    content: \"Two\"\n",
+        file.display()
+    ));
+
+    let (code, stdout, stderr) = run_mdp(&[
+        "apply", config.to_str().unwrap(), "--force", "--no-backup", "--progress", "--format", "json",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    // 捕获的输出中不应出现进度条的绘制内容或终端控制序列
+    assert!(!stderr.contains("validating") && !stderr.contains('\u{1b}'), "{:?}", stderr);
+    assert!(!stdout.contains('\u{1b}'), "{:?}", stdout);
+    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(json["applied"], true);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+    let _ = fs::remove_file(&config);
+}
+
+// ============================================================================
+// 测试：merge 合并相邻的同类 block
+// ============================================================================
+
+#[test]
+fn test_merge_joins_adjacent_paragraphs() {
+    let file = create_test_file("# Doc\n\n## Notes\n\nFirst line.\n\nSecond line.\n\n- item\n\nAfter list.\n");
+    let file_str = file.to_str().unwrap();
+    let args = ["patch", "-f", file_str, "-H", "## Notes", "--op", "merge", "--no-backup"];
+
+    // 未 force 时只预览
+    let (code, stdout, _) = run_mdp(&args);
+    assert_ne!(code, 0);
+    assert!(stdout.contains("First line.\n-\n Second line."), "{}", stdout);
+
+    // 不同类型的相邻 block（段落与列表）保持分开
+    let (code, _, stderr) = run_mdp(&[&args[..], &["--force"]].concat());
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(
+        fs::read_to_string(&file).unwrap(),
+        "# Doc\n\n## Notes\n\nFirst line.\nSecond line.\n\n- item\n\nAfter list.\n"
+    );
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：--format jsonl 流式输出
+// ============================================================================
+
+#[test]
+fn test_plan_jsonl_lines_are_independent_json() {
+    let file = create_test_file("# Doc\n\n## A\n\nOne\n\n## B\n\nTwo\n");
+    let config = write_batch_config(&format!(
+        "  - file: {0}\n    heading: [\"## A\"]\n    operation: append\n    content: \"More A\"\n\
+         \x20 - file: {0}\n    heading: [\"## B\"]\n    operation: append\n    content: \"More B\"\n",
+        file.display()
+    ));
+
+    let (code, stdout, stderr) = run_mdp(&["plan", config.to_str().unwrap(), "--format", "jsonl"]);
+    assert_eq!(code, 0, "{}", stderr);
+    let lines: Vec<serde_json::Value> = stdout
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap_or_else(|e| panic!("{}: {:?}", e, line)))
+        .collect();
+    // 每个操作一行（解析时输出），随后每个文件一行
+    assert_eq!(lines.len(), 3, "{}", stdout);
+    assert_eq!(lines[0]["heading"], "## A");
+    assert_eq!(lines[0]["status"], "resolved");
+    assert_eq!(lines[1]["heading"], "## B");
+    assert_eq!(lines[2]["status"], "dry-run");
+    assert!(lines[2]["diff"].as_str().unwrap().contains("+More B"));
+
+    // 清理
+    let _ = fs::remove_file(&file);
+    let _ = fs::remove_file(&config);
+}
+
+// ============================================================================
+// 测试：--verify-structure 在写入前重新解析校验结构
+// ============================================================================
+
+#[test]
+fn test_verify_structure_rejects_accidental_heading() {
+    let content = "# Doc\n\n## Notes\n\nFirst.\n\n## Next\n\nMore.\n";
+    let file = create_test_file(content);
+    let file_str = file.to_str().unwrap();
+
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Notes", "--op", "append",
+        "-c", "#hashtag\n# not meant as a heading", "--verify-structure", "--force", "--no-backup",
+    ]);
+    assert_ne!(code, 0);
+    assert!(stderr.contains("Structure verification failed"), "{}", stderr);
+    assert_eq!(fs::read_to_string(&file).unwrap(), content);
+
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Notes", "--op", "append",
+        "-c", "Second.", "--verify-structure", "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert!(fs::read_to_string(&file).unwrap().contains("First.\n\nSecond.\n\n## Next"));
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：--keep-going 列出歧义 heading 的所有候选
+// ============================================================================
+
+#[test]
+fn test_keep_going_reports_ambiguous_candidates() {
+    let content = "# Guide\n\n## Notes\n\nA\n\n# Reference\n\n## Notes\n\nB\n";
+    let file = create_test_file(content);
+    let file_str = file.to_str().unwrap();
+
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Notes", "--op", "append", "-c", "C", "--keep-going", "--force",
+    ]);
+    assert_eq!(code, 4, "{}", stderr);
+    assert!(stderr.contains("-H '# Guide ## Notes' (or --occurrence 0)"), "{}", stderr);
+    assert!(stderr.contains("-H '# Reference ## Notes' (or --occurrence 1)"), "{}", stderr);
+    assert_eq!(fs::read_to_string(&file).unwrap(), content);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：--coarse-blocks 将连续段落视为一个 block
+// ============================================================================
+
+#[test]
+fn test_coarse_blocks_replace_targets_whole_body() {
+    let file = create_test_file("# Doc\n\n## About\n\nFirst paragraph.\n\nSecond paragraph.\n\n```sh\nmdp --help\n```\n");
+    let file_str = file.to_str().unwrap();
+
+    // 粗粒度下段落合并为一个 block，代码块仍独立
+    let (_, fine, _) = run_mdp(&["parse", "-f", file_str]);
+    let (_, coarse, _) = run_mdp(&["parse", "-f", file_str, "--coarse-blocks"]);
+    assert_eq!(fine.matches("paragraph").count(), 2, "{}", fine);
+    assert_eq!(coarse.matches("paragraph").count(), 1, "{}", coarse);
+    assert_eq!(coarse.matches("code").count(), 1, "{}", coarse);
+
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## About", "-i", "0", "--op", "replace",
+        "-c", "One paragraph now.", "--coarse-blocks", "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(
+        fs::read_to_string(&file).unwrap(),
+        "# Doc\n\n## About\n\nOne paragraph now.\n\n```sh\nmdp --help\n```\n"
+    );
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：--ignore-emoji 忽略 heading 中的 emoji
+// ============================================================================
+
+#[test]
+fn test_ignore_emoji_matches_shortcode_heading() {
+    let file = create_test_file("# Doc\n\n## :rocket: Launch\n\nCountdown.\n");
+    let file_str = file.to_str().unwrap();
+
+    let args = ["patch", "-f", file_str, "-H", "## Launch", "--op", "append", "-c", "Liftoff.", "--force", "--no-backup"];
+    let (code, _, _) = run_mdp(&args);
+    assert_eq!(code, 2);
+
+    let (code, _, stderr) = run_mdp(&[&args[..], &["--ignore-emoji"]].concat());
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(
+        fs::read_to_string(&file).unwrap(),
+        "# Doc\n\n## :rocket: Launch\n\nCountdown.\n\nLiftoff.\n"
+    );
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：apply --output-dir 写入镜像目录，源文件不变
+// ============================================================================
+
+#[test]
+fn test_apply_output_dir_leaves_sources_untouched() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir_all(dir.path().join("docs/guide")).unwrap();
+    fs::write(dir.path().join("docs/index.md"), "# Index\n\n## Links\n\n- home\n").unwrap();
+    fs::write(dir.path().join("docs/guide/setup.md"), "# Setup\n\n## Steps\n\nInstall.\n").unwrap();
+    fs::write(
+        dir.path().join("patches.yaml"),
+        "operations:\n\
+         \x20 - file: docs/index.md\n    heading: [\"## Links\"]\n    operation: list-append\n    content: \"- guide\"\n\
+         \x20 - file: docs/guide/setup.md\n    heading: [\"## Steps\"]\n    operation: append\n    content: \"Run.\"\n",
+    )
+    .unwrap();
+
+    let output = Command::new(mdp_bin())
+        .args(["apply", "patches.yaml", "--force", "--output-dir", "out"])
+        .current_dir(dir.path())
+        .output()
+        .expect("Failed to execute mdp");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // 源文件保持不变，且没有备份
+    assert_eq!(fs::read_to_string(dir.path().join("docs/index.md")).unwrap(), "# Index\n\n## Links\n\n- home\n");
+    assert_eq!(fs::read_to_string(dir.path().join("docs/guide/setup.md")).unwrap(), "# Setup\n\n## Steps\n\nInstall.\n");
+    assert!(!dir.path().join("docs/index.md.bak").exists());
+
+    assert_eq!(
+        fs::read_to_string(dir.path().join("out/docs/index.md")).unwrap(),
+        "# Index\n\n## Links\n\n- home\n- guide\n"
+    );
+    assert_eq!(
+        fs::read_to_string(dir.path().join("out/docs/guide/setup.md")).unwrap(),
+        "# Setup\n\n## Steps\n\nInstall.\n\nRun.\n"
+    );
+}
+
+// ============================================================================
+// 测试：--heading-regex 按正则匹配 heading
+// ============================================================================
+
+#[test]
+fn test_heading_regex_matches_version_heading() {
+    let file = create_test_file("# Changelog\n\n## Unreleased\n\n- wip\n\n## v2.0\n\n- big\n\n## v1.9\n\n- small\n");
+    let file_str = file.to_str().unwrap();
+
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", r"^## v2\.\d+$", "--heading-regex",
+        "--op", "list-append", "--content=- also big", "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert!(fs::read_to_string(&file).unwrap().contains("- big\n- also big\n\n## v1.9"));
+
+    // 多个版本匹配时报告歧义（退出码 4）
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", r"^## v\d+\.\d+$", "--heading-regex",
+        "--op", "list-append", "--content=- ambiguous", "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 4, "{}", stderr);
+    assert!(!fs::read_to_string(&file).unwrap().contains("ambiguous"));
+
+    // 非法正则在读取文件之前报错
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", "/nonexistent/doc.md", "-H", r"^## v(\d+$", "--heading-regex",
+        "--op", "list-append", "--content=- x", "--force",
+    ]);
+    assert_ne!(code, 0);
+    assert!(stderr.contains(r"Invalid heading regex '^## v(\d+$'"), "{}", stderr);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：ensure 操作按 fingerprint 更新或追加
+// ============================================================================
+
+#[test]
+fn test_ensure_updates_or_appends() {
+    let file = create_test_file("# Status\n\nIntro.\n\nVersion: 1.0\n\n## Notes\n\nNone.\n");
+    let file_str = file.to_str().unwrap();
+    let args = |content: &'static str| {
+        vec![
+            "patch", "-f", file_str, "-H", "# Status", "--op", "ensure", "-p", "^Version:",
+            "-c", content, "--force", "--no-backup",
+        ]
+    };
+
+    // 已存在匹配的 block：原地替换
+    let (code, _, stderr) = run_mdp(&args("Version: 1.1"));
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(
+        fs::read_to_string(&file).unwrap(),
+        "# Status\n\nIntro.\n\nVersion: 1.1\n\n## Notes\n\nNone.\n"
+    );
+
+    // 不存在时追加到 section 末尾；再次执行不重复追加
+    fs::write(&file, "# Status\n\nIntro.\n\n## Notes\n\nNone.\n").unwrap();
+    for _ in 0..2 {
+        let (code, _, stderr) = run_mdp(&args("Version: 1.1"));
+        assert_eq!(code, 0, "{}", stderr);
+    }
+    assert_eq!(
+        fs::read_to_string(&file).unwrap(),
+        "# Status\n\nIntro.\n\nVersion: 1.1\n\n## Notes\n\nNone.\n"
+    );
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：--max-file-bytes 在读取前拒绝超大文件
+// ============================================================================
+
+#[test]
+fn test_max_file_bytes_guard() {
+    let content = format!("# Big\n\n{}\n", "filler text ".repeat(200));
+    let file = create_test_file(&content);
+    let file_str = file.to_str().unwrap();
+
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "# Big", "--op", "append", "-c", "More.",
+        "--max-file-bytes", "1024", "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 1);
+    assert!(stderr.contains("exceeding --max-file-bytes 1024"), "{}", stderr);
+    assert_eq!(fs::read_to_string(&file).unwrap(), content);
+
+    // 批量模式同样生效
+    let config = write_batch_config(&format!(
+        "  - file: {}\n    heading: [\"# Big\"]\n    operation: append\n    content: \"More.\"\n",
+        file_str
+    ));
+    let (code, _, stderr) = run_mdp(&["plan", config.to_str().unwrap(), "--max-file-bytes", "1024"]);
+    assert_eq!(code, 1);
+    assert!(stderr.contains("exceeding --max-file-bytes 1024"), "{}", stderr);
+
+    // 上限足够时正常执行
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "# Big", "--op", "append", "-c", "More.",
+        "--max-file-bytes", "100000", "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+    let _ = fs::remove_file(&config);
+}
+
+// ============================================================================
+// 测试：--fingerprint-file 从文件读取 fingerprint
+// ============================================================================
+
+#[test]
+fn test_fingerprint_file() {
+    let file = create_test_file("# Config\n\nport = \"8080\" # default\n");
+    let file_str = file.to_str().unwrap();
+    // 包含引号与 `#` 的模式在命令行上难以转义
+    let pattern = create_test_file("^port = \"\\d+\" # default$\n");
+    let pattern_str = pattern.to_str().unwrap();
+
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "# Config", "--op", "replace",
+        "--fingerprint-file", pattern_str, "-c", "port = \"9090\"", "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(fs::read_to_string(&file).unwrap(), "# Config\n\nport = \"9090\"\n");
+
+    // 不再匹配：fingerprint 校验失败（退出码 3）
+    let (code, _, _) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "# Config", "--op", "replace",
+        "--fingerprint-file", pattern_str, "-c", "port = \"1\"", "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 3);
+
+    // 与 -p 互斥
+    let (code, _, _) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "# Config", "--op", "replace",
+        "--fingerprint-file", pattern_str, "-p", "port", "-c", "x",
+    ]);
+    assert_ne!(code, 0);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+    let _ = fs::remove_file(&pattern);
+}
+
+// ============================================================================
+// 测试：--profile 控制表格与 HTML 块的识别
+// ============================================================================
+
+#[test]
+fn test_profile_controls_block_features() {
+    let file = create_test_file("# Data\n\n| a | b |\n|---|---|\n| 1 | 2 |\n\n<3 markdown\n");
+    let file_str = file.to_str().unwrap();
+    let block_types = |profile: &str| -> Vec<String> {
+        let (code, stdout, stderr) = run_mdp(&["parse", "-f", file_str, "--format", "json", "--profile", profile]);
+        assert_eq!(code, 0, "{}", stderr);
+        let sections: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+        sections[0]["blocks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|b| b["block_type"]["type"].as_str().unwrap().to_string())
+            .collect()
+    };
+
+    assert_eq!(block_types("gfm"), ["table", "paragraph"]);
+    // CommonMark 没有表格扩展：表格行只是段落
+    assert_eq!(block_types("commonmark"), ["paragraph", "paragraph"]);
+    // 默认（internal）规则下任何以 `<` 开头的行都开启 HTML 块
+    assert_eq!(block_types("internal"), ["table", "html"]);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：insert-sorted 保持列表有序
+// ============================================================================
+
+#[test]
+fn test_insert_sorted_alphabetized_list() {
+    let file = create_test_file("# Tools\n\n- [cargo](https://c)\n- [rustc](https://r)\n- [rustup](https://u)\n");
+    let file_str = file.to_str().unwrap();
+
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "# Tools", "--op", "insert-sorted",
+        "--content=- [clippy](https://y)", "--sort-key", r"\[(\w+)\]", "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(
+        fs::read_to_string(&file).unwrap(),
+        "# Tools\n\n- [cargo](https://c)\n- [clippy](https://y)\n- [rustc](https://r)\n- [rustup](https://u)\n"
+    );
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：short 格式报告行数与字节数变化
+// ============================================================================
+
+#[test]
+fn test_short_format_reports_line_and_byte_deltas() {
+    let file = create_test_file("# Doc\n\nOld line.\n");
+    let file_str = file.to_str().unwrap();
+
+    // 删除 "Old line."（10 字节），新增三行共 20 字节（含换行；`---` 开头的内容行也计入）
+    let (code, stdout, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "# Doc", "--op", "replace", "-c", "New line.\n\n--- rule",
+        "--force", "--no-backup", "-F", "short",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert!(
+        stdout.contains("Applied: +3/-1 lines (net +2), +20/-10 bytes"),
+        "{}",
+        stdout
+    );
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：--merge-append 更新近似重复的 block 并给出警告
+// ============================================================================
+
+#[test]
+fn test_merge_append_warns_on_near_duplicate() {
+    let file = create_test_file("# Install\n\nInstall with cargo install md-patch to get the mdp binary.\n");
+    let file_str = file.to_str().unwrap();
+
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "# Install", "--op", "append",
+        "-c", "Install with cargo install md-patch to get the mdp binary quickly.",
+        "--merge-append", "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert!(stderr.contains("Merge-append: updated the similar block at line 3"), "{}", stderr);
+    assert_eq!(
+        fs::read_to_string(&file).unwrap(),
+        "# Install\n\nInstall with cargo install md-patch to get the mdp binary quickly.\n"
+    );
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：--emit both 将文档输出到 stdout、diff 输出到 stderr
+// ============================================================================
+
+#[test]
+fn test_emit_both_document_and_diff() {
+    let original = "# Notes\n\nFirst.\n";
+    let file = create_test_file(original);
+    let file_str = file.to_str().unwrap();
+
+    let (code, stdout, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "# Notes", "--op", "append", "-c", "Second.", "--emit", "both",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(stdout, "# Notes\n\nFirst.\n\nSecond.\n");
+    assert!(stderr.contains("+++ b/"), "{}", stderr);
+    assert!(stderr.contains("+Second.\n"), "{}", stderr);
+
+    // 原文件保持不变，也不产生备份
+    assert_eq!(fs::read_to_string(&file).unwrap(), original);
+    assert!(!file.with_extension("md.bak").exists());
+
+    // 未授权的破坏性操作仍被阻止
+    let (code, _, _) = run_mdp(&["patch", "-f", file_str, "-H", "# Notes", "--op", "delete", "--emit", "document"]);
+    assert_ne!(code, 0);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：convert 操作转换 block 类型
+// ============================================================================
+
+#[test]
+fn test_convert_list_to_quote() {
+    let file = create_test_file("# Quotes\n\n- Simple is better\n- than complex\n");
+    let file_str = file.to_str().unwrap();
+
+    // 缺少 --force 时被阻止
+    let (code, _, _) = run_mdp(&["patch", "-f", file_str, "-H", "# Quotes", "--op", "convert", "--to", "quote"]);
+    assert_ne!(code, 0);
+
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "# Quotes", "--op", "convert", "--to", "quote", "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(fs::read_to_string(&file).unwrap(), "# Quotes\n\n> Simple is better\n> than complex\n");
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：get 输出目标 block 的原始内容
+// ============================================================================
+
+#[test]
+fn test_get_prints_block_content() {
+    let content = "# Guide\n\nSome *intro* text\nover two lines.\n\n```rust\nfn main() {\n    println!(\"hi\");\n}\n```\n";
+    let file = create_test_file(content);
+    let file_str = file.to_str().unwrap();
+
+    let (code, stdout, stderr) = run_mdp(&["get", "-f", file_str, "-H", "# Guide", "-i", "0"]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(stdout, "Some *intro* text\nover two lines.\n");
+
+    let (code, stdout, _) = run_mdp(&["get", "-f", file_str, "-H", "# Guide", "-i", "1"]);
+    assert_eq!(code, 0);
+    assert_eq!(stdout, "```rust\nfn main() {\n    println!(\"hi\");\n}\n```\n");
+
+    let (code, stdout, _) = run_mdp(&["get", "-f", file_str, "-H", "# Guide", "-i", "1", "--format", "json"]);
+    assert_eq!(code, 0);
+    let block: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(block["block_type"]["type"], "code");
+    assert_eq!(block["block_type"]["lang"], "rust");
+    assert_eq!(block["start_line"], 6);
+    assert_eq!(block["end_line"], 10);
+    let (start, end) = (block["start"].as_u64().unwrap() as usize, block["end"].as_u64().unwrap() as usize);
+    assert_eq!(&content[start..end], block["content"].as_str().unwrap());
+
+    // 文件保持不变
+    assert_eq!(fs::read_to_string(&file).unwrap(), content);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：heading 结束序列与 --plain-headings
+// ============================================================================
+
+#[test]
+fn test_plain_headings_match_formatted_heading() {
+    let file = create_test_file("# Doc\n\n## **Bold** ##\n\nOld.\n");
+    let file_str = file.to_str().unwrap();
+
+    // 结束的 `##` 默认去掉，但行内强调需要 --plain-headings
+    let (code, _, _) = run_mdp(&["patch", "-f", file_str, "-H", "## Bold", "--op", "append", "-c", "New.", "--force"]);
+    assert_eq!(code, 2);
+
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Bold", "--plain-headings", "--op", "append", "-c", "New.",
+        "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(fs::read_to_string(&file).unwrap(), "# Doc\n\n## **Bold** ##\n\nOld.\n\nNew.\n");
+
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## **Bold**", "--op", "append", "-c", "Newer.", "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：append-line-if-absent 按行 key 判断是否已存在
+// ============================================================================
+
+#[test]
+fn test_append_line_if_absent_paragraph() {
+    let file = create_test_file("# Supported\n\nLinux: yes\nmacOS: yes\n");
+    let file_str = file.to_str().unwrap();
+    let args = |line: &'static str| {
+        vec![
+            "patch", "-f", file_str, "-H", "# Supported", "--op", "append-line-if-absent", "-c", line,
+            "--line-key", "^Windows:", "--force", "--no-backup", "--report-unchanged",
+        ]
+    };
+
+    let (code, _, stderr) = run_mdp(&args("Windows: partial"));
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(fs::read_to_string(&file).unwrap(), "# Supported\n\nLinux: yes\nmacOS: yes\nWindows: partial\n");
+
+    let (code, stdout, stderr) = run_mdp(&args("Windows: yes"));
+    assert_eq!(code, 0, "{}", stderr);
+    assert!(stdout.contains("Unchanged: append-line-if-absent skipped: line 5 already matches"), "{}", stdout);
+    assert_eq!(fs::read_to_string(&file).unwrap(), "# Supported\n\nLinux: yes\nmacOS: yes\nWindows: partial\n");
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：--exit-code-map 重新映射退出码
+// ============================================================================
+
+#[test]
+fn test_exit_code_map_remaps_heading_not_found() {
+    let file = create_test_file("# Doc\n\nText.\n");
+    let file_str = file.to_str().unwrap();
+    let missing = ["patch", "-f", file_str, "-H", "## Missing", "--op", "append", "-c", "x"];
+
+    let (code, _, _) = run_mdp(&missing);
+    assert_eq!(code, 2);
+
+    let mut args = missing.to_vec();
+    args.extend(["--exit-code-map", "heading_not_found=40,fingerprint_mismatch=41"]);
+    let (code, _, stderr) = run_mdp(&args);
+    assert_eq!(code, 40, "{}", stderr);
+
+    // 未重新映射的类别保持默认退出码
+    let (code, _, _) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "# Doc", "--op", "replace", "-p", "^Other", "-c", "x",
+        "--exit-code-map", "heading_not_found=40",
+    ]);
+    assert_eq!(code, 3);
+
+    // 未知名称在参数解析阶段报错
+    let mut args = missing.to_vec();
+    args.extend(["--exit-code-map", "not_a_name=40"]);
+    let (code, _, stderr) = run_mdp(&args);
+    assert_ne!(code, 40);
+    assert!(stderr.contains("unknown exit code name 'not_a_name'"), "{}", stderr);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：definition list 与 definition-append 操作
+// ============================================================================
+
+#[test]
+fn test_definition_append_cli() {
+    let file = create_test_file("# Glossary\n\nBlock\n: A unit of content.\n");
+    let file_str = file.to_str().unwrap();
+    let args = [
+        "patch", "-f", file_str, "-H", "# Glossary", "--op", "definition-append", "-c",
+        "Heading\n: A line starting with #.", "--force", "--no-backup",
+    ];
+    let expected = "# Glossary\n\nBlock\n: A unit of content.\nHeading\n: A line starting with #.\n";
+
+    let (code, _, stderr) = run_mdp(&args);
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(fs::read_to_string(&file).unwrap(), expected);
+
+    // 重复执行不再追加
+    let (code, _, stderr) = run_mdp(&args);
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(fs::read_to_string(&file).unwrap(), expected);
+
+    // commonmark profile 下没有 definition list，目标退化为段落
+    let (code, _, stderr) = run_mdp(&[
+        "--profile", "commonmark", "patch", "-f", file_str, "-H", "# Glossary", "--op", "definition-append", "-c",
+        "Term\n: Def.", "--force", "--no-backup",
+    ]);
+    assert_ne!(code, 0);
+    assert!(stderr.contains("requires a definition list block"), "{}", stderr);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：--dry-run-json 输出完整的候选文档
+// ============================================================================
+
+#[test]
+fn test_dry_run_json_new_content_matches_applied() {
+    let original = "# Notes\n\nFirst.\n\n## Later\n\nText.\n";
+    let file = create_test_file(original);
+    let file_str = file.to_str().unwrap();
+    let args = ["patch", "-f", file_str, "-H", "# Notes", "--op", "append", "-c", "Second."];
+
+    let mut dry = args.to_vec();
+    dry.push("--dry-run-json");
+    let (code, stdout, stderr) = run_mdp(&dry);
+    assert_eq!(code, 0, "{}", stderr);
+    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(json["applied"], false);
+    assert_eq!(json["changes"][0]["status"], "dry-run");
+    assert_eq!(fs::read_to_string(&file).unwrap(), original);
+
+    let mut applied = args.to_vec();
+    applied.extend(["--force", "--no-backup", "-F", "json"]);
+    let (code, stdout, stderr) = run_mdp(&applied);
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(json["new_content"], fs::read_to_string(&file).unwrap());
+
+    // 普通 JSON 输出不包含 new_content
+    let applied_json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert!(applied_json.get("new_content").is_none());
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：tangle 将链接到文件的代码块导出
+// ============================================================================
+
+#[test]
+fn test_tangle_extracts_linked_code_blocks() {
+    let dir = tempfile::tempdir().unwrap();
+    let original = "# Lib\n\n```rust file=src/lib.rs\npub fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n```\n\n\
+                    ## Script\n\n<!-- file: scripts/build.sh -->\n\n```sh\ncargo build\n```\n\n```rust\n// not linked\n```\n";
+    let doc = dir.path().join("doc.md");
+    fs::write(&doc, original).unwrap();
+    let out = dir.path().join("out");
+
+    let (code, stdout, stderr) = run_mdp(&["tangle", "-f", doc.to_str().unwrap(), "--out-dir", out.to_str().unwrap()]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert!(stdout.contains("lib.rs (3 lines)"), "{}", stdout);
+    assert_eq!(
+        fs::read_to_string(out.join("src/lib.rs")).unwrap(),
+        "pub fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n"
+    );
+    assert_eq!(fs::read_to_string(out.join("scripts/build.sh")).unwrap(), "cargo build\n");
+    assert_eq!(fs::read_to_string(&doc).unwrap(), original);
+
+    // --lang 过滤
+    let rust_only = dir.path().join("rust-only");
+    let (code, _, stderr) = run_mdp(&[
+        "tangle", "-f", doc.to_str().unwrap(), "--out-dir", rust_only.to_str().unwrap(), "--lang", "rust",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert!(rust_only.join("src/lib.rs").exists());
+    assert!(!rust_only.join("scripts/build.sh").exists());
+
+    // 拒绝逃出输出目录的路径
+    fs::write(&doc, "# Doc\n\n```sh file=../escape.sh\nrm -rf /\n```\n").unwrap();
+    let (code, _, stderr) = run_mdp(&["tangle", "-f", doc.to_str().unwrap(), "--out-dir", out.to_str().unwrap()]);
+    assert_ne!(code, 0);
+    assert!(stderr.contains("must be a relative path"), "{}", stderr);
+    assert!(!dir.path().join("escape.sh").exists());
+}
+
+// ============================================================================
+// 测试：weave 用源文件内容更新代码块
+// ============================================================================
+
+#[test]
+fn test_weave_updates_linked_code_block() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir_all(dir.path().join("src")).unwrap();
+    fs::write(dir.path().join("src/lib.rs"), "pub fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n").unwrap();
+    let doc = dir.path().join("doc.md");
+    fs::write(&doc, "# Lib\n\n```rust file=src/lib.rs\npub fn add() {}\n```\n\nText.\n").unwrap();
+    let args = [
+        "weave", "-f", doc.to_str().unwrap(), "--from", dir.path().to_str().unwrap(), "--force", "--no-backup",
+    ];
+    let expected = "# Lib\n\n```rust file=src/lib.rs\npub fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n```\n\nText.\n";
+
+    let (code, stdout, stderr) = run_mdp(&args);
+    assert_eq!(code, 0, "{}", stderr);
+    assert!(stdout.contains("+    a + b"), "{}", stdout);
+    assert_eq!(fs::read_to_string(&doc).unwrap(), expected);
+
+    // 已同步时为 noop
+    let (code, stdout, stderr) = run_mdp(&args);
+    assert_eq!(code, 0, "{}", stderr);
+    assert!(stdout.contains("No changes"), "{}", stdout);
+    assert_eq!(fs::read_to_string(&doc).unwrap(), expected);
+
+    // tangle 之后再 weave 不产生变化
+    let (code, _, stderr) = run_mdp(&["tangle", "-f", doc.to_str().unwrap(), "--out-dir", dir.path().to_str().unwrap()]);
+    assert_eq!(code, 0, "{}", stderr);
+    let (code, stdout, _) = run_mdp(&args);
+    assert_eq!(code, 0);
+    assert!(stdout.contains("No changes"), "{}", stdout);
+}
+
+// ============================================================================
+// 测试：--region 将操作限制在受管区域内
+// ============================================================================
+
+#[test]
+fn test_region_scopes_patch() {
+    let original = "# Guide\n\nIntro by hand.\n\n<!-- mdp:begin api -->\n## Endpoints\n\n- GET /a\n<!-- mdp:end api -->\n\n## Endpoints\n\nHand-written notes.\n";
+    let file = create_test_file(original);
+    let file_str = file.to_str().unwrap();
+
+    // 区域内的同名 heading 唯一解析，不会误改区域外的那一个
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Endpoints", "--op", "list-append", "--content=- GET /b", "--region", "api",
+        "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert!(fs::read_to_string(&file).unwrap().contains("- GET /a\n- GET /b\n<!-- mdp:end api -->"));
+
+    // 区域外的 heading 找不到
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "# Guide", "--op", "replace", "-c", "x", "--region", "api", "--force",
+        "--no-backup",
+    ]);
+    assert_ne!(code, 0);
+    assert!(stderr.contains("not found") || stderr.contains("out of range"), "{}", stderr);
+
+    // 未知区域
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Endpoints", "--op", "append", "-c", "x", "--region", "nope",
+    ]);
+    assert_ne!(code, 0);
+    assert!(stderr.contains("Region 'nope' not found"), "{}", stderr);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：--normalize-eol 统一写出的换行符
+// ============================================================================
+
+#[test]
+fn test_normalize_eol_mixed_to_lf() {
+    let file = create_test_file("# Doc\r\n\r\nFirst.\r\n\r\n## Sub\n\nText.\r\n");
+    let file_str = file.to_str().unwrap();
+
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Sub", "--op", "append", "-c", "Added.", "--normalize-eol", "lf",
+        "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(
+        fs::read_to_string(&file).unwrap(),
+        "# Doc\n\nFirst.\n\n## Sub\n\nText.\n\nAdded.\n"
+    );
+
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Sub", "--op", "append", "-c", "Added.", "--normalize-eol", "crlf",
+        "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(
+        fs::read_to_string(&file).unwrap(),
+        "# Doc\r\n\r\nFirst.\r\n\r\n## Sub\r\n\r\nText.\r\n\r\nAdded.\r\n"
+    );
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：--block-hash 按内容哈希前缀定位 block
+// ============================================================================
+
+#[test]
+fn test_block_hash_addressing() {
+    let file = create_test_file("# Doc\n\nFirst.\n\nSecond.\n");
+    let file_str = file.to_str().unwrap();
+
+    let (code, stdout, stderr) = run_mdp(&["get", "-f", file_str, "-H", "# Doc", "-i", "1", "--format", "json"]);
+    assert_eq!(code, 0, "{}", stderr);
+    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    let hash = json["sha256"].as_str().unwrap().to_string();
+
+    // 在前面插入 block 后，哈希地址仍指向同一个 block
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "# Doc", "--op", "append", "-c", "Zeroth.", "--after-heading", "--force",
+        "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "# Doc", "--block-hash", &hash[..10], "--op", "replace", "-c", "Second!",
+        "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(fs::read_to_string(&file).unwrap(), "# Doc\n\nZeroth.\n\nFirst.\n\nSecond!\n");
+
+    // 内容已变化：哈希不再匹配（与 fingerprint 不匹配使用同一退出码）
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "# Doc", "--block-hash", &hash[..10], "--op", "replace", "-c", "x", "--force",
+    ]);
+    assert_eq!(code, 3, "{}", stderr);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：depends_on 让操作基于前一操作的结果重新解析
+// ============================================================================
+
+#[test]
+fn test_batch_depends_on_created_heading() {
+    let file = create_test_file("# Doc\n\nIntro.\n");
+    let config = write_batch_config(&format!(
+        "  - file: {0}\n    id: add-section\n    heading: [\"# Doc\"]\n    operation: append\n    content: \"## Changelog\\n\\nTBD\"\n\
+         \x20 - file: {0}\n    depends_on: [add-section]\n    heading: [\"# Doc\", \"## Changelog\"]\n    operation: replace\n    fingerprint: \"^TBD$\"\n    content: \"- Initial release\"\n",
+        file.display()
+    ));
+    let config_str = config.to_str().unwrap();
+
+    // 预览：依赖未写入，依赖它的操作基于预览内容求值
+    let (code, stdout, stderr) = run_mdp(&["plan", config_str]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert!(stdout.contains("+- Initial release"), "{}", stdout);
+    assert_eq!(fs::read_to_string(&file).unwrap(), "# Doc\n\nIntro.\n");
+
+    let (code, _, stderr) = run_mdp(&["apply", config_str, "--force", "--no-backup"]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(
+        fs::read_to_string(&file).unwrap(),
+        "# Doc\n\nIntro.\n\n## Changelog\n\n- Initial release\n"
+    );
+
+    // 未声明依赖时，第二个操作在预先解析阶段就找不到 heading
+    let without = fs::read_to_string(&config).unwrap().replace("    depends_on: [add-section]\n", "");
+    fs::write(&config, without).unwrap();
+    fs::write(&file, "# Doc\n\nIntro.\n").unwrap();
+    let (code, _, _) = run_mdp(&["apply", config_str, "--force", "--no-backup"]);
+    assert_eq!(code, 2);
+
+    // 依赖必须指向更早声明的 id
+    fs::write(&config, format!(
+        "operations:\n  - file: {}\n    depends_on: [missing]\n    heading: [\"# Doc\"]\n    operation: append\n    content: x\n",
+        file.display()
+    ))
+    .unwrap();
+    let (code, _, stderr) = run_mdp(&["apply", config_str]);
+    assert_ne!(code, 0);
+    assert!(stderr.contains("depends_on 'missing'"), "{}", stderr);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+    let _ = fs::remove_file(&config);
+}
+
+// ============================================================================
+// 测试：--summary-only
+// ============================================================================
+
+#[test]
+fn test_batch_summary_only_json() {
+    let a = create_test_file("# A\n\nOne.\n");
+    let b = create_test_file("# B\n\nTwo.\n");
+    let config = write_batch_config(&format!(
+        "  - file: {0}\n    heading: [\"# A\"]\n    operation: append\n    content: \"Added.\"\n\
+         \x20 - file: {1}\n    heading: [\"# B\"]\n    operation: append-line-if-absent\n    content: \"Two.\"\n",
+        a.display(),
+        b.display()
+    ));
+    let config_str = config.to_str().unwrap();
+
+    // 只有汇总，没有逐个文件/操作的明细
+    let (code, stdout, stderr) = run_mdp(&["plan", config_str, "--summary-only"]);
+    assert_eq!(code, 0, "{}", stderr);
+    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert!(json.get("files").is_none() && json.get("changes").is_none(), "{}", stdout);
+    assert_eq!(json["operations"], 2);
+    assert_eq!(json["applied"], 0);
+    assert_eq!(json["pending"], 1);
+    assert_eq!(json["noop"], 1);
+    assert_eq!(json["files_touched"], 1);
+    let added = json["bytes_added"].as_i64().unwrap();
+    assert!(added > 0);
+    assert_eq!(json["byte_delta"].as_i64().unwrap(), added - json["bytes_removed"].as_i64().unwrap());
+
+    let (code, stdout, stderr) = run_mdp(&["apply", config_str, "--force", "--no-backup", "--summary-only"]);
+    assert_eq!(code, 0, "{}", stderr);
+    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(json["applied"], 1);
+    assert_eq!(json["pending"], 0);
+    assert_eq!(json["noop"], 1);
+    assert_eq!(fs::read_to_string(&a).unwrap(), "# A\n\nOne.\n\nAdded.\n");
+
+    // 清理
+    let _ = fs::remove_file(&a);
+    let _ = fs::remove_file(&b);
+    let _ = fs::remove_file(&config);
+}
+
+// ============================================================================
+// 测试：trim-trailing
+// ============================================================================
+
+#[test]
+fn test_trim_trailing_whole_file_cli() {
+    let file = create_test_file("# Doc \n\nLine one. \t\r\n\n## Sub\n\n- item\t\n");
+    let file_str = file.to_str().unwrap();
+
+    let (code, _, stderr) = run_mdp(&["patch", "-f", file_str, "-o", "trim-trailing", "--whole-file", "--force", "--no-backup"]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(fs::read_to_string(&file).unwrap(), "# Doc\n\nLine one.\r\n\n## Sub\n\n- item\n");
+
+    // 已经干净：noop
+    let (code, stdout, _) = run_mdp(&[
+        "patch", "-f", file_str, "-o", "trim-trailing", "--whole-file", "--force", "--no-backup", "--report-unchanged",
+    ]);
+    assert_eq!(code, 0);
+    assert!(stdout.contains("Unchanged: trim-trailing skipped: no line ends with whitespace"), "{}", stdout);
+
+    // --whole-file 只适用于 trim-trailing
+    let (code, _, stderr) = run_mdp(&["patch", "-f", file_str, "-o", "append", "-c", "x", "--whole-file"]);
+    assert_ne!(code, 0);
+    assert!(stderr.contains("--whole-file only applies to trim-trailing"), "{}", stderr);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：--index-range 批量删除
+// ============================================================================
+
+#[test]
+fn test_delete_index_range_cli() {
+    let original = "# Doc\n\n## Log\n\nKeep first.\n\nOld 1.\n\nOld 2.\n\nOld 3.\n\nKeep last.\n\n## Next\n\nUntouched.\n";
+    let file = create_test_file(original);
+    let file_str = file.to_str().unwrap();
+
+    // 需要 --force
+    let (code, _, _) = run_mdp(&["patch", "-f", file_str, "-H", "## Log", "-o", "delete", "--index-range", "1", "3"]);
+    assert_ne!(code, 0);
+    assert_eq!(fs::read_to_string(&file).unwrap(), original);
+
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Log", "-o", "delete", "--index-range", "1", "3", "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(
+        fs::read_to_string(&file).unwrap(),
+        "# Doc\n\n## Log\n\nKeep first.\n\nKeep last.\n\n## Next\n\nUntouched.\n"
+    );
+
+    // 只适用于 delete
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Log", "-o", "replace", "-c", "x", "--index-range", "0", "1", "--force",
+    ]);
+    assert_ne!(code, 0);
+    assert!(stderr.contains("--index-range only applies to delete"), "{}", stderr);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：<!-- mdp:ignore --> 指令
+// ============================================================================
+
+#[test]
+fn test_ignore_directive_protects_block() {
+    let original = "# Notes\n\nAuto.\n\n<!-- mdp:ignore -->\n\nHand-maintained.\n\nAlso auto.\n";
+    let file = create_test_file(original);
+    let file_str = file.to_str().unwrap();
+
+    // 被忽略的 block 不计入索引：索引 1 是其后的 block
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "# Notes", "-i", "1", "-o", "replace", "-c", "Regenerated.", "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(
+        fs::read_to_string(&file).unwrap(),
+        "# Notes\n\nAuto.\n\n<!-- mdp:ignore -->\n\nHand-maintained.\n\nRegenerated.\n"
+    );
+
+    // 按行号定位被忽略的 block：报错
+    let (code, _, stderr) = run_mdp(&["patch", "-f", file_str, "--line", "7", "-o", "delete", "--force"]);
+    assert_ne!(code, 0);
+    assert!(stderr.contains("cannot be targeted"), "{}", stderr);
+
+    // 覆盖整个正文的操作同样不能修改它
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "# Notes", "-o", "replace-body", "-c", "Gone.", "--force", "--no-backup",
+    ]);
+    assert_ne!(code, 0);
+    assert!(stderr.contains("marked <!-- mdp:ignore -->"), "{}", stderr);
+    assert!(fs::read_to_string(&file).unwrap().contains("Hand-maintained."));
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：JSON 输出中的增删行数
+// ============================================================================
+
+#[test]
+fn test_json_change_reports_line_counts() {
+    let file = create_test_file("# Doc\n\nText.\n\n## Next\n\nMore.\n");
+    let file_str = file.to_str().unwrap();
+
+    let (code, stdout, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "# Doc", "-o", "append", "-c", "Added line one.\nAdded line two.", "--force", "--no-backup", "--format", "json",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    // 一个分隔空行加两行内容
+    assert_eq!(json["changes"][0]["additions"], 3);
+    assert_eq!(json["changes"][0]["deletions"], 0);
+
+    let (code, stdout, _) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Next", "-o", "replace", "-c", "Changed.", "--force", "--no-backup", "--format", "json",
+    ]);
+    assert_eq!(code, 0);
+    let json: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(json["changes"][0]["additions"], 1);
+    assert_eq!(json["changes"][0]["deletions"], 1);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：--content-env
+// ============================================================================
+
+#[test]
+fn test_content_from_environment_variable() {
+    let file = create_test_file("# Doc\n\nIntro.\n");
+    let file_str = file.to_str().unwrap();
+    let value = "Line with \"quotes\" and $dollar.\n\n- item";
+
+    let (code, _, stderr) = run_mdp_with_env(
+        &["patch", "-f", file_str, "-H", "# Doc", "-o", "append", "--content-env", "MDP_TEST_CONTENT", "--force", "--no-backup"],
+        &[("MDP_TEST_CONTENT", value)],
+    );
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(fs::read_to_string(&file).unwrap(), format!("# Doc\n\nIntro.\n\n{}\n", value));
+
+    // 变量未设置时报错
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "# Doc", "-o", "append", "--content-env", "MDP_TEST_UNSET_CONTENT",
+    ]);
+    assert_ne!(code, 0);
+    assert!(stderr.contains("Environment variable 'MDP_TEST_UNSET_CONTENT' is not set"), "{}", stderr);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：配置中的正则在读取文件前校验
+// ============================================================================
+
+#[test]
+fn test_invalid_fingerprint_regex_fails_before_file_io() {
+    // 第一个操作的文件不存在：若先读文件，报错会是读取失败
+    let config = write_batch_config(
+        "  - file: /nonexistent/mdp-missing.md\n    heading: [\"# Doc\"]\n    operation: append\n    content: x\n\
+         \x20 - file: /nonexistent/mdp-missing.md\n    heading: [\"# Doc\"]\n    operation: replace\n    fingerprint: \"^(unclosed\"\n    content: y\n",
+    );
+
+    let (code, _, stderr) = run_mdp(&["plan", config.to_str().unwrap()]);
+    assert_ne!(code, 0);
+    assert!(stderr.contains("Operation 2: invalid fingerprint regex '^(unclosed'"), "{}", stderr);
+    assert!(!stderr.contains("Failed to read"), "{}", stderr);
+
+    // 清理
+    let _ = fs::remove_file(&config);
+}
+
+// ============================================================================
+// 测试：文件锁（并发写入）
+// ============================================================================
+
+#[test]
+fn test_concurrent_appends_are_serialized_by_lock() {
+    let file = create_test_file("# Log\n\nStart.\n");
+    let file_str = file.to_str().unwrap().to_string();
+
+    // 同时启动多个进程追加不同的内容，加锁后全部保留
+    let children: Vec<_> = (0..8)
+        .map(|n| {
+            Command::new(mdp_bin())
+                .args(["patch", "-f", &file_str, "-H", "# Log", "-i", "0", "-o", "append"])
+                .args(["-c", &format!("Entry {}.", n), "--force", "--no-backup"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    for child in children {
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    }
+    let result = fs::read_to_string(&file).unwrap();
+    for n in 0..8 {
+        assert!(result.contains(&format!("Entry {}.", n)), "lost entry {}:\n{}", n, result);
+    }
+    let lock = PathBuf::from(format!("{}.mdp.lock", fs::canonicalize(&file).unwrap().display()));
+    assert!(!lock.exists());
+
+    // 残留的锁：超时报错，--no-lock 跳过
+    fs::write(&lock, "0\n").unwrap();
+    let args = ["patch", "-f", file_str.as_str(), "-H", "# Log", "-o", "append", "-c", "Late.", "--force", "--no-backup"];
+    let (code, _, stderr) = run_mdp(&[&args[..], &["--lock-timeout", "0"]].concat());
+    assert_ne!(code, 0);
+    assert!(stderr.contains("Timed out after 0s waiting for"), "{}", stderr);
+    let (code, _, stderr) = run_mdp(&[&args[..], &["--no-lock"]].concat());
+    assert_eq!(code, 0, "{}", stderr);
+    assert!(lock.exists());
+
+    // 清理
+    let _ = fs::remove_file(&lock);
+    let _ = fs::remove_file(&file);
+}
+
+#[test]
+fn test_batch_lock_same_file_two_spellings() {
+    let file = create_test_file("# Log\n\nStart.\n");
+    let dir = file.parent().unwrap();
+    let other = dir.join("..").join(dir.file_name().unwrap()).join(file.file_name().unwrap());
+    let ops = format!(
+        "  - file: {}\n    heading: [\"# Log\"]\n    operation: append\n    content: \"One.\"\n\
+         \x20 - file: {}\n    heading: [\"# Log\"]\n    operation: append\n    content: \"Two.\"\n",
+        file.display(),
+        other.display()
+    );
+    let config = write_batch_config(&ops);
+    let config_str = config.to_str().unwrap();
+
+    // 同一文件的两种写法只加一次锁，不会等待自己
+    let (code, _, stderr) = run_mdp(&["apply", config_str, "--allow-overlap", "--force", "--no-backup", "--lock-timeout", "1"]);
+    assert_eq!(code, 0, "{}", stderr);
+    let lock = PathBuf::from(format!("{}.mdp.lock", fs::canonicalize(&file).unwrap().display()));
+    assert!(!lock.exists());
+
+    // 清理
+    let _ = fs::remove_file(&file);
+    let _ = fs::remove_file(&config);
+}
+
+// ============================================================================
+// 测试：--match-style
+// ============================================================================
+
+#[test]
+fn test_append_match_style_list_item() {
+    let file = create_test_file("# Release\n\n## Changes\n\n1. Faster parser\n2. New flags\n\n## Next\n\nTBD.\n");
+    let file_str = file.to_str().unwrap();
+
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Changes", "-o", "append", "-c", "Better errors", "--match-style", "--force", "--no-backup",
+    ]);
+    assert_eq!(code, 0, "{}", stderr);
+    assert_eq!(
+        fs::read_to_string(&file).unwrap(),
+        "# Release\n\n## Changes\n\n1. Faster parser\n2. New flags\n3. Better errors\n\n## Next\n\nTBD.\n"
+    );
+
+    // 只适用于 append
+    let (code, _, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Changes", "-o", "replace", "-c", "x", "--match-style", "--force",
+    ]);
+    assert_ne!(code, 0);
+    assert!(stderr.contains("--match-style only applies to append operations"), "{}", stderr);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}
+
+// ============================================================================
+// 测试：JSON 格式下的错误输出
+// ============================================================================
+
+#[test]
+fn test_json_format_reports_errors_as_json() {
+    let file = create_test_file("# Doc\n\n## API\n\nText\n");
+    let file_str = file.to_str().unwrap();
+
+    let (code, stdout, stderr) = run_mdp(&[
+        "patch", "-f", file_str, "-H", "## Missing", "--op", "append", "-c", "x", "-i", "1", "--format", "json",
+    ]);
+    assert_eq!(code, 2, "{}", stderr);
+    assert!(stdout.is_empty(), "{}", stdout);
+    let json: serde_json::Value = serde_json::from_str(&stderr).unwrap();
+    assert_eq!(json["success"], false);
+    assert_eq!(json["error"]["code"], "heading_not_found");
+    assert!(json["error"]["message"].as_str().unwrap().contains("Heading not found: ## Missing"));
+    assert_eq!(json["error"]["context"]["file"], file_str);
+    assert_eq!(json["error"]["context"]["index"], 1);
+
+    // jsonl 输出单行；文本格式保持原样
+    let (_, _, stderr) = run_mdp(&["patch", "-f", file_str, "-H", "## Missing", "--op", "append", "-c", "x", "--format", "jsonl"]);
+    assert_eq!(stderr.lines().count(), 1, "{}", stderr);
+    serde_json::from_str::<serde_json::Value>(&stderr).unwrap();
+    let (_, _, stderr) = run_mdp(&["patch", "-f", file_str, "-H", "## Missing", "--op", "append", "-c", "x"]);
+    assert!(stderr.starts_with("Error: Heading not found"), "{}", stderr);
+
+    // 清理
+    let _ = fs::remove_file(&file);
+}